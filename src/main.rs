@@ -2,22 +2,35 @@ mod server;
 mod sql;
 
 use clap::Parser;
+use server::audit::{set_audit_sink, NoopAuditSink, WriterAuditSink};
 use std::{error::Error, net::SocketAddr};
 
 #[derive(Parser)]
 struct ServerOptions {
     #[arg(long, env, default_value_t = 8080)]
     port: u16,
+    /// Compress query and raw responses for clients that accept gzip
+    #[arg(long, env, default_value_t = false)]
+    compress_responses: bool,
+    /// Write every statement sent to clickhouse to this file as JSON lines, or to stdout if set to -
+    #[arg(long, env)]
+    audit_log: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     init_tracing_opentelemetry::tracing_subscriber_ext::init_subscribers()?;
 
-    let router = server::router();
-
     let options = ServerOptions::parse();
 
+    match options.audit_log.as_deref() {
+        Some("-") => set_audit_sink(Box::new(WriterAuditSink::stdout())),
+        Some(path) => set_audit_sink(Box::new(WriterAuditSink::file(path)?)),
+        None => set_audit_sink(Box::new(NoopAuditSink)),
+    }
+
+    let router = server::router(options.compress_responses);
+
     let address: SocketAddr = format!("0.0.0.0:{}", options.port).parse()?;
 
     tracing::info!("Server listening on port {}", address.port());
@@ -1,34 +1,94 @@
 pub mod api;
 
 use axum::{
+    middleware,
     routing::{get, post},
     Router,
 };
 use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
+use tower_http::compression::CompressionLayer;
+pub mod audit;
 mod client;
 mod config;
 mod error;
+mod query_id;
 mod routes;
 use self::{error::ServerError, routes::*};
-pub use config::Config;
+pub use config::{ColumnMask, Config, TableConfig};
+
+pub fn router(compress_responses: bool) -> Router {
+    // query results can be large, so these routes may be compressed
+    let result_routes = with_compression(
+        Router::new()
+            .route("/query", post(post_query))
+            .route("/raw", post(post_raw)),
+        compress_responses,
+    );
+
+    // routes executing statements for the client return the clickhouse query_id used
+    let query_id_routes = result_routes
+        .route("/explain", post(post_explain))
+        .layer(middleware::from_fn(query_id::query_id_scope));
 
-pub fn router() -> Router {
     Router::new()
         .route("/capabilities", get(get_capabilities))
         .route("/schema", get(get_schema))
-        .route("/query", post(post_query))
+        .route("/schema/version", get(get_schema_version))
+        .merge(query_id_routes)
         .route("/mutation", post(post_mutation))
-        .route("/raw", post(post_raw))
-        .route("/explain", post(post_explain))
+        .route("/explain/ast", post(post_explain_ast))
         .fallback(fallback)
+        // make the source name and request id available to audit records
+        .layer(middleware::from_fn(audit::audit_scope))
         // include trace context as header into the response
         .layer(OtelInResponseLayer)
         //start OpenTelemetry trace on incoming request
         .layer(OtelAxumLayer::default())
         .route("/health", get(get_health)) // request processed without span / trace
+        .route("/ready", get(get_ready))
+}
+
+/// gzip responses for clients that advertise support using the accept-encoding header.
+/// Compressed bodies are streamed, so large responses remain chunked
+fn with_compression(router: Router, compress_responses: bool) -> Router {
+    if compress_responses {
+        router.layer(CompressionLayer::new())
+    } else {
+        router
+    }
 }
 
 #[axum_macros::debug_handler]
 async fn fallback(uri: axum::http::Uri) -> impl axum::response::IntoResponse {
     ServerError::NotFound(uri)
 }
+
+#[tokio::test]
+async fn compresses_responses_when_client_accepts_gzip() {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    let router = with_compression(
+        Router::new().route("/query", post(|| async { "rows ".repeat(100) })),
+        true,
+    );
+
+    let response = router
+        .oneshot(
+            Request::post("/query")
+                .header("accept-encoding", "gzip")
+                .body(Body::empty())
+                .expect("valid request"),
+        )
+        .await
+        .expect("infallible");
+
+    assert_eq!(
+        response
+            .headers()
+            .get("content-encoding")
+            .and_then(|value| value.to_str().ok()),
+        Some("gzip"),
+        "compresses the response"
+    );
+}
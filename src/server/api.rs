@@ -5,4 +5,6 @@ pub mod query_request;
 pub mod query_response;
 pub mod raw_request;
 pub mod raw_response;
+pub mod readiness_response;
 pub mod schema_response;
+pub mod schema_version_response;
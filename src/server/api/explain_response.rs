@@ -1,9 +1,64 @@
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
 
+#[skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ExplainResponse {
     /// Lines of the formatted explain plan response
     pub lines: Vec<String>,
     /// The generated query - i.e. SQL for a relational DB
     pub query: String,
+    /// The kind of explanation returned
+    pub mode: ExplainMode,
+    /// The query plan as a tree of steps, if requested
+    pub plan: Option<ExplainPlanNode>,
+    /// The generated query with bound parameters, if requested
+    pub parameterized_query: Option<String>,
+    /// The values bound to each placeholder of the parameterized query
+    pub parameters: Option<IndexMap<String, serde_json::Value>>,
+}
+
+/// A step of a query plan, as returned by EXPLAIN json = 1
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExplainPlanNode {
+    /// The name of the step, such as Expression or ReadFromMergeTree
+    #[serde(alias = "Node Type")]
+    pub step: String,
+    /// What the step does, if clickhouse describes it
+    #[serde(alias = "Description")]
+    pub description: Option<String>,
+    /// The steps providing the input of this step
+    #[serde(alias = "Plans", default)]
+    pub children: Vec<ExplainPlanNode>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExplainMode {
+    /// The query plan
+    #[default]
+    Plan,
+    /// The query execution pipeline
+    Pipeline,
+    /// The estimated number of rows, marks, and parts read from each table
+    Estimate,
+    /// The query after syntax optimizations
+    Syntax,
+    /// The abstract syntax tree of the query
+    Ast,
+}
+
+impl ExplainMode {
+    /// The explain statement prefix for this mode. Plans use a plain EXPLAIN, as clickhouse does by default
+    pub fn statement_prefix(&self) -> &'static str {
+        match self {
+            ExplainMode::Plan => "EXPLAIN",
+            ExplainMode::Pipeline => "EXPLAIN PIPELINE",
+            ExplainMode::Estimate => "EXPLAIN ESTIMATE",
+            ExplainMode::Syntax => "EXPLAIN SYNTAX",
+            ExplainMode::Ast => "EXPLAIN AST",
+        }
+    }
 }
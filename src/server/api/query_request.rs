@@ -5,12 +5,14 @@ use serde_with::skip_serializing_none;
 
 mod binary_array_comparison_operator;
 mod binary_comparison_operator;
+mod computed_field;
 mod scalar_type;
 mod single_column_aggregate_function;
 mod unary_comparison_operator;
 
 pub use binary_array_comparison_operator::BinaryArrayComparisonOperator;
 pub use binary_comparison_operator::BinaryComparisonOperator;
+pub use computed_field::{CalendarPeriod, ComputedField, DateDiffUnit};
 pub use scalar_type::ScalarType;
 pub use single_column_aggregate_function::SingleColumnAggregateFunction;
 pub use unary_comparison_operator::UnaryComparisonOperator;
@@ -27,16 +29,24 @@ pub type ColumnMapping = IndexMap<String, String>;
 #[serde(untagged)]
 pub enum QueryRequest {
     Table {
-        /// If present, a list of columns and values for the columns that the query must be repeated for, applying the column values as a filter for each query.
+        /// If present, a list of columns and values for the columns that the query must be repeated for, applying the column values as a filter for each query. An empty list returns no rows.
         foreach: Option<Vec<ForEach>>,
+        /// If present, additional named foreach collections. The query is repeated for every combination of rows across all foreach collections, applying the column values of each as a filter.
+        foreach_collections: Option<IndexMap<String, Vec<ForEach>>>,
+        /// If true and the query is repeated using foreach, additionally compute the query aggregates over all foreach rows combined, returned as the root aggregates.
+        foreach_total_aggregates: Option<bool>,
         query: Query,
         table: TableName,
         /// The relationships between tables involved in the entire query request
         table_relationships: Vec<TableRelationships>,
     },
     Target {
-        /// If present, a list of columns and values for the columns that the query must be repeated for, applying the column values as a filter for each query.
+        /// If present, a list of columns and values for the columns that the query must be repeated for, applying the column values as a filter for each query. An empty list returns no rows.
         foreach: Option<Vec<ForEach>>,
+        /// If present, additional named foreach collections. The query is repeated for every combination of rows across all foreach collections, applying the column values of each as a filter.
+        foreach_collections: Option<IndexMap<String, Vec<ForEach>>>,
+        /// If true and the query is repeated using foreach, additionally compute the query aggregates over all foreach rows combined, returned as the root aggregates.
+        foreach_total_aggregates: Option<bool>,
         query: Query,
         target: Target,
         /// The relationships between tables involved in the entire query request
@@ -71,16 +81,50 @@ pub struct Query {
     pub aggregates_limit: Option<Number>,
     /// Fields of the query
     pub fields: Option<Fields>,
+    /// Optionally group rows by the values of these fields, returning one row per group. Fields are referred to by their alias, and must be column or computed fields. Every other field must then be an aggregate field
+    pub group_by: Option<Vec<String>>,
     /// Optionally limit to N results
     pub limit: Option<Number>,
     /// Optionally offset from the Nth result. This applies to both row and aggregation results.
     pub offset: Option<Number>,
     /// Optionally order the results by the value of one or more fields
     pub order_by: Option<OrderBy>,
+    /// Optionally sample the rows of the table. Requires the table to declare a sampling key
+    pub sample: Option<Sample>,
+    /// Parameters bound to the table when it is a parameterized view. The key of the map is the view parameter name
+    pub view_parameters: Option<ViewParameters>,
+    /// If true, relationship fields that are null for a row are omitted from that row, rather than returned as explicit nulls
+    pub omit_null_relationships: Option<bool>,
+    /// If true, the response includes a cursor to request the next page with. The query must have a limit, and be ordered by columns that are also returned as fields. Only applies to the root query
+    pub paginate: Option<bool>,
+    /// A cursor from a previous response, to continue after the last row of that page. Cannot be combined with an offset. Only applies to the root query
+    pub cursor: Option<String>,
+    /// The values of the order columns of the last row seen, to continue after that row without a cursor. The query must have a limit, and be ordered by columns that are also returned as fields, and cannot be combined with an offset. Only applies to the root query
+    pub after: Option<Vec<ScalarValue>>,
     #[serde(rename = "where")]
     pub selection: Option<Expression>,
+    /// Optionally filter rows with PREWHERE, before the other columns of the table are read. Rows must match both this and `where`. Only columns of the queried table may be compared
+    pub prewhere: Option<Expression>,
+    /// Optionally filter aggregate results, applied after aggregation. Columns refer to aggregates of this query by their alias
+    pub having: Option<Expression>,
 }
 
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sample {
+    /// The fraction of rows to sample, between 0 and 1, or an approximate number of rows if greater than 1
+    pub ratio: Number,
+    /// Optionally offset the sample, as a fraction between 0 and 1. Queries with the same ratio and offset sample the same rows
+    pub offset: Option<Number>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ViewParameter {
+    pub value: ScalarValue,
+    pub value_type: ScalarType,
+}
+
+pub type ViewParameters = IndexMap<String, ViewParameter>;
 pub type Aggregates = IndexMap<String, Aggregate>;
 pub type Fields = IndexMap<String, Field>;
 
@@ -104,6 +148,9 @@ pub enum Relationship {
     Table {
         /// A mapping between columns on the source table to columns on the target table
         column_mapping: ColumnMapping,
+        /// If true, rows are related when any of the mapped columns match, rather than all of them. Intended for polymorphic associations.
+        /// Such relationships cannot be ordered by
+        match_any_column: Option<bool>,
         relationship_type: RelationshipType,
         /// The target of the relationship.
         /// For backwards compatibility with previous versions of dc-api we allow the alternative property name "target_table" and allow table names to be parsed into Target::TTable
@@ -112,6 +159,9 @@ pub enum Relationship {
     Target {
         /// A mapping between columns on the source table to columns on the target table
         column_mapping: ColumnMapping,
+        /// If true, rows are related when any of the mapped columns match, rather than all of them. Intended for polymorphic associations.
+        /// Such relationships cannot be ordered by
+        match_any_column: Option<bool>,
         relationship_type: RelationshipType,
         /// The target of the relationship.
         /// For backwards compatibility with previous versions of dc-api we allow the alternative property name "target_table" and allow table names to be parsed into Target::TTable
@@ -142,6 +192,34 @@ pub struct OrderByElement {
     pub target: OrderByTarget,
     /// The relationship path from the current query table to the table that contains the target to order by. This is always non-empty for aggregate order by targets
     pub target_path: Vec<String>,
+    /// If present, rows are added for missing values between the ordered values of the column, with default values for all other fields.
+    /// Only applies to columns of the root table that are also returned as fields, when the query is not repeated using foreach
+    pub fill: Option<OrderByFill>,
+    /// Where null values are placed. If absent, nulls are last when ascending and first when descending
+    pub nulls: Option<OrderByNulls>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderByNulls {
+    First,
+    Last,
+    /// Leave null placement to the database
+    Default,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderByFill {
+    /// The first value to fill from. Defaults to the first ordered value
+    pub from: Option<ScalarValue>,
+    /// The value to fill up to, exclusive. Defaults to the last ordered value
+    pub to: Option<ScalarValue>,
+    /// The type of the from and to values
+    pub value_type: ScalarType,
+    /// The distance between filled values, in units if present. Defaults to 1, or 1 day for dates
+    pub step: Option<Number>,
+    pub unit: Option<DateDiffUnit>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -165,6 +243,22 @@ pub enum OrderByTarget {
     },
     #[serde(rename = "column")]
     Column { column: String },
+    /// Order by a function of a column. Only the functions listed in OrderByFunction are allowed
+    #[serde(rename = "expression")]
+    Expression {
+        column: String,
+        function: OrderByFunction,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderByFunction {
+    Lower,
+    Upper,
+    Length,
+    LengthUtf8,
+    Abs,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -204,10 +298,35 @@ pub enum Expression {
         operator: BinaryComparisonOperator,
         value: ComparisonValue,
     },
+    /// Matches rows where the column is between the low and high values, inclusive
+    #[serde(rename = "between")]
+    Between {
+        column: ComparisonColumn,
+        low: ComparisonValue,
+        high: ComparisonValue,
+    },
+    #[serde(rename = "not_between")]
+    NotBetween {
+        column: ComparisonColumn,
+        low: ComparisonValue,
+        high: ComparisonValue,
+    },
     #[serde(rename = "not")]
     Not { expression: Box<Expression> },
     #[serde(rename = "and")]
     And { expressions: Vec<Expression> },
+    /// A raw sql predicate, only accepted when enabled in config. Arguments are bound to `{0}`, `{1}`, etc. in the sql, and each must be used
+    #[serde(rename = "raw_sql")]
+    RawSql {
+        sql: String,
+        arguments: Option<Vec<RawSqlArgument>>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RawSqlArgument {
+    pub value: ScalarValue,
+    pub value_type: ScalarType,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -229,6 +348,9 @@ pub enum ComparisonValue {
     },
     #[serde(rename = "column")]
     AnotherColumnComparison { column: ComparisonColumn },
+    /// Compare against a server side time anchor such as `now` or `today`, evaluated by clickhouse at query time
+    #[serde(rename = "server_time")]
+    ServerTimeAnchor { anchor: String },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -239,31 +361,95 @@ pub struct ComparisonColumn {
     pub name: String,
     /// The relationship path from the current query table to the table that contains the specified column. Empty Vec means the current query table.
     pub path: Option<Vec<String>>,
+    /// Timezone of a DateTime or DateTime64 column. If set, values compared against the column are read in this timezone
+    pub timezone: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Field {
     #[serde(rename = "relationship")]
-    Relationship { query: Query, relationship: String },
+    Relationship {
+        /// If present, the relationship is only included for rows matching this expression, and is null otherwise
+        include_when: Option<Expression>,
+        query: Query,
+        relationship: String,
+    },
     #[serde(rename = "column")]
     Column {
         column: String,
         column_type: ScalarType,
+        /// If true, leading and trailing whitespace is trimmed from string values
+        trim: Option<bool>,
+        #[serde(flatten)]
+        type_parameters: ColumnTypeParameters,
     },
+    #[serde(rename = "computed")]
+    Computed { function: ComputedField },
+    /// An array of the values of one column of all related rows, such as their ids. Cheaper than a relationship field when only that column is needed
+    #[serde(rename = "related_column")]
+    RelatedColumn {
+        relationship: String,
+        column: String,
+        column_type: ScalarType,
+    },
+    /// An aggregate of the rows of each group. Only valid in queries with a `group_by`
+    #[serde(rename = "aggregate")]
+    Aggregate { aggregate: Aggregate },
+}
+
+/// Parameters of a column type that its scalar type does not carry
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ColumnTypeParameters {
+    /// Precision of a Decimal column. Decimals are returned as strings unless both precision and scale are known
+    pub precision: Option<u32>,
+    /// Scale of a Decimal column
+    pub scale: Option<u32>,
+    /// Timezone of a DateTime or DateTime64 column, such as `UTC`. Values are returned in the server timezone if absent
+    pub timezone: Option<String>,
+    /// Length of a FixedString column. FixedString values are returned as strings if absent
+    pub length: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Aggregate {
     #[serde(rename = "column_count")]
-    ColumnCount { column: String, distinct: bool },
+    ColumnCount {
+        column: String,
+        distinct: bool,
+        /// Further columns counted together with `column`, only for distinct counts
+        additional_columns: Option<Vec<String>>,
+        /// If false, distinct counts are approximated with `uniq`. If true, they are always exact. Defaults to exact unless the table is larger than the configured threshold
+        exact: Option<bool>,
+    },
     #[serde(rename = "single_column")]
     SingleColumn {
         column: String,
         function: SingleColumnAggregateFunction,
         result_type: ScalarType,
+        /// Parameters of the aggregated column type
+        #[serde(flatten)]
+        type_parameters: ColumnTypeParameters,
+    },
+    /// The values of a column as an array. Null values are left out
+    #[serde(rename = "column_array")]
+    ColumnArray {
+        column: String,
+        column_type: ScalarType,
+        /// Optionally keep only the first N values
+        limit: Option<u64>,
+        /// Parameters of the aggregated column type
+        #[serde(flatten)]
+        type_parameters: ColumnTypeParameters,
     },
     #[serde(rename = "star_count")]
     StarCount,
+    /// Count the rows matching an expression. The query selection applies first, so only rows matching both are counted
+    #[serde(rename = "conditional_count")]
+    ConditionalCount {
+        #[serde(rename = "where")]
+        selection: Expression,
+    },
 }
@@ -8,4 +8,31 @@ pub enum BinaryComparisonOperator {
     Equal,
     GreaterThan,
     GreaterThanOrEqual,
+    /// Inequality, emitted directly rather than as a negated equality
+    #[serde(rename = "_neq")]
+    NotEqual,
+    /// Compare the length of an array column
+    #[serde(rename = "_length_eq")]
+    LengthEqual,
+    #[serde(rename = "_length_gt")]
+    LengthGreaterThan,
+    #[serde(rename = "_length_gte")]
+    LengthGreaterThanOrEqual,
+    #[serde(rename = "_length_lt")]
+    LengthLessThan,
+    #[serde(rename = "_length_lte")]
+    LengthLessThanOrEqual,
+    /// Check whether a bitmap column contains an integer
+    #[serde(rename = "_bitmap_contains")]
+    BitmapContains,
+    /// Case insensitive equality of a string column
+    #[serde(rename = "_ieq")]
+    CaseInsensitiveEqual,
+    /// Match a string column against a regular expression. The pattern is passed verbatim to clickhouse's re2 engine,
+    /// so it matches anywhere in the value unless anchored with ^ and $
+    #[serde(rename = "_regex")]
+    Regex,
+    /// Negation of `_regex`
+    #[serde(rename = "_nregex")]
+    NotRegex,
 }
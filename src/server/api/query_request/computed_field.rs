@@ -0,0 +1,135 @@
+use std::num::NonZeroU64;
+
+use serde::{Deserialize, Serialize};
+
+use super::ScalarType;
+
+/// A field computed from the columns of the current table, rather than read from a single column
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ComputedField {
+    /// The rank of the row within its partition, following the query's order. Ties leave gaps in the sequence
+    #[serde(rename = "rank")]
+    Rank,
+    /// The rank of the row within its partition, following the query's order. Ties do not leave gaps in the sequence
+    #[serde(rename = "dense_rank")]
+    DenseRank,
+    /// The 1-based index of the first occurrence of needle within the column, or 0 if not found
+    #[serde(rename = "position", alias = "locate")]
+    Position { column: String, needle: String },
+    /// The start of the calendar period containing the date or datetime column, as a date
+    #[serde(rename = "start_of_period")]
+    StartOfPeriod {
+        column: String,
+        period: CalendarPeriod,
+    },
+    /// The number of unit boundaries crossed between the start and end date or datetime columns
+    #[serde(rename = "date_diff")]
+    DateDiff {
+        unit: DateDiffUnit,
+        start_column: String,
+        end_column: String,
+    },
+    /// The number of integers in a bitmap column
+    #[serde(rename = "bitmap_cardinality")]
+    BitmapCardinality { column: String },
+    /// True if the date or datetime column is more than threshold units after its value in the previous row,
+    /// following the query's order. False for the first row of each partition
+    #[serde(rename = "gap_exceeds")]
+    GapExceeds {
+        column: String,
+        unit: DateDiffUnit,
+        threshold: u64,
+    },
+    /// The element at the 1-based index of an array column, or null if the array is shorter than the index
+    #[serde(rename = "array_element")]
+    ArrayElement {
+        column: String,
+        index: NonZeroU64,
+        element_type: ScalarType,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CalendarPeriod {
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DateDiffUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+impl DateDiffUnit {
+    /// The unit as understood by clickhouse
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DateDiffUnit::Second => "second",
+            DateDiffUnit::Minute => "minute",
+            DateDiffUnit::Hour => "hour",
+            DateDiffUnit::Day => "day",
+            DateDiffUnit::Week => "week",
+            DateDiffUnit::Month => "month",
+            DateDiffUnit::Quarter => "quarter",
+            DateDiffUnit::Year => "year",
+        }
+    }
+    /// The clickhouse function returning an interval of this unit
+    pub fn interval_function(&self) -> &'static str {
+        match self {
+            DateDiffUnit::Second => "toIntervalSecond",
+            DateDiffUnit::Minute => "toIntervalMinute",
+            DateDiffUnit::Hour => "toIntervalHour",
+            DateDiffUnit::Day => "toIntervalDay",
+            DateDiffUnit::Week => "toIntervalWeek",
+            DateDiffUnit::Month => "toIntervalMonth",
+            DateDiffUnit::Quarter => "toIntervalQuarter",
+            DateDiffUnit::Year => "toIntervalYear",
+        }
+    }
+}
+
+impl ComputedField {
+    /// The scalar type of the computed value
+    pub fn result_type(&self) -> ScalarType {
+        match self {
+            ComputedField::Rank
+            | ComputedField::DenseRank
+            | ComputedField::Position { .. }
+            | ComputedField::BitmapCardinality { .. } => ScalarType::UInt64,
+            ComputedField::StartOfPeriod { .. } => ScalarType::Date,
+            ComputedField::DateDiff { .. } => ScalarType::Int64,
+            ComputedField::GapExceeds { .. } => ScalarType::Bool,
+            ComputedField::ArrayElement { element_type, .. } => element_type.clone(),
+        }
+    }
+    /// The columns referenced by this computed field
+    pub fn columns_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            ComputedField::Rank | ComputedField::DenseRank => vec![],
+            ComputedField::Position { column, .. }
+            | ComputedField::StartOfPeriod { column, .. }
+            | ComputedField::BitmapCardinality { column }
+            | ComputedField::GapExceeds { column, .. }
+            | ComputedField::ArrayElement { column, .. } => vec![column],
+            ComputedField::DateDiff {
+                start_column,
+                end_column,
+                ..
+            } => vec![start_column, end_column],
+        }
+    }
+}
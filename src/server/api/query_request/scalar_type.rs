@@ -98,5 +98,7 @@ pub enum ScalarType {
     MinDate32,
     MinDateTime,
     MinDateTime64,
+    /// AggregateFunction(groupBitmap, ...) columns, storing a set of integers as a roaring bitmap
+    Bitmap,
     Unknown,
 }
@@ -1,6 +1,8 @@
+use std::hash::{Hash, Hasher};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum SingleColumnAggregateFunction {
     Max,
@@ -17,4 +19,42 @@ pub enum SingleColumnAggregateFunction {
     SumMerge,
     MinMerge,
     MaxMerge,
+    /// Sums the values of a map column per key
+    SumMap,
+    /// Estimates the number of distinct values using a theta sketch
+    UniqTheta,
+    Median,
+    /// The value at the given level, between 0 and 1. For example, 0.95 for the 95th percentile
+    Quantile {
+        level: f64,
+    },
+}
+
+// quantile levels are floats, so equality and hashing compare their bits.
+// adding zero turns -0.0 into 0.0, so the two are equal and hash the same
+fn level_bits(level: f64) -> u64 {
+    (level + 0.0).to_bits()
+}
+
+impl PartialEq for SingleColumnAggregateFunction {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                SingleColumnAggregateFunction::Quantile { level },
+                SingleColumnAggregateFunction::Quantile { level: other_level },
+            ) => level_bits(*level) == level_bits(*other_level),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for SingleColumnAggregateFunction {}
+
+impl Hash for SingleColumnAggregateFunction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let SingleColumnAggregateFunction::Quantile { level } = self {
+            level_bits(*level).hash(state);
+        }
+    }
 }
@@ -4,4 +4,7 @@ use serde::{Deserialize, Serialize};
 #[serde(rename_all = "snake_case")]
 pub enum UnaryComparisonOperator {
     IsNull,
+    /// Emitted directly, rather than as a negated is null
+    #[serde(rename = "_is_not_null")]
+    IsNotNull,
 }
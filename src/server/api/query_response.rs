@@ -3,6 +3,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_with::skip_serializing_none;
 
+use super::query_request::{Field, Query};
+
 #[skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct QueryResponse {
@@ -10,6 +12,96 @@ pub struct QueryResponse {
     aggregates: Option<IndexMap<String, Value>>,
     /// The rows returned by the query, corresponding to the query's fields
     rows: Option<Vec<IndexMap<String, Option<RowFieldValue>>>>,
+    /// An opaque cursor to request the next page with, present when the query paginates and more rows may follow
+    cursor: Option<String>,
+    /// Warnings about how the query was run, such as reads that may include duplicate rows
+    warnings: Option<Vec<String>>,
+}
+
+impl QueryResponse {
+    pub fn rows(&self) -> Option<&Vec<IndexMap<String, Option<RowFieldValue>>>> {
+        self.rows.as_ref()
+    }
+    pub fn set_cursor(&mut self, cursor: Option<String>) {
+        self.cursor = cursor;
+    }
+    pub fn set_warnings(&mut self, warnings: Vec<String>) {
+        self.warnings = (!warnings.is_empty()).then_some(warnings);
+    }
+    /// Replace conditional relationships, returned as an array of at most one object since tuples cannot be null,
+    /// with that object, or null if the row did not match the condition. Applies to nested relationships as well
+    pub fn unwrap_conditional_relationships(&mut self, query: &Query) {
+        let (Some(rows), Some(fields)) = (self.rows.as_mut(), query.fields.as_ref()) else {
+            return;
+        };
+
+        for row in rows.iter_mut() {
+            for (alias, field) in fields {
+                let Field::Relationship {
+                    query,
+                    include_when,
+                    ..
+                } = field
+                else {
+                    continue;
+                };
+                let Some(value) = row.get_mut(alias) else {
+                    continue;
+                };
+                if include_when.is_some() {
+                    *value = match value.take() {
+                        Some(RowFieldValue::ColumnFieldValue(Value::Array(objects))) => objects
+                            .into_iter()
+                            .next()
+                            .and_then(|object| serde_json::from_value(object).ok())
+                            .map(RowFieldValue::RelationshipFieldValue),
+                        value => value,
+                    };
+                }
+                if let Some(RowFieldValue::RelationshipFieldValue(response)) = value {
+                    response.unwrap_conditional_relationships(query);
+                }
+            }
+        }
+    }
+    /// Remove null relationship fields from rows of queries that ask for them to be omitted, including nested relationships
+    pub fn omit_null_relationships(&mut self, query: &Query) {
+        let (Some(rows), Some(fields)) = (self.rows.as_mut(), query.fields.as_ref()) else {
+            return;
+        };
+        let omit = query.omit_null_relationships.unwrap_or(false);
+
+        for row in rows.iter_mut() {
+            for (alias, field) in fields {
+                let Field::Relationship { query, .. } = field else {
+                    continue;
+                };
+                match row.get_mut(alias) {
+                    Some(Some(RowFieldValue::RelationshipFieldValue(response))) => {
+                        response.omit_null_relationships(query)
+                    }
+                    Some(None) if omit => {
+                        row.shift_remove(alias);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    /// Sort the keys of aggregates and rows alphabetically, including nested relationships
+    pub fn sort_keys(&mut self) {
+        if let Some(aggregates) = self.aggregates.as_mut() {
+            aggregates.sort_keys();
+        }
+        for row in self.rows.iter_mut().flatten() {
+            row.sort_keys();
+            for value in row.values_mut() {
+                if let Some(RowFieldValue::RelationshipFieldValue(response)) = value {
+                    response.sort_keys();
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -18,3 +110,109 @@ pub enum RowFieldValue {
     RelationshipFieldValue(QueryResponse),
     ColumnFieldValue(Value),
 }
+
+#[test]
+fn omits_null_relationships_only_when_requested() {
+    let response = serde_json::json!({
+        "rows": [{ "name": "Alice", "manager": null }]
+    });
+    let query = |omit_null_relationships: bool| -> Query {
+        serde_json::from_value(serde_json::json!({
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String" },
+                "manager": {
+                    "type": "relationship",
+                    "relationship": "manager",
+                    "query": {
+                        "fields": {
+                            "name": { "type": "column", "column": "name", "column_type": "String" }
+                        }
+                    }
+                }
+            },
+            "omit_null_relationships": omit_null_relationships
+        }))
+        .expect("valid query")
+    };
+
+    let mut explicit_null: QueryResponse =
+        serde_json::from_value(response.clone()).expect("valid response");
+    explicit_null.omit_null_relationships(&query(false));
+    assert_eq!(
+        serde_json::to_value(explicit_null).expect("serializable"),
+        serde_json::json!({ "rows": [{ "name": "Alice", "manager": null }] }),
+        "keeps explicit nulls by default"
+    );
+
+    let mut omitted: QueryResponse = serde_json::from_value(response).expect("valid response");
+    omitted.omit_null_relationships(&query(true));
+    assert_eq!(
+        serde_json::to_value(omitted).expect("serializable"),
+        serde_json::json!({ "rows": [{ "name": "Alice" }] }),
+        "omits null relationships when requested"
+    );
+}
+
+#[test]
+fn returns_excluded_conditional_relationships_as_null() {
+    let query: Query = serde_json::from_value(serde_json::json!({
+        "fields": {
+            "name": { "type": "column", "column": "name", "column_type": "String" },
+            "articles": {
+                "type": "relationship",
+                "relationship": "articles",
+                "include_when": {
+                    "type": "unary_op",
+                    "operator": "is_null",
+                    "column": { "name": "banned_at", "column_type": "DateTime" }
+                },
+                "query": {
+                    "fields": {
+                        "title": { "type": "column", "column": "title", "column_type": "String" }
+                    }
+                }
+            }
+        }
+    }))
+    .expect("valid query");
+    let mut response: QueryResponse = serde_json::from_value(serde_json::json!({
+        "rows": [
+            { "name": "Alice", "articles": [{ "rows": [{ "title": "Hello" }] }] },
+            { "name": "Bob", "articles": [] }
+        ]
+    }))
+    .expect("valid response");
+
+    response.unwrap_conditional_relationships(&query);
+    assert_eq!(
+        serde_json::to_value(response).expect("serializable"),
+        serde_json::json!({
+            "rows": [
+                { "name": "Alice", "articles": { "rows": [{ "title": "Hello" }] } },
+                { "name": "Bob", "articles": null }
+            ]
+        }),
+        "unwraps included relationships, and returns excluded ones as null"
+    );
+}
+
+#[test]
+fn sorts_keys_only_when_requested() {
+    let response =
+        r#"{"rows":[{"title":"Hello","id":1,"author":{"rows":[{"name":"Alice","age":30}]}}]}"#;
+
+    let insertion_order: QueryResponse = serde_json::from_str(response).expect("valid response");
+    assert_eq!(
+        serde_json::to_string(&insertion_order).expect("serializable"),
+        response,
+        "keeps the order of fields by default"
+    );
+
+    let mut sorted: QueryResponse = serde_json::from_str(response).expect("valid response");
+    sorted.sort_keys();
+    assert_eq!(
+        serde_json::to_string(&sorted).expect("serializable"),
+        r#"{"rows":[{"author":{"rows":[{"age":30,"name":"Alice"}]},"id":1,"title":"Hello"}]}"#,
+        "sorts keys of rows and nested relationships"
+    );
+}
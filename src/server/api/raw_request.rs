@@ -1,7 +1,14 @@
-use serde::{Serialize, Deserialize};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RawRequest {
     /// A string representing a raw query
     pub query: String,
+    /// Optional values for `{name:Type}` placeholders in the query, substituted by clickhouse.
+    /// Values may be strings, numbers, booleans, or null
+    pub parameters: Option<IndexMap<String, serde_json::Value>>,
+    /// Optional clickhouse settings for this query, added to the settings of the source.
+    /// Settings the source or connector already set cannot be overridden
+    pub settings: Option<IndexMap<String, String>>,
 }
@@ -0,0 +1,18 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadinessResponse {
+    /// The status of each checked source, by source name
+    pub sources: IndexMap<String, SourceReadiness>,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SourceReadiness {
+    /// True if the source answered a query
+    pub ready: bool,
+    /// Why the source is not ready
+    pub message: Option<String>,
+}
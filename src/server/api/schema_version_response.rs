@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SchemaVersionResponse {
+    /// A hash of the tables, columns, and column types of the database. Changes whenever the schema changes
+    pub version: String,
+}
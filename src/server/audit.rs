@@ -0,0 +1,261 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    sync::{Mutex, OnceLock},
+};
+
+use axum::{
+    http::{HeaderName, Request},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+use super::config::Config;
+
+/// A statement about to be sent to clickhouse
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord<'a> {
+    pub statement: &'a str,
+    pub source_name: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+    /// If true, the source asked for values in the statement to be redacted before they are stored
+    pub redact: bool,
+}
+
+/// Receives every statement sent to clickhouse, for audit logging
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// The default sink, which discards all records
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {
+    fn record(&self, _record: &AuditRecord) {}
+}
+
+/// Writes each record as a line of JSON
+pub struct WriterAuditSink {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl WriterAuditSink {
+    pub fn stdout() -> Self {
+        Self {
+            writer: Mutex::new(Box::new(io::stdout())),
+        }
+    }
+    /// Appends records to the file, creating it if needed
+    pub fn file(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Mutex::new(Box::new(file)),
+        })
+    }
+}
+
+impl AuditSink for WriterAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        let statement = if record.redact {
+            redact_statement(record.statement)
+        } else {
+            record.statement.to_owned()
+        };
+        let record = AuditRecord {
+            statement: &statement,
+            ..record.clone()
+        };
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // audit logging must not fail the query, so write errors are only traced
+        if let Err(err) = serde_json::to_writer(&mut *writer, &record)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(writer))
+        {
+            tracing::error!("Failed to write audit record: {}", err);
+        }
+    }
+}
+
+/// replace the contents of string literals, which may hold user supplied values
+fn redact_statement(statement: &str) -> String {
+    let mut redacted = String::with_capacity(statement.len());
+    let mut chars = statement.chars();
+    while let Some(char) = chars.next() {
+        redacted.push(char);
+        if char == '\'' {
+            while let Some(char) = chars.next() {
+                match char {
+                    '\\' => {
+                        chars.next();
+                    }
+                    '\'' => break,
+                    _ => {}
+                }
+            }
+            redacted.push_str("?'");
+        }
+    }
+    redacted
+}
+
+static AUDIT_SINK: OnceLock<Box<dyn AuditSink>> = OnceLock::new();
+
+/// Set the sink all statements are recorded to. Only the first sink set is used
+pub fn set_audit_sink(sink: Box<dyn AuditSink>) {
+    if AUDIT_SINK.set(sink).is_err() {
+        tracing::warn!("Audit sink already set, ignoring");
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AuditScope {
+    source_name: Option<String>,
+    request_id: Option<String>,
+}
+
+tokio::task_local! {
+    static AUDIT_SCOPE: AuditScope;
+}
+
+static SOURCE_HEADER: HeaderName = HeaderName::from_static("x-hasura-dataconnector-sourcename");
+static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// middleware making the source name and request id available to audit records of statements executed by the request
+pub async fn audit_scope<B>(request: Request<B>, next: Next<B>) -> Response {
+    let header = |name: &HeaderName| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+    };
+    let scope = AuditScope {
+        source_name: header(&SOURCE_HEADER),
+        request_id: header(&REQUEST_ID_HEADER),
+    };
+    AUDIT_SCOPE.scope(scope, next.run(request)).await
+}
+
+/// record a statement to the audit sink, if one is set
+pub fn audit(statement: &str, config: &Config) {
+    if let Some(sink) = AUDIT_SINK.get() {
+        record_statement(sink.as_ref(), statement, config);
+    }
+}
+
+/// record a statement to the sink, along with the source name and request id of the current audit scope
+fn record_statement(sink: &dyn AuditSink, statement: &str, config: &Config) {
+    let scope = AUDIT_SCOPE.try_with(|scope| scope.clone()).ok();
+    sink.record(&AuditRecord {
+        statement,
+        source_name: scope
+            .as_ref()
+            .and_then(|scope| scope.source_name.as_deref()),
+        request_id: scope.as_ref().and_then(|scope| scope.request_id.as_deref()),
+        redact: config.redact_audit_log.unwrap_or(false),
+    });
+}
+
+#[test]
+fn records_statements_with_their_scope() {
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct RecordingSink(Arc<Mutex<Vec<String>>>);
+
+    impl AuditSink for RecordingSink {
+        fn record(&self, record: &AuditRecord) {
+            self.0.lock().expect("not poisoned").push(format!(
+                "{} {} {} {}",
+                record.source_name.unwrap_or_default(),
+                record.request_id.unwrap_or_default(),
+                record.redact,
+                record.statement
+            ));
+        }
+    }
+
+    // records to its own sink, rather than the sink shared by every test in the process
+    let records = Arc::new(Mutex::new(vec![]));
+    let sink = RecordingSink(records.clone());
+
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://127.0.0.1:9",
+        "username": "default",
+        "password": "",
+        "redact_audit_log": true
+    }))
+    .expect("valid config");
+    let scope = AuditScope {
+        source_name: Some("analytics".to_owned()),
+        request_id: Some("request-1".to_owned()),
+    };
+    AUDIT_SCOPE.sync_scope(scope, || {
+        record_statement(&sink, "SELECT 'secret'", &config)
+    });
+    record_statement(&sink, "SELECT 1", &config);
+
+    assert_eq!(
+        *records.lock().expect("not poisoned"),
+        vec![
+            "analytics request-1 true SELECT 'secret'".to_owned(),
+            "  true SELECT 1".to_owned()
+        ],
+        "records the statement, leaving redaction to the sink"
+    );
+    assert_eq!(redact_statement("SELECT 'se\\'cret', 1"), "SELECT '?', 1");
+}
+
+#[tokio::test]
+async fn records_statements_executed_within_a_scope() {
+    use axum::{routing::post, Router};
+
+    use super::client::{execute_query, stand_in_clickhouse};
+
+    // the sink is shared by every test in the process, so only records of this test's scope are kept
+    static RECORDS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    struct ScopedSink;
+
+    impl AuditSink for ScopedSink {
+        fn record(&self, record: &AuditRecord) {
+            if record.request_id == Some("audited-request") {
+                RECORDS.lock().expect("not poisoned").push(format!(
+                    "{} {}",
+                    record.source_name.unwrap_or_default(),
+                    record.statement
+                ));
+            }
+        }
+    }
+    set_audit_sink(Box::new(ScopedSink));
+
+    // stands in for clickhouse, answering any statement
+    let clickhouse = Router::new().route(
+        "/",
+        post(|| async { r#"{ "meta": [], "data": [], "rows": 0, "statistics": {} }"# }),
+    );
+    let config: Config =
+        serde_json::from_value(stand_in_clickhouse(clickhouse)).expect("valid config");
+
+    let scope = AuditScope {
+        source_name: Some("analytics".to_owned()),
+        request_id: Some("audited-request".to_owned()),
+    };
+    AUDIT_SCOPE
+        .scope(
+            scope,
+            execute_query::<serde_json::Value>(&config, "SELECT 1 FORMAT JSON;"),
+        )
+        .await
+        .expect("query succeeds");
+
+    assert_eq!(
+        *RECORDS.lock().expect("not poisoned"),
+        vec!["analytics SELECT 1 FORMAT JSON;".to_owned()],
+        "records statements sent to clickhouse with the scope of the request executing them"
+    );
+}
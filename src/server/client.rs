@@ -23,24 +23,212 @@
 //     Ok(response)
 // }
 
-use std::error::Error;
+use std::{collections::HashMap, error::Error, sync::Mutex, time::Duration};
 
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{de::DeserializeOwned, Deserialize};
 
-use super::config::Config;
+use super::{audit::audit, config::Config, query_id::current_query_id};
+
+/// the connection settings of a source, sources with the same settings share a client
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientSettings {
+    pool_max_idle_connections: Option<usize>,
+    pool_idle_timeout_seconds: Option<u64>,
+    connect_timeout_seconds: Option<u64>,
+    http_compression: bool,
+    https_only: bool,
+    ca_cert_path: Option<String>,
+    accept_invalid_certs: bool,
+}
+
+impl ClientSettings {
+    fn new(config: &Config) -> Self {
+        Self {
+            pool_max_idle_connections: config.pool_max_idle_connections,
+            pool_idle_timeout_seconds: config.pool_idle_timeout_seconds,
+            connect_timeout_seconds: config.connect_timeout_seconds,
+            http_compression: config.http_compression.unwrap_or(false),
+            https_only: config.tls.as_ref().is_some_and(|tls| tls.enabled),
+            ca_cert_path: config.tls.as_ref().and_then(|tls| tls.ca_cert_path.clone()),
+            accept_invalid_certs: config
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.accept_invalid_certs)
+                .unwrap_or(false),
+        }
+    }
+    fn build(&self) -> Result<reqwest::Client, Box<dyn Error>> {
+        // only ask for compressed responses when enabled, decompressing them as they are read
+        let builder = reqwest::Client::builder().gzip(self.http_compression);
+        let builder = match self.pool_max_idle_connections {
+            Some(max_idle) => builder.pool_max_idle_per_host(max_idle),
+            None => builder,
+        };
+        let builder = match self.pool_idle_timeout_seconds {
+            Some(seconds) => builder.pool_idle_timeout(Duration::from_secs(seconds)),
+            None => builder,
+        };
+        let builder = match self.connect_timeout_seconds {
+            Some(seconds) => builder.connect_timeout(Duration::from_secs(seconds)),
+            None => builder,
+        };
+        let builder = match &self.ca_cert_path {
+            Some(path) => {
+                let pem = std::fs::read(path)
+                    .map_err(|err| format!("Could not read ca certificate at {}: {}", path, err))?;
+                let certificate = reqwest::Certificate::from_pem(&pem)
+                    .map_err(|err| format!("Invalid ca certificate at {}: {}", path, err))?;
+                builder.add_root_certificate(certificate)
+            }
+            None => builder,
+        };
+        let client = builder
+            .https_only(self.https_only)
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .build()?;
+        Ok(client)
+    }
+}
+
+static CLIENTS: Mutex<Option<HashMap<ClientSettings, reqwest::Client>>> = Mutex::new(None);
+
+/// a client shared by all requests with the same settings, so connections to clickhouse are kept alive and reused.
+/// clients that fail to build are not kept, so fixing a certificate takes effect on the next request
+fn http_client(config: &Config) -> Result<reqwest::Client, Box<dyn Error>> {
+    let settings = ClientSettings::new(config);
+    let mut clients = CLIENTS.lock().expect("clients lock is not poisoned");
+    let clients = clients.get_or_insert_with(HashMap::new);
+    if let Some(client) = clients.get(&settings) {
+        return Ok(client.clone());
+    }
+    let client = settings.build()?;
+    clients.insert(settings, client.clone());
+    Ok(client)
+}
+
+/// the url of the source, using https when tls is enabled
+fn source_url(config: &Config) -> String {
+    let tls_enabled = config.tls.as_ref().is_some_and(|tls| tls.enabled);
+    match config.url.strip_prefix("http://") {
+        Some(rest) if tls_enabled => format!("https://{}", rest),
+        _ => config.url.to_owned(),
+    }
+}
+
+/// settings are sent as url parameters, so names must be plain identifiers that do not collide with
+/// the parameters the connector sets itself, such as query parameters or the query id
+fn is_setting_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '_')
+        && !name.starts_with("param_")
+        && !matches!(
+            name,
+            "query" | "query_id" | "database" | "user" | "password"
+        )
+}
+
+/// the credentials and custom headers sent with every request to the source.
+/// all values are marked sensitive, so they are left out of debug output
+fn source_headers(config: &Config) -> Result<HeaderMap, Box<dyn Error>> {
+    let credentials = [
+        ("X-ClickHouse-User", &config.username),
+        ("X-ClickHouse-Key", &config.password),
+    ];
+    let mut headers = HeaderMap::new();
+    for (name, value) in credentials.into_iter().chain(
+        config
+            .headers
+            .iter()
+            .flatten()
+            .map(|(name, value)| (name.as_str(), value)),
+    ) {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name {}", name))?;
+        let mut value = HeaderValue::from_str(value)
+            .map_err(|_| format!("Invalid value for header {}", name))?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// the clickhouse settings the connector sends itself, from options of the source config
+pub const CONNECTOR_SETTINGS: [&str; 3] = [
+    "allow_experimental_analyzer",
+    "enable_http_compression",
+    "max_execution_time",
+];
+
+/// a request executing the statement, which is recorded to the audit log.
+/// parameters are substituted by clickhouse for `{name:Type}` placeholders in the statement
+fn clickhouse_request(
+    config: &Config,
+    statement: &str,
+    parameters: &[(String, String)],
+) -> Result<reqwest::RequestBuilder, Box<dyn Error>> {
+    audit(statement, config);
+
+    let request = http_client(config)?
+        .post(source_url(config))
+        .query(&[("query_id", current_query_id())])
+        .query(
+            &parameters
+                .iter()
+                .map(|(name, value)| (format!("param_{name}"), value))
+                .collect::<Vec<_>>(),
+        )
+        .headers(source_headers(config)?)
+        .body(statement.to_owned());
+
+    // settings passed as url parameters apply to any statement, including explain and raw statements
+    let request = match config.allow_experimental_analyzer {
+        Some(allow) => request.query(&[("allow_experimental_analyzer", u8::from(allow))]),
+        None => request,
+    };
+
+    let request = match &config.clickhouse_settings {
+        Some(settings) => {
+            if let Some(name) = settings.keys().find(|name| !is_setting_name(name)) {
+                return Err(format!("Invalid clickhouse setting name {}", name).into());
+            }
+            request.query(settings)
+        }
+        None => request,
+    };
+
+    let request = match config.http_compression {
+        Some(true) => request.query(&[("enable_http_compression", 1)]),
+        _ => request,
+    };
+
+    // clickhouse cancels the query as well, rather than running it after we stop waiting
+    let request = match config.query_timeout_seconds {
+        Some(seconds) => request
+            .timeout(Duration::from_secs(seconds))
+            .query(&[("max_execution_time", seconds)]),
+        None => request,
+    };
+
+    Ok(request)
+}
 
 pub async fn execute_query<T: DeserializeOwned>(
     config: &Config,
     statement: &str,
 ) -> Result<Vec<T>, Box<dyn Error>> {
-    let client = reqwest::Client::new();
-    let request = client
-        .post(&config.url)
-        .header("X-ClickHouse-User", &config.username)
-        .header("X-ClickHouse-Key", &config.password)
-        .body(statement.to_owned())
-        .send()
-        .await?;
+    execute_query_with_parameters(config, statement, &[]).await
+}
+
+pub async fn execute_query_with_parameters<T: DeserializeOwned>(
+    config: &Config,
+    statement: &str,
+    parameters: &[(String, String)],
+) -> Result<Vec<T>, Box<dyn Error>> {
+    let request = clickhouse_request(config, statement, parameters)?;
+    let request = request.send().await?;
 
     let response = request.text().await?;
 
@@ -49,20 +237,37 @@ pub async fn execute_query<T: DeserializeOwned>(
     Ok(payload.data)
 }
 
+/// execute a statement without buffering the result, for the caller to stream the response body.
+/// Only errors that clickhouse reports before it starts responding are returned as errors
+pub async fn execute_query_streaming(
+    config: &Config,
+    statement: &str,
+    parameters: &[(String, String)],
+) -> Result<reqwest::Response, Box<dyn Error>> {
+    let request = clickhouse_request(config, statement, parameters)?;
+    let response = request.send().await?;
+
+    if !response.status().is_success() {
+        return Err(response.text().await?.into());
+    }
+
+    Ok(response)
+}
+
 pub async fn ping(config: &Config) -> Result<(), Box<dyn Error>> {
-    let last_char = config.url.chars().last();
+    let url = source_url(config);
+    let last_char = url.chars().last();
 
     let url = if let Some('/') = last_char {
-        format!("{}ping", config.url)
+        format!("{}ping", url)
     } else {
-        format!("{}/ping", config.url)
+        format!("{}/ping", url)
     };
 
-    let client = reqwest::Client::new();
+    let client = http_client(config)?;
     let _request = client
         .get(&url)
-        .header("X-ClickHouse-User", &config.username)
-        .header("X-ClickHouse-Key", &config.password)
+        .headers(source_headers(config)?)
         .send()
         .await?;
 
@@ -89,3 +294,320 @@ struct ClickHouseResponseMeta {
     #[serde(rename = "type")]
     column_type: String,
 }
+
+/// serves a router standing in for clickhouse, returning a source config that connects to it
+#[cfg(test)]
+pub fn stand_in_clickhouse(clickhouse: axum::Router) -> serde_json::Value {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("can bind");
+    let address = listener.local_addr().expect("has an address");
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .expect("can serve")
+            .serve(clickhouse.into_make_service_with_connect_info::<std::net::SocketAddr>()),
+    );
+    serde_json::json!({
+        "url": format!("http://{address}/"),
+        "username": "default",
+        "password": ""
+    })
+}
+
+#[tokio::test]
+async fn forwards_analyzer_setting_for_every_statement() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::RawQuery, routing::post, Router};
+
+    // stands in for clickhouse, recording the url parameters of each statement
+    let parameters = Arc::new(Mutex::new(vec![]));
+    let recorded_parameters = parameters.clone();
+    let clickhouse = Router::new().route(
+        "/",
+        post(move |RawQuery(query): RawQuery| async move {
+            recorded_parameters
+                .lock()
+                .expect("not poisoned")
+                .push(query.unwrap_or_default());
+            r#"{ "meta": [], "data": [], "rows": 0, "statistics": {} }"#
+        }),
+    );
+
+    let mut config = stand_in_clickhouse(clickhouse);
+    config["allow_experimental_analyzer"] = false.into();
+    let config: Config = serde_json::from_value(config).expect("valid config");
+
+    execute_query::<serde_json::Value>(&config, "SELECT 1 FORMAT JSON;")
+        .await
+        .expect("query succeeds");
+    execute_query::<serde_json::Value>(&config, "EXPLAIN SELECT 1 FORMAT JSON;")
+        .await
+        .expect("explain succeeds");
+    execute_query_streaming(&config, "SELECT 1 FORMAT JSONEachRow;", &[])
+        .await
+        .expect("raw query succeeds");
+
+    let parameters = parameters.lock().expect("not poisoned");
+    assert_eq!(parameters.len(), 3);
+    assert!(
+        parameters
+            .iter()
+            .all(|parameters| parameters.contains("allow_experimental_analyzer=0")),
+        "every statement forces the analyzer off, got {parameters:?}"
+    );
+}
+
+#[tokio::test]
+async fn reuses_connections_across_statements() {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use axum::{extract::ConnectInfo, routing::post, Router};
+
+    // stands in for clickhouse, recording the client address of each statement
+    let peers = Arc::new(Mutex::new(vec![]));
+    let clickhouse = Router::new().route(
+        "/",
+        post({
+            let peers = peers.clone();
+            move |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                peers.lock().expect("not poisoned").push(peer);
+                r#"{ "meta": [], "data": [], "rows": 0, "statistics": {} }"#
+            }
+        }),
+    );
+
+    let mut config = stand_in_clickhouse(clickhouse);
+    config["pool_max_idle_connections"] = 4.into();
+    let config: Config = serde_json::from_value(config).expect("valid config");
+
+    for _ in 0..3 {
+        execute_query::<serde_json::Value>(&config, "SELECT 1 FORMAT JSON;")
+            .await
+            .expect("query succeeds");
+    }
+
+    let peers = peers.lock().expect("not poisoned");
+    assert_eq!(peers.len(), 3);
+    assert!(
+        peers.iter().all(|peer| *peer == peers[0]),
+        "sends every statement over the same connection"
+    );
+}
+
+#[tokio::test]
+async fn times_out_slow_queries() {
+    use axum::{http::StatusCode, response::IntoResponse, routing::post, Router};
+
+    use super::error::ServerError;
+
+    // stands in for clickhouse, taking longer to answer than the timeout allows
+    let clickhouse = Router::new().route(
+        "/",
+        post(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            r#"{ "meta": [], "data": [], "rows": 0, "statistics": {} }"#
+        }),
+    );
+
+    let mut config = stand_in_clickhouse(clickhouse);
+    config["query_timeout_seconds"] = 1.into();
+    let config: Config = serde_json::from_value(config).expect("valid config");
+
+    let err = execute_query::<serde_json::Value>(&config, "SELECT sleep(3) FORMAT JSON;")
+        .await
+        .expect_err("query times out");
+    assert_eq!(
+        ServerError::from(err).into_response().status(),
+        StatusCode::GATEWAY_TIMEOUT,
+        "timeouts respond with 504"
+    );
+}
+
+#[tokio::test]
+async fn decodes_compressed_responses() {
+    use std::sync::Arc;
+
+    use axum::{
+        http::{header::ACCEPT_ENCODING, HeaderMap},
+        routing::post,
+        Router,
+    };
+    use tower_http::compression::CompressionLayer;
+
+    // stands in for clickhouse, gzipping responses when asked to and recording the encodings accepted
+    let accepted_encodings = Arc::new(Mutex::new(vec![]));
+    let clickhouse = Router::new()
+        .route(
+            "/",
+            post({
+                let accepted_encodings = accepted_encodings.clone();
+                move |headers: HeaderMap| async move {
+                    accepted_encodings.lock().expect("not poisoned").push(
+                        headers
+                            .get(ACCEPT_ENCODING)
+                            .map(|value| value.to_str().expect("ascii").to_owned()),
+                    );
+                    let data = vec![serde_json::json!({ "name": "compressible" }); 100];
+                    serde_json::json!({ "meta": [], "data": data, "rows": 100, "statistics": {} })
+                        .to_string()
+                }
+            }),
+        )
+        .layer(CompressionLayer::new());
+
+    let source_config = stand_in_clickhouse(clickhouse);
+    let config = |http_compression: bool| -> Config {
+        let mut config = source_config.clone();
+        config["http_compression"] = http_compression.into();
+        serde_json::from_value(config).expect("valid config")
+    };
+
+    let rows = execute_query::<serde_json::Value>(&config(true), "SELECT 1 FORMAT JSON;")
+        .await
+        .expect("decodes the compressed response");
+    assert_eq!(rows.len(), 100);
+    execute_query::<serde_json::Value>(&config(false), "SELECT 1 FORMAT JSON;")
+        .await
+        .expect("reads the plain response");
+
+    assert_eq!(
+        *accepted_encodings.lock().expect("not poisoned"),
+        vec![Some("gzip".to_owned()), None],
+        "only asks for compressed responses when enabled"
+    );
+}
+
+#[tokio::test]
+async fn connects_over_tls_when_enabled() {
+    let config = |tls: serde_json::Value| -> Config {
+        serde_json::from_value(serde_json::json!({
+            "url": "http://clickhouse.internal:8123/",
+            "username": "default",
+            "password": "",
+            "tls": tls
+        }))
+        .expect("valid config")
+    };
+
+    assert_eq!(
+        source_url(&config(serde_json::json!(null))),
+        "http://clickhouse.internal:8123/",
+        "keeps plain http by default"
+    );
+    assert_eq!(
+        source_url(&config(serde_json::json!({ "enabled": false }))),
+        "http://clickhouse.internal:8123/"
+    );
+    assert_eq!(
+        source_url(&config(serde_json::json!({ "enabled": true }))),
+        "https://clickhouse.internal:8123/",
+        "upgrades the url to https"
+    );
+
+    let err = execute_query::<serde_json::Value>(
+        &config(serde_json::json!({ "enabled": true, "ca_cert_path": "/nonexistent/ca.pem" })),
+        "SELECT 1 FORMAT JSON;",
+    )
+    .await
+    .expect_err("cannot read the certificate");
+    assert!(
+        err.to_string()
+            .starts_with("Could not read ca certificate at /nonexistent/ca.pem"),
+        "fails before connecting, naming the certificate path"
+    );
+}
+
+#[tokio::test]
+async fn sends_credentials_and_custom_headers() {
+    use std::sync::Arc;
+
+    use axum::{routing::post, Router};
+
+    // stands in for clickhouse, recording the headers of each request
+    let received = Arc::new(Mutex::new(vec![]));
+    let clickhouse = Router::new().route(
+        "/",
+        post({
+            let received = received.clone();
+            move |headers: axum::http::HeaderMap| async move {
+                received.lock().expect("not poisoned").push(headers);
+                r#"{ "meta": [], "data": [], "rows": 0, "statistics": {} }"#
+            }
+        }),
+    );
+
+    let mut config = stand_in_clickhouse(clickhouse);
+    config["username"] = "reader".into();
+    config["password"] = "hunter2".into();
+    config["headers"] =
+        serde_json::json!({ "Authorization": "Bearer secret-token", "X-Tenant": "acme" });
+    let config: Config = serde_json::from_value(config).expect("valid config");
+
+    let request = clickhouse_request(&config, "SELECT 1 FORMAT JSON;", &[]).expect("valid request");
+    let debug_output = format!("{:?}", request);
+    assert!(
+        !debug_output.contains("hunter2") && !debug_output.contains("secret-token"),
+        "secrets are left out of debug output"
+    );
+
+    execute_query::<serde_json::Value>(&config, "SELECT 1 FORMAT JSON;")
+        .await
+        .expect("query succeeds");
+
+    let received = received.lock().expect("not poisoned");
+    let headers = received.first().expect("received a request");
+    assert_eq!(headers["x-clickhouse-user"], "reader");
+    assert_eq!(headers["x-clickhouse-key"], "hunter2");
+    assert_eq!(headers["authorization"], "Bearer secret-token");
+    assert_eq!(headers["x-tenant"], "acme");
+}
+
+#[tokio::test]
+async fn sends_clickhouse_settings() {
+    use axum::{extract::RawQuery, routing::post, Router};
+
+    // stands in for clickhouse, returning the url parameters it was sent as the only row
+    let clickhouse = Router::new().route(
+        "/",
+        post(|RawQuery(parameters): RawQuery| async move {
+            serde_json::json!({
+                "meta": [],
+                "data": [{ "parameters": parameters }],
+                "rows": 1,
+                "statistics": {}
+            })
+            .to_string()
+        }),
+    );
+
+    let source_config = stand_in_clickhouse(clickhouse);
+    let config = |settings: serde_json::Value| -> Config {
+        let mut config = source_config.clone();
+        config["clickhouse_settings"] = settings;
+        serde_json::from_value(config).expect("valid config")
+    };
+
+    let rows = execute_query::<serde_json::Value>(
+        &config(serde_json::json!({ "max_threads": "4", "max_memory_usage": "1000000" })),
+        "SELECT 1 FORMAT JSON;",
+    )
+    .await
+    .expect("query succeeds");
+    let parameters = rows[0]["parameters"].as_str().expect("url parameters");
+    assert!(parameters.contains("max_threads=4"));
+    assert!(parameters.contains("max_memory_usage=1000000"));
+
+    for name in ["query_id", "param_id", "max_threads&readonly", ""] {
+        let err = execute_query::<serde_json::Value>(
+            &config(serde_json::json!({ name: "0" })),
+            "SELECT 1 FORMAT JSON;",
+        )
+        .await
+        .expect_err("rejects the setting");
+        assert!(
+            err.to_string()
+                .starts_with("Invalid clickhouse setting name"),
+            "rejects {:?}",
+            name
+        );
+    }
+}
@@ -26,6 +26,69 @@ pub struct Config {
     pub password: String,
     /// Optional additional configuration for tables
     pub tables: Option<Vec<TableConfig>>,
+    /// The session variables of the request, used to resolve table name templates. Set from `$session` by the configuration template on every request, rather than in the source configuration
+    pub session_variables: Option<HashMap<String, String>>,
+    /// If true, NaN and Inf values in Float32 and Float64 columns are returned as null
+    pub replace_non_finite_floats: Option<bool>,
+    /// If true, queries reading from ReplacingMergeTree tables without FINAL return a warning that results may include duplicate rows
+    pub warn_on_non_final_reads: Option<bool>,
+    /// If set, distinct counts over tables estimated to read more rows than this threshold are approximated, unless the aggregate sets `exact`
+    pub approximate_distinct_count_threshold: Option<u64>,
+    /// If true, requests may filter rows using raw sql predicates. Anyone able to send queries can then run arbitrary sql with the permissions of the clickhouse user
+    pub allow_raw_sql_predicates: Option<bool>,
+    /// Optional secret used to sign pagination cursors. Queries cannot paginate unless set
+    pub cursor_secret: Option<String>,
+    /// If true, counts are returned as strings so counts above 4294967295 are not truncated. Defaults to false, returning counts as numbers
+    pub large_count_as_string: Option<bool>,
+    /// If true, string literals are redacted from statements written to the audit log
+    pub redact_audit_log: Option<bool>,
+    /// If true, empty strings in String columns are treated as null when filtering
+    pub empty_string_as_null: Option<bool>,
+    /// If set, relationships without a limit return at most this many rows per parent row
+    pub default_relationship_limit: Option<u64>,
+    /// If set, queries without a limit return at most this many rows. A limit sent by the client overrides it
+    pub default_row_limit: Option<u64>,
+    /// If true, values in queries are sent to clickhouse as typed query parameters, rather than written into the statement
+    pub bind_query_parameters: Option<bool>,
+    /// Optional names of system tables that may be queried, such as tables or columns. Queries reading them run read only. Other system tables cannot be queried
+    pub allowed_system_tables: Option<Vec<String>>,
+    /// If true, query responses include an X-Query-Estimated-Rows header with the number of rows clickhouse estimates the query reads
+    pub estimated_rows_header: Option<bool>,
+    /// Maximum length of identifiers generated from field aliases, defaults to 255. Longer identifiers are truncated and suffixed with a hash
+    pub max_generated_identifier_length: Option<usize>,
+    /// If set, clickhouse's allow_experimental_analyzer setting is forced on or off for every statement, otherwise the server default applies
+    pub allow_experimental_analyzer: Option<bool>,
+    /// If true, raw queries may contain more than one statement. Defaults to false, rejecting raw queries with multiple statements
+    pub allow_multi_statement_raw_queries: Option<bool>,
+    /// If true, keys of query and raw query responses are sorted alphabetically, rather than following the order of the request's fields
+    pub sort_response_keys: Option<bool>,
+    /// Maximum number of idle connections to clickhouse kept open for reuse. Defaults to no limit
+    pub pool_max_idle_connections: Option<usize>,
+    /// Seconds an idle connection to clickhouse is kept open for reuse, defaults to 90
+    pub pool_idle_timeout_seconds: Option<u64>,
+    /// If set, queries running longer than this many seconds are cancelled, both by the connector and by clickhouse
+    pub query_timeout_seconds: Option<u64>,
+    /// If set, connecting to clickhouse fails after this many seconds
+    pub connect_timeout_seconds: Option<u64>,
+    /// If true, clickhouse is asked to gzip responses, which speeds up large results over slow networks
+    pub http_compression: Option<bool>,
+    /// Optional tls settings for connecting to clickhouse
+    pub tls: Option<TlsConfig>,
+    /// Optional headers sent with every request to clickhouse, such as an Authorization header for a proxy. Headers named X-ClickHouse-User or X-ClickHouse-Key replace the username and password
+    pub headers: Option<HashMap<String, String>>,
+    /// Optional clickhouse settings applied to every statement, such as max_threads or max_memory_usage
+    pub clickhouse_settings: Option<HashMap<String, String>>,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize, OpenapiType)]
+pub struct TlsConfig {
+    /// If true, clickhouse is connected to over https, even if the url starts with http://
+    pub enabled: bool,
+    /// Optional path to a pem encoded certificate authority to trust, in addition to the system ones
+    pub ca_cert_path: Option<String>,
+    /// If true, invalid certificates are accepted. Only use this for testing, as connections can then be intercepted
+    pub accept_invalid_certs: Option<bool>,
 }
 
 #[skip_serializing_none]
@@ -37,6 +100,14 @@ pub struct TableConfig {
     pub alias: Option<String>,
     /// Optional configuration for table columns
     pub columns: Option<Vec<ColumnConfig>>,
+    /// Optional template for the physical table name, with session variables in braces. For example: events_{x-hasura-tenant-id}
+    pub name_template: Option<String>,
+    /// Optional column that increases whenever rows change, such as an updated_at column. Query responses are only cacheable when all tables involved have one
+    pub version_column: Option<String>,
+    /// Optional physical tables read together as this one logical table, such as monthly tables. Rows of all tables are returned, as with UNION ALL, so the tables must share the columns queried
+    pub union_tables: Option<Vec<String>>,
+    /// If true, the table is read with FINAL, so ReplacingMergeTree and CollapsingMergeTree tables return merged rows. Reads are slower
+    pub read_final: Option<bool>,
 }
 
 #[skip_serializing_none]
@@ -46,6 +117,17 @@ pub struct ColumnConfig {
     pub name: String,
     /// Optional alias for this column. Required if the column name is not a valid graphql name
     pub alias: Option<String>,
+    /// Optional mask for sensitive string columns. Masked columns return a masked value in place of the raw value
+    pub mask: Option<ColumnMask>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, OpenapiType)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnMask {
+    /// Keep the first two characters, replacing the rest with ***
+    Partial,
+    /// Return the hex encoded SHA256 hash of the value
+    Hash,
 }
 
 static CONFIG_HEADER: HeaderName = HeaderName::from_static("x-hasura-dataconnector-config");
@@ -149,6 +231,35 @@ pub fn get_openapi_config_schema_response() -> ConfigSchemaResponse {
     // however, we cannot specify fields as nullable using these libraries, so hardcoding the config schema
     // TODO: if config type is changed, config schema should be changed also.
 
+    // nested objects are built separately, as the whole schema exceeds the json macro's recursion limit
+    let tls_schema_json = json!({
+        "title": "Tls",
+        "description": "Optional tls settings for connecting to clickhouse",
+        "nullable": true,
+        "type": "object",
+        "properties": {
+            "enabled": {
+                "title": "Enabled",
+                "description": "If true, clickhouse is connected to over https, even if the url starts with http://",
+                "nullable": false,
+                "type": "boolean"
+            },
+            "ca_cert_path": {
+                "title": "Ca cert path",
+                "description": "Optional path to a pem encoded certificate authority to trust, in addition to the system ones",
+                "nullable": true,
+                "type": "string"
+            },
+            "accept_invalid_certs": {
+                "title": "Accept invalid certs",
+                "description": "If true, invalid certificates are accepted. Only use this for testing, as connections can then be intercepted",
+                "nullable": true,
+                "type": "boolean"
+            }
+        },
+        "required": ["enabled"]
+    });
+
     let config_schema_json = json!({
         "type": "object",
         "nullable": false,
@@ -192,6 +303,31 @@ pub fn get_openapi_config_schema_response() -> ConfigSchemaResponse {
                             "nullable": true,
                             "type": "string"
                         },
+                        "name_template": {
+                            "title": "Name template",
+                            "description": "Optional template for the physical table name, with session variables in braces. For example: events_{x-hasura-tenant-id}",
+                            "nullable": true,
+                            "type": "string"
+                        },
+                        "version_column": {
+                            "title": "Version column",
+                            "description": "Optional column that increases whenever rows change, such as an updated_at column. Query responses are only cacheable when all tables involved have one",
+                            "nullable": true,
+                            "type": "string"
+                        },
+                        "union_tables": {
+                            "title": "Union tables",
+                            "description": "Optional physical tables read together as this one logical table, such as monthly tables. Rows of all tables are returned, as with UNION ALL, so the tables must share the columns queried",
+                            "nullable": true,
+                            "type": "array",
+                            "items": { "type": "string" }
+                        },
+                        "read_final": {
+                            "title": "Read final",
+                            "description": "If true, the table is read with FINAL, so ReplacingMergeTree and CollapsingMergeTree tables return merged rows. Reads are slower",
+                            "nullable": true,
+                            "type": "boolean"
+                        },
                         "columns": {
                             "description": "Optional configuration for table columns",
                             "type": "array",
@@ -210,6 +346,13 @@ pub fn get_openapi_config_schema_response() -> ConfigSchemaResponse {
                                         "description": "Optional alias for this column. Required if the column name is not a valid graphql name",
                                         "nullable": true,
                                         "type": "string"
+                                    },
+                                    "mask": {
+                                        "title": "Mask",
+                                        "description": "Optional mask for sensitive string columns. partial keeps the first two characters, hash returns the hex encoded SHA256 hash of the value",
+                                        "nullable": true,
+                                        "type": "string",
+                                        "enum": ["partial", "hash"]
                                     }
                                 },
                                 "required": ["name"]
@@ -218,6 +361,154 @@ pub fn get_openapi_config_schema_response() -> ConfigSchemaResponse {
                     },
                     "required": ["name"]
                 }
+            },
+            "replace_non_finite_floats": {
+                "title": "Replace non finite floats",
+                "description": "If true, NaN and Inf values in Float32 and Float64 columns are returned as null",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "warn_on_non_final_reads": {
+                "title": "Warn on non final reads",
+                "description": "If true, queries reading from ReplacingMergeTree tables without FINAL return a warning that results may include duplicate rows",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "approximate_distinct_count_threshold": {
+                "title": "Approximate distinct count threshold",
+                "description": "If set, distinct counts over tables estimated to read more rows than this threshold are approximated, unless the aggregate sets exact",
+                "nullable": true,
+                "type": "integer"
+            },
+            "allow_raw_sql_predicates": {
+                "title": "Allow raw sql predicates",
+                "description": "If true, requests may filter rows using raw sql predicates. Anyone able to send queries can then run arbitrary sql with the permissions of the clickhouse user",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "cursor_secret": {
+                "title": "Cursor secret",
+                "description": "Optional secret used to sign pagination cursors. Queries cannot paginate unless set",
+                "nullable": true,
+                "type": "string"
+            },
+            "large_count_as_string": {
+                "title": "Large count as string",
+                "description": "If true, counts are returned as strings so counts above 4294967295 are not truncated. Defaults to false, returning counts as numbers",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "redact_audit_log": {
+                "title": "Redact audit log",
+                "description": "If true, string literals are redacted from statements written to the audit log",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "empty_string_as_null": {
+                "title": "Empty string as null",
+                "description": "If true, empty strings in String columns are treated as null when filtering",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "default_relationship_limit": {
+                "title": "Default relationship limit",
+                "description": "If set, relationships without a limit return at most this many rows per parent row",
+                "nullable": true,
+                "type": "integer"
+            },
+            "default_row_limit": {
+                "title": "Default row limit",
+                "description": "If set, queries without a limit return at most this many rows. A limit sent by the client overrides it",
+                "nullable": true,
+                "type": "integer"
+            },
+            "bind_query_parameters": {
+                "title": "Bind query parameters",
+                "description": "If true, values in queries are sent to clickhouse as typed query parameters, rather than written into the statement",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "allowed_system_tables": {
+                "title": "Allowed system tables",
+                "description": "Optional names of system tables that may be queried, such as tables or columns. Queries reading them run read only. Other system tables cannot be queried",
+                "nullable": true,
+                "type": "array",
+                "items": { "type": "string" }
+            },
+            "estimated_rows_header": {
+                "title": "Estimated rows header",
+                "description": "If true, query responses include an X-Query-Estimated-Rows header with the number of rows clickhouse estimates the query reads",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "max_generated_identifier_length": {
+                "title": "Max generated identifier length",
+                "description": "Maximum length of identifiers generated from field aliases, defaults to 255. Longer identifiers are truncated and suffixed with a hash",
+                "nullable": true,
+                "type": "integer"
+            },
+            "allow_experimental_analyzer": {
+                "title": "Allow experimental analyzer",
+                "description": "If set, clickhouse's allow_experimental_analyzer setting is forced on or off for every statement, otherwise the server default applies",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "allow_multi_statement_raw_queries": {
+                "title": "Allow multi statement raw queries",
+                "description": "If true, raw queries may contain more than one statement. Defaults to false, rejecting raw queries with multiple statements",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "sort_response_keys": {
+                "title": "Sort response keys",
+                "description": "If true, keys of query and raw query responses are sorted alphabetically, rather than following the order of the request's fields",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "pool_max_idle_connections": {
+                "title": "Pool max idle connections",
+                "description": "Maximum number of idle connections to clickhouse kept open for reuse. Defaults to no limit",
+                "nullable": true,
+                "type": "integer"
+            },
+            "pool_idle_timeout_seconds": {
+                "title": "Pool idle timeout seconds",
+                "description": "Seconds an idle connection to clickhouse is kept open for reuse, defaults to 90",
+                "nullable": true,
+                "type": "integer"
+            },
+            "query_timeout_seconds": {
+                "title": "Query timeout seconds",
+                "description": "If set, queries running longer than this many seconds are cancelled, both by the connector and by clickhouse",
+                "nullable": true,
+                "type": "integer"
+            },
+            "connect_timeout_seconds": {
+                "title": "Connect timeout seconds",
+                "description": "If set, connecting to clickhouse fails after this many seconds",
+                "nullable": true,
+                "type": "integer"
+            },
+            "http_compression": {
+                "title": "Http compression",
+                "description": "If true, clickhouse is asked to gzip responses, which speeds up large results over slow networks",
+                "nullable": true,
+                "type": "boolean"
+            },
+            "tls": tls_schema_json,
+            "headers": {
+                "title": "Headers",
+                "description": "Optional headers sent with every request to clickhouse, such as an Authorization header for a proxy. Headers named X-ClickHouse-User or X-ClickHouse-Key replace the username and password",
+                "nullable": true,
+                "type": "object",
+                "additionalProperties": { "type": "string" }
+            },
+            "clickhouse_settings": {
+                "title": "Clickhouse settings",
+                "description": "Optional clickhouse settings applied to every statement, such as max_threads or max_memory_usage",
+                "nullable": true,
+                "type": "object",
+                "additionalProperties": { "type": "string" }
             }
         },
         "required": ["url", "username", "password"]
@@ -13,6 +13,8 @@ use super::api::error_response::{ErrorResponse, ErrorResponseType};
 
 pub enum ServerError {
     NotFound(Uri),
+    /// clickhouse did not respond within the source's query timeout
+    Timeout(String),
     UncaughtError {
         details: Option<serde_json::Value>,
         message: String,
@@ -40,6 +42,15 @@ impl IntoResponse for ServerError {
                 }),
             )
                 .into_response(),
+            Self::Timeout(message) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                axum::Json(ErrorResponse {
+                    details: None,
+                    message,
+                    error_type: ErrorResponseType::UncaughtError,
+                }),
+            )
+                .into_response(),
             Self::NotFound(uri) => (
                 StatusCode::NOT_FOUND,
                 format!("Path not found: {}", uri.path()),
@@ -71,6 +82,11 @@ impl From<std::io::Error> for ServerError {
 
 impl From<Box<dyn Error>> for ServerError {
     fn from(err: Box<dyn Error>) -> Self {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            if err.is_timeout() {
+                return Self::Timeout(format!("Query timed out: {}", err));
+            }
+        }
         Self::UncaughtError {
             details: None,
             message: err.to_string(),
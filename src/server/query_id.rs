@@ -0,0 +1,105 @@
+use axum::{
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+
+/// Carries the clickhouse query_id. If sent by the client it is forwarded as is, otherwise one is generated.
+/// Always returned on the response, so responses can be traced to `system.query_log`
+pub static QUERY_ID_HEADER: HeaderName = HeaderName::from_static("x-clickhouse-query-id");
+
+tokio::task_local! {
+    static QUERY_ID: String;
+}
+
+/// middleware assigning a query_id to statements executed by the request, and returning it as a response header
+pub async fn query_id_scope<B>(request: Request<B>, next: Next<B>) -> Response {
+    let query_id = request
+        .headers()
+        .get(&QUERY_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_owned())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let mut response = QUERY_ID.scope(query_id.clone(), next.run(request)).await;
+
+    if let Ok(value) = HeaderValue::from_str(&query_id) {
+        response.headers_mut().insert(&QUERY_ID_HEADER, value);
+    }
+
+    response
+}
+
+/// the query_id of the current request, if any.
+/// All statements executed for a request share this id
+pub fn current_query_id() -> Option<String> {
+    QUERY_ID.try_with(|query_id| query_id.to_owned()).ok()
+}
+
+#[tokio::test]
+async fn returns_forwarded_query_id() {
+    use axum::{
+        body::{Body, HttpBody},
+        middleware,
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    // stands in for a handler executing a statement, returning the query_id it would forward
+    let router = Router::new()
+        .route(
+            "/query",
+            post(|| async { current_query_id().unwrap_or_default() }),
+        )
+        .layer(middleware::from_fn(query_id_scope));
+
+    let response = router
+        .clone()
+        .oneshot(
+            Request::post("/query")
+                .header(&QUERY_ID_HEADER, "trace-1")
+                .body(Body::empty())
+                .expect("valid request"),
+        )
+        .await
+        .expect("infallible");
+    assert_eq!(
+        response.headers().get(&QUERY_ID_HEADER),
+        Some(&HeaderValue::from_static("trace-1")),
+        "returns the provided query id"
+    );
+    let body = response
+        .into_body()
+        .data()
+        .await
+        .expect("has a body")
+        .expect("readable body");
+    assert_eq!(body, "trace-1", "forwards the provided query id");
+
+    let response = router
+        .oneshot(
+            Request::post("/query")
+                .body(Body::empty())
+                .expect("valid request"),
+        )
+        .await
+        .expect("infallible");
+    let header = response
+        .headers()
+        .get(&QUERY_ID_HEADER)
+        .expect("generates a query id")
+        .to_owned();
+    let body = response
+        .into_body()
+        .data()
+        .await
+        .expect("has a body")
+        .expect("readable body");
+    assert_eq!(
+        header.as_bytes(),
+        body.as_ref(),
+        "returns the generated query id that was forwarded"
+    );
+}
@@ -1,15 +1,21 @@
 mod get_capabilities;
 mod get_health;
+mod get_ready;
 mod get_schema;
+mod get_schema_version;
 mod post_explain;
+mod post_explain_ast;
 mod post_mutation;
 mod post_query;
 mod post_raw;
 
 pub use get_capabilities::get_capabilities;
 pub use get_health::get_health;
+pub use get_ready::get_ready;
 pub use get_schema::get_schema;
+pub use get_schema_version::get_schema_version;
 pub use post_explain::post_explain;
+pub use post_explain_ast::post_explain_ast;
 pub use post_mutation::post_mutation;
 pub use post_query::post_query;
 pub use post_raw::post_raw;
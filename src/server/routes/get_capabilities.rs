@@ -8,7 +8,7 @@ use crate::server::{
             DataSchemaCapabilities, GraphQlType, QueryCapabilities, ScalarTypeCapabilities,
             SubqueryComparisonCapabilities,
         },
-        query_request::{ScalarType, SingleColumnAggregateFunction},
+        query_request::{BinaryComparisonOperator, ScalarType, SingleColumnAggregateFunction},
     },
     config::get_openapi_config_schema_response,
 };
@@ -47,6 +47,7 @@ pub async fn get_capabilities() -> Json<CapabilitiesResponse> {
 }
 
 fn scalar_types() -> IndexMap<ScalarType, ScalarTypeCapabilities> {
+    use BinaryComparisonOperator as CO;
     use ScalarType as ST;
     use SingleColumnAggregateFunction as CA;
     IndexMap::from_iter(
@@ -145,6 +146,7 @@ fn scalar_types() -> IndexMap<ScalarType, ScalarTypeCapabilities> {
             ST::MinDate32,
             ST::MinDateTime,
             ST::MinDateTime64,
+            ST::Bitmap,
             ST::Unknown,
         ]
         .into_iter()
@@ -234,6 +236,7 @@ fn scalar_types() -> IndexMap<ScalarType, ScalarTypeCapabilities> {
                 ST::MinDate32 => GraphQlType::String,
                 ST::MinDateTime => GraphQlType::String,
                 ST::MinDateTime64 => GraphQlType::String,
+                ST::Bitmap => GraphQlType::String,
             };
             let aggregate_functions = match &scalar_type {
                 ST::Bool => None,
@@ -485,9 +488,91 @@ fn scalar_types() -> IndexMap<ScalarType, ScalarTypeCapabilities> {
                 ST::Uuid => None,
                 ST::IPv4 => None,
                 ST::IPv6 => None,
-                ST::Unknown => None,
+                ST::Bitmap => None,
+                // complex types such as maps are exposed as unknown
+                ST::Unknown => Some(IndexMap::from_iter(vec![(CA::SumMap, ST::Unknown)])),
+            };
+            // the median of numeric columns is interpolated, so may be fractional.
+            // quantiles take a level parameter, which cannot be described as a capability
+            let aggregate_functions = match &scalar_type {
+                ST::UInt8
+                | ST::UInt16
+                | ST::UInt32
+                | ST::UInt64
+                | ST::UInt128
+                | ST::UInt256
+                | ST::Int8
+                | ST::Int16
+                | ST::Int32
+                | ST::Int64
+                | ST::Int128
+                | ST::Int256
+                | ST::Float32
+                | ST::Float64
+                | ST::Decimal => Some(
+                    aggregate_functions
+                        .unwrap_or_default()
+                        .into_iter()
+                        .chain(vec![(CA::Median, ST::Float64)])
+                        .collect(),
+                ),
+                _ => aggregate_functions,
+            };
+            // theta sketches estimate the distinct count of any plain value
+            let aggregate_functions = match &scalar_type {
+                ST::String
+                | ST::FixedString
+                | ST::UInt8
+                | ST::UInt16
+                | ST::UInt32
+                | ST::UInt64
+                | ST::UInt128
+                | ST::UInt256
+                | ST::Int8
+                | ST::Int16
+                | ST::Int32
+                | ST::Int64
+                | ST::Int128
+                | ST::Int256
+                | ST::Float32
+                | ST::Float64
+                | ST::Decimal
+                | ST::Date
+                | ST::Date32
+                | ST::DateTime
+                | ST::DateTime64
+                | ST::Uuid
+                | ST::IPv4
+                | ST::IPv6 => Some(
+                    aggregate_functions
+                        .unwrap_or_default()
+                        .into_iter()
+                        .chain(vec![(CA::UniqTheta, ST::UInt64)])
+                        .collect(),
+                ),
+                _ => aggregate_functions,
+            };
+            let comparison_operators = match &scalar_type {
+                // complex types such as arrays are exposed as unknown
+                ST::Unknown => Some(IndexMap::from_iter(vec![
+                    (CO::LengthEqual, ST::UInt32),
+                    (CO::LengthGreaterThan, ST::UInt32),
+                    (CO::LengthGreaterThanOrEqual, ST::UInt32),
+                    (CO::LengthLessThan, ST::UInt32),
+                    (CO::LengthLessThanOrEqual, ST::UInt32),
+                ])),
+                ST::Bitmap => Some(IndexMap::from_iter(vec![(CO::BitmapContains, ST::UInt32)])),
+                ST::String | ST::FixedString => Some(IndexMap::from_iter(vec![
+                    (CO::NotEqual, scalar_type.to_owned()),
+                    (CO::CaseInsensitiveEqual, ST::String),
+                    (CO::Regex, ST::String),
+                    (CO::NotRegex, ST::String),
+                ])),
+                _ => Some(IndexMap::from_iter(vec![(
+                    CO::NotEqual,
+                    scalar_type.to_owned(),
+                )])),
             };
-            let comparison_operators = Some(IndexMap::from_iter(vec![]));
             let scalar_type_capabilities = ScalarTypeCapabilities {
                 graphql_type,
                 aggregate_functions,
@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use indexmap::IndexMap;
+
+use crate::server::{
+    api::readiness_response::{ReadinessResponse, SourceReadiness},
+    client::execute_query,
+    config::{SourceConfig, SourceName},
+    Config,
+};
+
+/// how long a source has to answer before it is considered not ready
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the per source check, run by readiness rather than liveness so a down source never restarts the connector.
+/// Runs a trivial query against the source, with a timeout so a hung source does not hang the check
+async fn check_source(source_name: &str, config: &Config) -> SourceReadiness {
+    let check = execute_query::<serde_json::Value>(config, "SELECT 1 FORMAT JSON;");
+    let message = match tokio::time::timeout(READINESS_CHECK_TIMEOUT, check).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => Some(format!("Source {} is not ready: {}", source_name, err)),
+        Err(_) => Some(format!(
+            "Source {} did not respond within {} seconds",
+            source_name,
+            READINESS_CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    SourceReadiness {
+        ready: message.is_none(),
+        message,
+    }
+}
+
+/// readiness of the sources the request carries config for.
+/// The connector keeps no list of sources, as each request brings its own config
+#[axum_macros::debug_handler]
+pub async fn get_ready(source_name: Option<SourceName>, config: Option<SourceConfig>) -> Response {
+    let mut sources = IndexMap::new();
+    if let (Some(SourceName(source_name)), Some(SourceConfig(config))) = (source_name, config) {
+        let readiness = check_source(&source_name, &config).await;
+        sources.insert(source_name, readiness);
+    }
+
+    let status = if sources.values().all(|source| source.ready) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(ReadinessResponse { sources })).into_response()
+}
+
+#[tokio::test]
+async fn reports_the_status_of_each_source() {
+    use axum::{
+        body::{Body, HttpBody},
+        http::Request,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::server::client::stand_in_clickhouse;
+
+    // stands in for clickhouse, answering any statement
+    let clickhouse = Router::new().route(
+        "/",
+        post(|| async { r#"{ "meta": [], "data": [{ "1": 1 }], "rows": 1, "statistics": {} }"# }),
+    );
+    let config = stand_in_clickhouse(clickhouse);
+    let ready = |config: Option<serde_json::Value>| async move {
+        let request = Request::get("/ready");
+        let request = match config {
+            Some(config) => request
+                .header("x-hasura-dataconnector-sourcename", "clickhouse")
+                .header("x-hasura-dataconnector-config", config.to_string()),
+            None => request,
+        };
+        let response = Router::new()
+            .route("/ready", get(get_ready))
+            .oneshot(request.body(Body::empty()).expect("valid request"))
+            .await
+            .expect("infallible");
+        let status = response.status();
+        let body = response
+            .into_body()
+            .data()
+            .await
+            .expect("has a body")
+            .expect("readable body");
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("json body");
+        (status, body)
+    };
+
+    let (status, body) = ready(None).await;
+    assert_eq!(status, StatusCode::OK, "is ready without a source to check");
+    assert_eq!(body, serde_json::json!({ "sources": {} }));
+
+    let (status, body) = ready(Some(config.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        serde_json::json!({ "sources": { "clickhouse": { "ready": true } } })
+    );
+
+    let mut unreachable = config;
+    unreachable["url"] = "http://127.0.0.1:1/".into();
+    let (status, body) = ready(Some(unreachable)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["sources"]["clickhouse"]["ready"], false);
+    assert!(
+        body["sources"]["clickhouse"]["message"]
+            .as_str()
+            .expect("has a message")
+            .starts_with("Source clickhouse is not ready: "),
+        "explains why the source is not ready"
+    );
+}
@@ -301,6 +301,7 @@ fn get_scalar_type(data_type: &ClickhouseDataType) -> ScalarType {
                 ("min", Some(CDT::Date32)) => ScalarType::MinDate32,
                 ("min", Some(CDT::DateTime { .. })) => ScalarType::MinDateTime,
                 ("min", Some(CDT::DateTime64 { .. })) => ScalarType::MinDateTime64,
+                ("groupBitmap", Some(_)) => ScalarType::Bitmap,
 
                 _ => ScalarType::Unknown,
             }
@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::server::{
+    api::schema_version_response::SchemaVersionResponse,
+    client::execute_query,
+    config::{SourceConfig, SourceName, TableConfig},
+    error::ServerError,
+};
+
+/// how long a schema version is reused before the columns are read again
+const SCHEMA_VERSION_TTL: Duration = Duration::from_secs(10);
+
+/// recent schema versions and when they were read, by clickhouse url, user, and configured tables
+type SchemaVersions = HashMap<(String, String, String), (Instant, String)>;
+
+static SCHEMA_VERSIONS: Mutex<Option<SchemaVersions>> = Mutex::new(None);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SchemaColumn {
+    table: String,
+    name: String,
+    column_type: String,
+}
+
+/// a digest of the columns, which must be in a consistent order, and of the configured tables,
+/// whose aliases also change the schema
+fn schema_version(
+    columns: &[SchemaColumn],
+    tables: &Option<Vec<TableConfig>>,
+) -> Result<String, serde_json::Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(columns)?);
+    hasher.update(serde_json::to_vec(tables)?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[axum_macros::debug_handler]
+pub async fn get_schema_version(
+    SourceName(_source_name): SourceName,
+    SourceConfig(config): SourceConfig,
+) -> Result<Json<SchemaVersionResponse>, ServerError> {
+    let key = (
+        config.url.to_owned(),
+        config.username.to_owned(),
+        serde_json::to_string(&config.tables)?,
+    );
+
+    let cached = SCHEMA_VERSIONS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .and_then(|versions| versions.get(&key))
+        .filter(|(read_at, _)| read_at.elapsed() < SCHEMA_VERSION_TTL)
+        .map(|(_, version)| version.to_owned());
+
+    let version = match cached {
+        Some(version) => version,
+        None => {
+            let columns: Vec<SchemaColumn> = execute_query(
+                &config,
+                r#"SELECT table AS "table", name AS "name", type AS "column_type"
+FROM system.columns
+WHERE database = currentDatabase()
+ORDER BY table, position
+FORMAT JSON;"#,
+            )
+            .await?;
+            let version = schema_version(&columns, &config.tables)?;
+
+            SCHEMA_VERSIONS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_or_insert_with(HashMap::new)
+                .insert(key, (Instant::now(), version.to_owned()));
+
+            version
+        }
+    };
+
+    Ok(Json(SchemaVersionResponse { version }))
+}
+
+#[test]
+fn changes_version_when_columns_change() {
+    let column = |table: &str, name: &str, column_type: &str| SchemaColumn {
+        table: table.to_owned(),
+        name: name.to_owned(),
+        column_type: column_type.to_owned(),
+    };
+    let columns = vec![
+        column("users", "id", "UInt32"),
+        column("users", "name", "String"),
+    ];
+    let version = |columns: &[SchemaColumn], tables: serde_json::Value| {
+        schema_version(
+            columns,
+            &serde_json::from_value(tables).expect("valid table config"),
+        )
+        .expect("can compute version")
+    };
+
+    assert_eq!(
+        version(&columns, serde_json::Value::Null),
+        version(
+            &[
+                column("users", "id", "UInt32"),
+                column("users", "name", "String"),
+            ],
+            serde_json::Value::Null
+        ),
+        "the same columns have the same version"
+    );
+    assert_ne!(
+        version(&columns, serde_json::Value::Null),
+        version(
+            &[
+                column("users", "id", "UInt32"),
+                column("users", "name", "Nullable(String)"),
+            ],
+            serde_json::Value::Null
+        ),
+        "changing a column type changes the version"
+    );
+    assert_ne!(
+        version(&columns, serde_json::Value::Null),
+        version(
+            &[
+                column("users", "id", "UInt32"),
+                column("users", "name", "String"),
+                column("users", "email", "String"),
+            ],
+            serde_json::Value::Null
+        ),
+        "adding a column changes the version"
+    );
+    assert_ne!(
+        version(
+            &columns,
+            serde_json::json!([{ "name": "users", "alias": "people" }])
+        ),
+        version(
+            &columns,
+            serde_json::json!([{ "name": "users", "alias": "members" }])
+        ),
+        "changing a table alias changes the version"
+    );
+}
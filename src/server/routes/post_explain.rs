@@ -1,42 +1,312 @@
-use axum::Json;
+use axum::{extract::Query, Json};
 use axum_extra::extract::WithRejection;
-use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
+use serde::Deserialize;
 use tracing::{info_span, Instrument};
 
 use crate::{
     server::{
-        api::{explain_response::ExplainResponse, query_request::QueryRequest},
+        api::{
+            error_response::ErrorResponseType,
+            explain_response::{ExplainMode, ExplainPlanNode, ExplainResponse},
+            query_request::QueryRequest,
+        },
         client::execute_query,
         config::{SourceConfig, SourceName},
         error::ServerError,
     },
-    sql::{apply_aliases_to_query_request, QueryBuilder},
+    sql::{apply_aliases_to_query_request, QueryBuilder, QueryPortion},
 };
 
+#[derive(Debug, Deserialize)]
+pub struct ExplainParams {
+    /// If present, explain only this portion of the query
+    portion: Option<QueryPortion>,
+    /// The kind of explanation to return, defaults to the query plan
+    mode: Option<ExplainMode>,
+    /// If true, also return the query plan as a tree. Only available for the plan mode
+    plan: Option<bool>,
+    /// If true, also return the query with bound parameters, along with the parameter values
+    parameters: Option<bool>,
+    /// If true, parameter values are redacted from the response
+    redact_parameters: Option<bool>,
+}
+
 #[axum_macros::debug_handler]
 pub async fn post_explain(
     SourceName(_source_name): SourceName,
     SourceConfig(config): SourceConfig,
+    Query(params): Query<ExplainParams>,
     WithRejection(Json(request), _): WithRejection<Json<QueryRequest>, ServerError>,
 ) -> Result<Json<ExplainResponse>, ServerError> {
     let request = apply_aliases_to_query_request(request, &config)?;
-    let statement = QueryBuilder::build_sql_statement(&request, false)?;
+    let statement = match &params.portion {
+        Some(portion) => {
+            QueryBuilder::build_sql_statement_portion(&request, &config, false, portion)?
+        }
+        None => QueryBuilder::build_sql_statement(&request, &config, false)?,
+    };
     let statement_string = statement.to_string();
-    let explain_statement = format!("EXPLAIN {}", statement_string);
+    let mode = params.mode.unwrap_or_default();
+
+    // plan trees are explained as json only, and the lines derived from the tree, to avoid explaining twice
+    let (explain_statement, lines, plan) = if params.plan.unwrap_or(false) {
+        if !matches!(mode, ExplainMode::Plan) {
+            return Err(ServerError::UncaughtError {
+                details: None,
+                message: "Plan trees are only available for the plan explain mode".to_owned(),
+                error_type: ErrorResponseType::UncaughtError,
+            });
+        }
+        let explain_statement = format!("EXPLAIN json = 1, description = 1 {}", statement_string);
+        let plan_rows: Vec<ExplainRow> = execute_query(&config, &explain_statement)
+            .instrument(info_span!("get_query_plan_tree"))
+            .await?;
+        let plan = plan_tree(&plan_rows)?;
+        let mut lines = vec![];
+        plan_lines(&plan, 0, &mut lines);
+        (explain_statement, lines, Some(plan))
+    } else {
+        let explain_statement = format!("{} {}", mode.statement_prefix(), statement_string);
+        let query_plan: Vec<IndexMap<String, serde_json::Value>> =
+            execute_query(&config, &explain_statement)
+                .instrument(info_span!("get_query_plan"))
+                .await?;
+        (
+            explain_statement,
+            query_plan.into_iter().map(explain_line).collect(),
+            None,
+        )
+    };
 
-    let query_plan: Vec<ExplainRow> = execute_query(&config, &explain_statement)
-        .instrument(info_span!("get_query_plan"))
-        .await?;
+    let (parameterized_query, parameters) = if params.parameters.unwrap_or(false) {
+        let (parameterized_statement, parameters) =
+            QueryBuilder::build_parameterized_sql_statement(
+                &request,
+                &config,
+                params.portion.as_ref(),
+            )?;
+        let redact_parameters = params.redact_parameters.unwrap_or(false);
+        let parameters = parameters
+            .into_iter()
+            .map(|(placeholder, parameter)| {
+                let value = if redact_parameters {
+                    serde_json::Value::String("<redacted>".to_owned())
+                } else {
+                    parameter.value()
+                };
+                (placeholder, value)
+            })
+            .collect();
+        (Some(parameterized_statement.to_string()), Some(parameters))
+    } else {
+        (None, None)
+    };
 
     let response = ExplainResponse {
-        lines: query_plan.into_iter().map(|r| r.explain).collect(),
+        lines,
         query: explain_statement,
+        mode,
+        plan,
+        parameterized_query,
+        parameters,
     };
 
     Ok(Json(response))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize)]
 struct ExplainRow {
     explain: String,
 }
+
+#[derive(Debug, Deserialize)]
+struct ExplainPlan {
+    #[serde(rename = "Plan")]
+    plan: ExplainPlanNode,
+}
+
+/// the plan tree from the json text clickhouse returns, which may be split over several rows
+fn plan_tree(rows: &[ExplainRow]) -> Result<ExplainPlanNode, ServerError> {
+    let json: String = rows.iter().map(|row| row.explain.as_str()).collect();
+    let plans: Vec<ExplainPlan> = serde_json::from_str(&json)?;
+    plans
+        .into_iter()
+        .next()
+        .map(|plan| plan.plan)
+        .ok_or_else(|| ServerError::UncaughtError {
+            details: None,
+            message: "The database returned no query plan".to_owned(),
+            error_type: ErrorResponseType::UncaughtError,
+        })
+}
+
+/// the lines of a plan tree as a plain EXPLAIN formats them, with each step indented below its parent
+fn plan_lines(node: &ExplainPlanNode, depth: usize, lines: &mut Vec<String>) {
+    let indent = "  ".repeat(depth);
+    lines.push(match &node.description {
+        Some(description) => format!("{indent}{} ({description})", node.step),
+        None => format!("{indent}{}", node.step),
+    });
+    for child in &node.children {
+        plan_lines(child, depth + 1, lines);
+    }
+}
+
+/// most modes return a single explain column, estimates return a column per statistic
+fn explain_line(row: IndexMap<String, serde_json::Value>) -> String {
+    row.into_iter()
+        .map(|(column, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                value => value.to_string(),
+            };
+            if column == "explain" {
+                value
+            } else {
+                format!("{column}: {value}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[test]
+fn parses_plan_trees() {
+    let rows = [
+        ExplainRow {
+            explain:
+                r#"[{"Plan": {"Node Type": "Expression", "Description": "(Projection)", "Plans": ["#
+                    .to_owned(),
+        },
+        ExplainRow {
+            explain: r#"{"Node Type": "ReadFromMergeTree", "Description": "default.articles"}]}}]"#
+                .to_owned(),
+        },
+    ];
+
+    let plan = plan_tree(&rows).ok().expect("valid plan");
+    assert_eq!(
+        serde_json::to_value(&plan).expect("serializable"),
+        serde_json::json!({
+            "step": "Expression",
+            "description": "(Projection)",
+            "children": [{
+                "step": "ReadFromMergeTree",
+                "description": "default.articles",
+                "children": []
+            }]
+        }),
+        "nests child steps"
+    );
+
+    let mut lines = vec![];
+    plan_lines(&plan, 0, &mut lines);
+    assert_eq!(
+        lines,
+        vec![
+            "Expression ((Projection))",
+            "  ReadFromMergeTree (default.articles)"
+        ],
+        "indents child steps below their parent"
+    );
+}
+
+#[tokio::test]
+async fn explains_with_the_requested_mode() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::{Body, HttpBody},
+        http::{header::CONTENT_TYPE, Request},
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::server::client::stand_in_clickhouse;
+
+    // stands in for clickhouse, returning an estimate or plan tree if asked for one
+    let statements = Arc::new(Mutex::new(vec![]));
+    let recorded = statements.clone();
+    let clickhouse = Router::new().route(
+        "/",
+        post(|statement: String| async move {
+            // written out, as json! would sort the columns
+            let data = if statement.starts_with("EXPLAIN ESTIMATE ") {
+                r#"[{ "database": "default", "table": "articles", "parts": 1, "rows": "120", "marks": 1 }]"#
+            } else if statement.starts_with("EXPLAIN json = 1") {
+                r#"[{ "explain": "[{\"Plan\": {\"Node Type\": \"ReadFromMergeTree\", \"Description\": \"default.articles\"}}]" }]"#
+            } else {
+                r#"[{ "explain": "ReadFromMergeTree" }]"#
+            };
+            recorded.lock().expect("not poisoned").push(statement);
+            format!(r#"{{ "meta": [], "data": {data}, "rows": 1, "statistics": {{}} }}"#)
+        }),
+    );
+    let config = stand_in_clickhouse(clickhouse);
+    let explain = |uri: &'static str| {
+        Router::new().route("/explain", post(post_explain)).oneshot(
+            Request::post(uri)
+                .header("x-hasura-dataconnector-sourcename", "clickhouse")
+                .header("x-hasura-dataconnector-config", config.to_string())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({
+                        "table": ["articles"],
+                        "table_relationships": [],
+                        "query": {
+                            "fields": {
+                                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                            }
+                        }
+                    })
+                    .to_string(),
+                ))
+                .expect("valid request"),
+        )
+    };
+    let body = |response: axum::response::Response| async move {
+        let body = response
+            .into_body()
+            .data()
+            .await
+            .expect("has a body")
+            .expect("readable body");
+        serde_json::from_slice::<serde_json::Value>(&body).expect("json body")
+    };
+
+    let plan = body(explain("/explain").await.expect("infallible")).await;
+    assert_eq!(plan["mode"], "plan", "explains the plan by default");
+    assert!(plan["query"]
+        .as_str()
+        .expect("has a query")
+        .starts_with("EXPLAIN SELECT"));
+    assert_eq!(plan["lines"], serde_json::json!(["ReadFromMergeTree"]));
+
+    let estimate = body(explain("/explain?mode=estimate").await.expect("infallible")).await;
+    assert_eq!(estimate["mode"], "estimate");
+    assert!(estimate["query"]
+        .as_str()
+        .expect("has a query")
+        .starts_with("EXPLAIN ESTIMATE SELECT"));
+    assert_eq!(
+        estimate["lines"],
+        serde_json::json!(["database: default, table: articles, parts: 1, rows: 120, marks: 1"]),
+        "describes each estimated table on one line"
+    );
+
+    statements.lock().expect("not poisoned").clear();
+    let tree = body(explain("/explain?plan=true").await.expect("infallible")).await;
+    assert_eq!(tree["plan"]["step"], "ReadFromMergeTree");
+    assert_eq!(
+        tree["lines"],
+        serde_json::json!(["ReadFromMergeTree (default.articles)"]),
+        "derives the lines from the plan tree"
+    );
+    assert_eq!(
+        statements.lock().expect("not poisoned").len(),
+        1,
+        "explains the query once"
+    );
+}
@@ -0,0 +1,38 @@
+use axum::{extract::Query, Json};
+use axum_extra::extract::WithRejection;
+use serde::Deserialize;
+
+use crate::{
+    server::{
+        api::query_request::QueryRequest,
+        config::{SourceConfig, SourceName},
+        error::ServerError,
+    },
+    sql::{apply_aliases_to_query_request, QueryBuilder, QueryPortion, Statement},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ExplainAstParams {
+    /// If present, return only the ast of this portion of the query
+    portion: Option<QueryPortion>,
+}
+
+/// Returns the statement ast the query builder produces for the request, as json, without executing it.
+/// Intended for tooling and tests inspecting the structure of generated statements
+#[axum_macros::debug_handler]
+pub async fn post_explain_ast(
+    SourceName(_source_name): SourceName,
+    SourceConfig(config): SourceConfig,
+    Query(params): Query<ExplainAstParams>,
+    WithRejection(Json(request), _): WithRejection<Json<QueryRequest>, ServerError>,
+) -> Result<Json<Statement>, ServerError> {
+    let request = apply_aliases_to_query_request(request, &config)?;
+    let statement = match &params.portion {
+        Some(portion) => {
+            QueryBuilder::build_sql_statement_portion(&request, &config, false, portion)?
+        }
+        None => QueryBuilder::build_sql_statement(&request, &config, false)?,
+    };
+
+    Ok(Json(statement))
+}
@@ -1,36 +1,359 @@
-use axum::Json;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{
+        header::{ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
 use axum_extra::extract::WithRejection;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr, PickFirst};
+use sha2::{Digest, Sha256};
 use tracing::{info_span, Instrument};
 
 use crate::{
     server::{
         api::{
-            error_response::ErrorResponseType, query_request::QueryRequest,
+            error_response::ErrorResponseType,
+            query_request::{QueryRequest, Relationship, TableName, Target},
             query_response::QueryResponse,
         },
-        client::execute_query,
-        config::{SourceConfig, SourceName},
+        client::{execute_query, execute_query_with_parameters},
+        config::{Config, SourceConfig, SourceName},
         error::ServerError,
     },
-    sql::{apply_aliases_to_query_request, QueryBuilder},
+    sql::{
+        apply_aliases_to_query_request, apply_cursor_to_query_request, clickhouse_query_parameters,
+        next_cursor, QueryBuilder, QueryContext,
+    },
 };
 
+#[derive(Debug, Deserialize)]
+struct ReplacingMergeTreeTable {
+    name: String,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+struct TableRowEstimate {
+    table: String,
+    #[serde_as(as = "PickFirst<(_, DisplayFromStr)>")]
+    rows: u64,
+}
+
+/// how long the replacing merge tree tables of a database are reused before they are looked up again
+const REPLACING_MERGE_TREE_TABLES_TTL: Duration = Duration::from_secs(10);
+
+/// recent replacing merge tree table names and when they were read, by clickhouse url and user
+type ReplacingMergeTreeTables = HashMap<(String, String), (Instant, Vec<String>)>;
+
+static REPLACING_MERGE_TREE_TABLES: Mutex<Option<ReplacingMergeTreeTables>> = Mutex::new(None);
+
+/// the names of the replacing merge tree tables of the current database, reused across requests
+async fn replacing_merge_tree_tables(config: &Config) -> Result<Vec<String>, ServerError> {
+    let key = (config.url.to_owned(), config.username.to_owned());
+
+    let cached = REPLACING_MERGE_TREE_TABLES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .and_then(|tables| tables.get(&key))
+        .filter(|(read_at, _)| read_at.elapsed() < REPLACING_MERGE_TREE_TABLES_TTL)
+        .map(|(_, tables)| tables.to_owned());
+    if let Some(tables) = cached {
+        return Ok(tables);
+    }
+
+    let tables: Vec<ReplacingMergeTreeTable> = execute_query(
+        config,
+        "SELECT name FROM system.tables WHERE database = currentDatabase() AND engine LIKE '%ReplacingMergeTree' FORMAT JSON;",
+    )
+    .instrument(info_span!("get_replacing_merge_tree_tables"))
+    .await?;
+    let tables = tables
+        .into_iter()
+        .map(|table| table.name)
+        .collect::<Vec<_>>();
+
+    REPLACING_MERGE_TREE_TABLES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(HashMap::new)
+        .insert(key, (Instant::now(), tables.to_owned()));
+
+    Ok(tables)
+}
+
+/// how long a row estimate is reused before the statement is estimated again
+const ROW_ESTIMATE_TTL: Duration = Duration::from_secs(10);
+
+/// recent row estimates by table and when they were read, by clickhouse url, user, and estimated statement
+type RowEstimates = HashMap<(String, String, String), (Instant, HashMap<String, u64>)>;
+
+static ROW_ESTIMATES: Mutex<Option<RowEstimates>> = Mutex::new(None);
+
+/// the rows clickhouse estimates the statement reads from each table, reused across requests for the same statement
+async fn table_row_estimates(
+    statement: String,
+    config: &Config,
+) -> Result<HashMap<String, u64>, ServerError> {
+    let key = (config.url.to_owned(), config.username.to_owned(), statement);
+
+    let cached = ROW_ESTIMATES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .and_then(|estimates| estimates.get(&key))
+        .filter(|(read_at, _)| read_at.elapsed() < ROW_ESTIMATE_TTL)
+        .map(|(_, table_row_estimates)| table_row_estimates.to_owned());
+    if let Some(table_row_estimates) = cached {
+        return Ok(table_row_estimates);
+    }
+
+    let estimates: Vec<TableRowEstimate> =
+        execute_query(config, &format!("EXPLAIN ESTIMATE {}", key.2))
+            .instrument(info_span!("get_table_row_estimates"))
+            .await?;
+    let mut table_row_estimates = HashMap::new();
+    for estimate in estimates {
+        *table_row_estimates.entry(estimate.table).or_default() += estimate.rows;
+    }
+
+    let mut cache = ROW_ESTIMATES.lock().unwrap_or_else(PoisonError::into_inner);
+    let cache = cache.get_or_insert_with(HashMap::new);
+    // statements vary far more than schemas, so expired estimates are dropped rather than left to be replaced
+    cache.retain(|_, (read_at, _)| read_at.elapsed() < ROW_ESTIMATE_TTL);
+    cache.insert(key, (Instant::now(), table_row_estimates.to_owned()));
+
+    Ok(table_row_estimates)
+}
+
+/// look up information about the database that affects how the query is built, only when enabled in config
+async fn query_context(
+    request: &QueryRequest,
+    config: &Config,
+) -> Result<QueryContext, ServerError> {
+    let mut context = QueryContext::default();
+
+    if config.warn_on_non_final_reads.unwrap_or(false) {
+        context.replacing_merge_tree_tables = replacing_merge_tree_tables(config).await?;
+    }
+
+    // one estimate serves both approximate distinct counts and the estimated rows header
+    if config.approximate_distinct_count_threshold.is_some()
+        || config.estimated_rows_header.unwrap_or(false)
+    {
+        let statement = QueryBuilder::build_sql_statement(request, config, false)?;
+        context.table_row_estimates = table_row_estimates(statement.to_string(), config).await?;
+    }
+
+    Ok(context)
+}
+
+#[derive(Debug, Deserialize)]
+struct DataVersion {
+    version: String,
+}
+
+/// the tables a query request may read from, or None if any of them is not a plain table
+fn request_tables(request: &QueryRequest) -> Option<Vec<&TableName>> {
+    let (table, table_relationships) = match request {
+        QueryRequest::Table {
+            table,
+            table_relationships,
+            ..
+        } => (table, table_relationships),
+        QueryRequest::Target {
+            target: Target::Table { name },
+            table_relationships,
+            ..
+        } => (name, table_relationships),
+        QueryRequest::Target { .. } => return None,
+    };
+
+    let relationship_tables = table_relationships
+        .iter()
+        .flat_map(|table_relationships| table_relationships.relationships.values())
+        .map(|relationship| match relationship {
+            Relationship::Table { target_table, .. } => Some(target_table),
+            Relationship::Target {
+                target: Target::Table { name },
+                ..
+            } => Some(name),
+            Relationship::Target { .. } => None,
+        });
+
+    std::iter::once(Some(table))
+        .chain(relationship_tables)
+        .collect()
+}
+
+/// a signal that changes whenever the data read by the request changes, if all tables involved have a version column
+async fn data_version(
+    request: &QueryRequest,
+    config: &Config,
+) -> Result<Option<String>, ServerError> {
+    let tables = match request_tables(request) {
+        Some(tables) => tables,
+        None => return Ok(None),
+    };
+
+    // names are bound as identifier parameters, rather than written into the statement
+    let mut parameters = vec![];
+    let versions = tables
+        .iter()
+        .enumerate()
+        .map(|(index, table)| {
+            let table_name = table.last()?;
+            let table_config = config
+                .tables
+                .iter()
+                .flatten()
+                .find(|table_config| &table_config.name == table_name)?;
+            // templated tables resolve to a different physical table per session
+            if table_config.name_template.is_some() {
+                return None;
+            }
+            let version_column = table_config.version_column.as_ref()?;
+            parameters.push((format!("version_column_{index}"), version_column.to_owned()));
+            parameters.push((format!("table_{index}"), table_name.to_owned()));
+            Some(format!(
+                "(SELECT max({{version_column_{index}:Identifier}}) FROM {{table_{index}:Identifier}})"
+            ))
+        })
+        .collect::<Option<Vec<_>>>();
+
+    let versions = match versions {
+        Some(versions) => versions,
+        None => return Ok(None),
+    };
+
+    let rows: Vec<DataVersion> = execute_query_with_parameters(
+        config,
+        &format!(
+            r#"SELECT toString(tuple({})) AS "version" FORMAT JSON;"#,
+            versions.join(", ")
+        ),
+        &parameters,
+    )
+    .instrument(info_span!("get_data_version"))
+    .await?;
+
+    Ok(rows.into_iter().next().map(|row| row.version))
+}
+
+/// weak etag over everything that determines the query result: the statement, the values of its query parameters,
+/// which are sent separately when parameters are bound, and the version of the data it reads
+fn query_etag(statement: &str, parameters: &[(String, String)], data_version: &str) -> String {
+    let mut hasher = Sha256::new();
+    // values are prefixed with their length, so moving text between them changes the digest
+    for value in std::iter::once(statement)
+        .chain(
+            parameters
+                .iter()
+                .flat_map(|(name, value)| [name.as_str(), value.as_str()]),
+        )
+        .chain(std::iter::once(data_version))
+    {
+        hasher.update(value.len().to_le_bytes());
+        hasher.update(value);
+    }
+    format!(r#"W/"{:x}""#, hasher.finalize())
+}
+
+/// weak comparison of the etag against the If-None-Match header
+fn etag_matches(etag: &str, headers: &HeaderMap) -> bool {
+    let opaque_tag = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque_tag(tag) == opaque_tag(etag))
+}
+
+static ESTIMATED_ROWS_HEADER: HeaderName = HeaderName::from_static("x-query-estimated-rows");
+
+fn with_estimated_rows(mut response: Response, estimated_rows: u64) -> Response {
+    response
+        .headers_mut()
+        .insert(&ESTIMATED_ROWS_HEADER, HeaderValue::from(estimated_rows));
+    response
+}
+
+fn with_etag(mut response: Response, etag: &str) -> Response {
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        response.headers_mut().insert(ETAG, value);
+    }
+    response
+}
+
 #[axum_macros::debug_handler]
 pub async fn post_query(
     SourceName(_source_name): SourceName,
     SourceConfig(config): SourceConfig,
+    headers: HeaderMap,
     WithRejection(Json(request), _): WithRejection<Json<QueryRequest>, ServerError>,
-) -> Result<Json<QueryResponse>, ServerError> {
+) -> Result<Response, ServerError> {
     let request = apply_aliases_to_query_request(request, &config)?;
-    let statement = QueryBuilder::build_sql_statement(&request, false)?;
+    // the next cursor is issued for the request as received, not filtered to continue from its own cursor
+    let requested = request.clone();
+    let request = apply_cursor_to_query_request(request, &config)?;
+    let context = query_context(&request, &config).await?;
+    let estimated_rows = config
+        .estimated_rows_header
+        .unwrap_or(false)
+        .then(|| context.table_row_estimates.values().sum::<u64>());
+
+    let (statement_string, parameters, warnings) = if config.bind_query_parameters.unwrap_or(false)
+    {
+        let ((statement, parameters), warnings) =
+            QueryBuilder::build_parameterized_sql_statement_with_context(
+                &request, &config, context,
+            )?;
+        let parameters = clickhouse_query_parameters(&parameters);
+        (statement.to_string(), parameters, warnings)
+    } else {
+        let (statement, warnings) =
+            QueryBuilder::build_sql_statement_with_context(&request, &config, false, context)?;
+        (statement.to_string(), vec![], warnings)
+    };
+
+    let warnings = warnings
+        .into_iter()
+        .map(|warning| {
+            tracing::warn!("{}", warning);
+            warning.to_string()
+        })
+        .collect();
 
-    let statement_string = statement.to_string();
+    let etag = data_version(&request, &config)
+        .await?
+        .map(|data_version| query_etag(&statement_string, &parameters, &data_version));
 
-    let rows: Vec<QueryResponse> = execute_query(&config, &statement_string)
-        .instrument(info_span!("execute_query"))
-        .await?;
+    if let Some(etag) = &etag {
+        if etag_matches(etag, &headers) {
+            let response = with_etag(StatusCode::NOT_MODIFIED.into_response(), etag);
+            return Ok(match estimated_rows {
+                Some(estimated_rows) => with_estimated_rows(response, estimated_rows),
+                None => response,
+            });
+        }
+    }
 
-    let response: QueryResponse =
+    let rows: Vec<QueryResponse> =
+        execute_query_with_parameters(&config, &statement_string, &parameters)
+            .instrument(info_span!("execute_query"))
+            .await?;
+
+    let mut response: QueryResponse =
         rows.first()
             .cloned()
             .ok_or_else(|| ServerError::UncaughtError {
@@ -39,5 +362,333 @@ pub async fn post_query(
                 error_type: ErrorResponseType::UncaughtError,
             })?;
 
-    Ok(Json(response))
+    let (QueryRequest::Table { query, .. } | QueryRequest::Target { query, .. }) = &request;
+    response.unwrap_conditional_relationships(query);
+    response.omit_null_relationships(query);
+    response.set_cursor(next_cursor(&requested, &response, &config)?);
+    response.set_warnings(warnings);
+    if config.sort_response_keys.unwrap_or(false) {
+        response.sort_keys();
+    }
+
+    let response = Json(response).into_response();
+
+    let response = match &etag {
+        Some(etag) => with_etag(response, etag),
+        None => response,
+    };
+
+    Ok(match estimated_rows {
+        Some(estimated_rows) => with_estimated_rows(response, estimated_rows),
+        None => response,
+    })
+}
+
+#[tokio::test]
+async fn responds_not_modified_when_etag_matches() {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    };
+
+    use axum::{body::Body, extract::RawQuery, http::Request, routing::post, Router};
+    use tower::ServiceExt;
+
+    use crate::server::client::stand_in_clickhouse;
+
+    // stands in for clickhouse, answering data version lookups with the current version, and recording lookups and queries
+    let version = Arc::new(AtomicU64::new(1));
+    let lookups = Arc::new(Mutex::new(vec![]));
+    let queries = Arc::new(Mutex::new(vec![]));
+    let clickhouse = Router::new().route(
+        "/",
+        post({
+            let version = version.clone();
+            let lookups = lookups.clone();
+            let queries = queries.clone();
+            move |RawQuery(parameters): RawQuery, statement: String| async move {
+                let data = if statement.contains(r#"AS "version""#) {
+                    lookups
+                        .lock()
+                        .expect("not poisoned")
+                        .push((statement, parameters.unwrap_or_default()));
+                    serde_json::json!([{ "version": version.load(Ordering::SeqCst).to_string() }])
+                } else {
+                    queries.lock().expect("not poisoned").push(statement);
+                    serde_json::json!([{ "rows": [{ "id": 1 }] }])
+                };
+                serde_json::json!({ "meta": [], "data": data, "rows": 1, "statistics": {} })
+                    .to_string()
+            }
+        }),
+    );
+    let mut config = stand_in_clickhouse(clickhouse);
+    config["tables"] = serde_json::json!([{ "name": "users", "version_column": "updated_at" }]);
+    let query = |if_none_match: Option<&HeaderValue>| {
+        let request = Request::post("/query")
+            .header("x-hasura-dataconnector-sourcename", "clickhouse")
+            .header("x-hasura-dataconnector-config", config.to_string())
+            .header(axum::http::header::CONTENT_TYPE, "application/json");
+        let request = match if_none_match {
+            Some(etag) => request.header(IF_NONE_MATCH, etag),
+            None => request,
+        };
+        let body = serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [],
+            "query": {
+                "fields": { "id": { "type": "column", "column": "id", "column_type": "UInt32" } }
+            }
+        });
+        Router::new().route("/query", post(post_query)).oneshot(
+            request
+                .body(Body::from(body.to_string()))
+                .expect("valid request"),
+        )
+    };
+
+    let response = query(None).await.expect("infallible");
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .expect("sets an etag")
+        .to_owned();
+
+    let response = query(Some(&etag)).await.expect("infallible");
+    assert_eq!(
+        response.status(),
+        StatusCode::NOT_MODIFIED,
+        "repeated request is not modified"
+    );
+    assert_eq!(response.headers().get(ETAG), Some(&etag));
+    assert_eq!(
+        queries.lock().expect("not poisoned").len(),
+        1,
+        "does not run the query when it is not modified"
+    );
+
+    version.store(2, Ordering::SeqCst);
+    let response = query(Some(&etag)).await.expect("infallible");
+    assert_eq!(
+        response.status(),
+        StatusCode::OK,
+        "runs the query again once the data changes"
+    );
+    assert_ne!(response.headers().get(ETAG), Some(&etag));
+
+    let lookups = lookups.lock().expect("not poisoned");
+    let (statement, parameters) = lookups.first().expect("looked up the data version");
+    assert!(
+        statement.contains("(SELECT max({version_column_0:Identifier}) FROM {table_0:Identifier})")
+            && !statement.contains("users"),
+        "binds names rather than writing them into the lookup, got {statement}"
+    );
+    assert!(
+        parameters.contains("param_version_column_0=updated_at")
+            && parameters.contains("param_table_0=users"),
+        "sends the names as parameters, got {parameters}"
+    );
+}
+
+#[tokio::test]
+async fn sets_numeric_estimated_rows_header() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{body::Body, http::Request, routing::post, Router};
+    use tower::ServiceExt;
+
+    use crate::server::client::stand_in_clickhouse;
+
+    // stands in for clickhouse, estimating 1200 rows over two parts, and recording every statement
+    let statements = Arc::new(Mutex::new(vec![]));
+    let clickhouse = Router::new().route(
+        "/",
+        post({
+            let statements = statements.clone();
+            move |statement: String| async move {
+                let data = if statement.starts_with("EXPLAIN ESTIMATE") {
+                    serde_json::json!([
+                        { "table": "users", "rows": "1000" },
+                        { "table": "users", "rows": 200 }
+                    ])
+                } else if statement.contains(r#"AS "version""#) {
+                    serde_json::json!([{ "version": "(1)" }])
+                } else {
+                    serde_json::json!([{ "rows": [{ "id": 1 }] }])
+                };
+                statements.lock().expect("not poisoned").push(statement);
+                serde_json::json!({ "meta": [], "data": data, "rows": 1, "statistics": {} })
+                    .to_string()
+            }
+        }),
+    );
+    let mut source_config = stand_in_clickhouse(clickhouse);
+    source_config["tables"] =
+        serde_json::json!([{ "name": "users", "version_column": "updated_at" }]);
+    let query = |options: serde_json::Value, if_none_match: Option<&HeaderValue>| {
+        let mut config = source_config.clone();
+        config.as_object_mut().expect("config is an object").extend(
+            options
+                .as_object()
+                .expect("options are an object")
+                .to_owned(),
+        );
+        let request = Request::post("/query")
+            .header("x-hasura-dataconnector-sourcename", "clickhouse")
+            .header("x-hasura-dataconnector-config", config.to_string())
+            .header(axum::http::header::CONTENT_TYPE, "application/json");
+        let request = match if_none_match {
+            Some(etag) => request.header(IF_NONE_MATCH, etag),
+            None => request,
+        };
+        let body = serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [],
+            "query": {
+                "fields": { "id": { "type": "column", "column": "id", "column_type": "UInt32" } }
+            }
+        });
+        Router::new().route("/query", post(post_query)).oneshot(
+            request
+                .body(Body::from(body.to_string()))
+                .expect("valid request"),
+        )
+    };
+    let estimates = || {
+        statements
+            .lock()
+            .expect("not poisoned")
+            .drain(..)
+            .filter(|statement| statement.starts_with("EXPLAIN ESTIMATE"))
+            .count()
+    };
+    let estimated_rows = |response: &Response| {
+        response.headers().get(&ESTIMATED_ROWS_HEADER).map(|value| {
+            value
+                .to_str()
+                .expect("header is ascii")
+                .parse::<u64>()
+                .expect("header is numeric")
+        })
+    };
+
+    let response = query(serde_json::json!({}), None)
+        .await
+        .expect("infallible");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(estimated_rows(&response), None, "the header is opt in");
+    assert_eq!(estimates(), 0, "does not estimate rows unless asked to");
+
+    let options = serde_json::json!({
+        "estimated_rows_header": true,
+        "approximate_distinct_count_threshold": 1000000
+    });
+    let response = query(options.clone(), None).await.expect("infallible");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        estimated_rows(&response),
+        Some(1200),
+        "sums the estimates of every part"
+    );
+    assert_eq!(
+        estimates(),
+        1,
+        "one estimate serves both the header and approximate distinct counts"
+    );
+
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .expect("sets an etag")
+        .to_owned();
+    let response = query(options, Some(&etag)).await.expect("infallible");
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(
+        estimated_rows(&response),
+        Some(1200),
+        "not modified responses have the header as well"
+    );
+    assert_eq!(
+        estimates(),
+        0,
+        "reuses the recent estimate of the same statement"
+    );
+}
+
+#[tokio::test]
+async fn warns_about_non_final_reads_in_the_response() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::{Body, HttpBody},
+        http::Request,
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::server::client::stand_in_clickhouse;
+
+    // stands in for clickhouse, where users is a replacing merge tree, recording table lookups
+    let lookups = Arc::new(Mutex::new(0));
+    let clickhouse = Router::new().route(
+        "/",
+        post({
+            let lookups = lookups.clone();
+            move |statement: String| async move {
+                let data = if statement.contains("FROM system.tables") {
+                    *lookups.lock().expect("not poisoned") += 1;
+                    serde_json::json!([{ "name": "users" }])
+                } else {
+                    serde_json::json!([{ "rows": [{ "id": 1 }] }])
+                };
+                serde_json::json!({ "meta": [], "data": data, "rows": 1, "statistics": {} })
+                    .to_string()
+            }
+        }),
+    );
+    let mut config = stand_in_clickhouse(clickhouse);
+    config["warn_on_non_final_reads"] = true.into();
+    let query = || {
+        let body = serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [],
+            "query": {
+                "fields": { "id": { "type": "column", "column": "id", "column_type": "UInt32" } }
+            }
+        });
+        Router::new().route("/query", post(post_query)).oneshot(
+            Request::post("/query")
+                .header("x-hasura-dataconnector-sourcename", "clickhouse")
+                .header("x-hasura-dataconnector-config", config.to_string())
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .expect("valid request"),
+        )
+    };
+
+    for _ in 0..2 {
+        let response = query().await.expect("infallible");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .into_body()
+            .data()
+            .await
+            .expect("has a body")
+            .expect("readable body");
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("json body");
+        assert_eq!(
+            body["warnings"],
+            serde_json::json!([
+                "Table users is a ReplacingMergeTree read without FINAL, results may include duplicate rows"
+            ]),
+            "returns the warning with the rows"
+        );
+    }
+    assert_eq!(
+        *lookups.lock().expect("not poisoned"),
+        1,
+        "reuses the recent lookup of replacing merge tree tables"
+    );
 }
@@ -1,35 +1,604 @@
-use std::{str::FromStr, vec};
-
-use axum::Json;
+use axum::{
+    body::StreamBody,
+    extract::Query,
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response},
+    Json,
+};
 use axum_extra::extract::WithRejection;
 use indexmap::IndexMap;
+use serde::Deserialize;
 
-use crate::server::{
-    api::{raw_request::RawRequest, raw_response::RawResponse},
-    client::execute_query,
-    config::{SourceConfig, SourceName},
-    error::ServerError,
+use crate::{
+    server::{
+        api::{
+            error_response::ErrorResponseType, raw_request::RawRequest, raw_response::RawResponse,
+        },
+        client::{execute_query_streaming, execute_query_with_parameters, CONNECTOR_SETTINGS},
+        config::{SourceConfig, SourceName},
+        error::ServerError,
+    },
+    sql::escape_parameter,
 };
 
+#[derive(Debug, Deserialize)]
+pub struct RawParams {
+    /// The format of the response, defaults to json
+    format: Option<RawFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RawFormat {
+    /// all rows, buffered into a single json response
+    Json,
+    /// one json object per line, streamed as clickhouse returns them
+    Ndjson,
+}
+
+/// the characters of the query outside of string literals, quoted identifiers, and comments, along with their index.
+/// opening quotes are included, so words are split at them. comments are replaced by a single space for the same reason
+fn unquoted_chars(query: &str) -> Vec<(usize, char)> {
+    let mut unquoted = vec![];
+    let mut quote = None;
+    let mut chars = query.char_indices().peekable();
+    while let Some((index, char)) = chars.next() {
+        if let Some(quote_char) = quote {
+            if char == '\\' {
+                chars.next();
+            } else if char == quote_char {
+                quote = None;
+            }
+            continue;
+        }
+        match (char, chars.peek().map(|(_, next)| *next)) {
+            ('-', Some('-')) | ('#', _) => {
+                while chars.next_if(|(_, char)| *char != '\n').is_some() {}
+                unquoted.push((index, ' '));
+                continue;
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = None;
+                for (_, char) in chars.by_ref() {
+                    if previous == Some('*') && char == '/' {
+                        break;
+                    }
+                    previous = Some(char);
+                }
+                unquoted.push((index, ' '));
+                continue;
+            }
+            _ => {}
+        }
+        if matches!(char, '\'' | '"' | '`') {
+            quote = Some(char);
+        }
+        unquoted.push((index, char));
+    }
+    unquoted
+}
+
+/// the words of the query outside of string literals and quoted identifiers, along with the index they end at
+fn unquoted_words(query: &str) -> Vec<(&str, usize)> {
+    let mut words = vec![];
+    let mut word_start = None;
+    for (index, char) in unquoted_chars(query) {
+        if char.is_alphanumeric() || char == '_' {
+            word_start.get_or_insert(index);
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            words.push((&query[start..index], index));
+        }
+    }
+    if let Some(start) = word_start {
+        words.push((&query[start..], query.len()));
+    }
+    words
+}
+
+/// true if the query has a semicolon followed by anything other than whitespace or more semicolons
+fn has_multiple_statements(query: &str) -> bool {
+    let chars = unquoted_chars(query);
+    let first_separator = chars.iter().position(|(_, char)| *char == ';');
+    match first_separator {
+        Some(position) => chars[position..]
+            .iter()
+            .any(|(_, char)| !char.is_whitespace() && *char != ';'),
+        None => false,
+    }
+}
+
+/// the names of `{name:Type}` placeholders in the query, outside of string literals and quoted identifiers
+fn placeholder_names(query: &str) -> Vec<&str> {
+    unquoted_chars(query)
+        .into_iter()
+        .filter(|(_, char)| *char == '{')
+        .filter_map(|(index, _)| {
+            let rest = &query[index + 1..];
+            let (name, _type) = rest[..rest.find('}')?].split_once(':')?;
+            let name = name.trim();
+            let is_identifier = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|char| char.is_alphanumeric() || char == '_');
+            is_identifier.then_some(name)
+        })
+        .collect()
+}
+
+/// the parameter values to send to clickhouse, in the text format it parses for the placeholder type
+fn raw_parameters(
+    query: &str,
+    parameters: Option<IndexMap<String, serde_json::Value>>,
+) -> Result<Vec<(String, String)>, ServerError> {
+    let parameters = parameters.unwrap_or_default();
+    let bad_request = |message: String| ServerError::UncaughtError {
+        details: None,
+        message,
+        error_type: ErrorResponseType::UncaughtError,
+    };
+
+    if let Some(name) = placeholder_names(query)
+        .into_iter()
+        .find(|name| !parameters.contains_key(*name))
+    {
+        return Err(bad_request(format!(
+            "Missing value for query parameter \"{name}\""
+        )));
+    }
+
+    parameters
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::Null => "\\N".to_owned(),
+                serde_json::Value::Bool(value) => value.to_string(),
+                serde_json::Value::Number(value) => value.to_string(),
+                serde_json::Value::String(value) => escape_parameter(&value),
+                serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+                    return Err(bad_request(format!(
+                        "Query parameter \"{name}\" must be a string, number, boolean, or null"
+                    )))
+                }
+            };
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// set the output format of the query, unless it ends with a format clause already
+fn with_format(query: String, format: &str) -> String {
+    let statement = query.trim_end().trim_end_matches(';').trim_end();
+    let has_format = matches!(
+        unquoted_words(statement).as_slice(),
+        [.., (keyword, _), (_, end)] if keyword.eq_ignore_ascii_case("FORMAT") && *end == statement.len()
+    );
+    // a trailing line comment would swallow anything appended to its line
+    let ends_in_line_comment = matches!(
+        unquoted_chars(statement).last(),
+        Some((index, ' ')) if statement[*index..].starts_with("--") || statement[*index..].starts_with('#')
+    );
+    let separator = if ends_in_line_comment { "\n" } else { " " };
+    if has_format {
+        format!("{statement};")
+    } else {
+        format!("{statement}{separator}FORMAT {format};")
+    }
+}
+
 #[axum_macros::debug_handler]
 pub async fn post_raw(
     SourceName(_source_name): SourceName,
     SourceConfig(config): SourceConfig,
+    Query(params): Query<RawParams>,
     WithRejection(Json(request), _): WithRejection<Json<RawRequest>, ServerError>,
-) -> Result<Json<RawResponse>, ServerError> {
-    let query = request.query;
+) -> Result<Response, ServerError> {
+    if !config.allow_multi_statement_raw_queries.unwrap_or(false)
+        && has_multiple_statements(&request.query)
+    {
+        return Err(ServerError::UncaughtError {
+            details: None,
+            message: "Raw queries may only contain a single statement".to_owned(),
+            error_type: ErrorResponseType::UncaughtError,
+        });
+    }
 
-    let query = if query.contains("FORMAT JSON;") {
-        query
-    } else if query.contains(";") {
-        query.replace(";", " FORMAT JSON;")
-    } else {
-        format!("{query} FORMAT JSON;")
+    let parameters = raw_parameters(&request.query, request.parameters)?;
+
+    let mut config = config;
+    if let Some(settings) = request.settings {
+        let source_settings = config
+            .clickhouse_settings
+            .get_or_insert_with(Default::default);
+        // requests may add settings, but not override those chosen for the source
+        if let Some(name) = settings.keys().find(|name| {
+            source_settings.contains_key(*name) || CONNECTOR_SETTINGS.contains(&name.as_str())
+        }) {
+            return Err(ServerError::UncaughtError {
+                details: None,
+                message: format!(
+                    "Clickhouse setting \"{name}\" is set by the source and cannot be overridden"
+                ),
+                error_type: ErrorResponseType::UncaughtError,
+            });
+        }
+        source_settings.extend(settings);
+    }
+
+    match params.format.unwrap_or(RawFormat::Json) {
+        RawFormat::Json => {
+            let query = with_format(request.query, "JSON");
+
+            let mut rows: Vec<IndexMap<String, serde_json::Value>> =
+                execute_query_with_parameters(&config, &query, &parameters).await?;
+
+            if config.sort_response_keys.unwrap_or(false) {
+                rows.iter_mut().for_each(IndexMap::sort_keys);
+            }
+
+            let response = RawResponse { rows };
+
+            Ok(Json(response).into_response())
+        }
+        RawFormat::Ndjson => {
+            let query = with_format(request.query, "JSONEachRow");
+
+            let response = execute_query_streaming(&config, &query, &parameters).await?;
+
+            Ok((
+                [(CONTENT_TYPE, "application/x-ndjson")],
+                StreamBody::new(response.bytes_stream()),
+            )
+                .into_response())
+        }
+    }
+}
+
+#[test]
+fn keeps_explicit_formats() {
+    assert_eq!(
+        with_format("SELECT 1 FORMAT CSV".to_owned(), "JSON"),
+        "SELECT 1 FORMAT CSV;",
+        "keeps the format of the query"
+    );
+    assert_eq!(
+        with_format("SELECT * FROM t format TabSeparated ;\n".to_owned(), "JSON"),
+        "SELECT * FROM t format TabSeparated;"
+    );
+    assert_eq!(
+        with_format("SELECT 'a; FORMAT CSV' AS s;".to_owned(), "JSON"),
+        "SELECT 'a; FORMAT CSV' AS s FORMAT JSON;",
+        "ignores semicolons and formats within string literals"
+    );
+    assert_eq!(
+        with_format("SELECT 1 AS format".to_owned(), "JSON"),
+        "SELECT 1 AS format FORMAT JSON;",
+        "appends a format to queries without a trailing semicolon"
+    );
+    assert_eq!(
+        with_format("SELECT 1 -- one".to_owned(), "JSON"),
+        "SELECT 1 -- one\nFORMAT JSON;",
+        "appends the format after a trailing line comment"
+    );
+}
+
+#[test]
+fn detects_multiple_statements() {
+    assert!(!has_multiple_statements("SELECT 1"));
+    assert!(
+        !has_multiple_statements("SELECT 1;\n"),
+        "allows a single trailing semicolon"
+    );
+    assert!(
+        !has_multiple_statements("SELECT 'a; DROP TABLE t' AS s;"),
+        "ignores semicolons within string literals"
+    );
+    assert!(
+        !has_multiple_statements(r#"SELECT 1 AS "a;b""#),
+        "ignores semicolons within quoted identifiers"
+    );
+    assert!(
+        !has_multiple_statements("SELECT 1 -- a; b"),
+        "ignores semicolons within line comments"
+    );
+    assert!(
+        !has_multiple_statements("SELECT 1; # trailing comment\n/* another; */"),
+        "ignores comments after the trailing semicolon"
+    );
+    assert!(
+        has_multiple_statements("SELECT 1 -- it's\n; DROP TABLE t"),
+        "quotes within comments do not hide the rest of the query"
+    );
+    assert!(
+        has_multiple_statements("SELECT 1 /* it's */; DROP TABLE t"),
+        "quotes within block comments do not hide the rest of the query"
+    );
+    assert!(has_multiple_statements("SELECT 1; DROP TABLE t"));
+    assert!(has_multiple_statements("SELECT 1;; SELECT 2;"));
+}
+
+#[test]
+fn requires_values_for_every_placeholder() {
+    let parameters = |value: serde_json::Value| serde_json::from_value(value).ok();
+
+    let query =
+        "SELECT * FROM t WHERE id = {id:UInt32} AND name = {name: String} AND s = '{x:String}'";
+    assert_eq!(
+        raw_parameters(
+            query,
+            parameters(serde_json::json!({ "id": 5, "name": "a'b", "unused": null }))
+        )
+        .ok(),
+        Some(vec![
+            ("id".to_owned(), "5".to_owned()),
+            ("name".to_owned(), "a'b".to_owned()),
+            ("unused".to_owned(), "\\N".to_owned()),
+        ]),
+        "ignores placeholders within string literals"
+    );
+    assert!(
+        raw_parameters(query, parameters(serde_json::json!({ "id": 5 }))).is_err(),
+        "rejects queries missing a parameter"
+    );
+    assert!(
+        raw_parameters(
+            query,
+            parameters(serde_json::json!({ "id": [5], "name": "a" }))
+        )
+        .is_err(),
+        "rejects values that are not scalars"
+    );
+    assert_eq!(
+        raw_parameters(
+            "SELECT {path:String}, {text:String}, {missing:Nullable(String)}",
+            parameters(serde_json::json!({ "missing": null, "path": "a\\b", "text": "\\N" }))
+        )
+        .ok(),
+        Some(vec![
+            ("missing".to_owned(), "\\N".to_owned()),
+            ("path".to_owned(), "a\\\\b".to_owned()),
+            ("text".to_owned(), "\\\\N".to_owned()),
+        ]),
+        "escapes strings, so they are not read as escape sequences or null"
+    );
+    assert!(
+        raw_parameters("SELECT map('a', 1)", None).is_ok(),
+        "queries without placeholders need no parameters"
+    );
+}
+
+#[tokio::test]
+async fn sends_parameters_to_clickhouse() {
+    use axum::{
+        body::{Body, HttpBody},
+        extract::RawQuery,
+        http::Request,
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::server::client::stand_in_clickhouse;
+
+    // stands in for clickhouse, returning the url parameters it was sent as the only row
+    let clickhouse = Router::new().route(
+        "/",
+        post(|RawQuery(parameters): RawQuery| async move {
+            serde_json::json!({
+                "meta": [],
+                "data": [{ "parameters": parameters }],
+                "rows": 1,
+                "statistics": {}
+            })
+            .to_string()
+        }),
+    );
+    let config = stand_in_clickhouse(clickhouse);
+    let response = Router::new()
+        .route("/raw", post(post_raw))
+        .oneshot(
+            Request::post("/raw")
+                .header("x-hasura-dataconnector-sourcename", "clickhouse")
+                .header("x-hasura-dataconnector-config", config.to_string())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({
+                        "query": "SELECT {id:UInt32} AS id",
+                        "parameters": { "id": 5 },
+                        "settings": { "max_threads": "2" }
+                    })
+                    .to_string(),
+                ))
+                .expect("valid request"),
+        )
+        .await
+        .expect("infallible");
+
+    let body = response
+        .into_body()
+        .data()
+        .await
+        .expect("has a body")
+        .expect("readable body");
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("json body");
+    let parameters = body["rows"][0]["parameters"]
+        .as_str()
+        .expect("url parameters");
+    assert!(
+        parameters.contains("param_id=5"),
+        "sends parameters as clickhouse query parameters"
+    );
+    assert!(
+        parameters.contains("max_threads=2"),
+        "sends the settings of the request"
+    );
+}
+
+#[tokio::test]
+async fn rejects_multiple_statements_unless_allowed() {
+    use axum::{
+        body::{Body, HttpBody},
+        http::{Request, StatusCode},
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::server::client::stand_in_clickhouse;
+
+    let raw_request = |config: serde_json::Value| {
+        Request::post("/raw")
+            .header("x-hasura-dataconnector-sourcename", "clickhouse")
+            .header("x-hasura-dataconnector-config", config.to_string())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{ "query": "SELECT 1; SELECT 2" }"#))
+            .expect("valid request")
+    };
+
+    let response = Router::new()
+        .route("/raw", post(post_raw))
+        .oneshot(raw_request(serde_json::json!({
+            "url": "http://127.0.0.1:1/",
+            "username": "default",
+            "password": ""
+        })))
+        .await
+        .expect("infallible");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response
+        .into_body()
+        .data()
+        .await
+        .expect("has a body")
+        .expect("readable body");
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("json body");
+    assert_eq!(
+        body["message"], "Raw queries may only contain a single statement",
+        "rejects the query before sending it to clickhouse"
+    );
+
+    // stands in for clickhouse, accepting any script
+    let clickhouse = Router::new().route(
+        "/",
+        post(|| async { r#"{ "meta": [], "data": [], "rows": 0, "statistics": {} }"# }),
+    );
+    let mut config = stand_in_clickhouse(clickhouse);
+    config["allow_multi_statement_raw_queries"] = true.into();
+    let response = Router::new()
+        .route("/raw", post(post_raw))
+        .oneshot(raw_request(config))
+        .await
+        .expect("infallible");
+    assert_eq!(
+        response.status(),
+        StatusCode::OK,
+        "sends scripts when multiple statements are allowed"
+    );
+}
+
+#[tokio::test]
+async fn rejects_settings_set_by_the_source() {
+    use axum::{
+        body::{Body, HttpBody},
+        http::{Request, StatusCode},
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    let config = serde_json::json!({
+        "url": "http://127.0.0.1:1/",
+        "username": "default",
+        "password": "",
+        "clickhouse_settings": { "max_threads": "2" }
+    });
+    for settings in [
+        serde_json::json!({ "max_threads": "64" }),
+        serde_json::json!({ "max_execution_time": "0" }),
+    ] {
+        let response = Router::new()
+            .route("/raw", post(post_raw))
+            .oneshot(
+                Request::post("/raw")
+                    .header("x-hasura-dataconnector-sourcename", "clickhouse")
+                    .header("x-hasura-dataconnector-config", config.to_string())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({ "query": "SELECT 1", "settings": settings })
+                            .to_string(),
+                    ))
+                    .expect("valid request"),
+            )
+            .await
+            .expect("infallible");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response
+            .into_body()
+            .data()
+            .await
+            .expect("has a body")
+            .expect("readable body");
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("json body");
+        assert!(
+            body["message"]
+                .as_str()
+                .is_some_and(|message| message.contains("cannot be overridden")),
+            "rejects {settings} before sending the query to clickhouse"
+        );
+    }
+}
+
+#[tokio::test]
+async fn streams_rows_as_ndjson() {
+    use axum::{
+        body::{Body, HttpBody},
+        http::{Request, StatusCode},
+        routing::post,
+        Router,
     };
+    use tower::ServiceExt;
 
-    let rows: Vec<IndexMap<String, serde_json::Value>> = execute_query(&config, &query).await?;
+    use crate::server::client::stand_in_clickhouse;
 
-    let response = RawResponse { rows };
+    // stands in for clickhouse, echoing the statement it was sent as the only row
+    let clickhouse = Router::new().route(
+        "/",
+        post(|statement: String| async move {
+            format!("{}\n", serde_json::json!({ "statement": statement }))
+        }),
+    );
+    let config = stand_in_clickhouse(clickhouse);
+    let response = Router::new()
+        .route("/raw", post(post_raw))
+        .oneshot(
+            Request::post("/raw?format=ndjson")
+                .header("x-hasura-dataconnector-sourcename", "clickhouse")
+                .header("x-hasura-dataconnector-config", config.to_string())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{ "query": "SELECT 1;" }"#))
+                .expect("valid request"),
+        )
+        .await
+        .expect("infallible");
 
-    Ok(Json(response))
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response
+            .headers()
+            .get(CONTENT_TYPE)
+            .expect("has a content type"),
+        "application/x-ndjson"
+    );
+    let body = response
+        .into_body()
+        .data()
+        .await
+        .expect("has a body")
+        .expect("readable body");
+    assert_eq!(
+        body, "{\"statement\":\"SELECT 1 FORMAT JSONEachRow;\"}\n",
+        "forwards the rows returned by clickhouse"
+    );
 }
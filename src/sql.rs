@@ -1,5 +1,9 @@
 mod ast;
 mod query_builder;
+pub use ast::Statement;
 pub use query_builder::{
-    aliasing::apply_aliases_to_query_request, QueryBuilder, QueryBuilderError,
+    aliasing::apply_aliases_to_query_request,
+    cursor::{apply_cursor_to_query_request, next_cursor},
+    parameters::{clickhouse_query_parameters, escape_parameter},
+    QueryBuilder, QueryBuilderError, QueryContext, QueryPortion,
 };
@@ -1,5 +1,8 @@
-use std::fmt;
+use std::{collections::HashMap, fmt};
 
+use serde::Serialize;
+
+#[derive(Serialize)]
 pub struct Statement(pub Query);
 
 impl fmt::Display for Statement {
@@ -8,39 +11,54 @@ impl fmt::Display for Statement {
     }
 }
 
+#[derive(Serialize)]
 pub struct Query {
+    with: Vec<Cte>,
     projection: Vec<SelectItem>,
     from: Vec<TableWithJoins>,
+    prewhere: Option<Expr>,
     predicate: Option<Expr>,
     group_by: Vec<Expr>,
+    having: Option<Expr>,
     order_by: Vec<OrderByExpr>,
     limit_by: Option<LimitByExpr>,
     limit: Option<u64>,
     offset: Option<u64>,
+    settings: Vec<(Ident, Value)>,
 }
 
 impl Query {
     pub fn new(projection: Vec<SelectItem>) -> Self {
         Self {
+            with: vec![],
             projection,
             from: vec![],
+            prewhere: None,
             predicate: None,
             group_by: vec![],
+            having: None,
             order_by: vec![],
             limit_by: None,
             limit: None,
             offset: None,
+            settings: vec![],
         }
     }
     pub fn from(self, from: Vec<TableWithJoins>) -> Self {
         Self { from, ..self }
     }
+    pub fn prewhere(self, prewhere: Option<Expr>) -> Self {
+        Self { prewhere, ..self }
+    }
     pub fn predicate(self, predicate: Option<Expr>) -> Self {
         Self { predicate, ..self }
     }
     pub fn group_by(self, group_by: Vec<Expr>) -> Self {
         Self { group_by, ..self }
     }
+    pub fn having(self, having: Option<Expr>) -> Self {
+        Self { having, ..self }
+    }
     pub fn order_by(self, order_by: Vec<OrderByExpr>) -> Self {
         Self { order_by, ..self }
     }
@@ -53,23 +71,153 @@ impl Query {
     pub fn offset(self, offset: Option<u64>) -> Self {
         Self { offset, ..self }
     }
+    pub fn settings(self, settings: Vec<(Ident, Value)>) -> Self {
+        Self { settings, ..self }
+    }
     pub fn boxed(self) -> Box<Self> {
         Box::new(self)
     }
+    /// Move derived subqueries that appear more than once into a WITH clause, referencing each by name.
+    /// Derived subqueries are never correlated, so they can be defined once for the whole query
+    pub fn hoist_repeated_subqueries(mut self) -> Self {
+        let mut counts = HashMap::new();
+        for table in &self.from {
+            table.count_subqueries(&mut counts);
+        }
+        let mut hoisted = HoistedSubqueries {
+            repeated: counts
+                .into_iter()
+                .filter(|(_, count)| *count > 1)
+                .map(|(sql, _)| (sql, None))
+                .collect(),
+            named: 0,
+            ctes: vec![],
+        };
+        if hoisted.repeated.is_empty() {
+            return self;
+        }
+        for table in &mut self.from {
+            table.hoist_subqueries(&mut hoisted);
+        }
+        Self {
+            with: hoisted.ctes,
+            ..self
+        }
+    }
+}
+
+/// the subqueries to hoist by their sql, with the name of each once it has been defined
+struct HoistedSubqueries {
+    repeated: HashMap<String, Option<Ident>>,
+    named: usize,
+    ctes: Vec<Cte>,
+}
+
+impl TableWithJoins {
+    fn factors(&self) -> impl Iterator<Item = &TableFactor> {
+        std::iter::once(&self.relation).chain(self.joins.iter().map(|join| &join.relation))
+    }
+    fn factors_mut(&mut self) -> impl Iterator<Item = &mut TableFactor> {
+        std::iter::once(&mut self.relation)
+            .chain(self.joins.iter_mut().map(|join| &mut join.relation))
+    }
+    /// counts each derived subquery, without counting within repeats of a subquery already counted
+    fn count_subqueries(&self, counts: &mut HashMap<String, usize>) {
+        for factor in self.factors() {
+            if let TableFactor::Derived { subquery, .. } = factor {
+                let count = counts.entry(subquery.to_string()).or_insert(0);
+                *count += 1;
+                if *count == 1 {
+                    for table in &subquery.from {
+                        table.count_subqueries(counts);
+                    }
+                }
+            }
+        }
+    }
+    /// replaces repeated subqueries with a reference to their cte, defining it on first use
+    fn hoist_subqueries(&mut self, hoisted: &mut HoistedSubqueries) {
+        for factor in self.factors_mut() {
+            let subquery = match factor {
+                TableFactor::Derived { subquery, .. } => subquery,
+                _ => continue,
+            };
+            let sql = subquery.to_string();
+            let (name, first_use) = match hoisted.repeated.get(&sql) {
+                None => {
+                    for table in &mut subquery.from {
+                        table.hoist_subqueries(hoisted);
+                    }
+                    continue;
+                }
+                Some(Some(name)) => (name.to_owned(), false),
+                Some(None) => {
+                    let name = Ident::quoted(format!("_cte_{}", hoisted.named));
+                    hoisted.named += 1;
+                    hoisted.repeated.insert(sql, Some(name.to_owned()));
+                    (name, true)
+                }
+            };
+            let alias = match factor {
+                TableFactor::Derived { alias, .. } => alias.take(),
+                _ => None,
+            };
+            let reference = TableFactor::Table {
+                name: ObjectName(vec![name.to_owned()]),
+                alias,
+                is_final: false,
+                sample: None,
+            };
+            if let TableFactor::Derived { mut subquery, .. } = std::mem::replace(factor, reference)
+            {
+                if first_use {
+                    for table in &mut subquery.from {
+                        table.hoist_subqueries(hoisted);
+                    }
+                    hoisted.ctes.push(Cte {
+                        name,
+                        query: subquery,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// a named subquery of a WITH clause
+#[derive(Serialize)]
+pub struct Cte {
+    pub name: Ident,
+    pub query: Box<Query>,
+}
+
+impl fmt::Display for Cte {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} AS ({})", self.name, self.query)
+    }
 }
 
 impl fmt::Display for Query {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.with.is_empty() {
+            write!(f, "WITH {} ", display_separated(&self.with, ", "))?;
+        }
         write!(f, "SELECT {}", display_separated(&self.projection, ", "))?;
         if !self.from.is_empty() {
             write!(f, " FROM {}", display_separated(&self.from, ", "))?;
         }
+        if let Some(prewhere) = &self.prewhere {
+            write!(f, " PREWHERE {}", prewhere)?;
+        }
         if let Some(predicate) = &self.predicate {
             write!(f, " WHERE {}", predicate)?;
         }
         if !self.group_by.is_empty() {
             write!(f, " GROUP BY {}", display_separated(&self.group_by, ", "))?;
         }
+        if let Some(having) = &self.having {
+            write!(f, " HAVING {}", having)?;
+        }
         if !self.order_by.is_empty() {
             write!(f, " ORDER BY {}", display_separated(&self.order_by, ", "))?;
         }
@@ -86,22 +234,71 @@ impl fmt::Display for Query {
         if let Some(offset) = &self.offset {
             write!(f, " OFFSET {}", offset)?;
         }
+        if !self.settings.is_empty() {
+            let settings = self
+                .settings
+                .iter()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect::<Vec<_>>();
+            write!(f, " SETTINGS {}", settings.join(", "))?;
+        }
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct LimitByExpr {
     pub limit: u64,
     pub offset: Option<u64>,
     pub by: Vec<Expr>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+pub struct SampleExpr {
+    pub ratio: String,
+    pub offset: Option<String>,
+}
+
+impl fmt::Display for SampleExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SAMPLE {}", self.ratio)?;
+        if let Some(offset) = &self.offset {
+            write!(f, " OFFSET {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct OrderByExpr {
     pub expr: Expr,
     pub asc: Option<bool>,
     pub nulls_first: Option<bool>,
+    pub with_fill: Option<WithFill>,
+}
+
+/// fill gaps between ordered values with additional rows
+#[derive(Debug, Clone, Serialize)]
+pub struct WithFill {
+    pub from: Option<Expr>,
+    pub to: Option<Expr>,
+    pub step: Option<Expr>,
+}
+
+impl fmt::Display for WithFill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WITH FILL")?;
+        if let Some(from) = &self.from {
+            write!(f, " FROM {}", from)?;
+        }
+        if let Some(to) = &self.to {
+            write!(f, " TO {}", to)?;
+        }
+        if let Some(step) = &self.step {
+            write!(f, " STEP {}", step)?;
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Display for OrderByExpr {
@@ -117,10 +314,14 @@ impl fmt::Display for OrderByExpr {
             Some(false) => write!(f, " NULLS LAST")?,
             None => (),
         }
+        if let Some(with_fill) = &self.with_fill {
+            write!(f, " {}", with_fill)?;
+        }
         Ok(())
     }
 }
 
+#[derive(Serialize)]
 pub enum SelectItem {
     UnnamedExpr(Expr),
     ExprWithAlias { expr: Expr, alias: Ident },
@@ -139,6 +340,7 @@ impl fmt::Display for SelectItem {
     }
 }
 
+#[derive(Serialize)]
 pub struct TableWithJoins {
     pub relation: TableFactor,
     pub joins: Vec<Join>,
@@ -154,6 +356,7 @@ impl fmt::Display for TableWithJoins {
     }
 }
 
+#[derive(Serialize)]
 pub struct Join {
     pub relation: TableFactor,
     pub join_operator: JoinOperator,
@@ -216,6 +419,7 @@ impl fmt::Display for Join {
     }
 }
 
+#[derive(Serialize)]
 pub enum JoinOperator {
     Inner(JoinConstraint),
     LeftOuter(JoinConstraint),
@@ -224,6 +428,7 @@ pub enum JoinOperator {
     CrossJoin,
 }
 
+#[derive(Serialize)]
 pub enum JoinConstraint {
     On(Expr),
     Using(Vec<Ident>),
@@ -231,10 +436,14 @@ pub enum JoinConstraint {
     None,
 }
 
+#[derive(Serialize)]
 pub enum TableFactor {
     Table {
         name: ObjectName,
         alias: Option<Ident>,
+        /// read with FINAL, merging rows of ReplacingMergeTree and similar tables at query time
+        is_final: bool,
+        sample: Option<SampleExpr>,
     },
     Derived {
         subquery: Box<Query>,
@@ -249,11 +458,22 @@ pub enum TableFactor {
 impl fmt::Display for TableFactor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            TableFactor::Table { name, alias } => {
+            TableFactor::Table {
+                name,
+                alias,
+                is_final,
+                sample,
+            } => {
                 write!(f, "{}", name)?;
                 if let Some(alias) = alias {
                     write!(f, " AS {}", alias)?;
                 }
+                if *is_final {
+                    write!(f, " FINAL")?;
+                }
+                if let Some(sample) = sample {
+                    write!(f, " {}", sample)?;
+                }
             }
             TableFactor::Derived { subquery, alias } => {
                 write!(f, "({})", subquery)?;
@@ -272,7 +492,7 @@ impl fmt::Display for TableFactor {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ObjectName(pub Vec<Ident>);
 
 impl fmt::Display for ObjectName {
@@ -281,7 +501,7 @@ impl fmt::Display for ObjectName {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Expr {
     Identifier(Ident),
     CompoundIdentifier(Vec<Ident>),
@@ -311,6 +531,24 @@ pub enum Expr {
         expr: Box<Expr>,
         list: Vec<Expr>,
     },
+    Between {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    /// with an operand, conditions are values compared to the operand. Otherwise they are boolean expressions
+    Case {
+        operand: Option<Box<Expr>>,
+        conditions: Vec<(Expr, Expr)>,
+        else_result: Option<Box<Expr>>,
+    },
+    Cast {
+        expr: Box<Expr>,
+        data_type: DataType,
+    },
+    /// sql emitted verbatim. Must only be constructed from trusted input
+    Raw(String),
 }
 
 impl fmt::Display for Expr {
@@ -335,13 +573,165 @@ impl fmt::Display for Expr {
             Expr::NotInList { expr, list } => {
                 write!(f, "{} NOT IN ({})", expr, display_separated(list, ", "),)
             }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                let not = if *negated { "NOT " } else { "" };
+                write!(f, "{expr} {not}BETWEEN {low} AND {high}")
+            }
+            Expr::Case {
+                operand,
+                conditions,
+                else_result,
+            } => {
+                write!(f, "CASE")?;
+                if let Some(operand) = operand {
+                    write!(f, " {}", operand)?;
+                }
+                for (condition, result) in conditions {
+                    write!(f, " WHEN {} THEN {}", condition, result)?;
+                }
+                if let Some(else_result) = else_result {
+                    write!(f, " ELSE {}", else_result)?;
+                }
+                write!(f, " END")
+            }
+            Expr::Cast { expr, data_type } => write!(f, "CAST({} AS {})", expr, data_type),
+            Expr::Raw(sql) => write!(f, "{}", sql),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// A clickhouse data type, as used in casts
+#[derive(Debug, Clone, Serialize)]
+pub enum DataType {
+    Bool,
+    String,
+    FixedString(u32),
+    UInt8,
+    UInt16,
+    UInt32,
+    UInt64,
+    UInt128,
+    UInt256,
+    Int8,
+    Int16,
+    Int32,
+    Int64,
+    Int128,
+    Int256,
+    Float32,
+    Float64,
+    Decimal {
+        precision: u32,
+        scale: u32,
+    },
+    Date,
+    Date32,
+    DateTime {
+        timezone: Option<String>,
+    },
+    DateTime64 {
+        precision: u32,
+        timezone: Option<String>,
+    },
+    Json,
+    Uuid,
+    IPv4,
+    IPv6,
+    Nothing,
+    Nullable(Box<DataType>),
+    Array(Box<DataType>),
+    Map(Box<DataType>, Box<DataType>),
+    /// a tuple with named elements
+    Tuple(Vec<(Ident, DataType)>),
+}
+
+impl DataType {
+    pub fn nullable(data_type: DataType) -> Self {
+        DataType::Nullable(Box::new(data_type))
+    }
+    pub fn array(data_type: DataType) -> Self {
+        DataType::Array(Box::new(data_type))
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataType::Bool => write!(f, "Bool"),
+            DataType::String => write!(f, "String"),
+            DataType::FixedString(length) => write!(f, "FixedString({})", length),
+            DataType::UInt8 => write!(f, "UInt8"),
+            DataType::UInt16 => write!(f, "UInt16"),
+            DataType::UInt32 => write!(f, "UInt32"),
+            DataType::UInt64 => write!(f, "UInt64"),
+            DataType::UInt128 => write!(f, "UInt128"),
+            DataType::UInt256 => write!(f, "UInt256"),
+            DataType::Int8 => write!(f, "Int8"),
+            DataType::Int16 => write!(f, "Int16"),
+            DataType::Int32 => write!(f, "Int32"),
+            DataType::Int64 => write!(f, "Int64"),
+            DataType::Int128 => write!(f, "Int128"),
+            DataType::Int256 => write!(f, "Int256"),
+            DataType::Float32 => write!(f, "Float32"),
+            DataType::Float64 => write!(f, "Float64"),
+            DataType::Decimal { precision, scale } => {
+                write!(f, "Decimal({}, {})", precision, scale)
+            }
+            DataType::Date => write!(f, "Date"),
+            DataType::Date32 => write!(f, "Date32"),
+            DataType::DateTime { timezone: None } => write!(f, "DateTime"),
+            DataType::DateTime {
+                timezone: Some(timezone),
+            } => write!(
+                f,
+                "DateTime({})",
+                Value::SingleQuotedString(timezone.to_owned())
+            ),
+            DataType::DateTime64 {
+                precision,
+                timezone: None,
+            } => write!(f, "DateTime64({})", precision),
+            DataType::DateTime64 {
+                precision,
+                timezone: Some(timezone),
+            } => write!(
+                f,
+                "DateTime64({}, {})",
+                precision,
+                Value::SingleQuotedString(timezone.to_owned())
+            ),
+            DataType::Json => write!(f, "JSON"),
+            DataType::Uuid => write!(f, "UUID"),
+            DataType::IPv4 => write!(f, "IPv4"),
+            DataType::IPv6 => write!(f, "IPv6"),
+            DataType::Nothing => write!(f, "Nothing"),
+            DataType::Nullable(data_type) => write!(f, "Nullable({})", data_type),
+            DataType::Array(data_type) => write!(f, "Array({})", data_type),
+            DataType::Map(key, value) => write!(f, "Map({}, {})", key, value),
+            DataType::Tuple(elements) => {
+                write!(f, "Tuple(")?;
+                for (index, (name, data_type)) in elements.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} {}", name, data_type)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Function {
     pub name: ObjectName,
+    /// parameters of parametric aggregate functions, such as the level in `quantile(0.95)(column)`
+    pub parameters: Vec<Expr>,
     pub args: Vec<FunctionArgExpr>,
     pub over: Option<WindowSpec>,
     pub distinct: bool,
@@ -349,10 +739,13 @@ pub struct Function {
 
 impl fmt::Display for Function {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.parameters.is_empty() {
+            write!(f, "({})", display_separated(&self.parameters, ", "))?;
+        }
         write!(
             f,
-            "{}({}{})",
-            self.name,
+            "({}{})",
             if self.distinct { "DISTINCT " } else { "" },
             display_separated(&self.args, ", ")
         )?;
@@ -362,7 +755,7 @@ impl fmt::Display for Function {
         Ok(())
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct WindowSpec {
     pub partition_by: Vec<Expr>,
     pub order_by: Vec<OrderByExpr>,
@@ -387,7 +780,7 @@ impl fmt::Display for WindowSpec {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum FunctionArgExpr {
     Expr(Expr),
     /// Qualified wildcard, e.g. `alias.*` or `schema.table.*`.
@@ -406,7 +799,7 @@ impl fmt::Display for FunctionArgExpr {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum UnaryOperator {
     Not,
 }
@@ -419,7 +812,7 @@ impl fmt::Display for UnaryOperator {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum BinaryOperator {
     Gt,
     Lt,
@@ -439,20 +832,25 @@ impl fmt::Display for BinaryOperator {
             BinaryOperator::GtEq => write!(f, ">="),
             BinaryOperator::LtEq => write!(f, "<="),
             BinaryOperator::Eq => write!(f, "="),
-            BinaryOperator::NotEq => write!(f, "!="),
+            BinaryOperator::NotEq => write!(f, "<>"),
             BinaryOperator::And => write!(f, "AND"),
             BinaryOperator::Or => write!(f, "OR"),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Value {
     Number(String),
     SingleQuotedString(String),
     Boolean(bool),
     Null,
     Placeholder(String),
+    /// A clickhouse query parameter, substituted by clickhouse with the value sent for it, parsed as the type
+    QueryParameter {
+        name: String,
+        data_type: DataType,
+    },
 }
 
 impl fmt::Display for Value {
@@ -473,11 +871,12 @@ impl fmt::Display for Value {
             }
             Value::Null => write!(f, "NULL"),
             Value::Placeholder(s) => write!(f, "{}", s),
+            Value::QueryParameter { name, data_type } => write!(f, "{{{}:{}}}", name, data_type),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Ident {
     value: String,
     quoted: bool,
@@ -508,7 +907,9 @@ impl Ident {
 impl fmt::Display for Ident {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if self.quoted {
-            write!(f, "\"{}\"", self.value)
+            // docs: https://clickhouse.com/docs/en/sql-reference/syntax#identifiers
+            let escaped_value = self.value.replace('\\', r#"\\"#).replace('"', r#"\""#);
+            write!(f, "\"{}\"", escaped_value)
         } else {
             write!(f, "{}", self.value)
         }
@@ -1,15 +1,28 @@
-use std::vec;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::{Ipv4Addr, Ipv6Addr},
+    vec,
+};
 
 use super::ast::{
-    BinaryOperator, Expr, Function, FunctionArgExpr, Ident, Join, JoinConstraint, JoinOperator,
-    LimitByExpr, ObjectName, OrderByExpr, Query, SelectItem, Statement, TableFactor,
-    TableWithJoins, UnaryOperator, Value,
+    BinaryOperator, DataType, Expr, Function, FunctionArgExpr, Ident, Join, JoinConstraint,
+    JoinOperator, LimitByExpr, ObjectName, OrderByExpr, Query, SampleExpr, SelectItem, Statement,
+    TableFactor, TableWithJoins, UnaryOperator, Value, WindowSpec, WithFill,
+};
+use crate::server::{
+    api::query_request::{self, BinaryComparisonOperator, ScalarType},
+    ColumnMask, Config, TableConfig,
 };
-use crate::server::api::query_request::{self, BinaryComparisonOperator, ScalarType};
 use indexmap::IndexMap;
+use serde::Deserialize;
 pub mod aliasing;
+pub mod cursor;
 mod error;
+pub mod parameters;
+mod warning;
 pub use error::QueryBuilderError;
+pub use warning::QueryWarning;
 
 pub enum BoundParam {
     Number(serde_json::Number),
@@ -17,20 +30,154 @@ pub enum BoundParam {
         value: serde_json::Value,
         value_type: query_request::ScalarType,
     },
+    /// A list of values of the same type, bound as a single array parameter
+    Array {
+        values: Vec<serde_json::Value>,
+        value_type: query_request::ScalarType,
+    },
+}
+
+impl BoundParam {
+    /// The bound value, as json
+    pub fn value(&self) -> serde_json::Value {
+        match self {
+            BoundParam::Number(number) => serde_json::Value::Number(number.to_owned()),
+            BoundParam::Value { value, .. } => value.to_owned(),
+            BoundParam::Array { values, .. } => serde_json::Value::Array(values.to_owned()),
+        }
+    }
+}
+
+/// A statement built with bound parameters, and the values bound in it.
+/// Each value is rendered in the statement as an unquoted `__placeholder__N` placeholder, numbered from 0 in the order
+/// values are bound, and the map holds the value of each placeholder by that name. Every placeholder must be substituted,
+/// for example with a clickhouse query parameter, before the statement can be run
+pub type ParameterizedStatement = (Statement, IndexMap<String, BoundParam>);
+
+/// A portion of a query that can be built on its own
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryPortion {
+    Rows,
+    Aggregates,
 }
 
 fn sql_function(name: &str, args: Vec<Expr>) -> Expr {
     Expr::Function(Function {
         name: ObjectName(vec![Ident::unquoted(name)]),
+        parameters: vec![],
+        args: args.into_iter().map(FunctionArgExpr::Expr).collect(),
+        over: None,
+        distinct: false,
+    })
+}
+
+/// a CASE expression. With an operand, each condition is a value the operand is compared to,
+/// otherwise each condition is a boolean expression. Without an else result, unmatched rows are null
+fn case_expression(
+    operand: Option<Expr>,
+    conditions: Vec<(Expr, Expr)>,
+    else_result: Option<Expr>,
+) -> Expr {
+    Expr::Case {
+        operand: operand.map(Box::new),
+        conditions,
+        else_result: else_result.map(Box::new),
+    }
+}
+
+/// a json value as a sql literal of the given type, for statements built without bound parameters
+fn inline_value(value: serde_json::Value, value_type: &ScalarType) -> Expr {
+    match (value, value_type) {
+        (serde_json::Value::Null, _) => Expr::Value(Value::Null),
+        // strings compared to json columns are taken to be serialized json already
+        (serde_json::Value::String(string), _) => Expr::Value(Value::SingleQuotedString(string)),
+        (value, ScalarType::Json) => Expr::Value(Value::SingleQuotedString(value.to_string())),
+        (serde_json::Value::Number(number), _) => Expr::Value(Value::Number(number.to_string())),
+        (serde_json::Value::Bool(boolean), _) => Expr::Value(Value::Boolean(boolean)),
+        (serde_json::Value::Array(values), _) => sql_function(
+            "array",
+            values
+                .into_iter()
+                .map(|value| inline_value(value, value_type))
+                .collect(),
+        ),
+        (serde_json::Value::Object(entries), _) => sql_function(
+            "map",
+            entries
+                .into_iter()
+                .flat_map(|(key, value)| {
+                    [
+                        Expr::Value(Value::SingleQuotedString(key)),
+                        inline_value(value, value_type),
+                    ]
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// like sql_function, but returning the function itself, for use as a table function
+fn sql_function_parts(name: &str, args: Vec<Expr>) -> Function {
+    Function {
+        name: ObjectName(vec![Ident::unquoted(name)]),
+        parameters: vec![],
         args: args.into_iter().map(FunctionArgExpr::Expr).collect(),
         over: None,
         distinct: false,
+    }
+}
+
+/// escape a literal for use in a re2 regular expression
+fn escape_regex(literal: &str) -> String {
+    literal
+        .chars()
+        .flat_map(|char| {
+            let escape = "\\.+*?()|[]{}^$".contains(char);
+            escape
+                .then_some('\\')
+                .into_iter()
+                .chain(std::iter::once(char))
+        })
+        .collect()
+}
+
+fn window_function(name: &str, args: Vec<Expr>, over: WindowSpec) -> Expr {
+    Expr::Function(Function {
+        name: ObjectName(vec![Ident::unquoted(name)]),
+        parameters: vec![],
+        args: args.into_iter().map(FunctionArgExpr::Expr).collect(),
+        over: Some(over),
+        distinct: false,
     })
 }
 
 // we use the function name to alias aggregate columns when necessary.
 // the name should be reasonable short, and a valid part of a sql identifier when quoted
-fn function_name(function: &query_request::SingleColumnAggregateFunction) -> &'static str {
+/// whether nulls are ordered first, or None to leave it to clickhouse, which orders them last.
+/// unless requested otherwise, nulls are ordered as if greater than any value
+fn nulls_first(element: &query_request::OrderByElement) -> Option<bool> {
+    match (&element.nulls, &element.order_direction) {
+        (Some(query_request::OrderByNulls::First), _) => Some(true),
+        (Some(query_request::OrderByNulls::Last), _) => Some(false),
+        (Some(query_request::OrderByNulls::Default), _) => None,
+        (None, query_request::OrderDirection::Asc) => Some(false),
+        (None, query_request::OrderDirection::Desc) => Some(true),
+    }
+}
+
+fn order_by_function_name(function: &query_request::OrderByFunction) -> &'static str {
+    use query_request::OrderByFunction as OF;
+    match function {
+        OF::Lower => "lower",
+        OF::Upper => "upper",
+        OF::Length => "length",
+        OF::LengthUtf8 => "lengthUTF8",
+        OF::Abs => "abs",
+    }
+}
+
+fn function_name(function: &query_request::SingleColumnAggregateFunction) -> String {
     use query_request::SingleColumnAggregateFunction as CA;
     match function {
         CA::Avg => "avg",
@@ -47,7 +194,20 @@ fn function_name(function: &query_request::SingleColumnAggregateFunction) -> &'s
         CA::SumMerge => "sumMerge",
         CA::MinMerge => "minMerge",
         CA::MaxMerge => "maxMerge",
+        CA::SumMap => "sumMap",
+        CA::UniqTheta => "uniqTheta",
+        CA::Median => "median",
+        // dots in the level are replaced so the name contains none, e.g. quantile_0_95.
+        // adding zero writes -0.0 as 0, like the equal level 0.0
+        CA::Quantile { level } => {
+            return format!("quantile_{}", (level + 0.0).to_string().replace('.', "_"))
+        }
     }
+    .to_owned()
+}
+
+fn length_of(expr: Expr) -> Box<Expr> {
+    Box::new(sql_function("length", vec![expr]))
 }
 
 fn and_reducer(left: Expr, right: Expr) -> Expr {
@@ -65,12 +225,55 @@ fn or_reducer(left: Expr, right: Expr) -> Expr {
     }
 }
 
+/// grouped queries return one row per group, so every field must either be grouped by or aggregate the group
+fn validate_group_by(
+    fields: &query_request::Fields,
+    group_by: &Option<Vec<String>>,
+) -> Result<(), QueryBuilderError> {
+    let group_by = match group_by {
+        Some(group_by) => group_by,
+        None => {
+            return match fields
+                .iter()
+                .find(|(_, field)| matches!(field, query_request::Field::Aggregate { .. }))
+            {
+                Some((alias, _)) => Err(QueryBuilderError::InvalidGroupBy(format!(
+                    "aggregate field \"{alias}\" requires the query to be grouped"
+                ))),
+                None => Ok(()),
+            }
+        }
+    };
+    for alias in group_by {
+        match fields.get(alias) {
+            Some(query_request::Field::Column { .. } | query_request::Field::Computed { .. }) => {}
+            Some(_) => {
+                return Err(QueryBuilderError::InvalidGroupBy(format!(
+                    "field \"{alias}\" must be a column or computed field to be grouped by"
+                )))
+            }
+            None => {
+                return Err(QueryBuilderError::InvalidGroupBy(format!(
+                    "field \"{alias}\" is not a field of the query"
+                )))
+            }
+        }
+    }
+    for (alias, field) in fields {
+        if !group_by.contains(alias) && !matches!(field, query_request::Field::Aggregate { .. }) {
+            return Err(QueryBuilderError::InvalidGroupBy(format!(
+                "field \"{alias}\" must be grouped by or be an aggregate field"
+            )));
+        }
+    }
+    Ok(())
+}
 fn single_column_aggregate(
     function: &query_request::SingleColumnAggregateFunction,
     column: Expr,
-) -> Expr {
+) -> Result<Expr, QueryBuilderError> {
     use query_request::SingleColumnAggregateFunction as CA;
-    match function {
+    let expr = match function {
         CA::Avg => sql_function("avg", vec![column]),
         CA::Max => sql_function("max", vec![column]),
         CA::Min => sql_function("min", vec![column]),
@@ -85,43 +288,162 @@ fn single_column_aggregate(
         CA::SumMerge => sql_function("sumMerge", vec![column]),
         CA::MinMerge => sql_function("minMerge", vec![column]),
         CA::MaxMerge => sql_function("maxMerge", vec![column]),
+        // maps are returned as json, like other complex types
+        CA::SumMap => sql_function("toJSONString", vec![sql_function("sumMap", vec![column])]),
+        CA::UniqTheta => sql_function("uniqTheta", vec![column]),
+        CA::Median => sql_function("median", vec![column]),
+        CA::Quantile { level } => {
+            if !(0.0..=1.0).contains(level) {
+                return Err(QueryBuilderError::InvalidQuantileLevel(*level));
+            }
+            Expr::Function(Function {
+                name: ObjectName(vec![Ident::unquoted("quantile")]),
+                parameters: vec![Expr::Value(Value::Number(level.to_string()))],
+                args: vec![FunctionArgExpr::Expr(column)],
+                over: None,
+                distinct: false,
+            })
+        }
+    };
+    Ok(expr)
+}
+
+/// server time anchors are emitted as function calls, evaluated by clickhouse at query time.
+/// only date and datetime columns may be compared to an anchor
+fn server_time_anchor(
+    anchor: &str,
+    column: &query_request::ComparisonColumn,
+) -> Result<Expr, QueryBuilderError> {
+    use query_request::ScalarType as ST;
+    match column.column_type {
+        ST::Date | ST::Date32 | ST::DateTime | ST::DateTime64 => {}
+        _ => {
+            return Err(QueryBuilderError::ServerTimeComparisonOnNonTemporalColumn(
+                column.name.to_owned(),
+            ))
+        }
+    }
+    match anchor {
+        "now" | "today" | "yesterday" => Ok(sql_function(anchor, vec![])),
+        _ => Err(QueryBuilderError::UnsupportedServerTimeAnchor(
+            anchor.to_owned(),
+        )),
+    }
+}
+
+/// ip literals are validated before being sent to clickhouse, so malformed values fail with a clear error
+fn validate_ip_literal(
+    value: &serde_json::Value,
+    column_type: &ScalarType,
+) -> Result<(), QueryBuilderError> {
+    let is_valid = match (column_type, value) {
+        (ScalarType::IPv4, serde_json::Value::String(ip)) => ip.parse::<Ipv4Addr>().is_ok(),
+        (ScalarType::IPv6, serde_json::Value::String(ip)) => ip.parse::<Ipv6Addr>().is_ok(),
+        (ScalarType::IPv4 | ScalarType::IPv6, _) => false,
+        _ => true,
+    };
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(QueryBuilderError::InvalidIpLiteral(value.to_string()))
+    }
+}
+
+/// older schemas store booleans as UInt8, so booleans compared to such columns are sent as 1 or 0
+fn coerce_boolean_literal(
+    value: &serde_json::Value,
+    column_type: &ScalarType,
+) -> serde_json::Value {
+    match (column_type, value) {
+        (ScalarType::UInt8, serde_json::Value::Bool(boolean)) => u8::from(*boolean).into(),
+        _ => value.to_owned(),
+    }
+}
+
+/// large integers cannot be represented precisely in json, so may be given as strings of digits.
+/// Values outside the range of the column type are rejected rather than left to overflow in clickhouse
+fn validate_big_int_literal(
+    value: &serde_json::Value,
+    column_type: &ScalarType,
+) -> Result<(), QueryBuilderError> {
+    // the largest magnitude of negative and positive values of each type
+    let (min, max) = match column_type {
+        ScalarType::Int128 => (
+            Some("170141183460469231731687303715884105728"),
+            "170141183460469231731687303715884105727",
+        ),
+        ScalarType::UInt128 => (None, "340282366920938463463374607431768211455"),
+        ScalarType::Int256 => (
+            Some("57896044618658097711785492504343953926634992332820282019728792003956564819968"),
+            "57896044618658097711785492504343953926634992332820282019728792003956564819967",
+        ),
+        ScalarType::UInt256 => (
+            None,
+            "115792089237316195423570985008687907853269984665640564039457584007913129639935",
+        ),
+        _ => return Ok(()),
+    };
+    let int = match value {
+        serde_json::Value::String(int) => int.to_owned(),
+        serde_json::Value::Number(number) if !number.is_f64() => number.to_string(),
+        _ => return Err(QueryBuilderError::InvalidBigIntLiteral(value.to_string())),
+    };
+    let (bound, digits) = match int.strip_prefix('-') {
+        Some(digits) => (min, digits),
+        None => (Some(max), int.as_str()),
+    };
+    let magnitude = digits.trim_start_matches('0');
+
+    let is_valid = !digits.is_empty()
+        && digits.bytes().all(|b| b.is_ascii_digit())
+        && matches!(bound, Some(bound) if (magnitude.len(), magnitude) <= (bound.len(), bound));
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(QueryBuilderError::InvalidBigIntLiteral(value.to_string()))
     }
 }
 
-fn root_foreach_row_type(query: &query_request::Query) -> String {
-    format!("Array(Tuple(query {}))", query_object_type(query))
+fn root_foreach_row_type(query: &query_request::Query, large_counts: bool) -> DataType {
+    DataType::array(DataType::Tuple(vec![(
+        Ident::unquoted("query"),
+        query_object_type(query, large_counts),
+    )]))
+}
+fn root_rows_type(fields: &query_request::Fields, large_counts: bool) -> DataType {
+    DataType::array(rows_object_type(fields, large_counts))
 }
-fn root_rows_type(fields: &query_request::Fields) -> String {
-    format!("Array({})", rows_object_type(fields))
+fn root_aggregates_type(aggregates: &query_request::Aggregates, large_counts: bool) -> DataType {
+    aggregates_object_type(aggregates, large_counts)
 }
-fn root_aggregates_type(aggregates: &query_request::Aggregates) -> String {
-    aggregates_object_type(aggregates)
+/// the type of objects without any keys
+fn empty_object_type() -> DataType {
+    DataType::Map(Box::new(DataType::Nothing), Box::new(DataType::Nothing))
 }
 
-fn query_object_type(query: &query_request::Query) -> String {
-    match (&query.fields, &query.aggregates) {
-        (None, None) => "Map(Nothing, Nothing)".to_owned(),
-        (Some(fields), None) => {
-            let fields_type = rows_object_type(fields);
-            format!("Tuple(rows Array({}))", fields_type)
-        }
-        (None, Some(aggregates)) => {
-            let aggregates_type = aggregates_object_type(aggregates);
-            format!("Tuple(aggregates {})", aggregates_type)
-        }
-        (Some(fields), Some(aggregates)) => {
-            let fields_type = rows_object_type(fields);
-            let aggregates_type = aggregates_object_type(aggregates);
-            format!(
-                "Tuple(rows Array({}), aggregates {})",
-                fields_type, aggregates_type
-            )
-        }
+fn query_object_type(query: &query_request::Query, large_counts: bool) -> DataType {
+    let rows = query.fields.as_ref().map(|fields| {
+        (
+            Ident::unquoted("rows"),
+            DataType::array(rows_object_type(fields, large_counts)),
+        )
+    });
+    let aggregates = query.aggregates.as_ref().map(|aggregates| {
+        (
+            Ident::unquoted("aggregates"),
+            aggregates_object_type(aggregates, large_counts),
+        )
+    });
+    match (rows, aggregates) {
+        (None, None) => empty_object_type(),
+        (rows, aggregates) => DataType::Tuple(rows.into_iter().chain(aggregates).collect()),
     }
 }
-fn rows_object_type(fields: &query_request::Fields) -> String {
+fn rows_object_type(fields: &query_request::Fields, large_counts: bool) -> DataType {
     if fields.is_empty() {
-        "Map(Nothing, Nothing)".to_string()
+        empty_object_type()
     } else {
         let field_types = fields
             .iter()
@@ -130,155 +452,549 @@ fn rows_object_type(fields: &query_request::Fields) -> String {
                     query_request::Field::Column {
                         column: _,
                         column_type,
-                    } => type_cast_string(column_type),
+                        trim: _,
+                        type_parameters,
+                    } => column_type_cast(column_type, type_parameters),
+                    query_request::Field::Computed { function } => {
+                        type_cast(&function.result_type())
+                    }
+                    // tuples cannot be null, so conditional relationships are an array of at most one object
+                    query_request::Field::Relationship {
+                        include_when: Some(_),
+                        query,
+                        relationship: _,
+                    } => DataType::array(query_object_type(query, large_counts)),
                     query_request::Field::Relationship {
+                        include_when: None,
                         query,
                         relationship: _,
-                    } => query_object_type(query),
+                    } => query_object_type(query, large_counts),
+                    query_request::Field::RelatedColumn { column_type, .. } => {
+                        DataType::array(type_cast(column_type))
+                    }
+                    query_request::Field::Aggregate { aggregate } => {
+                        aggregate_type(aggregate, large_counts)
+                    }
                 };
-                format!("\"{}\" {}", column_name, field_type)
+                (Ident::quoted(column_name), field_type)
             })
-            .collect::<Vec<_>>();
-        format!("Tuple({})", field_types.join(", "))
+            .collect();
+        DataType::Tuple(field_types)
     }
 }
-fn aggregates_object_type(aggregates: &query_request::Aggregates) -> String {
+fn aggregates_object_type(aggregates: &query_request::Aggregates, large_counts: bool) -> DataType {
     if aggregates.is_empty() {
-        "Map(Nothing, Nothing)".to_string()
+        empty_object_type()
     } else {
         let aggregates_types = aggregates
             .iter()
             .map(|(column_name, aggregate)| {
-                let aggregate_type = match aggregate {
-                    // note! casting from UInt64 to UInt32 here
-                    // UInt64 is serialized as a JSON string, but test suite expects JSON numbers
-                    // todo: once we are able to specify return type for these aggregates, update this cast to the correct type
-                    query_request::Aggregate::ColumnCount { .. } => "UInt32".to_owned(),
-                    query_request::Aggregate::StarCount => "UInt32".to_owned(),
-                    query_request::Aggregate::SingleColumn { result_type, .. } => {
-                        type_cast_string(result_type)
-                    }
-                };
-                format!("\"{}\" {}", column_name, aggregate_type)
+                (
+                    Ident::quoted(column_name),
+                    aggregate_type(aggregate, large_counts),
+                )
             })
-            .collect::<Vec<_>>();
-        format!("Tuple({})", aggregates_types.join(", "))
+            .collect();
+        DataType::Tuple(aggregates_types)
+    }
+}
+/// counts are cast to UInt32 unless large counts are enabled, see `Config::large_count_as_string`
+fn aggregate_type(aggregate: &query_request::Aggregate, large_counts: bool) -> DataType {
+    match aggregate {
+        // UInt64 is serialized as a JSON string, so counts are UInt64 only when opted into
+        query_request::Aggregate::ColumnCount { .. }
+        | query_request::Aggregate::StarCount
+        | query_request::Aggregate::ConditionalCount { .. }
+            if large_counts =>
+        {
+            DataType::UInt64
+        }
+        // note! casting from UInt64 to UInt32 here
+        // UInt64 is serialized as a JSON string, but test suite expects JSON numbers
+        // todo: once we are able to specify return type for these aggregates, update this cast to the correct type
+        query_request::Aggregate::ColumnCount { .. } => DataType::UInt32,
+        query_request::Aggregate::StarCount => DataType::UInt32,
+        query_request::Aggregate::ConditionalCount { .. } => DataType::UInt32,
+        // clickhouse avg always returns a float, whatever the column type
+        query_request::Aggregate::SingleColumn {
+            function: query_request::SingleColumnAggregateFunction::Avg,
+            ..
+        } => DataType::nullable(DataType::Float64),
+        // clickhouse sums decimals as Decimal128, or Decimal256 for wider columns, keeping the scale
+        query_request::Aggregate::SingleColumn {
+            function: query_request::SingleColumnAggregateFunction::Sum,
+            result_type,
+            type_parameters:
+                type_parameters @ query_request::ColumnTypeParameters {
+                    precision: Some(precision),
+                    ..
+                },
+            ..
+        } => {
+            let sum_precision = if *precision > 38 { 76 } else { 38 };
+            column_type_cast(
+                result_type,
+                &query_request::ColumnTypeParameters {
+                    precision: Some(sum_precision),
+                    ..type_parameters.to_owned()
+                },
+            )
+        }
+        query_request::Aggregate::SingleColumn {
+            result_type,
+            type_parameters,
+            ..
+        } => column_type_cast(result_type, type_parameters),
+        query_request::Aggregate::ColumnArray {
+            column_type,
+            type_parameters,
+            ..
+        } => DataType::array(column_type_cast(column_type, type_parameters)),
+    }
+}
+/// like `type_cast`, but uses the column type parameters where known.
+/// Decimals stay numeric given their precision and scale, fixed strings keep their length, and dates keep their timezone
+fn column_type_cast(
+    scalar_type: &query_request::ScalarType,
+    type_parameters: &query_request::ColumnTypeParameters,
+) -> DataType {
+    use query_request::ScalarType as ST;
+    let timezone = type_parameters.timezone.to_owned();
+    match (scalar_type, type_parameters, timezone) {
+        (
+            ST::Decimal,
+            query_request::ColumnTypeParameters {
+                precision: Some(precision),
+                scale: Some(scale),
+                ..
+            },
+            _,
+        ) => DataType::nullable(DataType::Decimal {
+            precision: *precision,
+            scale: *scale,
+        }),
+        (
+            ST::FixedString,
+            query_request::ColumnTypeParameters {
+                length: Some(length),
+                ..
+            },
+            _,
+        ) => DataType::nullable(DataType::FixedString(*length)),
+        (ST::DateTime, _, Some(timezone)) => DataType::nullable(DataType::DateTime {
+            timezone: Some(timezone),
+        }),
+        (ST::DateTime64, _, Some(timezone)) => DataType::nullable(DataType::DateTime64 {
+            precision: 9,
+            timezone: Some(timezone),
+        }),
+        _ => type_cast(scalar_type),
     }
 }
 /// given a scalar type, return the type for the variant of this type that is nullable
 /// used when casting rows to named tuples, which is later used to cast to JSON
 /// we always wrap the type name in Nullable() as we don't know if the underlying column is nulable or not
-fn type_cast_string(scalar_type: &query_request::ScalarType) -> String {
+fn type_cast(scalar_type: &query_request::ScalarType) -> DataType {
     use query_request::ScalarType as ST;
-    match scalar_type {
-        ST::Bool => "Nullable(Bool)",
-        ST::String => "Nullable(String)",
-        ST::FixedString => "Nullable(FixedString)",
-        ST::UInt8 => "Nullable(UInt8)",
-        ST::UInt16 => "Nullable(UInt16)",
-        ST::UInt32 => "Nullable(UInt32)",
-        ST::UInt64 => "Nullable(UInt64)",
-        ST::UInt128 => "Nullable(UInt128)",
-        ST::UInt256 => "Nullable(UInt256)",
-        ST::Int8 => "Nullable(Int8)",
-        ST::Int16 => "Nullable(Int16)",
-        ST::Int32 => "Nullable(Int32)",
-        ST::Int64 => "Nullable(Int64)",
-        ST::Int128 => "Nullable(Int128)",
-        ST::Int256 => "Nullable(Int256)",
-        ST::Float32 => "Nullable(Float32)",
-        ST::Float64 => "Nullable(Float64)",
-        // casting decimal to string. Not sure if this is correct.
-        // cannot cast to decimal without making a call on precision and scale
-        // could go for max precision, but impossible to know scale
-        ST::Decimal => "Nullable(String)",
-        ST::Date => "Nullable(Date)",
-        ST::Date32 => "Nullable(Date32)",
-        ST::DateTime => "Nullable(DateTime)",
-        ST::DateTime64 => "Nullable(DateTime64(9))",
-        ST::Json => "Nullable(JSON)",
-        ST::Uuid => "Nullable(UUID)",
-        ST::IPv4 => "Nullable(IPv4)",
-        ST::IPv6 => "Nullable(IPv6)",
-        ST::Unknown => "Nullable(String)",
+    use DataType as DT;
+    let data_type = match scalar_type {
+        ST::Bool => DT::Bool,
+        ST::String => DT::String,
+        // FixedString requires a length, see column_type_cast for columns where it is known
+        ST::FixedString => DT::String,
+        ST::UInt8 => DT::UInt8,
+        ST::UInt16 => DT::UInt16,
+        ST::UInt32 => DT::UInt32,
+        ST::UInt64 => DT::UInt64,
+        ST::UInt128 => DT::UInt128,
+        ST::UInt256 => DT::UInt256,
+        ST::Int8 => DT::Int8,
+        ST::Int16 => DT::Int16,
+        ST::Int32 => DT::Int32,
+        ST::Int64 => DT::Int64,
+        ST::Int128 => DT::Int128,
+        ST::Int256 => DT::Int256,
+        ST::Float32 => DT::Float32,
+        ST::Float64 => DT::Float64,
+        // casting decimal to string, as precision and scale are unknown here.
+        // see column_type_cast for decimals where they are known
+        ST::Decimal => DT::String,
+        ST::Date => DT::Date,
+        ST::Date32 => DT::Date32,
+        ST::DateTime => DT::DateTime { timezone: None },
+        ST::DateTime64 => DT::DateTime64 {
+            precision: 9,
+            timezone: None,
+        },
+        ST::Json => DT::Json,
+        ST::Uuid => DT::Uuid,
+        ST::IPv4 => DT::IPv4,
+        ST::IPv6 => DT::IPv6,
+        ST::Unknown => DT::String,
         // AggregateFunction types are not really meant to be looked at directly, casting to string for now
-        ST::AvgUInt8 => "Nullable(String)",
-        ST::AvgUInt16 => "Nullable(String)",
-        ST::AvgUInt32 => "Nullable(String)",
-        ST::AvgUInt64 => "Nullable(String)",
-        ST::AvgUInt128 => "Nullable(String)",
-        ST::AvgUInt256 => "Nullable(String)",
-        ST::AvgInt8 => "Nullable(String)",
-        ST::AvgInt16 => "Nullable(String)",
-        ST::AvgInt32 => "Nullable(String)",
-        ST::AvgInt64 => "Nullable(String)",
-        ST::AvgInt128 => "Nullable(String)",
-        ST::AvgInt256 => "Nullable(String)",
-        ST::AvgFloat32 => "Nullable(String)",
-        ST::AvgFloat64 => "Nullable(String)",
-        ST::AvgDecimal => "Nullable(String)",
-        ST::SumUInt8 => "Nullable(String)",
-        ST::SumUInt16 => "Nullable(String)",
-        ST::SumUInt32 => "Nullable(String)",
-        ST::SumUInt64 => "Nullable(String)",
-        ST::SumUInt128 => "Nullable(String)",
-        ST::SumUInt256 => "Nullable(String)",
-        ST::SumInt8 => "Nullable(String)",
-        ST::SumInt16 => "Nullable(String)",
-        ST::SumInt32 => "Nullable(String)",
-        ST::SumInt64 => "Nullable(String)",
-        ST::SumInt128 => "Nullable(String)",
-        ST::SumInt256 => "Nullable(String)",
-        ST::SumFloat32 => "Nullable(String)",
-        ST::SumFloat64 => "Nullable(String)",
-        ST::SumDecimal => "Nullable(String)",
-        ST::MaxUInt8 => "Nullable(String)",
-        ST::MaxUInt16 => "Nullable(String)",
-        ST::MaxUInt32 => "Nullable(String)",
-        ST::MaxUInt64 => "Nullable(String)",
-        ST::MaxUInt128 => "Nullable(String)",
-        ST::MaxUInt256 => "Nullable(String)",
-        ST::MaxInt8 => "Nullable(String)",
-        ST::MaxInt16 => "Nullable(String)",
-        ST::MaxInt32 => "Nullable(String)",
-        ST::MaxInt64 => "Nullable(String)",
-        ST::MaxInt128 => "Nullable(String)",
-        ST::MaxInt256 => "Nullable(String)",
-        ST::MaxFloat32 => "Nullable(String)",
-        ST::MaxFloat64 => "Nullable(String)",
-        ST::MaxDecimal => "Nullable(String)",
-        ST::MinUInt8 => "Nullable(String)",
-        ST::MinUInt16 => "Nullable(String)",
-        ST::MinUInt32 => "Nullable(String)",
-        ST::MinUInt64 => "Nullable(String)",
-        ST::MinUInt128 => "Nullable(String)",
-        ST::MinUInt256 => "Nullable(String)",
-        ST::MinInt8 => "Nullable(String)",
-        ST::MinInt16 => "Nullable(String)",
-        ST::MinInt32 => "Nullable(String)",
-        ST::MinInt64 => "Nullable(String)",
-        ST::MinInt128 => "Nullable(String)",
-        ST::MinInt256 => "Nullable(String)",
-        ST::MinFloat32 => "Nullable(String)",
-        ST::MinFloat64 => "Nullable(String)",
-        ST::MinDecimal => "Nullable(String)",
-        ST::MaxDate => "Nullable(String)",
-        ST::MaxDate32 => "Nullable(String)",
-        ST::MaxDateTime => "Nullable(String)",
-        ST::MaxDateTime64 => "Nullable(String)",
-        ST::MinDate => "Nullable(String)",
-        ST::MinDate32 => "Nullable(String)",
-        ST::MinDateTime => "Nullable(String)",
-        ST::MinDateTime64 => "Nullable(String)",
-    }
-    .to_owned()
+        ST::AvgUInt8 => DT::String,
+        ST::AvgUInt16 => DT::String,
+        ST::AvgUInt32 => DT::String,
+        ST::AvgUInt64 => DT::String,
+        ST::AvgUInt128 => DT::String,
+        ST::AvgUInt256 => DT::String,
+        ST::AvgInt8 => DT::String,
+        ST::AvgInt16 => DT::String,
+        ST::AvgInt32 => DT::String,
+        ST::AvgInt64 => DT::String,
+        ST::AvgInt128 => DT::String,
+        ST::AvgInt256 => DT::String,
+        ST::AvgFloat32 => DT::String,
+        ST::AvgFloat64 => DT::String,
+        ST::AvgDecimal => DT::String,
+        ST::SumUInt8 => DT::String,
+        ST::SumUInt16 => DT::String,
+        ST::SumUInt32 => DT::String,
+        ST::SumUInt64 => DT::String,
+        ST::SumUInt128 => DT::String,
+        ST::SumUInt256 => DT::String,
+        ST::SumInt8 => DT::String,
+        ST::SumInt16 => DT::String,
+        ST::SumInt32 => DT::String,
+        ST::SumInt64 => DT::String,
+        ST::SumInt128 => DT::String,
+        ST::SumInt256 => DT::String,
+        ST::SumFloat32 => DT::String,
+        ST::SumFloat64 => DT::String,
+        ST::SumDecimal => DT::String,
+        ST::MaxUInt8 => DT::String,
+        ST::MaxUInt16 => DT::String,
+        ST::MaxUInt32 => DT::String,
+        ST::MaxUInt64 => DT::String,
+        ST::MaxUInt128 => DT::String,
+        ST::MaxUInt256 => DT::String,
+        ST::MaxInt8 => DT::String,
+        ST::MaxInt16 => DT::String,
+        ST::MaxInt32 => DT::String,
+        ST::MaxInt64 => DT::String,
+        ST::MaxInt128 => DT::String,
+        ST::MaxInt256 => DT::String,
+        ST::MaxFloat32 => DT::String,
+        ST::MaxFloat64 => DT::String,
+        ST::MaxDecimal => DT::String,
+        ST::MinUInt8 => DT::String,
+        ST::MinUInt16 => DT::String,
+        ST::MinUInt32 => DT::String,
+        ST::MinUInt64 => DT::String,
+        ST::MinUInt128 => DT::String,
+        ST::MinUInt256 => DT::String,
+        ST::MinInt8 => DT::String,
+        ST::MinInt16 => DT::String,
+        ST::MinInt32 => DT::String,
+        ST::MinInt64 => DT::String,
+        ST::MinInt128 => DT::String,
+        ST::MinInt256 => DT::String,
+        ST::MinFloat32 => DT::String,
+        ST::MinFloat64 => DT::String,
+        ST::MinDecimal => DT::String,
+        ST::MaxDate => DT::String,
+        ST::MaxDate32 => DT::String,
+        ST::MaxDateTime => DT::String,
+        ST::MaxDateTime64 => DT::String,
+        ST::MinDate => DT::String,
+        ST::MinDate32 => DT::String,
+        ST::MinDateTime => DT::String,
+        ST::MinDateTime64 => DT::String,
+        ST::Bitmap => DT::String,
+    };
+    DataType::nullable(data_type)
 }
 
+/// rows are limited per partition using LIMIT BY, or overall using LIMIT and OFFSET
+type LimitByLimitOffset = (Option<LimitByExpr>, Option<u64>, Option<u64>);
+
 pub struct QueryBuilder<'request> {
     request: &'request query_request::QueryRequest,
+    config: &'request Config,
     bind_params: bool,
+    /// set when bound parameters are sent to clickhouse as query parameters, rather than returned with the statement
+    query_parameters: bool,
     parameters: IndexMap<String, BoundParam>,
     parameter_index: i32,
+    context: QueryContext,
+    warnings: Vec<QueryWarning>,
+    /// set when the query reads an allowed system table, so the statement is run read only
+    reads_system_tables: bool,
+    /// aggregate expressions by alias, that columns of a having expression refer to while it is built
+    having_aggregates: IndexMap<String, Expr>,
+    /// the parent table, column mapping, and parent selection of a relationship matching any mapped column, while its child query is built
+    match_any_parent: Option<(
+        query_request::TableName,
+        &'request query_request::ColumnMapping,
+        Option<query_request::Expression>,
+    )>,
+}
+
+/// Information about the database, looked up before building a query
+#[derive(Debug, Default)]
+pub struct QueryContext {
+    /// Names of tables using a ReplacingMergeTree engine
+    pub replacing_merge_tree_tables: Vec<String>,
+    /// Estimated number of rows read from each table, by table name
+    pub table_row_estimates: HashMap<String, u64>,
+}
+
+/// a foreach collection rendered as a table, along with the columns it should be joined on
+struct ForeachTable<'request> {
+    relation: TableFactor,
+    alias: String,
+    columns: Vec<&'request String>,
+}
+
+fn foreach_table<'request>(
+    alias: String,
+    foreach: &'request [query_request::ForEach],
+) -> Result<ForeachTable<'request>, QueryBuilderError> {
+    // rows are folded into columns, which must all have one value per row
+    if let Some((first_row, rows)) = foreach.split_first() {
+        for (index, foreach_row) in rows.iter().enumerate() {
+            let differing_keys = first_row
+                .keys()
+                .filter(|key| !foreach_row.contains_key(*key))
+                .chain(
+                    foreach_row
+                        .keys()
+                        .filter(|key| !first_row.contains_key(*key)),
+                )
+                .cloned()
+                .collect::<Vec<_>>();
+            if !differing_keys.is_empty() {
+                return Err(QueryBuilderError::MismatchedForeachKeys(
+                    index + 1,
+                    differing_keys,
+                ));
+            }
+        }
+    }
+
+    let foreach_obj: IndexMap<String, Vec<_>> =
+        foreach
+            .iter()
+            .fold(IndexMap::new(), |mut accumulator, foreach_row| {
+                for (key, value) in foreach_row.iter() {
+                    if let Some(foreach_column) = accumulator.get_mut(key) {
+                        foreach_column.push(value.value.to_owned());
+                    } else {
+                        accumulator.insert(key.to_owned(), vec![value.value.to_owned()]);
+                    }
+                }
+                accumulator
+            });
+    let foreach_obj_json_string = serde_json::to_string(&foreach_obj)
+        .map_err(|err| QueryBuilderError::Internal(err.to_string()))?;
+
+    let foreach_expr = Function {
+        name: ObjectName(vec![Ident::unquoted("format")]),
+        parameters: vec![],
+        args: vec![
+            FunctionArgExpr::Expr(Expr::Identifier(Ident::unquoted("JSONColumns"))),
+            FunctionArgExpr::Expr(Expr::Value(Value::SingleQuotedString(
+                foreach_obj_json_string,
+            ))),
+        ],
+        over: None,
+        distinct: false,
+    };
+
+    let relation = TableFactor::TableFunction {
+        function: foreach_expr,
+        alias: Some(Ident::quoted(&alias)),
+    };
+    let columns = foreach
+        .first()
+        .map(|foreach_row| foreach_row.keys().collect())
+        .unwrap_or_default();
+
+    Ok(ForeachTable {
+        relation,
+        alias,
+        columns,
+    })
+}
+
+/// project a masked value in place of the raw column value
+fn mask_expression(
+    mask: ColumnMask,
+    expr: Expr,
+    column: &str,
+    column_type: &ScalarType,
+) -> Result<Expr, QueryBuilderError> {
+    if !matches!(column_type, ScalarType::String | ScalarType::FixedString) {
+        return Err(QueryBuilderError::MaskedColumnNotString(column.to_owned()));
+    }
+    Ok(match mask {
+        ColumnMask::Partial => sql_function(
+            "concat",
+            vec![
+                sql_function(
+                    "left",
+                    vec![expr, Expr::Value(Value::Number("2".to_owned()))],
+                ),
+                Expr::Value(Value::SingleQuotedString("***".to_owned())),
+            ],
+        ),
+        ColumnMask::Hash => sql_function("hex", vec![sql_function("SHA256", vec![expr])]),
+    })
+}
+
+/// Comparisons of a column across a relationship path are rewritten as an exists expression over the first relationship,
+/// comparing the column along the rest of the path. Rows match if any related row satisfies the comparison
+fn through_relationship_path(
+    expression: &query_request::Expression,
+) -> Option<query_request::Expression> {
+    use query_request::Expression as E;
+    let column = match expression {
+        E::BinaryComparisonOperator { column, .. }
+        | E::UnaryComparisonOperator { column, .. }
+        | E::BinaryArrayComparisonOperator { column, .. }
+        | E::Between { column, .. }
+        | E::NotBetween { column, .. } => column,
+        _ => return None,
+    };
+    let (relationship, remaining_path) = column.path.as_deref()?.split_first()?;
+
+    let mut selection = expression.to_owned();
+    match &mut selection {
+        E::BinaryComparisonOperator { column, .. }
+        | E::UnaryComparisonOperator { column, .. }
+        | E::BinaryArrayComparisonOperator { column, .. }
+        | E::Between { column, .. }
+        | E::NotBetween { column, .. } => column.path = Some(remaining_path.to_vec()),
+        _ => {}
+    }
+
+    Some(E::Exists {
+        in_table: query_request::ExistsInTable::RelatedTable {
+            relationship: relationship.to_owned(),
+        },
+        selection: Box::new(selection),
+    })
+}
+
+/// see `Config::max_generated_identifier_length`
+const DEFAULT_MAX_GENERATED_IDENTIFIER_LENGTH: usize = 255;
+
+/// the table alias having expressions are built against, see `QueryBuilder::having_expression`
+const HAVING_TABLE_ALIAS: &str = "_having";
+
+/// true if the expression, or any expression nested in it, filters on a table other than its own
+fn filters_other_tables(expression: &query_request::Expression) -> bool {
+    use query_request::Expression as E;
+    match expression {
+        E::And { expressions } | E::Or { expressions } => {
+            expressions.iter().any(filters_other_tables)
+        }
+        E::Not { expression } => filters_other_tables(expression),
+        E::Exists { .. } => true,
+        _ => through_relationship_path(expression).is_some(),
+    }
+}
+
+/// an expression matching rows equal to any row of the foreach collection
+fn foreach_selection(foreach: &[query_request::ForEach]) -> query_request::Expression {
+    let expressions = foreach
+        .iter()
+        .map(|foreach_row| query_request::Expression::And {
+            expressions: foreach_row
+                .iter()
+                .map(|(column, foreach_value)| {
+                    query_request::Expression::BinaryComparisonOperator {
+                        column: query_request::ComparisonColumn {
+                            column_type: foreach_value.value_type.to_owned(),
+                            name: column.to_owned(),
+                            path: None,
+                            timezone: None,
+                        },
+                        operator: BinaryComparisonOperator::Equal,
+                        value: query_request::ComparisonValue::ScalarValueComparison {
+                            value: foreach_value.value.to_owned(),
+                            value_type: foreach_value.value_type.to_owned(),
+                        },
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    query_request::Expression::Or { expressions }
+}
+
+/// sampling is applied to the origin table of a query, so queries with the same sample select the same rows
+fn sample_expr(
+    sample: &Option<query_request::Sample>,
+) -> Result<Option<SampleExpr>, QueryBuilderError> {
+    let sample = match sample {
+        Some(sample) => sample,
+        None => return Ok(None),
+    };
+
+    let ratio = sample.ratio.as_f64().unwrap_or(f64::NAN);
+    if !(ratio > 0.0 && (ratio <= 1.0 || sample.ratio.is_u64())) {
+        return Err(QueryBuilderError::InvalidSample(format!(
+            "ratio must be a fraction between 0 and 1 or a whole number of rows, got {}",
+            sample.ratio
+        )));
+    }
+
+    if let Some(offset) = &sample.offset {
+        let offset_value = offset.as_f64().unwrap_or(f64::NAN);
+        if !(0.0..1.0).contains(&offset_value) {
+            return Err(QueryBuilderError::InvalidSample(format!(
+                "offset must be a fraction between 0 and 1, got {}",
+                offset
+            )));
+        }
+    }
+
+    Ok(Some(SampleExpr {
+        ratio: sample.ratio.to_string(),
+        offset: sample.offset.as_ref().map(|offset| offset.to_string()),
+    }))
+}
+
+/// substitute session variables in braces. Substituted values are limited to a safe identifier charset
+fn resolve_table_name_template(
+    name_template: &str,
+    session_variables: Option<&HashMap<String, String>>,
+) -> Result<String, QueryBuilderError> {
+    let mut table_name = String::new();
+    let mut remaining = name_template;
+
+    while let Some(start) = remaining.find('{') {
+        let end = remaining[start..]
+            .find('}')
+            .ok_or_else(|| QueryBuilderError::InvalidTableNameTemplate(name_template.to_owned()))?
+            + start;
+        let variable = remaining[start + 1..end].to_lowercase();
+        let value = session_variables
+            .and_then(|session_variables| {
+                session_variables
+                    .iter()
+                    .find(|(name, _)| name.to_lowercase() == variable)
+            })
+            .map(|(_, value)| value)
+            .ok_or_else(|| QueryBuilderError::MissingSessionVariable(variable.to_owned()))?;
+
+        if value.is_empty()
+            || !value
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || char == '_')
+        {
+            return Err(QueryBuilderError::UnsafeSessionVariable(variable));
+        }
+
+        table_name.push_str(&remaining[..start]);
+        table_name.push_str(value);
+        remaining = &remaining[end + 1..];
+    }
+    table_name.push_str(remaining);
+
+    Ok(table_name)
 }
 
 fn get_target_table(
@@ -301,27 +1017,51 @@ fn get_relationship_target_table(
     match relationship {
         query_request::Relationship::Table {
             column_mapping: _,
+            match_any_column: _,
             relationship_type: _,
             target_table,
         } => Ok(target_table),
         query_request::Relationship::Target {
             column_mapping: _,
+            match_any_column: _,
             relationship_type: _,
             target,
         } => get_target_table(target),
     }
 }
+fn get_relationship_match_any_column(relationship: &query_request::Relationship) -> bool {
+    match relationship {
+        query_request::Relationship::Table {
+            match_any_column, ..
+        }
+        | query_request::Relationship::Target {
+            match_any_column, ..
+        } => match_any_column.unwrap_or(false),
+    }
+}
+/// relationship join conditions require all mapped columns to match, unless configured to match any column
+fn get_relationship_column_reducer(
+    relationship: &query_request::Relationship,
+) -> fn(Expr, Expr) -> Expr {
+    if get_relationship_match_any_column(relationship) {
+        or_reducer
+    } else {
+        and_reducer
+    }
+}
 fn get_relationship_column_mapping(
     relationship: &query_request::Relationship,
 ) -> &query_request::ColumnMapping {
     match relationship {
         query_request::Relationship::Table {
             column_mapping,
+            match_any_column: _,
             relationship_type: _,
             target_table: _,
         } => column_mapping,
         query_request::Relationship::Target {
             column_mapping,
+            match_any_column: _,
             relationship_type: _,
             target: _,
         } => column_mapping,
@@ -329,70 +1069,504 @@ fn get_relationship_column_mapping(
 }
 
 impl<'request> QueryBuilder<'request> {
-    fn new(request: &'request query_request::QueryRequest, bind_params: bool) -> Self {
+    fn new(
+        request: &'request query_request::QueryRequest,
+        config: &'request Config,
+        bind_params: bool,
+    ) -> Self {
         Self {
             request,
+            config,
             bind_params,
+            query_parameters: false,
             parameters: IndexMap::new(),
             parameter_index: 0,
+            context: QueryContext::default(),
+            warnings: vec![],
+            reads_system_tables: false,
+            having_aggregates: IndexMap::new(),
+            match_any_parent: None,
         }
     }
     pub fn build_sql_statement(
         request: &'request query_request::QueryRequest,
+        config: &'request Config,
         bind_params: bool,
     ) -> Result<Statement, QueryBuilderError> {
-        let mut builder = Self::new(request, bind_params);
+        let mut builder = Self::new(request, config, bind_params);
 
         let query = builder.root_query()?;
 
-        let statement = Statement(query);
+        let statement = builder.statement(query);
 
         Ok(statement)
     }
-    fn request_table_relationships(&self) -> &'request Vec<query_request::TableRelationships> {
-        match self.request {
-            query_request::QueryRequest::Table {
-                foreach: _,
-                query: _,
-                table: _,
-                table_relationships,
-            } => table_relationships,
-            query_request::QueryRequest::Target {
-                foreach: _,
-                query: _,
-                target: _,
-                table_relationships,
-            } => table_relationships,
+    /// Build the statement using information looked up from the database, returning any warnings collected along the way
+    pub fn build_sql_statement_with_context(
+        request: &'request query_request::QueryRequest,
+        config: &'request Config,
+        bind_params: bool,
+        context: QueryContext,
+    ) -> Result<(Statement, Vec<QueryWarning>), QueryBuilderError> {
+        let mut builder = Self::new(request, config, bind_params);
+        builder.context = context;
+
+        let query = builder.root_query()?;
+
+        let statement = builder.statement(query);
+
+        Ok((statement, builder.warnings))
+    }
+    /// Like `build_sql_statement_with_context`, but binding parameters as clickhouse `{name:Type}` query parameters,
+    /// to be sent along with the statement, see `clickhouse_query_parameters`
+    pub fn build_parameterized_sql_statement_with_context(
+        request: &'request query_request::QueryRequest,
+        config: &'request Config,
+        context: QueryContext,
+    ) -> Result<(ParameterizedStatement, Vec<QueryWarning>), QueryBuilderError> {
+        let mut builder = Self::new(request, config, true);
+        builder.query_parameters = true;
+        builder.context = context;
+
+        let query = builder.root_query()?;
+
+        let statement = builder.statement(query);
+
+        Ok(((statement, builder.parameters), builder.warnings))
+    }
+    /// Statements reading system tables are run read only
+    fn statement(&self, query: Query) -> Statement {
+        let query = query.hoist_repeated_subqueries();
+        if self.reads_system_tables {
+            Statement(query.settings(vec![(
+                Ident::unquoted("readonly"),
+                Value::Number("1".to_owned()),
+            )]))
+        } else {
+            Statement(query)
         }
     }
-    fn request_foreach(
-        &self,
-    ) -> &'request Option<Vec<IndexMap<String, query_request::ForEachValue>>> {
-        match self.request {
-            query_request::QueryRequest::Table {
-                foreach,
-                query: _,
-                table: _,
-                table_relationships: _,
-            } => foreach,
-            query_request::QueryRequest::Target {
-                foreach,
-                query: _,
-                target: _,
-                table_relationships: _,
-            } => foreach,
+    fn warn(&mut self, warning: QueryWarning) {
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
         }
     }
-    fn table_relationship(
-        &self,
+    /// The name of the table in the database. Tables may be configured with a name template, resolved using session variables
+    fn physical_table_name(
+        &mut self,
         table: &query_request::TableName,
-        relationship_name: &str,
-    ) -> Result<&'request query_request::Relationship, QueryBuilderError> {
-        let table_relationships = self.request_table_relationships();
-        let source_table = table_relationships
+    ) -> Result<ObjectName, QueryBuilderError> {
+        // system tables may only be read if allowed by the config
+        if let [database, table_name] = table.as_slice() {
+            if database.eq_ignore_ascii_case("system") {
+                let allowed = self
+                    .config
+                    .allowed_system_tables
+                    .iter()
+                    .flatten()
+                    .any(|allowed_table| allowed_table == table_name);
+                if !allowed {
+                    return Err(QueryBuilderError::SystemTableNotAllowed(
+                        table_name.to_owned(),
+                    ));
+                }
+                self.reads_system_tables = true;
+            }
+        }
+        let name_template = self
+            .config
+            .tables
             .iter()
-            .find(|table_relationships| table_relationships.source_table == *table)
-            .ok_or_else(|| QueryBuilderError::TableMissing(table.to_owned()))?;
+            .flatten()
+            .find_map(
+                |table_config| match (&table_config.name_template, table.as_slice()) {
+                    (Some(name_template), [table_name]) if &table_config.name == table_name => {
+                        Some(name_template)
+                    }
+                    _ => None,
+                },
+            );
+
+        match name_template {
+            Some(name_template) => {
+                let table_name = resolve_table_name_template(
+                    name_template,
+                    self.config.session_variables.as_ref(),
+                )?;
+                Ok(ObjectName(vec![Ident::quoted(table_name)]))
+            }
+            None => Ok(ObjectName(table.iter().map(Ident::quoted).collect())),
+        }
+    }
+    /// The mask configured for a column, if any
+    fn column_mask(&self, table: &query_request::TableName, column: &str) -> Option<ColumnMask> {
+        self.config
+            .tables
+            .iter()
+            .flatten()
+            .filter(|table_config| table.as_slice() == [table_config.name.as_str()])
+            .flat_map(|table_config| table_config.columns.iter().flatten())
+            .find(|column_config| column_config.name == column)
+            .and_then(|column_config| column_config.mask)
+    }
+    /// A column of the origin table, masked if configured, for projections other than column fields
+    fn masked_origin_column(
+        &self,
+        table: &query_request::TableName,
+        column: &str,
+        column_type: &ScalarType,
+    ) -> Result<Expr, QueryBuilderError> {
+        let expr = Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(column)]);
+        match self.column_mask(table, column) {
+            Some(mask) => mask_expression(mask, expr, column, column_type),
+            None => Ok(expr),
+        }
+    }
+    /// Distinct counts are approximated when the table is estimated to read more rows than the configured threshold
+    fn approximate_distinct_counts(&self, table: &query_request::TableName) -> bool {
+        let threshold = match self.config.approximate_distinct_count_threshold {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        table
+            .last()
+            .and_then(|table_name| self.context.table_row_estimates.get(table_name))
+            .is_some_and(|&estimate| estimate > threshold)
+    }
+    /// The table a query reads from, aliased as "_origin"
+    fn origin_table(
+        &mut self,
+        table: &query_request::TableName,
+        query: &query_request::Query,
+    ) -> Result<TableFactor, QueryBuilderError> {
+        let is_replacing_merge_tree = table.last().is_some_and(|table_name| {
+            self.context
+                .replacing_merge_tree_tables
+                .contains(table_name)
+        });
+        if is_replacing_merge_tree && !self.reads_final(table) {
+            self.warn(QueryWarning::NonFinalReplacingMergeTreeRead(
+                table.to_owned(),
+            ));
+        }
+
+        let name = self.physical_table_name(table)?;
+        let alias = Ident::quoted(if self.match_any_parent.is_some() {
+            "_child"
+        } else {
+            "_origin"
+        });
+
+        // parameterized views are selected from like table functions, with each parameter passed as `name = value`
+        if let Some(view_parameters) = &query.view_parameters {
+            if query.sample.is_some() {
+                return Err(QueryBuilderError::InvalidSample(
+                    "parameterized views cannot be sampled".to_owned(),
+                ));
+            }
+            let args = view_parameters
+                .iter()
+                .map(|(parameter, view_parameter)| {
+                    FunctionArgExpr::Expr(Expr::BinaryOp {
+                        left: Box::new(Expr::Identifier(Ident::quoted(parameter))),
+                        op: BinaryOperator::Eq,
+                        right: Box::new(self.bind_parameter(BoundParam::Value {
+                            value: view_parameter.value.to_owned(),
+                            value_type: view_parameter.value_type.to_owned(),
+                        })),
+                    })
+                })
+                .collect();
+            return self.with_match_any_parent(TableFactor::TableFunction {
+                function: Function {
+                    name,
+                    parameters: vec![],
+                    args,
+                    over: None,
+                    distinct: false,
+                },
+                alias: Some(alias),
+            });
+        }
+
+        let sample = sample_expr(&query.sample)?;
+        let child = self.table_factor(table, name, alias, sample)?;
+        self.with_match_any_parent(child)
+    }
+    /// The child table of the relationship matching any column whose child query is being built, if any
+    fn with_match_any_parent(
+        &mut self,
+        child: TableFactor,
+    ) -> Result<TableFactor, QueryBuilderError> {
+        match self.match_any_parent.clone() {
+            Some((parent_table, column_mapping, parent_selection)) => self.paired_with_parent_keys(
+                child,
+                &parent_table,
+                column_mapping,
+                parent_selection.as_ref(),
+                Ident::quoted("_origin"),
+            ),
+            None => Ok(child),
+        }
+    }
+    /// Children of a relationship matching any mapped column are paired with the key of every parent row they match,
+    /// exposed as `_parent.{source column}`. Grouping children by these keys rather than by the target columns
+    /// yields exactly one group per parent, even when a child matches several parents or a parent through several columns.
+    /// Only keys of parent rows matching the parent selection, if any, are read, rather than those of the whole parent table.
+    /// The child must be aliased `_child`
+    fn paired_with_parent_keys(
+        &mut self,
+        child: TableFactor,
+        parent_table: &query_request::TableName,
+        column_mapping: &query_request::ColumnMapping,
+        parent_selection: Option<&query_request::Expression>,
+        alias: Ident,
+    ) -> Result<TableFactor, QueryBuilderError> {
+        let parent_name = self.physical_table_name(parent_table)?;
+        // the parent selection is built as though for the parent query, which is not itself a child of a relationship matching any column
+        let outer_match_any_parent = self.match_any_parent.take();
+        let parent_selection = parent_selection
+            .map(|expression| {
+                self.selection_expression(expression, &mut 0, true, "_origin", parent_table)
+            })
+            .transpose();
+        self.match_any_parent = outer_match_any_parent;
+        let (parent_selection, parent_joins) = match parent_selection? {
+            Some((expr, joins)) => (Some(expr), joins),
+            None => (None, vec![]),
+        };
+        let parent_keys = Query::new(
+            column_mapping
+                .keys()
+                .map(|source_col| SelectItem::ExprWithAlias {
+                    expr: Expr::CompoundIdentifier(vec![
+                        Ident::quoted("_origin"),
+                        Ident::quoted(source_col),
+                    ]),
+                    alias: Ident::quoted(source_col),
+                })
+                .collect(),
+        )
+        .from(vec![TableWithJoins {
+            relation: self.table_factor(
+                parent_table,
+                parent_name,
+                Ident::quoted("_origin"),
+                None,
+            )?,
+            joins: parent_joins,
+        }])
+        .predicate(parent_selection)
+        .group_by(
+            column_mapping
+                .keys()
+                .map(|source_col| {
+                    Expr::CompoundIdentifier(vec![
+                        Ident::quoted("_origin"),
+                        Ident::quoted(source_col),
+                    ])
+                })
+                .collect(),
+        );
+
+        let join_expr = column_mapping
+            .iter()
+            .map(|(source_col, target_col)| Expr::BinaryOp {
+                left: Box::new(Expr::CompoundIdentifier(vec![
+                    Ident::quoted("_child"),
+                    Ident::quoted(target_col),
+                ])),
+                op: BinaryOperator::Eq,
+                right: Box::new(Expr::CompoundIdentifier(vec![
+                    Ident::quoted("_parent"),
+                    Ident::quoted(source_col),
+                ])),
+            })
+            .reduce(or_reducer)
+            .unwrap_or(Expr::Value(Value::Boolean(true)));
+
+        let projection = std::iter::once(SelectItem::QualifiedWildcard(ObjectName(vec![
+            Ident::quoted("_child"),
+        ])))
+        .chain(
+            column_mapping
+                .keys()
+                .map(|source_col| SelectItem::ExprWithAlias {
+                    expr: Expr::CompoundIdentifier(vec![
+                        Ident::quoted("_parent"),
+                        Ident::quoted(source_col),
+                    ]),
+                    alias: Ident::quoted(format!("_parent.{source_col}")),
+                }),
+        )
+        .collect();
+
+        let paired = Query::new(projection).from(vec![TableWithJoins {
+            relation: child,
+            joins: vec![Join {
+                relation: TableFactor::Derived {
+                    subquery: Box::new(parent_keys),
+                    alias: Some(Ident::quoted("_parent")),
+                },
+                join_operator: JoinOperator::Inner(JoinConstraint::On(join_expr)),
+            }],
+        }]);
+
+        Ok(TableFactor::Derived {
+            subquery: Box::new(paired),
+            alias: Some(alias),
+        })
+    }
+    /// The table factor reading from a table. Tables configured as a union of physical tables
+    /// are read through the merge table function, as though they were a single table
+    fn table_factor(
+        &self,
+        table: &query_request::TableName,
+        name: ObjectName,
+        alias: Ident,
+        sample: Option<SampleExpr>,
+    ) -> Result<TableFactor, QueryBuilderError> {
+        let union_tables = self
+            .table_config(table)
+            .and_then(|table_config| table_config.union_tables.as_ref());
+
+        match union_tables {
+            Some(union_tables) => {
+                if sample.is_some() {
+                    return Err(QueryBuilderError::InvalidSample(
+                        "union tables cannot be sampled".to_owned(),
+                    ));
+                }
+                let pattern = format!(
+                    "^({})$",
+                    union_tables
+                        .iter()
+                        .map(|table_name| escape_regex(table_name))
+                        .collect::<Vec<_>>()
+                        .join("|")
+                );
+                Ok(TableFactor::TableFunction {
+                    function: sql_function_parts(
+                        "merge",
+                        vec![
+                            sql_function("currentDatabase", vec![]),
+                            Expr::Value(Value::SingleQuotedString(pattern)),
+                        ],
+                    ),
+                    alias: Some(alias),
+                })
+            }
+            None => Ok(TableFactor::Table {
+                name,
+                alias: Some(alias),
+                is_final: self.reads_final(table),
+                sample,
+            }),
+        }
+    }
+    fn table_config(&self, table: &query_request::TableName) -> Option<&TableConfig> {
+        match table.as_slice() {
+            [table_name] => self
+                .config
+                .tables
+                .iter()
+                .flatten()
+                .find(|table_config| &table_config.name == table_name),
+            _ => None,
+        }
+    }
+    /// see `TableConfig::read_final`
+    fn reads_final(&self, table: &query_request::TableName) -> bool {
+        self.table_config(table)
+            .and_then(|table_config| table_config.read_final)
+            .unwrap_or(false)
+    }
+    fn request_table_relationships(&self) -> &'request Vec<query_request::TableRelationships> {
+        match self.request {
+            query_request::QueryRequest::Table {
+                foreach: _,
+                foreach_collections: _,
+                foreach_total_aggregates: _,
+                query: _,
+                table: _,
+                table_relationships,
+            } => table_relationships,
+            query_request::QueryRequest::Target {
+                foreach: _,
+                foreach_collections: _,
+                foreach_total_aggregates: _,
+                query: _,
+                target: _,
+                table_relationships,
+            } => table_relationships,
+        }
+    }
+    fn request_foreach(
+        &self,
+    ) -> &'request Option<Vec<IndexMap<String, query_request::ForEachValue>>> {
+        match self.request {
+            query_request::QueryRequest::Table {
+                foreach,
+                foreach_collections: _,
+                foreach_total_aggregates: _,
+                query: _,
+                table: _,
+                table_relationships: _,
+            } => foreach,
+            query_request::QueryRequest::Target {
+                foreach,
+                foreach_collections: _,
+                foreach_total_aggregates: _,
+                query: _,
+                target: _,
+                table_relationships: _,
+            } => foreach,
+        }
+    }
+    fn request_foreach_collections(
+        &self,
+    ) -> &'request Option<IndexMap<String, Vec<query_request::ForEach>>> {
+        match self.request {
+            query_request::QueryRequest::Table {
+                foreach: _,
+                foreach_collections,
+                foreach_total_aggregates: _,
+                query: _,
+                table: _,
+                table_relationships: _,
+            } => foreach_collections,
+            query_request::QueryRequest::Target {
+                foreach: _,
+                foreach_collections,
+                foreach_total_aggregates: _,
+                query: _,
+                target: _,
+                table_relationships: _,
+            } => foreach_collections,
+        }
+    }
+    fn request_foreach_total_aggregates(&self) -> bool {
+        match self.request {
+            query_request::QueryRequest::Table {
+                foreach_total_aggregates,
+                ..
+            }
+            | query_request::QueryRequest::Target {
+                foreach_total_aggregates,
+                ..
+            } => foreach_total_aggregates.unwrap_or(false),
+        }
+    }
+    fn table_relationship(
+        &self,
+        table: &query_request::TableName,
+        relationship_name: &str,
+    ) -> Result<&'request query_request::Relationship, QueryBuilderError> {
+        let table_relationships = self.request_table_relationships();
+        let source_table = table_relationships
+            .iter()
+            .find(|table_relationships| table_relationships.source_table == *table)
+            .ok_or_else(|| QueryBuilderError::TableMissing(table.to_owned()))?;
 
         let relationship = source_table
             .relationships
@@ -406,94 +1580,180 @@ impl<'request> QueryBuilder<'request> {
 
         Ok(relationship)
     }
-    fn root_query(&mut self) -> Result<Query, QueryBuilderError> {
-        let (table, query) = match self.request {
+    /// Build only one portion of the request's query, without the wrapping root query or foreach joins.
+    /// Useful to isolate the cost of that portion when explaining a query
+    pub fn build_sql_statement_portion(
+        request: &'request query_request::QueryRequest,
+        config: &'request Config,
+        bind_params: bool,
+        portion: &QueryPortion,
+    ) -> Result<Statement, QueryBuilderError> {
+        let mut builder = Self::new(request, config, bind_params);
+
+        let query = builder.portion_query(portion)?;
+
+        Ok(builder.statement(query))
+    }
+    /// Build the statement with bound parameters, returning the parameters alongside the statement, without running it.
+    /// If a portion is given, only that portion is built
+    pub fn build_parameterized_sql_statement(
+        request: &'request query_request::QueryRequest,
+        config: &'request Config,
+        portion: Option<&QueryPortion>,
+    ) -> Result<ParameterizedStatement, QueryBuilderError> {
+        let mut builder = Self::new(request, config, true);
+
+        let query = match portion {
+            Some(portion) => builder.portion_query(portion)?,
+            None => builder.root_query()?,
+        };
+
+        Ok((builder.statement(query), builder.parameters))
+    }
+    fn portion_query(&mut self, portion: &QueryPortion) -> Result<Query, QueryBuilderError> {
+        let (table, query) = self.request_table_query()?;
+
+        let query = match portion {
+            QueryPortion::Rows => {
+                let fields = query
+                    .fields
+                    .as_ref()
+                    .ok_or(QueryBuilderError::MissingQueryPortion("rows"))?;
+                self.rows_subquery(table, &[], fields, query, &None)?
+            }
+            QueryPortion::Aggregates => {
+                let aggregates = query
+                    .aggregates
+                    .as_ref()
+                    .ok_or(QueryBuilderError::MissingQueryPortion("aggregates"))?;
+                self.aggregates_subquery(table, &[], aggregates, query, &None)?
+            }
+        };
+
+        Ok(*query)
+    }
+    fn request_table_query(
+        &self,
+    ) -> Result<
+        (
+            &'request query_request::TableName,
+            &'request query_request::Query,
+        ),
+        QueryBuilderError,
+    > {
+        match self.request {
             query_request::QueryRequest::Table {
                 foreach: _,
+                foreach_collections: _,
+                foreach_total_aggregates: _,
                 query,
                 table,
                 table_relationships: _,
-            } => (table, query),
+            } => Ok((table, query)),
             query_request::QueryRequest::Target {
                 foreach: _,
+                foreach_collections: _,
+                foreach_total_aggregates: _,
                 query,
                 target,
                 table_relationships: _,
-            } => (get_target_table(target)?, query),
-        };
+            } => Ok((get_target_table(target)?, query)),
+        }
+    }
+    fn root_query(&mut self) -> Result<Query, QueryBuilderError> {
+        let (table, query) = self.request_table_query()?;
+        let large_counts = self.config.large_count_as_string.unwrap_or(false);
+
+        // the unnamed foreach collection keeps the plain "_foreach" alias, named collections are suffixed with their name
+        let foreach_collections = self
+            .request_foreach()
+            .iter()
+            .map(|foreach| ("_foreach".to_owned(), foreach))
+            .chain(
+                self.request_foreach_collections()
+                    .iter()
+                    .flatten()
+                    .map(|(name, foreach)| (format!("_foreach.{name}"), foreach)),
+            )
+            .collect::<Vec<_>>();
 
-        let foreach = match self.request {
-            query_request::QueryRequest::Table { foreach, .. } => foreach,
-            query_request::QueryRequest::Target { foreach, .. } => foreach,
+        // the grand total is the same query, filtered to rows matching any row of every foreach collection
+        let total_aggregates = match &query.aggregates {
+            Some(aggregates)
+                if self.request_foreach_total_aggregates() && !foreach_collections.is_empty() =>
+            {
+                let foreach_selection = foreach_collections
+                    .iter()
+                    .map(|(_, foreach)| foreach_selection(foreach));
+                let total_query = query_request::Query {
+                    fields: None,
+                    selection: Some(query_request::Expression::And {
+                        expressions: query
+                            .selection
+                            .iter()
+                            .cloned()
+                            .chain(foreach_selection)
+                            .collect(),
+                    }),
+                    ..query.clone()
+                };
+                let total_subquery = self.query_subquery(table, &vec![], &total_query, None)?;
+                Some((total_subquery, aggregates))
+            }
+            _ => None,
         };
 
-        let root_subquery = match foreach {
-            Some(foreach) => {
-                // todo: verify that all objects of the foreach collection have the same keys.
-                // fail gracefully if not
-                // handle the case where there are no objects in the foreach collection. Unsure if this could happen at all?
+        let root_subquery = if foreach_collections.is_empty() {
+            self.query_subquery(table, &vec![], query, None)?
+        } else if foreach_collections
+            .iter()
+            .any(|(_, foreach)| foreach.is_empty())
+        {
+            // there are no combinations of foreach rows to repeat the query for, so the response has no rows
+            Query::new(vec![SelectItem::ExprWithAlias {
+                expr: sql_function("tuple", vec![sql_function("array", vec![])]),
+                alias: Ident::quoted("query"),
+            }])
+            .boxed()
+        } else {
+            let mut foreach_tables: Vec<ForeachTable> = vec![];
 
-                let foreach_obj: IndexMap<String, Vec<_>> =
-                    foreach
-                        .iter()
-                        .fold(IndexMap::new(), |mut accumulator, foreach_row| {
-                            for (key, value) in foreach_row.iter() {
-                                if let Some(foreach_column) = accumulator.get_mut(key) {
-                                    foreach_column.push(value.value.to_owned());
-                                } else {
-                                    accumulator
-                                        .insert(key.to_owned(), vec![value.value.to_owned()]);
-                                }
-                            }
-                            accumulator
-                        });
-                let foreach_obj_json_string = serde_json::to_string(&foreach_obj)
-                    .map_err(|err| QueryBuilderError::Internal(err.to_string()))?;
-
-                let foreach_expr = Function {
-                    name: ObjectName(vec![Ident::unquoted("format")]),
-                    args: vec![
-                        FunctionArgExpr::Expr(Expr::Identifier(Ident::unquoted("JSONColumns"))),
-                        FunctionArgExpr::Expr(Expr::Value(Value::SingleQuotedString(
-                            foreach_obj_json_string,
-                        ))),
-                    ],
-                    over: None,
-                    distinct: false,
-                };
+            for (alias, foreach) in foreach_collections {
+                let foreach_table = foreach_table(alias, foreach)?;
 
-                let foreach_table = TableFactor::TableFunction {
-                    function: foreach_expr,
-                    alias: Some(Ident::quoted("_foreach")),
-                };
-                let foreach_columns: Vec<_> = foreach[0].keys().collect();
+                // each column is joined to the foreach table that holds it, so key sets must not overlap
+                for column in &foreach_table.columns {
+                    if foreach_tables
+                        .iter()
+                        .any(|other_table| other_table.columns.contains(column))
+                    {
+                        return Err(QueryBuilderError::DuplicateForeachColumn(
+                            column.to_string(),
+                        ));
+                    }
+                }
 
-                self.query_subquery(
-                    table,
-                    &vec![],
-                    query,
-                    Some((foreach_table, &foreach_columns)),
-                )?
+                foreach_tables.push(foreach_table);
             }
-            None => self.query_subquery(table, &vec![], query, None)?,
+
+            self.query_subquery(table, &vec![], query, Some(foreach_tables))?
         };
 
         let query_expr =
             Expr::CompoundIdentifier(vec![Ident::quoted("_query"), Ident::quoted("query")]);
 
-        let root_projection = if self.request_foreach().is_some() {
-            let cast_typestring = root_foreach_row_type(query);
+        let root_projection = if self.request_foreach().is_some()
+            || self.request_foreach_collections().is_some()
+        {
+            let cast_type = root_foreach_row_type(query, large_counts);
             vec![SelectItem::ExprWithAlias {
-                expr: sql_function(
-                    "cast",
-                    vec![
-                        sql_function(
-                            "tupleElement",
-                            vec![query_expr, Expr::Value(Value::Number("1".to_owned()))],
-                        ),
-                        Expr::Value(Value::SingleQuotedString(cast_typestring)),
-                    ],
-                ),
+                expr: Expr::Cast {
+                    expr: Box::new(sql_function(
+                        "tupleElement",
+                        vec![query_expr, Expr::Value(Value::Number("1".to_owned()))],
+                    )),
+                    data_type: cast_type,
+                },
                 alias: Ident::quoted("rows"),
             }]
         } else {
@@ -501,70 +1761,51 @@ impl<'request> QueryBuilder<'request> {
                 (None, None) => vec![SelectItem::UnnamedExpr(Expr::Value(Value::Null))],
                 (None, Some(aggregates)) => {
                     vec![SelectItem::ExprWithAlias {
-                        expr: sql_function(
-                            "cast",
-                            vec![
-                                sql_function(
-                                    "tupleElement",
-                                    vec![query_expr, Expr::Value(Value::Number("1".to_owned()))],
-                                ),
-                                Expr::Value(Value::SingleQuotedString(root_aggregates_type(
-                                    aggregates,
-                                ))),
-                            ],
-                        ),
+                        expr: Expr::Cast {
+                            expr: Box::new(sql_function(
+                                "tupleElement",
+                                vec![query_expr, Expr::Value(Value::Number("1".to_owned()))],
+                            )),
+                            data_type: root_aggregates_type(aggregates, large_counts),
+                        },
                         alias: Ident::quoted("aggregates"),
                     }]
                 }
                 (Some(fields), None) => {
                     vec![SelectItem::ExprWithAlias {
-                        expr: sql_function(
-                            "cast",
-                            vec![
-                                sql_function(
-                                    "tupleElement",
-                                    vec![query_expr, Expr::Value(Value::Number("1".to_owned()))],
-                                ),
-                                Expr::Value(Value::SingleQuotedString(root_rows_type(fields))),
-                            ],
-                        ),
+                        expr: Expr::Cast {
+                            expr: Box::new(sql_function(
+                                "tupleElement",
+                                vec![query_expr, Expr::Value(Value::Number("1".to_owned()))],
+                            )),
+                            data_type: root_rows_type(fields, large_counts),
+                        },
                         alias: Ident::quoted("rows"),
                     }]
                 }
                 (Some(fields), Some(aggregates)) => {
                     vec![
                         SelectItem::ExprWithAlias {
-                            expr: sql_function(
-                                "cast",
-                                vec![
-                                    sql_function(
-                                        "tupleElement",
-                                        vec![
-                                            query_expr.clone(),
-                                            Expr::Value(Value::Number("1".to_owned())),
-                                        ],
-                                    ),
-                                    Expr::Value(Value::SingleQuotedString(root_rows_type(fields))),
-                                ],
-                            ),
+                            expr: Expr::Cast {
+                                expr: Box::new(sql_function(
+                                    "tupleElement",
+                                    vec![
+                                        query_expr.clone(),
+                                        Expr::Value(Value::Number("1".to_owned())),
+                                    ],
+                                )),
+                                data_type: root_rows_type(fields, large_counts),
+                            },
                             alias: Ident::quoted("rows"),
                         },
                         SelectItem::ExprWithAlias {
-                            expr: sql_function(
-                                "cast",
-                                vec![
-                                    sql_function(
-                                        "tupleElement",
-                                        vec![
-                                            query_expr,
-                                            Expr::Value(Value::Number("2".to_owned())),
-                                        ],
-                                    ),
-                                    Expr::Value(Value::SingleQuotedString(root_aggregates_type(
-                                        aggregates,
-                                    ))),
-                                ],
-                            ),
+                            expr: Expr::Cast {
+                                expr: Box::new(sql_function(
+                                    "tupleElement",
+                                    vec![query_expr, Expr::Value(Value::Number("2".to_owned()))],
+                                )),
+                                data_type: root_aggregates_type(aggregates, large_counts),
+                            },
                             alias: Ident::quoted("aggregates"),
                         },
                     ]
@@ -572,12 +1813,44 @@ impl<'request> QueryBuilder<'request> {
             }
         };
 
+        let (root_projection, root_joins) = match total_aggregates {
+            Some((total_subquery, aggregates)) => {
+                let total_expr =
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_total"), Ident::quoted("query")]);
+                let total_projection = SelectItem::ExprWithAlias {
+                    expr: Expr::Cast {
+                        expr: Box::new(sql_function(
+                            "tupleElement",
+                            vec![total_expr, Expr::Value(Value::Number("1".to_owned()))],
+                        )),
+                        data_type: root_aggregates_type(aggregates, large_counts),
+                    },
+                    alias: Ident::quoted("aggregates"),
+                };
+                let total_join = Join {
+                    relation: TableFactor::Derived {
+                        subquery: total_subquery,
+                        alias: Some(Ident::quoted("_total")),
+                    },
+                    join_operator: JoinOperator::CrossJoin,
+                };
+                (
+                    root_projection
+                        .into_iter()
+                        .chain(std::iter::once(total_projection))
+                        .collect(),
+                    vec![total_join],
+                )
+            }
+            None => (root_projection, vec![]),
+        };
+
         let root_from = vec![TableWithJoins {
             relation: TableFactor::Derived {
                 subquery: root_subquery,
                 alias: Some(Ident::quoted("_query")),
             },
-            joins: vec![],
+            joins: root_joins,
         }];
 
         Ok(Query::new(root_projection).from(root_from))
@@ -587,11 +1860,15 @@ impl<'request> QueryBuilder<'request> {
         table: &query_request::TableName,
         join_cols: &Vec<&String>,
         query: &query_request::Query,
-        foreach: Option<(TableFactor, &[&String])>,
+        foreach: Option<Vec<ForeachTable>>,
     ) -> Result<Box<Query>, QueryBuilderError> {
-        let foreach_columns = foreach
-            .as_ref()
-            .map(|(_, foreach_columns)| *foreach_columns);
+        let foreach_columns = foreach.as_ref().map(|foreach_tables| {
+            foreach_tables
+                .iter()
+                .flat_map(|foreach_table| foreach_table.columns.iter().copied())
+                .collect::<Vec<_>>()
+        });
+        let foreach_columns = foreach_columns.as_deref();
         let (rows_subquery, rows_expr) = match &query.fields {
             None => (None, None),
             Some(fields) => {
@@ -657,13 +1934,23 @@ impl<'request> QueryBuilder<'request> {
         // note: if rows not required. join not required either
         // also note: will need to change this cross join for subqueries that do have some kind of predicate
         let query_from = match foreach {
-            Some((foreach_table, foreach_columns)) => {
+            Some(foreach_tables) => {
+                // each foreach column is compared on the foreach table that holds it
+                let foreach_join_columns = foreach_tables
+                    .iter()
+                    .flat_map(|foreach_table| {
+                        foreach_table
+                            .columns
+                            .iter()
+                            .map(|&col| (foreach_table.alias.as_str(), col))
+                    })
+                    .collect::<Vec<_>>();
                 let rows_join = rows_subquery.map(|rows_subquery| {
-                    let join_expr = foreach_columns
+                    let join_expr = foreach_join_columns
                         .iter()
-                        .map(|&col| {
+                        .map(|&(foreach_alias, col)| {
                             let left = Expr::CompoundIdentifier(vec![
-                                Ident::quoted("_foreach"),
+                                Ident::quoted(foreach_alias),
                                 Ident::quoted(col),
                             ]);
                             let right = Expr::CompoundIdentifier(vec![
@@ -687,11 +1974,11 @@ impl<'request> QueryBuilder<'request> {
                     }
                 });
                 let aggregates_join = aggregates_subquery.map(|aggregates_subquery| {
-                    let join_expr = foreach_columns
+                    let join_expr = foreach_join_columns
                         .iter()
-                        .map(|&col| {
+                        .map(|&(foreach_alias, col)| {
                             let left = Expr::CompoundIdentifier(vec![
-                                Ident::quoted("_foreach"),
+                                Ident::quoted(foreach_alias),
                                 Ident::quoted(col),
                             ]);
                             let right = Expr::CompoundIdentifier(vec![
@@ -722,9 +2009,21 @@ impl<'request> QueryBuilder<'request> {
                     (Some(rows_join), Some(aggregates_join)) => vec![rows_join, aggregates_join],
                 };
 
+                // additional foreach collections are cross joined, so the query is repeated for every combination
+                let mut foreach_relations = foreach_tables
+                    .into_iter()
+                    .map(|foreach_table| foreach_table.relation);
+                let foreach_relation = foreach_relations.next().ok_or_else(|| {
+                    QueryBuilderError::Internal("Expected at least one foreach table".to_string())
+                })?;
+                let foreach_joins = foreach_relations.map(|relation| Join {
+                    relation,
+                    join_operator: JoinOperator::CrossJoin,
+                });
+
                 vec![TableWithJoins {
-                    relation: foreach_table,
-                    joins,
+                    relation: foreach_relation,
+                    joins: foreach_joins.chain(joins).collect(),
                 }]
             }
             None => match (rows_subquery, aggregates_subquery) {
@@ -777,6 +2076,34 @@ impl<'request> QueryBuilder<'request> {
         query: &query_request::Query,
         foreach_columns: &Option<&[&String]>,
     ) -> Result<Box<Query>, QueryBuilderError> {
+        // rows without a limit are capped at the configured default, to bound fan out and runaway scans
+        let default_limit = if join_cols.is_empty() {
+            self.config.default_row_limit.map(|limit| {
+                (
+                    limit,
+                    QueryWarning::DefaultRowLimit(table.to_owned(), limit),
+                )
+            })
+        } else {
+            self.config.default_relationship_limit.map(|limit| {
+                (
+                    limit,
+                    QueryWarning::DefaultRelationshipLimit(table.to_owned(), limit),
+                )
+            })
+        };
+        let capped_query;
+        let query = match (default_limit, &query.limit) {
+            (Some((default_limit, warning)), None) => {
+                self.warn(warning);
+                capped_query = query_request::Query {
+                    limit: Some(default_limit.into()),
+                    ..query.to_owned()
+                };
+                &capped_query
+            }
+            _ => query,
+        };
         let row_subquery = self.row_subquery(table, join_cols, fields, query, foreach_columns)?;
 
         let column_exprs = fields
@@ -786,7 +2113,7 @@ impl<'request> QueryBuilder<'request> {
                     alias.clone(),
                     Expr::CompoundIdentifier(vec![
                         Ident::quoted("_row"),
-                        Ident::quoted(format!("_projection.{alias}")),
+                        self.generated_ident("_projection", alias),
                     ]),
                 )
             })
@@ -870,37 +2197,172 @@ impl<'request> QueryBuilder<'request> {
         query: &query_request::Query,
         foreach_columns: &Option<&[&String]>,
     ) -> Result<Box<Query>, QueryBuilderError> {
+        validate_group_by(fields, &query.group_by)?;
+
         let selection_columns_expressions =
             join_cols.iter().map(|&col| SelectItem::ExprWithAlias {
                 expr: Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(col)]),
                 alias: Ident::quoted(format!("_selection.{col}")),
             });
 
-        let row_columns_expressions = fields.iter().map(|(alias, field)| match field {
-            query_request::Field::Column {
-                column,
-                column_type,
-            } => {
-                let identifier =
-                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(column)]);
+        let (row_order_by, order_by_joins) =
+            self.order_by_expressions_joins(table, &query.order_by)?;
 
-                let expr = match column_type {
-                    ScalarType::Unknown => sql_function("toJSONString", vec![identifier]),
-                    _ => identifier,
-                };
-                SelectItem::ExprWithAlias {
-                    expr,
-                    alias: Ident::quoted(format!("_projection.{alias}")),
-                }
+        let partition_cols = match foreach_columns {
+            Some(foreach_columns) => join_cols.iter().chain(*foreach_columns).copied().collect(),
+            None => join_cols.to_vec(),
+        };
+
+        // exists subqueries may appear both in the selection and in relationship include predicates
+        let mut exists_index = 0;
+        let (row_selection, mut exists_joins) = match &query.selection {
+            Some(expression) => {
+                let (expr, joins) = self.selection_expression(
+                    expression,
+                    &mut exists_index,
+                    true,
+                    "_origin",
+                    table,
+                )?;
+                (Some(expr), joins)
             }
-            query_request::Field::Relationship { .. } => SelectItem::ExprWithAlias {
-                expr: Expr::CompoundIdentifier(vec![
-                    Ident::quoted(format!("_rel.{alias}")),
-                    Ident::quoted("query"),
-                ]),
-                alias: Ident::quoted(format!("_projection.{alias}")),
-            },
-        });
+            None => (None, vec![]),
+        };
+
+        // window used by computed fields that depend on the position of the row within its partition
+        let row_window = WindowSpec {
+            partition_by: partition_cols
+                .iter()
+                .map(|&col| {
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(col)])
+                })
+                .collect(),
+            order_by: row_order_by.clone(),
+        };
+
+        let mut include_joins = vec![];
+        let row_columns_expressions = fields
+            .iter()
+            .map(|(alias, field)| match field {
+                query_request::Field::Column {
+                    column,
+                    column_type,
+                    trim,
+                    ..
+                } => {
+                    let identifier = Expr::CompoundIdentifier(vec![
+                        Ident::quoted("_origin"),
+                        Ident::quoted(column),
+                    ]);
+
+                    let replace_non_finite_floats =
+                        self.config.replace_non_finite_floats.unwrap_or(false);
+                    let expr = match column_type {
+                        ScalarType::Unknown => sql_function("toJSONString", vec![identifier]),
+                        ScalarType::Float32 | ScalarType::Float64 if replace_non_finite_floats => {
+                            sql_function(
+                                "if",
+                                vec![
+                                    sql_function("isFinite", vec![identifier.clone()]),
+                                    identifier,
+                                    Expr::Value(Value::Null),
+                                ],
+                            )
+                        }
+                        ScalarType::String | ScalarType::FixedString if trim.unwrap_or(false) => {
+                            sql_function("trimBoth", vec![identifier])
+                        }
+                        _ => identifier,
+                    };
+                    let expr = match self.column_mask(table, column) {
+                        Some(mask) => mask_expression(mask, expr, column, column_type)?,
+                        None => expr,
+                    };
+                    Ok(SelectItem::ExprWithAlias {
+                        expr,
+                        alias: self.generated_ident("_projection", alias),
+                    })
+                }
+                query_request::Field::Computed { function } => Ok(SelectItem::ExprWithAlias {
+                    expr: self.computed_field_expression(function, &row_window)?,
+                    alias: self.generated_ident("_projection", alias),
+                }),
+                query_request::Field::Relationship { include_when, .. } => {
+                    let relationship_expr = Expr::CompoundIdentifier(vec![
+                        self.generated_ident("_rel", alias),
+                        Ident::quoted("query"),
+                    ]);
+                    let expr = match include_when {
+                        Some(expression) => {
+                            let (include_expr, joins) = self.selection_expression(
+                                expression,
+                                &mut exists_index,
+                                true,
+                                "_origin",
+                                table,
+                            )?;
+                            include_joins.extend(joins);
+                            // an empty array for excluded rows, returned as null, see `QueryResponse::unwrap_conditional_relationships`
+                            case_expression(
+                                None,
+                                vec![(
+                                    include_expr,
+                                    sql_function("array", vec![relationship_expr]),
+                                )],
+                                Some(sql_function("array", vec![])),
+                            )
+                        }
+                        None => relationship_expr,
+                    };
+                    Ok(SelectItem::ExprWithAlias {
+                        expr,
+                        alias: self.generated_ident("_projection", alias),
+                    })
+                }
+                query_request::Field::RelatedColumn { .. } => Ok(SelectItem::ExprWithAlias {
+                    expr: Expr::CompoundIdentifier(vec![
+                        self.generated_ident("_rel", alias),
+                        Ident::quoted("query"),
+                    ]),
+                    alias: self.generated_ident("_projection", alias),
+                }),
+                query_request::Field::Aggregate { aggregate } => {
+                    let (expr, joins) = self.grouped_aggregate_expression(
+                        table,
+                        alias,
+                        aggregate,
+                        &mut exists_index,
+                    )?;
+                    include_joins.extend(joins);
+                    Ok(SelectItem::ExprWithAlias {
+                        expr,
+                        alias: self.generated_ident("_projection", alias),
+                    })
+                }
+            })
+            .collect::<Result<Vec<_>, QueryBuilderError>>()?;
+        exists_joins.extend(include_joins);
+
+        // grouped rows are grouped by the expressions of their grouped fields, and by their parent and foreach columns
+        let row_group_by = match &query.group_by {
+            Some(group_by) => partition_cols
+                .iter()
+                .map(|&col| {
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(col)])
+                })
+                .chain(
+                    fields
+                        .keys()
+                        .zip(&row_columns_expressions)
+                        .filter(|(alias, _)| group_by.contains(alias))
+                        .filter_map(|(_, item)| match item {
+                            SelectItem::ExprWithAlias { expr, .. } => Some(expr.to_owned()),
+                            _ => None,
+                        }),
+                )
+                .collect(),
+            None => vec![],
+        };
 
         let row_foreach_column_expressions = match foreach_columns {
             Some(foreach_columns) => foreach_columns
@@ -916,14 +2378,6 @@ impl<'request> QueryBuilder<'request> {
             None => vec![],
         };
 
-        let (row_order_by, order_by_joins) =
-            self.order_by_expressions_joins(table, &query.order_by)?;
-
-        let partition_cols = match foreach_columns {
-            Some(foreach_columns) => join_cols.iter().chain(*foreach_columns).copied().collect(),
-            None => join_cols.to_vec(),
-        };
-
         let row_projection = selection_columns_expressions
             .chain(row_columns_expressions)
             .chain(row_foreach_column_expressions)
@@ -935,62 +2389,112 @@ impl<'request> QueryBuilder<'request> {
             row_projection
         };
 
-        let (row_selection, exists_joins) = match &query.selection {
-            Some(expression) => {
-                let mut exists_index = 0;
-                let (expr, joins) = self.selection_expression(
-                    expression,
-                    &mut exists_index,
-                    true,
-                    "_origin",
-                    table,
-                )?;
-                (Some(expr), joins)
-            }
-            None => (None, vec![]),
-        };
-
         let relationship_joins = fields
             .iter()
             .filter_map(|(alias, field)| match field {
-                query_request::Field::Column { .. } => None,
-                query_request::Field::Relationship {
-                    query,
-                    relationship,
-                } => Some((alias, query, relationship)),
+                query_request::Field::Column { .. }
+                | query_request::Field::Computed { .. }
+                | query_request::Field::Aggregate { .. } => None,
+                query_request::Field::Relationship { relationship, .. }
+                | query_request::Field::RelatedColumn { relationship, .. } => {
+                    Some((alias, field, relationship))
+                }
             })
-            .map(|(alias, query, relationship)| {
+            .map(|(alias, field, relationship)| {
                 let relationship = self.table_relationship(table, relationship)?;
                 let column_mappings = get_relationship_column_mapping(relationship);
                 let relationship_table = get_relationship_target_table(relationship)?;
+                let match_any_column = get_relationship_match_any_column(relationship);
 
-                let join_expr = column_mappings
-                    .iter()
-                    .map(|(source_col, target_col)| Expr::BinaryOp {
-                        left: Box::new(Expr::CompoundIdentifier(vec![
-                            Ident::quoted("_origin"),
-                            Ident::quoted(source_col),
-                        ])),
-                        op: BinaryOperator::Eq,
-                        right: Box::new(Expr::CompoundIdentifier(vec![
-                            Ident::quoted(format!("_rel.{alias}")),
-                            Ident::quoted(format!("_selection.{target_col}")),
-                        ])),
-                    })
-                    .reduce(and_reducer)
-                    .unwrap_or(Expr::Value(Value::Boolean(true)));
+                // children of relationships matching any column are grouped by the keys of the parent they match,
+                // so each parent joins exactly one group. Keys may be null, so are compared null safe
+                let parent_key_cols = column_mappings
+                    .keys()
+                    .map(|source_col| format!("_parent.{source_col}"))
+                    .collect::<Vec<_>>();
+                let join_expr = if match_any_column {
+                    column_mappings
+                        .keys()
+                        .zip(&parent_key_cols)
+                        .map(|(source_col, parent_key_col)| {
+                            sql_function(
+                                "isNotDistinctFrom",
+                                vec![
+                                    Expr::CompoundIdentifier(vec![
+                                        Ident::quoted("_origin"),
+                                        Ident::quoted(source_col),
+                                    ]),
+                                    Expr::CompoundIdentifier(vec![
+                                        self.generated_ident("_rel", alias),
+                                        Ident::quoted(format!("_selection.{parent_key_col}")),
+                                    ]),
+                                ],
+                            )
+                        })
+                        .reduce(and_reducer)
+                } else {
+                    column_mappings
+                        .iter()
+                        .map(|(source_col, target_col)| Expr::BinaryOp {
+                            left: Box::new(Expr::CompoundIdentifier(vec![
+                                Ident::quoted("_origin"),
+                                Ident::quoted(source_col),
+                            ])),
+                            op: BinaryOperator::Eq,
+                            right: Box::new(Expr::CompoundIdentifier(vec![
+                                self.generated_ident("_rel", alias),
+                                Ident::quoted(format!("_selection.{target_col}")),
+                            ])),
+                        })
+                        .reduce(and_reducer)
+                }
+                .unwrap_or(Expr::Value(Value::Boolean(true)));
+
+                // only project each target column once, even if several source columns map to it
+                let mut join_cols = vec![];
+                if match_any_column {
+                    join_cols.extend(&parent_key_cols);
+                } else {
+                    for target_col in column_mappings.values() {
+                        if !join_cols.contains(&target_col) {
+                            join_cols.push(target_col);
+                        }
+                    }
+                }
+                let join_cols = &join_cols;
 
-                let join_cols = &column_mappings.values().collect();
+                let outer_match_any_parent = std::mem::replace(
+                    &mut self.match_any_parent,
+                    match_any_column
+                        .then(|| (table.to_owned(), column_mappings, query.selection.clone())),
+                );
+                let subquery = match field {
+                    query_request::Field::RelatedColumn {
+                        column,
+                        column_type,
+                        ..
+                    } => self.related_column_subquery(
+                        relationship_table,
+                        join_cols,
+                        column,
+                        column_type,
+                    ),
+                    query_request::Field::Relationship { query, .. } => {
+                        self.query_subquery(relationship_table, join_cols, query, None)
+                    }
+                    query_request::Field::Column { .. }
+                    | query_request::Field::Computed { .. }
+                    | query_request::Field::Aggregate { .. } => Err(QueryBuilderError::Internal(
+                        "only relationship fields are joined".to_owned(),
+                    )),
+                };
+                self.match_any_parent = outer_match_any_parent;
+                let subquery = subquery?;
 
                 Ok(Join {
                     relation: TableFactor::Derived {
-                        subquery: self.query_subquery(
-                            relationship_table,
-                            join_cols,
-                            query,
-                            None,
-                        )?,
-                        alias: Some(Ident::quoted(format!("_rel.{alias}"))),
+                        subquery,
+                        alias: Some(self.generated_ident("_rel", alias)),
                     },
                     join_operator: JoinOperator::LeftOuter(JoinConstraint::On(join_expr)),
                 })
@@ -998,10 +2502,7 @@ impl<'request> QueryBuilder<'request> {
             .collect::<Result<Vec<_>, _>>()?;
 
         let row_from = vec![TableWithJoins {
-            relation: TableFactor::Table {
-                name: ObjectName(table.iter().map(Ident::quoted).collect()),
-                alias: Some(Ident::quoted("_origin")),
-            },
+            relation: self.origin_table(table, query)?,
             joins: relationship_joins
                 .into_iter()
                 .chain(order_by_joins)
@@ -1009,23 +2510,75 @@ impl<'request> QueryBuilder<'request> {
                 .collect(),
         }];
 
+        let row_order_by =
+            self.fill_order_by(row_order_by, fields, query, !partition_cols.is_empty())?;
+
         let partion_rows_by = partition_cols
             .into_iter()
             .map(|col| Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(col)]))
             .collect::<Vec<_>>();
 
         let (limit_by, limit, offset) =
-            self.limit_by_limit_offset(partion_rows_by, &query.limit, &query.offset);
+            self.limit_by_limit_offset(partion_rows_by, &query.limit, &query.offset)?;
 
         Ok(Query::new(row_projection)
             .from(row_from)
+            .prewhere(self.prewhere_expression(table, query)?)
             .predicate(row_selection)
+            .group_by(row_group_by)
             .order_by(row_order_by)
             .limit_by(limit_by)
             .limit(limit)
             .offset(offset)
             .boxed())
     }
+    /// the values of a column of the related rows, grouped into an array per parent row
+    fn related_column_subquery(
+        &mut self,
+        table: &query_request::TableName,
+        join_cols: &[&String],
+        column: &str,
+        column_type: &ScalarType,
+    ) -> Result<Box<Query>, QueryBuilderError> {
+        let join_cols_expressions = join_cols
+            .iter()
+            .map(|&col| {
+                Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(col)])
+            })
+            .collect::<Vec<_>>();
+        let projection = join_cols
+            .iter()
+            .zip(&join_cols_expressions)
+            .map(|(col, expr)| SelectItem::ExprWithAlias {
+                expr: expr.to_owned(),
+                alias: Ident::quoted(format!("_selection.{col}")),
+            })
+            .chain(std::iter::once(SelectItem::ExprWithAlias {
+                expr: sql_function(
+                    "groupArray",
+                    vec![self.masked_origin_column(table, column, column_type)?],
+                ),
+                alias: Ident::quoted("query"),
+            }))
+            .collect();
+
+        let name = self.physical_table_name(table)?;
+        let alias = Ident::quoted(if self.match_any_parent.is_some() {
+            "_child"
+        } else {
+            "_origin"
+        });
+        let child = self.table_factor(table, name, alias, None)?;
+        let from = vec![TableWithJoins {
+            relation: self.with_match_any_parent(child)?,
+            joins: vec![],
+        }];
+
+        Ok(Query::new(projection)
+            .from(from)
+            .group_by(join_cols_expressions)
+            .boxed())
+    }
     fn aggregates_subquery(
         &mut self,
         table: &query_request::TableName,
@@ -1036,27 +2589,54 @@ impl<'request> QueryBuilder<'request> {
     ) -> Result<Box<Query>, QueryBuilderError> {
         let aggregate_subquery =
             self.aggregate_subquery(table, join_cols, aggregates, query, foreach_columns)?;
+        let approximate_distinct_counts = self.approximate_distinct_counts(table);
+        if approximate_distinct_counts {
+            // counts approximated on request are not warned about
+            for aggregate in aggregates.values() {
+                if let query_request::Aggregate::ColumnCount {
+                    column,
+                    distinct: true,
+                    exact: None,
+                    ..
+                } = aggregate
+                {
+                    self.warn(QueryWarning::ApproximateDistinctCount(
+                        table.to_owned(),
+                        column.to_owned(),
+                    ));
+                }
+            }
+        }
         let column_exprs = aggregates
             .iter()
             .map(|(alias, field)| {
                 let colum_expr = match field {
                     query_request::Aggregate::StarCount => Expr::Function(Function {
                         name: ObjectName(vec![Ident::unquoted("COUNT")]),
+                        parameters: vec![],
                         args: vec![FunctionArgExpr::Wildcard],
                         over: None,
                         distinct: false,
                     }),
                     query_request::Aggregate::ColumnCount {
-                        column: _,
+                        distinct: true,
+                        additional_columns,
+                        exact,
+                        ..
+                    } if !exact.unwrap_or(!approximate_distinct_counts) => {
+                        let columns = self.counted_columns(alias, additional_columns);
+                        sql_function("uniq", columns)
+                    }
+                    query_request::Aggregate::ColumnCount {
                         distinct,
+                        additional_columns,
+                        ..
                     } => {
-                        let column = Expr::CompoundIdentifier(vec![
-                            Ident::quoted("_row"),
-                            Ident::quoted(format!("_projection.{alias}")),
-                        ]);
+                        let columns = self.counted_columns(alias, additional_columns);
                         Expr::Function(Function {
                             name: ObjectName(vec![Ident::unquoted("COUNT")]),
-                            args: vec![FunctionArgExpr::Expr(column)],
+                            parameters: vec![],
+                            args: columns.into_iter().map(FunctionArgExpr::Expr).collect(),
                             over: None,
                             distinct: distinct.to_owned(),
                         })
@@ -1064,15 +2644,43 @@ impl<'request> QueryBuilder<'request> {
                     query_request::Aggregate::SingleColumn { function, .. } => {
                         let column = Expr::CompoundIdentifier(vec![
                             Ident::quoted("_row"),
-                            Ident::quoted(format!("_projection.{alias}")),
+                            self.generated_ident("_projection", alias),
+                        ]);
+                        single_column_aggregate(function, column)?
+                    }
+                    query_request::Aggregate::ColumnArray { limit, .. } => {
+                        let column = Expr::CompoundIdentifier(vec![
+                            Ident::quoted("_row"),
+                            self.generated_ident("_projection", alias),
                         ]);
-                        single_column_aggregate(function, column)
+                        Expr::Function(Function {
+                            name: ObjectName(vec![Ident::unquoted("groupArray")]),
+                            parameters: limit
+                                .iter()
+                                .map(|limit| Expr::Value(Value::Number(limit.to_string())))
+                                .collect(),
+                            args: vec![FunctionArgExpr::Expr(column)],
+                            over: None,
+                            distinct: false,
+                        })
+                    }
+                    query_request::Aggregate::ConditionalCount { .. } => {
+                        let condition = Expr::CompoundIdentifier(vec![
+                            Ident::quoted("_row"),
+                            self.generated_ident("_projection", alias),
+                        ]);
+                        sql_function("countIf", vec![condition])
                     }
                 };
 
-                (alias.clone(), colum_expr)
+                Ok((alias.clone(), colum_expr))
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>, QueryBuilderError>>()?;
+
+        let having = match &query.having {
+            Some(expression) => Some(self.having_expression(expression, &column_exprs, table)?),
+            None => None,
+        };
 
         let aggregates_projection = join_cols
             .iter()
@@ -1139,8 +2747,157 @@ impl<'request> QueryBuilder<'request> {
         Ok(Query::new(aggregates_projection)
             .from(aggregates_from)
             .group_by(aggregates_group_by)
+            .having(having)
             .boxed())
     }
+    /// the projected columns of a column count, the first column followed by any additional columns
+    fn counted_columns(&self, alias: &str, additional_columns: &Option<Vec<String>>) -> Vec<Expr> {
+        let additional_aliases = (1..=additional_columns.as_ref().map_or(0, Vec::len))
+            .map(|index| format!("{alias}.{index}"));
+        std::iter::once(alias.to_owned())
+            .chain(additional_aliases)
+            .map(|alias| {
+                Expr::CompoundIdentifier(vec![
+                    Ident::quoted("_row"),
+                    self.generated_ident("_projection", &alias),
+                ])
+            })
+            .collect()
+    }
+    /// the aggregate fields of grouped queries are computed over the rows of each group directly,
+    /// rather than over projected rows like the aggregates of a query
+    fn grouped_aggregate_expression(
+        &mut self,
+        table: &query_request::TableName,
+        alias: &str,
+        aggregate: &query_request::Aggregate,
+        exists_index: &mut usize,
+    ) -> Result<(Expr, Vec<Join>), QueryBuilderError> {
+        let expr = match aggregate {
+            query_request::Aggregate::StarCount => Expr::Function(Function {
+                name: ObjectName(vec![Ident::unquoted("COUNT")]),
+                parameters: vec![],
+                args: vec![FunctionArgExpr::Wildcard],
+                over: None,
+                distinct: false,
+            }),
+            query_request::Aggregate::ColumnCount {
+                column,
+                distinct,
+                additional_columns,
+                exact,
+            } => {
+                let columns = std::iter::once(column)
+                    .chain(additional_columns.iter().flatten())
+                    .map(|column| {
+                        Expr::CompoundIdentifier(vec![
+                            Ident::quoted("_origin"),
+                            Ident::quoted(column),
+                        ])
+                    })
+                    .collect::<Vec<_>>();
+                if columns.len() > 1 && !distinct {
+                    return Err(QueryBuilderError::InvalidAggregate(format!(
+                        "counts of more than one column must be distinct, see aggregate \"{alias}\""
+                    )));
+                }
+                let approximate_distinct_counts = self.approximate_distinct_counts(table);
+                if *distinct && approximate_distinct_counts && exact.is_none() {
+                    self.warn(QueryWarning::ApproximateDistinctCount(
+                        table.to_owned(),
+                        column.to_owned(),
+                    ));
+                }
+                if *distinct && !exact.unwrap_or(!approximate_distinct_counts) {
+                    sql_function("uniq", columns)
+                } else {
+                    Expr::Function(Function {
+                        name: ObjectName(vec![Ident::unquoted("COUNT")]),
+                        parameters: vec![],
+                        args: columns.into_iter().map(FunctionArgExpr::Expr).collect(),
+                        over: None,
+                        distinct: distinct.to_owned(),
+                    })
+                }
+            }
+            query_request::Aggregate::SingleColumn {
+                column,
+                function,
+                result_type,
+                ..
+            } => single_column_aggregate(
+                function,
+                self.masked_origin_column(table, column, result_type)?,
+            )?,
+            query_request::Aggregate::ColumnArray {
+                column,
+                column_type,
+                limit,
+                ..
+            } => Expr::Function(Function {
+                name: ObjectName(vec![Ident::unquoted("groupArray")]),
+                parameters: limit
+                    .iter()
+                    .map(|limit| Expr::Value(Value::Number(limit.to_string())))
+                    .collect(),
+                args: vec![FunctionArgExpr::Expr(self.masked_origin_column(
+                    table,
+                    column,
+                    column_type,
+                )?)],
+                over: None,
+                distinct: false,
+            }),
+            query_request::Aggregate::ConditionalCount { selection } => {
+                let (expr, joins) =
+                    self.selection_expression(selection, exists_index, true, "_origin", table)?;
+                return Ok((sql_function("countIf", vec![expr]), joins));
+            }
+        };
+        Ok((expr, vec![]))
+    }
+    /// prewhere is evaluated against the origin table alone, so it may not filter by other tables
+    fn prewhere_expression(
+        &mut self,
+        table: &query_request::TableName,
+        query: &query_request::Query,
+    ) -> Result<Option<Expr>, QueryBuilderError> {
+        let expression = match &query.prewhere {
+            Some(expression) => expression,
+            None => return Ok(None),
+        };
+        if filters_other_tables(expression) {
+            return Err(QueryBuilderError::InvalidPrewhere(
+                "prewhere cannot filter by related or unrelated tables".to_string(),
+            ));
+        }
+        // the children of these relationships are read through a subquery, which prewhere cannot apply to
+        if self.match_any_parent.is_some() {
+            return Err(QueryBuilderError::InvalidPrewhere(
+                "prewhere cannot be used in relationships matching any column".to_string(),
+            ));
+        }
+        let (expr, _) = self.selection_expression(expression, &mut 0, false, "_origin", table)?;
+        Ok(Some(expr))
+    }
+    /// filter aggregated groups. Columns of the expression are replaced with the aggregate of the same alias
+    fn having_expression(
+        &mut self,
+        expression: &query_request::Expression,
+        aggregates: &[(String, Expr)],
+        table: &query_request::TableName,
+    ) -> Result<Expr, QueryBuilderError> {
+        if filters_other_tables(expression) {
+            return Err(QueryBuilderError::InvalidHaving(
+                "aggregates cannot be filtered by related or unrelated tables".to_string(),
+            ));
+        }
+        self.having_aggregates = aggregates.iter().cloned().collect();
+        let result =
+            self.selection_expression(expression, &mut 0, false, HAVING_TABLE_ALIAS, table);
+        self.having_aggregates.clear();
+        Ok(result?.0)
+    }
     fn aggregate_subquery(
         &mut self,
         table: &query_request::TableName,
@@ -1155,20 +2912,80 @@ impl<'request> QueryBuilder<'request> {
                 alias: Ident::quoted(format!("_selection.{col}")),
             });
 
-        let aggregate_columns_expressions =
-            aggregates.iter().filter_map(|(alias, agg)| match agg {
-                query_request::Aggregate::ColumnCount { column, .. }
-                | query_request::Aggregate::SingleColumn { column, .. } => {
-                    Some(SelectItem::ExprWithAlias {
-                        expr: Expr::CompoundIdentifier(vec![
-                            Ident::quoted("_origin"),
-                            Ident::quoted(column),
-                        ]),
-                        alias: Ident::quoted(format!("_projection.{alias}")),
-                    })
+        // exists subqueries may appear both in the selection and in conditional counts
+        let mut exists_index = 0;
+        let (aggregate_selection, mut exists_joins) = match &query.selection {
+            Some(expression) => {
+                let (expr, joins) = self.selection_expression(
+                    expression,
+                    &mut exists_index,
+                    true,
+                    "_origin",
+                    table,
+                )?;
+                (Some(expr), joins)
+            }
+            None => (None, vec![]),
+        };
+
+        let mut aggregate_columns_expressions = vec![];
+        for (alias, agg) in aggregates {
+            let expr = match agg {
+                query_request::Aggregate::ColumnCount {
+                    column,
+                    distinct,
+                    additional_columns: Some(additional_columns),
+                    ..
+                } if !additional_columns.is_empty() => {
+                    if !distinct {
+                        return Err(QueryBuilderError::InvalidAggregate(format!(
+                            "counts of more than one column must be distinct, see aggregate \"{alias}\""
+                        )));
+                    }
+                    for (index, column) in additional_columns.iter().enumerate() {
+                        aggregate_columns_expressions.push(SelectItem::ExprWithAlias {
+                            expr: Expr::CompoundIdentifier(vec![
+                                Ident::quoted("_origin"),
+                                Ident::quoted(column),
+                            ]),
+                            alias: self
+                                .generated_ident("_projection", &format!("{alias}.{}", index + 1)),
+                        });
+                    }
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(column)])
+                }
+                // masked columns are aggregated by their masked values, so aggregates like max never return raw values
+                query_request::Aggregate::SingleColumn {
+                    column,
+                    result_type,
+                    ..
+                } => self.masked_origin_column(table, column, result_type)?,
+                query_request::Aggregate::ColumnArray {
+                    column,
+                    column_type,
+                    ..
+                } => self.masked_origin_column(table, column, column_type)?,
+                query_request::Aggregate::ColumnCount { column, .. } => {
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(column)])
+                }
+                query_request::Aggregate::ConditionalCount { selection } => {
+                    let (expr, joins) = self.selection_expression(
+                        selection,
+                        &mut exists_index,
+                        true,
+                        "_origin",
+                        table,
+                    )?;
+                    exists_joins.extend(joins);
+                    expr
                 }
-                query_request::Aggregate::StarCount => None,
+                query_request::Aggregate::StarCount => continue,
+            };
+            aggregate_columns_expressions.push(SelectItem::ExprWithAlias {
+                expr,
+                alias: self.generated_ident("_projection", alias),
             });
+        }
 
         let aggregate_foreach_column_expressions = match foreach_columns {
             Some(foreach_columns) => foreach_columns
@@ -1202,26 +3019,8 @@ impl<'request> QueryBuilder<'request> {
             aggregate_projection
         };
 
-        let (aggregate_selection, exists_joins) = match &query.selection {
-            Some(expression) => {
-                let mut exists_index = 0;
-                let (expr, joins) = self.selection_expression(
-                    expression,
-                    &mut exists_index,
-                    true,
-                    "_origin",
-                    table,
-                )?;
-                (Some(expr), joins)
-            }
-            None => (None, vec![]),
-        };
-
         let aggregate_from = vec![TableWithJoins {
-            relation: TableFactor::Table {
-                name: ObjectName(table.iter().map(Ident::quoted).collect()),
-                alias: Some(Ident::quoted("_origin")),
-            },
+            relation: self.origin_table(table, query)?,
             joins: exists_joins.into_iter().chain(order_by_joins).collect(),
         }];
 
@@ -1231,10 +3030,11 @@ impl<'request> QueryBuilder<'request> {
             .collect::<Vec<_>>();
 
         let (limit_by, limit, offset) =
-            self.limit_by_limit_offset(partion_rows_by, &query.aggregates_limit, &query.offset);
+            self.limit_by_limit_offset(partion_rows_by, &query.aggregates_limit, &query.offset)?;
 
         Ok(Query::new(aggregate_projection)
             .from(aggregate_from)
+            .prewhere(self.prewhere_expression(table, query)?)
             .predicate(aggregate_selection)
             .order_by(order_by)
             .limit_by(limit_by)
@@ -1250,6 +3050,36 @@ impl<'request> QueryBuilder<'request> {
         match order_by {
             None => Ok((vec![], vec![])),
             Some(order_by) => {
+                // order by joins only project targets whose path follows the order by relations,
+                // any other target would reference a join that does not exist
+                for element in &order_by.elements {
+                    let mut relations = &order_by.relations;
+                    for relationship in &element.target_path {
+                        relations = match relations.get(relationship) {
+                            Some(relation) => &relation.subrelations,
+                            None => {
+                                let column = match &element.target {
+                                    query_request::OrderByTarget::StarCountAggregate => {
+                                        "*".to_owned()
+                                    }
+                                    query_request::OrderByTarget::SingleColumnAggregate {
+                                        column,
+                                        ..
+                                    }
+                                    | query_request::OrderByTarget::Column { column }
+                                    | query_request::OrderByTarget::Expression { column, .. } => {
+                                        column.to_owned()
+                                    }
+                                };
+                                return Err(QueryBuilderError::OrderByColumnMissing(
+                                    column,
+                                    element.target_path.to_owned(),
+                                ));
+                            }
+                        };
+                    }
+                }
+
                 // discard parent columns at the root level, since all columns are exposed on origin
                 let (_, order_by_joins) =
                     self.order_by_joins(table, &vec![], &order_by.relations, order_by)?;
@@ -1275,7 +3105,9 @@ impl<'request> QueryBuilder<'request> {
                                 format!("_agg.{}.{}", function_name(function), column)
                             }
 
-                            query_request::OrderByTarget::Column { column } => {
+                            // the function is applied when ordering, to the column projected by order by joins
+                            query_request::OrderByTarget::Column { column }
+                            | query_request::OrderByTarget::Expression { column, .. } => {
                                 if element.target_path.is_empty() {
                                     column.to_owned()
                                 } else {
@@ -1308,6 +3140,14 @@ impl<'request> QueryBuilder<'request> {
                 "COALESCE",
                 vec![column, Expr::Value(Value::Number("0".to_owned()))],
             ),
+            // avg is always a float, so sorts on 0 like other numeric aggregates
+            query_request::OrderByTarget::SingleColumnAggregate {
+                function: query_request::SingleColumnAggregateFunction::Avg,
+                ..
+            } => sql_function(
+                "COALESCE",
+                vec![column, Expr::Value(Value::Number("0".to_owned()))],
+            ),
             // sort on default value for aggregates
             query_request::OrderByTarget::SingleColumnAggregate { result_type, .. } => {
                 use query_request::ScalarType as ST;
@@ -1402,10 +3242,14 @@ impl<'request> QueryBuilder<'request> {
                     | ST::MinDate32
                     | ST::MinDateTime
                     | ST::MinDateTime64 => Value::Null,
+                    ST::Bitmap => Value::Null,
                 };
                 sql_function("COALESCE", vec![column, Expr::Value(default_sorting_value)])
             }
             query_request::OrderByTarget::Column { .. } => column,
+            query_request::OrderByTarget::Expression { function, .. } => {
+                sql_function(order_by_function_name(function), vec![column])
+            }
         };
         OrderByExpr {
             expr,
@@ -1413,12 +3257,131 @@ impl<'request> QueryBuilder<'request> {
                 query_request::OrderDirection::Asc => true,
                 query_request::OrderDirection::Desc => false,
             }),
-            nulls_first: Some(match order_by_element.order_direction {
-                query_request::OrderDirection::Asc => false,
-                query_request::OrderDirection::Desc => true,
-            }),
+            nulls_first: nulls_first(order_by_element),
+            with_fill: None,
         }
     }
+    /// add WITH FILL to ordered columns that request it. Filled rows only hold the filled column,
+    /// so the column is ordered by its field alias for the filled values to be returned
+    fn fill_order_by(
+        &mut self,
+        order_by: Vec<OrderByExpr>,
+        fields: &query_request::Fields,
+        query: &query_request::Query,
+        partitioned: bool,
+    ) -> Result<Vec<OrderByExpr>, QueryBuilderError> {
+        let elements = match &query.order_by {
+            Some(order_by) => &order_by.elements,
+            None => return Ok(order_by),
+        };
+        order_by
+            .into_iter()
+            .zip(elements)
+            .map(|(order_by_expr, element)| {
+                let fill = match &element.fill {
+                    Some(fill) => fill,
+                    None => return Ok(order_by_expr),
+                };
+                let column = match &element.target {
+                    query_request::OrderByTarget::Column { column }
+                        if element.target_path.is_empty() =>
+                    {
+                        column
+                    }
+                    _ => {
+                        return Err(QueryBuilderError::InvalidFill(
+                            "only columns of the queried table can be filled".to_owned(),
+                        ))
+                    }
+                };
+                // filled rows would not be related to any parent or foreach row
+                if partitioned {
+                    return Err(QueryBuilderError::InvalidFill(format!(
+                        "column \"{}\" cannot be filled in relationship or foreach queries",
+                        column
+                    )));
+                }
+                let alias = fields
+                    .iter()
+                    .find_map(|(alias, field)| match field {
+                        query_request::Field::Column {
+                            column: field_column,
+                            ..
+                        } if field_column == column => Some(alias),
+                        _ => None,
+                    })
+                    .ok_or_else(|| {
+                        QueryBuilderError::InvalidFill(format!(
+                            "column \"{}\" must be returned as a field to be filled",
+                            column
+                        ))
+                    })?;
+
+                // dates are bound as strings, so bounds are converted to the type of the filled column
+                let conversion = match &fill.value_type {
+                    ScalarType::Date => Some("toDate"),
+                    ScalarType::Date32 => Some("toDate32"),
+                    ScalarType::DateTime => Some("toDateTime"),
+                    ScalarType::DateTime64 => Some("toDateTime64"),
+                    ScalarType::Int128 => Some("toInt128"),
+                    ScalarType::Int256 => Some("toInt256"),
+                    ScalarType::UInt128 => Some("toUInt128"),
+                    ScalarType::UInt256 => Some("toUInt256"),
+                    ScalarType::UInt8
+                    | ScalarType::UInt16
+                    | ScalarType::UInt32
+                    | ScalarType::UInt64
+                    | ScalarType::Int8
+                    | ScalarType::Int16
+                    | ScalarType::Int32
+                    | ScalarType::Int64
+                    | ScalarType::Float32
+                    | ScalarType::Float64 => None,
+                    value_type => {
+                        return Err(QueryBuilderError::InvalidFill(format!(
+                        "column \"{}\" of type {:?} cannot be filled, only dates and numbers can",
+                        column, value_type
+                    )))
+                    }
+                };
+                let mut bound_value = |value: &Option<query_request::ScalarValue>| {
+                    value.as_ref().map(|value| {
+                        let param = self.bind_parameter(BoundParam::Value {
+                            value: value.to_owned(),
+                            value_type: fill.value_type.to_owned(),
+                        });
+                        match (conversion, &fill.value_type) {
+                            (Some(function), ScalarType::DateTime64) => sql_function(
+                                function,
+                                vec![param, Expr::Value(Value::Number("9".to_owned()))],
+                            ),
+                            (Some(function), _) => sql_function(function, vec![param]),
+                            (None, _) => param,
+                        }
+                    })
+                };
+                let from = bound_value(&fill.from);
+                let to = bound_value(&fill.to);
+                let step = fill
+                    .step
+                    .as_ref()
+                    .map(|step| Expr::Value(Value::Number(step.to_string())));
+                let step = match &fill.unit {
+                    Some(unit) => Some(sql_function(
+                        unit.interval_function(),
+                        vec![step.unwrap_or(Expr::Value(Value::Number("1".to_owned())))],
+                    )),
+                    None => step,
+                };
+
+                Ok(OrderByExpr {
+                    expr: Expr::Identifier(self.generated_ident("_projection", alias)),
+                    with_fill: Some(WithFill { from, to, step }),
+                    ..order_by_expr
+                })
+            })
+            .collect()
+    }
     fn order_by_joins(
         &mut self,
         table: &query_request::TableName,
@@ -1435,6 +3398,12 @@ impl<'request> QueryBuilder<'request> {
         };
         for (relationship_name, order_by_relation) in relations {
             let relationship = self.table_relationship(table, relationship_name)?;
+            // a parent may match several groups of such a relationship, which would repeat the ordered rows
+            if get_relationship_match_any_column(relationship) {
+                return Err(QueryBuilderError::OrderByMatchAnyColumn(
+                    relationship_name.to_owned(),
+                ));
+            }
             let column_mappings = get_relationship_column_mapping(relationship);
             let relationship_table = get_relationship_target_table(relationship)?;
 
@@ -1469,7 +3438,8 @@ impl<'request> QueryBuilder<'request> {
                             function,
                             result_type: _,
                         } => format!("_agg.{}.{}", function_name(function), column),
-                        query_request::OrderByTarget::Column { column } => {
+                        query_request::OrderByTarget::Column { column }
+                        | query_request::OrderByTarget::Expression { column, .. } => {
                             format!("_col.{column}")
                         }
                     };
@@ -1477,6 +3447,7 @@ impl<'request> QueryBuilder<'request> {
                         query_request::OrderByTarget::StarCountAggregate => {
                             Expr::Function(Function {
                                 name: ObjectName(vec![Ident::unquoted("COUNT")]),
+                                parameters: vec![],
                                 args: vec![FunctionArgExpr::Wildcard],
                                 over: None,
                                 distinct: false,
@@ -1488,9 +3459,10 @@ impl<'request> QueryBuilder<'request> {
                             result_type: _,
                         } => {
                             let column_expr = Expr::Identifier(Ident::quoted(column));
-                            single_column_aggregate(function, column_expr)
+                            single_column_aggregate(function, column_expr)?
                         }
-                        query_request::OrderByTarget::Column { column } => {
+                        query_request::OrderByTarget::Column { column }
+                        | query_request::OrderByTarget::Expression { column, .. } => {
                             Expr::Identifier(Ident::quoted(column))
                         }
                     };
@@ -1500,7 +3472,9 @@ impl<'request> QueryBuilder<'request> {
                     };
                     projection_cols.insert(col_alias, projection_col);
                     // add the column to the group by clause, if it's not an aggregate
-                    if let query_request::OrderByTarget::Column { column } = &element.target {
+                    if let query_request::OrderByTarget::Column { column }
+                    | query_request::OrderByTarget::Expression { column, .. } = &element.target
+                    {
                         let group_by_col = Expr::Identifier(Ident::quoted(column));
                         group_by_cols.insert(column, group_by_col);
                     }
@@ -1556,14 +3530,10 @@ impl<'request> QueryBuilder<'request> {
             // cols for join and ordering, aggregates
             let join_projection = projection_cols.into_values().collect();
             let join_from = vec![TableWithJoins {
-                relation: TableFactor::Table {
-                    name: ObjectName(
-                        get_relationship_target_table(relationship)?
-                            .iter()
-                            .map(Ident::quoted)
-                            .collect(),
-                    ),
-                    alias: Some(Ident::quoted("_origin")),
+                relation: {
+                    let table = get_relationship_target_table(relationship)?;
+                    let name = self.physical_table_name(table)?;
+                    self.table_factor(table, name, Ident::quoted("_origin"), None)?
                 },
                 joins: exists_joins,
             }];
@@ -1608,6 +3578,58 @@ impl<'request> QueryBuilder<'request> {
         }
         Ok((parent_join_columns, joins))
     }
+    /// the right hand side of a comparison against a column
+    fn comparison_value(
+        &mut self,
+        table_alias: &str,
+        column: &query_request::ComparisonColumn,
+        value: &query_request::ComparisonValue,
+    ) -> Result<Expr, QueryBuilderError> {
+        Ok(match value {
+            query_request::ComparisonValue::ScalarValueComparison { value, value_type } => {
+                validate_ip_literal(value, &column.column_type)?;
+                validate_big_int_literal(value, &column.column_type)?;
+                let param = self.bind_parameter(BoundParam::Value {
+                    value: coerce_boolean_literal(value, &column.column_type),
+                    value_type: value_type.to_owned(),
+                });
+                // compare ip columns using the native type rather than as strings,
+                // parse large integers given as strings, and read dates in the column timezone where known
+                let timezone = column
+                    .timezone
+                    .as_ref()
+                    .map(|timezone| Expr::Value(Value::SingleQuotedString(timezone.to_owned())));
+                match (&column.column_type, timezone) {
+                    (ScalarType::IPv4, _) => sql_function("toIPv4", vec![param]),
+                    (ScalarType::IPv6, _) => sql_function("toIPv6", vec![param]),
+                    (ScalarType::Int128, _) => sql_function("toInt128", vec![param]),
+                    (ScalarType::Int256, _) => sql_function("toInt256", vec![param]),
+                    (ScalarType::UInt128, _) => sql_function("toUInt128", vec![param]),
+                    (ScalarType::UInt256, _) => sql_function("toUInt256", vec![param]),
+                    (ScalarType::DateTime, Some(timezone)) => {
+                        sql_function("toDateTime", vec![param, timezone])
+                    }
+                    (ScalarType::DateTime64, Some(timezone)) => sql_function(
+                        "toDateTime64",
+                        vec![param, Expr::Value(Value::Number("9".to_owned())), timezone],
+                    ),
+                    _ => param,
+                }
+            }
+            query_request::ComparisonValue::ServerTimeAnchor { anchor } => {
+                server_time_anchor(anchor, column)?
+            }
+            query_request::ComparisonValue::AnotherColumnComparison { column } => {
+                // columns of the same table can be compared, but comparisons may not cross relationships
+                if column.path.as_ref().is_some_and(|path| !path.is_empty()) {
+                    return Err(QueryBuilderError::RightHandColumnComparisonNotSupported(
+                        column.name.to_owned(),
+                    ));
+                }
+                self.comparison_column(table_alias, column)?
+            }
+        })
+    }
     fn selection_expression(
         &mut self,
         expression: &query_request::Expression,
@@ -1616,6 +3638,15 @@ impl<'request> QueryBuilder<'request> {
         table_alias: &str,
         table: &query_request::TableName,
     ) -> Result<(Expr, Vec<Join>), QueryBuilderError> {
+        if let Some(expression) = through_relationship_path(expression) {
+            return self.selection_expression(
+                &expression,
+                exists_index,
+                origin,
+                table_alias,
+                table,
+            );
+        }
         match expression {
             query_request::Expression::And { expressions } => {
                 let exprs = expressions
@@ -1698,10 +3729,48 @@ impl<'request> QueryBuilder<'request> {
                 };
                 Ok((expr, joins))
             }
+            query_request::Expression::RawSql { sql, arguments } => {
+                if !self.config.allow_raw_sql_predicates.unwrap_or(false) {
+                    return Err(QueryBuilderError::RawSqlNotAllowed);
+                }
+                let sql = self.raw_sql(sql, arguments.as_deref().unwrap_or_default())?;
+                Ok((Expr::Nested(Box::new(Expr::Raw(sql))), vec![]))
+            }
             query_request::Expression::UnaryComparisonOperator { column, operator } => {
                 let expr = Box::new(self.comparison_column(table_alias, column)?);
                 let expr = match operator {
+                    query_request::UnaryComparisonOperator::IsNull
+                        if self.empty_string_as_null(column) =>
+                    {
+                        Expr::Nested(Box::new(Expr::BinaryOp {
+                            left: Box::new(Expr::IsNull(expr.clone())),
+                            op: BinaryOperator::Or,
+                            right: Box::new(Expr::BinaryOp {
+                                left: expr,
+                                op: BinaryOperator::Eq,
+                                right: Box::new(Expr::Value(Value::SingleQuotedString(
+                                    String::new(),
+                                ))),
+                            }),
+                        }))
+                    }
                     query_request::UnaryComparisonOperator::IsNull => Expr::IsNull(expr),
+                    query_request::UnaryComparisonOperator::IsNotNull
+                        if self.empty_string_as_null(column) =>
+                    {
+                        Expr::Nested(Box::new(Expr::BinaryOp {
+                            left: Box::new(Expr::IsNotNull(expr.clone())),
+                            op: BinaryOperator::And,
+                            right: Box::new(Expr::BinaryOp {
+                                left: expr,
+                                op: BinaryOperator::NotEq,
+                                right: Box::new(Expr::Value(Value::SingleQuotedString(
+                                    String::new(),
+                                ))),
+                            }),
+                        }))
+                    }
+                    query_request::UnaryComparisonOperator::IsNotNull => Expr::IsNotNull(expr),
                 };
                 Ok((expr, vec![]))
             }
@@ -1710,54 +3779,86 @@ impl<'request> QueryBuilder<'request> {
                 operator,
                 value,
             } => {
-                let left = Box::new(self.comparison_column(table_alias, column)?);
+                let left = Box::new(self.nullable_comparison_column(table_alias, column)?);
+                let right = Box::new(self.comparison_value(table_alias, column, value)?);
 
-                let right = match value {
-                    query_request::ComparisonValue::ScalarValueComparison { value, value_type } => {
-                        Box::new(self.bind_parameter(BoundParam::Value {
-                            value: value.to_owned(),
-                            value_type: value_type.to_owned(),
-                        }))
+                use BinaryComparisonOperator as BCO;
+                // length operators compare a function of the column, rather than the column itself
+                let (left, op) = match operator {
+                    BCO::LessThan => (left, BinaryOperator::Lt),
+                    BCO::LessThanOrEqual => (left, BinaryOperator::LtEq),
+                    BCO::Equal => (left, BinaryOperator::Eq),
+                    BCO::NotEqual => (left, BinaryOperator::NotEq),
+                    BCO::GreaterThan => (left, BinaryOperator::Gt),
+                    BCO::GreaterThanOrEqual => (left, BinaryOperator::GtEq),
+                    BCO::LengthEqual => (length_of(*left), BinaryOperator::Eq),
+                    BCO::LengthGreaterThan => (length_of(*left), BinaryOperator::Gt),
+                    BCO::LengthGreaterThanOrEqual => (length_of(*left), BinaryOperator::GtEq),
+                    BCO::LengthLessThan => (length_of(*left), BinaryOperator::Lt),
+                    BCO::LengthLessThanOrEqual => (length_of(*left), BinaryOperator::LtEq),
+                    BCO::BitmapContains => {
+                        let expr = sql_function("bitmapContains", vec![*left, *right]);
+                        return Ok((expr, vec![]));
                     }
-                    query_request::ComparisonValue::AnotherColumnComparison { column } => {
-                        // technically, we could support column comparisons, but only if they don't cross relationships
-                        // we can check the origin flag for this, to validate we're not traversing a relationship.
-                        return Err(QueryBuilderError::RightHandColumnComparisonNotSupported(
-                            column.name.to_owned(),
-                        ));
+                    BCO::Regex => {
+                        let expr = sql_function("match", vec![*left, *right]);
+                        return Ok((expr, vec![]));
+                    }
+                    BCO::NotRegex => {
+                        let expr = Expr::UnaryOp {
+                            op: UnaryOperator::Not,
+                            expr: Box::new(sql_function("match", vec![*left, *right])),
+                        };
+                        return Ok((expr, vec![]));
+                    }
+                    BCO::CaseInsensitiveEqual => {
+                        let expr = Expr::BinaryOp {
+                            left: Box::new(sql_function("lower", vec![*left])),
+                            op: BinaryOperator::Eq,
+                            right: Box::new(sql_function("lower", vec![*right])),
+                        };
+                        return Ok((expr, vec![]));
                     }
                 };
 
-                let expr = Expr::BinaryOp {
-                    left,
-                    right,
-                    op: match operator {
-                        BinaryComparisonOperator::LessThan => BinaryOperator::Lt,
-                        BinaryComparisonOperator::LessThanOrEqual => BinaryOperator::LtEq,
-                        BinaryComparisonOperator::Equal => BinaryOperator::Eq,
-                        BinaryComparisonOperator::GreaterThan => BinaryOperator::Gt,
-                        BinaryComparisonOperator::GreaterThanOrEqual => BinaryOperator::GtEq,
-                    },
-                };
+                let expr = Expr::BinaryOp { left, right, op };
 
                 Ok((expr, vec![]))
             }
+            query_request::Expression::Between { column, low, high }
+            | query_request::Expression::NotBetween { column, low, high } => {
+                let expr = Expr::Between {
+                    expr: Box::new(self.nullable_comparison_column(table_alias, column)?),
+                    low: Box::new(self.comparison_value(table_alias, column, low)?),
+                    high: Box::new(self.comparison_value(table_alias, column, high)?),
+                    negated: matches!(expression, query_request::Expression::NotBetween { .. }),
+                };
+                Ok((expr, vec![]))
+            }
             query_request::Expression::BinaryArrayComparisonOperator {
                 column,
                 operator,
                 value_type,
                 values,
             } => {
-                let expr = Box::new(self.comparison_column(table_alias, column)?);
-                let list = values
-                    .iter()
-                    .map(|value| {
-                        self.bind_parameter(BoundParam::Value {
-                            value: value.to_owned(),
-                            value_type: value_type.to_owned(),
+                let expr = Box::new(self.nullable_comparison_column(table_alias, column)?);
+                // bound lists are a single array parameter, inlined lists are a parenthesized list of values
+                let list = if self.bind_params {
+                    vec![self.bind_parameter(BoundParam::Array {
+                        values: values.to_owned(),
+                        value_type: value_type.to_owned(),
+                    })]
+                } else {
+                    values
+                        .iter()
+                        .map(|value| {
+                            self.bind_parameter(BoundParam::Value {
+                                value: value.to_owned(),
+                                value_type: value_type.to_owned(),
+                            })
                         })
-                    })
-                    .collect();
+                        .collect()
+                };
 
                 let expr = match operator {
                     query_request::BinaryArrayComparisonOperator::In => Expr::InList { expr, list },
@@ -1772,6 +3873,7 @@ impl<'request> QueryBuilder<'request> {
                     let join_alias = format!("_exists_{}", exists_index);
                     *exists_index += 1;
 
+                    let mut match_any_parent = None;
                     // assuming the only columns we care about are join columns.
                     // this may not be true if we support column comparison operators.
                     let (select_expr, join_expr, table_name, projection, group_by, limit) =
@@ -1806,9 +3908,67 @@ impl<'request> QueryBuilder<'request> {
                                     limit,
                                 )
                             }
+                            // children of relationships matching any column are paired with the key of each parent they match,
+                            // and grouped by it, so a parent never joins more than one group
+                            query_request::ExistsInTable::RelatedTable { relationship }
+                                if get_relationship_match_any_column(
+                                    self.table_relationship(table, relationship)?,
+                                ) =>
+                            {
+                                let relationship = self.table_relationship(table, relationship)?;
+                                let column_mappings = get_relationship_column_mapping(relationship);
+                                let relationship_table =
+                                    get_relationship_target_table(relationship)?;
+                                match_any_parent = Some((table, column_mappings));
+
+                                let parent_key = |source_col: &String| {
+                                    Expr::CompoundIdentifier(vec![
+                                        Ident::quoted(join_alias.clone()),
+                                        Ident::quoted(format!("_parent.{source_col}")),
+                                    ])
+                                };
+                                let select_expr = column_mappings
+                                    .keys()
+                                    .map(|source_col| {
+                                        sql_function(
+                                            "isNotDistinctFrom",
+                                            vec![
+                                                parent_key(source_col),
+                                                Expr::CompoundIdentifier(vec![
+                                                    Ident::quoted(table_alias),
+                                                    Ident::quoted(source_col),
+                                                ]),
+                                            ],
+                                        )
+                                    })
+                                    .reduce(and_reducer)
+                                    .map(|expr| Expr::Nested(Box::new(expr)))
+                                    .unwrap_or(Expr::Value(Value::Boolean(true)));
+                                let join_expr = select_expr.clone();
+
+                                let projection = column_mappings
+                                    .keys()
+                                    .map(|source_col| SelectItem::ExprWithAlias {
+                                        expr: parent_key(source_col),
+                                        alias: Ident::quoted(format!("_parent.{source_col}")),
+                                    })
+                                    .collect();
+                                let group_by = column_mappings.keys().map(parent_key).collect();
+                                let limit = None;
+
+                                (
+                                    select_expr,
+                                    join_expr,
+                                    relationship_table,
+                                    projection,
+                                    group_by,
+                                    limit,
+                                )
+                            }
                             query_request::ExistsInTable::RelatedTable { relationship } => {
                                 let relationship = self.table_relationship(table, relationship)?;
                                 let column_mappings = get_relationship_column_mapping(relationship);
+                                let column_reducer = get_relationship_column_reducer(relationship);
                                 let relationship_table =
                                     get_relationship_target_table(relationship)?;
 
@@ -1829,10 +3989,10 @@ impl<'request> QueryBuilder<'request> {
                                             right: Box::new(right),
                                         }
                                     })
-                                    .reduce(and_reducer)
+                                    .reduce(column_reducer)
                                     .map(|expr| match expr {
                                         Expr::BinaryOp {
-                                            op: BinaryOperator::And,
+                                            op: BinaryOperator::And | BinaryOperator::Or,
                                             ..
                                         } => Expr::Nested(Box::new(expr)),
                                         _ => expr,
@@ -1850,6 +4010,7 @@ impl<'request> QueryBuilder<'request> {
                                         alias: Ident::quoted(target_col),
                                     })
                                     .collect();
+                                // one row per join key, so the left join never repeats parent rows however many related rows match
                                 let group_by = column_mappings
                                     .iter()
                                     .map(|(_, target_col)| {
@@ -1882,13 +4043,28 @@ impl<'request> QueryBuilder<'request> {
                         table_name,
                     )?;
 
-                    let from = vec![TableWithJoins {
-                        relation: TableFactor::Table {
-                            name: ObjectName(table_name.iter().map(Ident::quoted).collect()),
-                            alias: Some(Ident::quoted(join_alias.clone())),
-                        },
-                        joins,
-                    }];
+                    let name = self.physical_table_name(table_name)?;
+                    let relation = match match_any_parent {
+                        Some((parent_table, column_mapping)) => {
+                            let child =
+                                self.table_factor(table_name, name, Ident::quoted("_child"), None)?;
+                            // the parent selection is the one being built, so it is not pushed down
+                            self.paired_with_parent_keys(
+                                child,
+                                parent_table,
+                                column_mapping,
+                                None,
+                                Ident::quoted(join_alias.clone()),
+                            )?
+                        }
+                        None => self.table_factor(
+                            table_name,
+                            name,
+                            Ident::quoted(join_alias.clone()),
+                            None,
+                        )?,
+                    };
+                    let from = vec![TableWithJoins { relation, joins }];
 
                     let subquery = Query::new(projection)
                         .from(from)
@@ -1931,6 +4107,7 @@ impl<'request> QueryBuilder<'request> {
                         query_request::ExistsInTable::RelatedTable { relationship } => {
                             let relationship = self.table_relationship(table, relationship)?;
                             let column_mappings = get_relationship_column_mapping(relationship);
+                            let column_reducer = get_relationship_column_reducer(relationship);
                             let relationship_table = get_relationship_target_table(relationship)?;
 
                             let select_expr = column_mappings
@@ -1950,10 +4127,10 @@ impl<'request> QueryBuilder<'request> {
                                         right: Box::new(right),
                                     }
                                 })
-                                .reduce(and_reducer)
+                                .reduce(column_reducer)
                                 .map(|expr| match expr {
                                     Expr::BinaryOp {
-                                        op: BinaryOperator::And,
+                                        op: BinaryOperator::And | BinaryOperator::Or,
                                         ..
                                     } => Expr::Nested(Box::new(expr)),
                                     _ => expr,
@@ -1975,9 +4152,9 @@ impl<'request> QueryBuilder<'request> {
 
                     let join = Join {
                         join_operator: JoinOperator::LeftOuter(JoinConstraint::On(join_expr)),
-                        relation: TableFactor::Table {
-                            name: ObjectName(table_name.iter().map(Ident::quoted).collect()),
-                            alias: Some(Ident::quoted(join_alias)),
+                        relation: {
+                            let name = self.physical_table_name(table_name)?;
+                            self.table_factor(table_name, name, Ident::quoted(join_alias), None)?
                         },
                     };
 
@@ -1994,6 +4171,115 @@ impl<'request> QueryBuilder<'request> {
             }
         }
     }
+    fn computed_field_expression(
+        &mut self,
+        function: &query_request::ComputedField,
+        window: &WindowSpec,
+    ) -> Result<Expr, QueryBuilderError> {
+        use query_request::ComputedField as CF;
+        let expr = match function {
+            CF::Rank => window_function("rank", vec![], window.to_owned()),
+            CF::DenseRank => window_function("dense_rank", vec![], window.to_owned()),
+            CF::Position { column, needle } => {
+                let column =
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(column)]);
+                let needle = self.bind_parameter(BoundParam::Value {
+                    value: serde_json::Value::String(needle.to_owned()),
+                    value_type: ScalarType::String,
+                });
+                sql_function("position", vec![column, needle])
+            }
+            CF::StartOfPeriod { column, period } => {
+                use query_request::CalendarPeriod as CP;
+                let column =
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(column)]);
+                let function_name = match period {
+                    CP::Day => "toStartOfDay",
+                    CP::Week => "toStartOfWeek",
+                    CP::Month => "toStartOfMonth",
+                    CP::Quarter => "toStartOfQuarter",
+                    CP::Year => "toStartOfYear",
+                };
+                // toStartOfDay returns a datetime, cast all periods to date for a consistent type
+                sql_function("toDate", vec![sql_function(function_name, vec![column])])
+            }
+            CF::BitmapCardinality { column } => sql_function(
+                "bitmapCardinality",
+                vec![Expr::CompoundIdentifier(vec![
+                    Ident::quoted("_origin"),
+                    Ident::quoted(column),
+                ])],
+            ),
+            CF::DateDiff {
+                unit,
+                start_column,
+                end_column,
+            } => sql_function(
+                "dateDiff",
+                vec![
+                    Expr::Value(Value::SingleQuotedString(unit.as_str().to_owned())),
+                    Expr::CompoundIdentifier(vec![
+                        Ident::quoted("_origin"),
+                        Ident::quoted(start_column),
+                    ]),
+                    Expr::CompoundIdentifier(vec![
+                        Ident::quoted("_origin"),
+                        Ident::quoted(end_column),
+                    ]),
+                ],
+            ),
+            CF::GapExceeds {
+                column,
+                unit,
+                threshold,
+            } => {
+                let column =
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(column)]);
+                // the first row defaults to its own value, so it never has a gap
+                let previous = window_function(
+                    "lagInFrame",
+                    vec![
+                        column.clone(),
+                        Expr::Value(Value::Number("1".to_owned())),
+                        column.clone(),
+                    ],
+                    window.to_owned(),
+                );
+                let gap = sql_function(
+                    "dateDiff",
+                    vec![
+                        Expr::Value(Value::SingleQuotedString(unit.as_str().to_owned())),
+                        previous,
+                        column,
+                    ],
+                );
+                Expr::BinaryOp {
+                    left: Box::new(gap),
+                    op: BinaryOperator::Gt,
+                    right: Box::new(Expr::Value(Value::Number(threshold.to_string()))),
+                }
+            }
+            CF::ArrayElement { column, index, .. } => {
+                let column =
+                    Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted(column)]);
+                let index = Expr::Value(Value::Number(index.to_string()));
+                // indexing past the end throws or returns a default depending on settings, return null instead
+                sql_function(
+                    "if",
+                    vec![
+                        Expr::BinaryOp {
+                            left: Box::new(sql_function("length", vec![column.clone()])),
+                            op: BinaryOperator::GtEq,
+                            right: Box::new(index.clone()),
+                        },
+                        sql_function("arrayElement", vec![column, index]),
+                        Expr::Value(Value::Null),
+                    ],
+                )
+            }
+        };
+        Ok(expr)
+    }
     fn comparison_column(
         &mut self,
         table_alias: &str,
@@ -2007,6 +4293,19 @@ impl<'request> QueryBuilder<'request> {
             }
         }
 
+        if table_alias == HAVING_TABLE_ALIAS {
+            return self
+                .having_aggregates
+                .get(&column.name)
+                .cloned()
+                .ok_or_else(|| {
+                    QueryBuilderError::InvalidHaving(format!(
+                        "no aggregate named \"{}\"",
+                        column.name
+                    ))
+                });
+        }
+
         let expr = Expr::CompoundIdentifier(vec![
             Ident::quoted(table_alias),
             Ident::quoted(&column.name),
@@ -2014,36 +4313,130 @@ impl<'request> QueryBuilder<'request> {
 
         Ok(expr)
     }
+    /// true if empty strings in this column should be treated as null, see `Config::empty_string_as_null`
+    fn empty_string_as_null(&self, column: &query_request::ComparisonColumn) -> bool {
+        self.config.empty_string_as_null.unwrap_or(false)
+            && column.column_type == ScalarType::String
+    }
+    /// the column to compare against values. Empty strings are replaced with null if they are treated as such,
+    /// so they match comparisons exactly as null would
+    fn nullable_comparison_column(
+        &mut self,
+        table_alias: &str,
+        column: &query_request::ComparisonColumn,
+    ) -> Result<Expr, QueryBuilderError> {
+        let expr = self.comparison_column(table_alias, column)?;
+        if self.empty_string_as_null(column) {
+            Ok(sql_function(
+                "nullIf",
+                vec![expr, Expr::Value(Value::SingleQuotedString(String::new()))],
+            ))
+        } else {
+            Ok(expr)
+        }
+    }
+    /// an internal identifier for a user supplied alias. Identifiers longer than the configured maximum are truncated,
+    /// and suffixed with a hash of the full identifier so they remain unique
+    fn generated_ident(&self, prefix: &str, alias: &str) -> Ident {
+        let identifier = format!("{prefix}.{alias}");
+        let max_length = self
+            .config
+            .max_generated_identifier_length
+            .unwrap_or(DEFAULT_MAX_GENERATED_IDENTIFIER_LENGTH);
+        if identifier.len() <= max_length {
+            return Ident::quoted(identifier);
+        }
+        let mut hasher = DefaultHasher::new();
+        identifier.hash(&mut hasher);
+        let suffix = format!("~{:016x}", hasher.finish());
+        let mut truncated_length = max_length.saturating_sub(suffix.len());
+        while !identifier.is_char_boundary(truncated_length) {
+            truncated_length -= 1;
+        }
+        Ident::quoted(format!("{}{}", &identifier[..truncated_length], suffix))
+    }
+    /// substitute the `{N}` placeholders of a raw sql predicate with bound arguments. The template is scanned
+    /// once, so text within an argument is never read as a placeholder
+    fn raw_sql(
+        &mut self,
+        sql: &str,
+        arguments: &[query_request::RawSqlArgument],
+    ) -> Result<String, QueryBuilderError> {
+        let mut params: Vec<Option<String>> = vec![None; arguments.len()];
+        let mut rendered = String::with_capacity(sql.len());
+        let mut rest = sql;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let digits = after
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(after.len());
+            if digits == 0 || !after[digits..].starts_with('}') {
+                rendered.push('{');
+                rest = after;
+                continue;
+            }
+            let argument = after[..digits]
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| arguments.get(index).map(|argument| (index, argument)));
+            let Some((index, argument)) = argument else {
+                return Err(QueryBuilderError::InvalidRawSql(format!(
+                    "placeholder {{{}}} has no argument",
+                    &after[..digits]
+                )));
+            };
+            let param = match &params[index] {
+                Some(param) => param.to_owned(),
+                None => {
+                    let param = self
+                        .bind_parameter(BoundParam::Value {
+                            value: argument.value.to_owned(),
+                            value_type: argument.value_type.to_owned(),
+                        })
+                        .to_string();
+                    params[index] = Some(param.to_owned());
+                    param
+                }
+            };
+            rendered.push_str(&param);
+            rest = &after[digits + 1..];
+        }
+        rendered.push_str(rest);
+
+        if let Some(index) = params.iter().position(Option::is_none) {
+            return Err(QueryBuilderError::InvalidRawSql(format!(
+                "argument {index} is not used by a placeholder"
+            )));
+        }
+
+        Ok(rendered)
+    }
     fn bind_parameter(&mut self, param: BoundParam) -> Expr {
         if self.bind_params {
             let placeholder_string = format!("__placeholder__{}", self.parameter_index);
             self.parameter_index += 1;
-            self.parameters.insert(placeholder_string.clone(), param);
-            Expr::Value(Value::Placeholder(placeholder_string))
+            let value = if self.query_parameters {
+                Value::QueryParameter {
+                    name: placeholder_string.clone(),
+                    data_type: parameters::parameter_type(&param),
+                }
+            } else {
+                Value::Placeholder(placeholder_string.clone())
+            };
+            self.parameters.insert(placeholder_string, param);
+            Expr::Value(value)
         } else {
             match param {
                 BoundParam::Number(number) => Expr::Value(Value::Number(number.to_string())),
-                BoundParam::Value {
-                    value,
-                    value_type: _,
-                } => match value {
-                    serde_json::Value::Number(number) => {
-                        Expr::Value(Value::Number(number.to_string()))
-                    }
-                    serde_json::Value::String(string) => {
-                        Expr::Value(Value::SingleQuotedString(string))
-                    }
-                    serde_json::Value::Bool(boolean) => Expr::Value(Value::Boolean(boolean)),
-                    // feels like a hack.
-                    serde_json::Value::Null => Expr::Value(Value::Null),
-                    // note sure this works, should test
-                    serde_json::Value::Array(_) => {
-                        Expr::Value(Value::SingleQuotedString(value.to_string()))
-                    }
-                    serde_json::Value::Object(_) => {
-                        Expr::Value(Value::SingleQuotedString(value.to_string()))
-                    }
-                },
+                BoundParam::Value { value, value_type } => inline_value(value, &value_type),
+                BoundParam::Array { values, value_type } => sql_function(
+                    "array",
+                    values
+                        .into_iter()
+                        .map(|value| inline_value(value, &value_type))
+                        .collect(),
+                ),
             }
         }
     }
@@ -2052,37 +4445,3934 @@ impl<'request> QueryBuilder<'request> {
         partion_rows_by: Vec<Expr>,
         limit: &Option<serde_json::Number>,
         offset: &Option<serde_json::Number>,
-    ) -> (Option<LimitByExpr>, Option<u64>, Option<u64>) {
+    ) -> Result<LimitByLimitOffset, QueryBuilderError> {
+        let as_u64 = |name: &str, number: &Option<serde_json::Number>| {
+            number
+                .as_ref()
+                .map(|number| {
+                    number.as_u64().ok_or_else(|| {
+                        QueryBuilderError::InvalidLimit(format!(
+                            "{} {} is not a non negative integer",
+                            name, number
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        let limit = as_u64("limit", limit)?;
+        let offset = as_u64("offset", offset)?;
+
+        // clickhouse reads up to limit + offset rows, which must not overflow
+        if let (Some(limit), Some(offset)) = (limit, offset) {
+            if limit.checked_add(offset).is_none() {
+                return Err(QueryBuilderError::InvalidLimit(format!(
+                    "limit {} plus offset {} is too large",
+                    limit, offset
+                )));
+            }
+        }
+
         if partion_rows_by.is_empty() {
-            (
-                None,
-                limit
-                    .as_ref()
-                    .map(|limit| limit.as_u64().expect("limit should be valid u64")),
-                offset
-                    .as_ref()
-                    .map(|offset| offset.as_u64().expect("offset should be valid u64")),
-            )
+            Ok((None, limit, offset))
         } else {
-            let limit_by = match (limit.as_ref(), offset.as_ref()) {
+            let limit_by = match (limit, offset) {
                 (None, None) => None,
                 (None, Some(offset)) => Some(LimitByExpr {
-                    limit: u64::MAX,
-                    offset: Some(offset.as_u64().expect("offset should be valid u64")),
-                    by: partion_rows_by,
-                }),
-                (Some(limit), None) => Some(LimitByExpr {
-                    limit: limit.as_u64().expect("limit should be valid u64"),
-                    offset: None,
+                    limit: u64::MAX - offset,
+                    offset: Some(offset),
                     by: partion_rows_by,
                 }),
-                (Some(limit), Some(offset)) => Some(LimitByExpr {
-                    limit: limit.as_u64().expect("limit should be valid u64"),
-                    offset: Some(offset.as_u64().expect("offset should be valid u64")),
+                (Some(limit), offset) => Some(LimitByExpr {
+                    limit,
+                    offset,
                     by: partion_rows_by,
                 }),
             };
-            (limit_by, None, None)
+            Ok((limit_by, None, None))
         }
     }
 }
+
+#[cfg(test)]
+fn test_config() -> Config {
+    serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": ""
+    }))
+    .expect("valid config")
+}
+
+#[test]
+fn can_compare_date_column_to_server_time_anchor() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "day", "column_type": "Date" },
+                "value": { "type": "server_time", "anchor": "today" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"WHERE "_origin"."day" = today()"#),
+        "compares against today() without binding"
+    );
+}
+
+#[test]
+fn can_project_dense_rank_over_ordered_partition() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["authors"],
+        "table_relationships": [{
+            "source_table": ["authors"],
+            "relationships": {
+                "articles": {
+                    "target_table": ["articles"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "author_id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "articles": {
+                    "type": "relationship",
+                    "relationship": "articles",
+                    "query": {
+                        "fields": {
+                            "title": { "type": "column", "column": "title", "column_type": "String" },
+                            "rank": { "type": "computed", "function": { "type": "dense_rank" } }
+                        },
+                        "order_by": {
+                            "relations": {},
+                            "elements": [{
+                                "order_direction": "desc",
+                                "target": { "type": "column", "column": "score" },
+                                "target_path": []
+                            }]
+                        }
+                    }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"dense_rank() OVER (PARTITION BY "_origin"."author_id" ORDER BY "_origin"."score" DESC NULLS FIRST) AS "_projection.rank""#),
+        "projects dense_rank over the relationship partition, following the query order"
+    );
+    assert!(
+        statement.contains(r#""rank" Nullable(UInt64)"#),
+        "casts the rank as UInt64"
+    );
+}
+
+#[test]
+fn can_cross_join_named_foreach_collections() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["sales"],
+        "table_relationships": [],
+        "foreach": [
+            { "year": { "value": 2022, "value_type": "UInt16" } },
+            { "year": { "value": 2023, "value_type": "UInt16" } }
+        ],
+        "foreach_collections": {
+            "regions": [
+                { "region": { "value": "north", "value_type": "String" } },
+                { "region": { "value": "south", "value_type": "String" } }
+            ]
+        },
+        "query": {
+            "aggregates": { "count": { "type": "star_count" } }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"AS "_foreach"  CROSS JOIN format(JSONColumns, '{"region":["north","south"]}') AS "_foreach.regions""#),
+        "cross joins the named foreach collection"
+    );
+    assert!(
+        statement.contains(r#"ON "_foreach"."year" = "_aggregates"."_foreach.year" AND "_foreach.regions"."region" = "_aggregates"."_foreach.region""#),
+        "joins each foreach column on its own collection"
+    );
+}
+
+#[test]
+fn rejects_overlapping_foreach_collections() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["sales"],
+        "table_relationships": [],
+        "foreach": [
+            { "year": { "value": 2022, "value_type": "UInt16" } }
+        ],
+        "foreach_collections": {
+            "years": [
+                { "year": { "value": 2023, "value_type": "UInt16" } }
+            ]
+        },
+        "query": {
+            "aggregates": { "count": { "type": "star_count" } }
+        }
+    }))
+    .expect("valid query request");
+
+    assert!(matches!(
+        QueryBuilder::build_sql_statement(&request, &test_config(), false),
+        Err(QueryBuilderError::DuplicateForeachColumn(column)) if column == "year"
+    ));
+}
+
+#[test]
+fn can_return_foreach_total_aggregates() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["sales"],
+        "table_relationships": [],
+        "foreach": [
+            { "year": { "value": 2022, "value_type": "UInt16" } },
+            { "year": { "value": 2023, "value_type": "UInt16" } }
+        ],
+        "foreach_total_aggregates": true,
+        "query": {
+            "aggregates": { "count": { "type": "star_count" } }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement
+            .contains(r#"AS "_aggregates" ON "_foreach"."year" = "_aggregates"."_foreach.year""#),
+        "returns per key counts"
+    );
+    assert!(
+        statement.contains(
+            r#"CAST(tupleElement("_total"."query", 1) AS Tuple("count" UInt32)) AS "aggregates""#
+        ),
+        "returns the grand total count"
+    );
+    assert!(
+        statement.contains(r#"WHERE ("_origin"."year" = 2022 OR "_origin"."year" = 2023)"#),
+        "counts only rows matching a foreach row"
+    );
+}
+
+#[test]
+fn can_replace_non_finite_floats_with_null() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["measurements"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "ratio": { "type": "column", "column": "ratio", "column_type": "Float64" },
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            }
+        }
+    }))
+    .expect("valid query request");
+    let config = Config {
+        replace_non_finite_floats: Some(true),
+        ..test_config()
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(
+            r#"if(isFinite("_origin"."ratio"), "_origin"."ratio", NULL) AS "_projection.ratio""#
+        ),
+        "replaces NaN and Inf values with null"
+    );
+    assert!(
+        statement.contains(r#""_origin"."id" AS "_projection.id""#),
+        "leaves non float columns untouched"
+    );
+}
+
+#[test]
+fn can_project_position_of_substring() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["articles"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "at": {
+                    "type": "computed",
+                    "function": { "type": "position", "column": "title", "needle": "news" }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"position("_origin"."title", 'news') AS "_projection.at""#),
+        "projects the position of the needle"
+    );
+    assert!(
+        statement.contains(r#""at" Nullable(UInt64)"#),
+        "casts the position as UInt64"
+    );
+
+    let bound_statement = QueryBuilder::build_sql_statement(&request, &test_config(), true)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        bound_statement.contains(r#"position("_origin"."title", __placeholder__0)"#),
+        "binds the needle as a parameter"
+    );
+}
+
+#[test]
+fn can_build_only_the_aggregates_portion() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["sales"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "aggregates": { "count": { "type": "star_count" } }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement_portion(
+        &request,
+        &test_config(),
+        false,
+        &QueryPortion::Aggregates,
+    )
+    .expect("can build statement")
+    .to_string();
+
+    assert!(
+        statement.starts_with(r#"SELECT tuple(COUNT(*)) AS "aggregates" FROM"#),
+        "builds the aggregates subquery as the statement"
+    );
+    assert!(
+        !statement.contains(r#""_projection.id""#),
+        "does not include the rows portion"
+    );
+}
+
+#[test]
+fn can_compare_ipv4_column_using_native_type() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["requests"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "client_ip", "column_type": "IPv4" },
+                "value": { "type": "scalar", "value": "192.168.0.1", "value_type": "IPv4" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"WHERE "_origin"."client_ip" = toIPv4('192.168.0.1')"#),
+        "wraps the ipv4 literal"
+    );
+}
+
+#[test]
+fn can_compare_ipv6_column_using_native_type() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["requests"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "client_ip", "column_type": "IPv6" },
+                "value": { "type": "scalar", "value": "2001:db8::1", "value_type": "IPv6" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"WHERE "_origin"."client_ip" = toIPv6('2001:db8::1')"#),
+        "wraps the ipv6 literal"
+    );
+}
+
+#[test]
+fn rejects_invalid_ip_literal() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["requests"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "client_ip", "column_type": "IPv4" },
+                "value": { "type": "scalar", "value": "2001:db8::1", "value_type": "IPv4" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    assert!(matches!(
+        QueryBuilder::build_sql_statement(&request, &test_config(), false),
+        Err(QueryBuilderError::InvalidIpLiteral(_))
+    ));
+}
+
+#[test]
+fn can_filter_on_array_length() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["posts"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "_length_gt",
+                "column": { "name": "tags", "column_type": "Unknown" },
+                "value": { "type": "scalar", "value": 3, "value_type": "UInt32" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"WHERE length("_origin"."tags") > 3"#),
+        "compares the array length"
+    );
+}
+
+#[test]
+fn samples_same_rows_given_same_offset() {
+    let statement = |sample: serde_json::Value| {
+        let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+            "table": ["hits"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "sample": sample
+            }
+        }))
+        .expect("valid query request");
+        QueryBuilder::build_sql_statement(&request, &test_config(), false)
+            .expect("can build statement")
+            .to_string()
+    };
+
+    assert!(
+        statement(serde_json::json!({ "ratio": 0.1, "offset": 0.5 }))
+            .contains(r#"FROM "hits" AS "_origin" SAMPLE 0.1 OFFSET 0.5)"#),
+        "samples the origin table from the given offset, so the same offset reads the same rows"
+    );
+    assert!(
+        statement(serde_json::json!({ "ratio": 0.1, "offset": 0.25 }))
+            .contains(r#"FROM "hits" AS "_origin" SAMPLE 0.1 OFFSET 0.25)"#),
+        "another offset samples other rows"
+    );
+    assert!(
+        statement(serde_json::json!({ "ratio": 0.1 }))
+            .contains(r#"FROM "hits" AS "_origin" SAMPLE 0.1)"#),
+        "samples without an offset unless one is given"
+    );
+}
+
+#[test]
+fn can_project_start_of_month() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["orders"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "month": {
+                    "type": "computed",
+                    "function": { "type": "start_of_period", "column": "created_at", "period": "month" }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement
+            .contains(r#"toDate(toStartOfMonth("_origin"."created_at")) AS "_projection.month""#),
+        "projects the start of the month"
+    );
+    assert!(
+        statement.contains(r#""month" Nullable(Date)"#),
+        "casts the start of the month as a date"
+    );
+}
+
+#[test]
+fn groups_counts_by_start_of_month() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["orders"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "month": {
+                    "type": "computed",
+                    "function": { "type": "start_of_period", "column": "created_at", "period": "month" }
+                },
+                "orders": {
+                    "type": "aggregate",
+                    "aggregate": { "type": "star_count" }
+                }
+            },
+            "group_by": ["month"]
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"COUNT(*) AS "_projection.orders""#),
+        "counts the rows of each group"
+    );
+    assert!(
+        statement.contains(r#"GROUP BY toDate(toStartOfMonth("_origin"."created_at"))"#),
+        "groups rows by the start of their month"
+    );
+    assert!(
+        statement.contains(r#""month" Nullable(Date), "orders" UInt32"#),
+        "casts the month and the count of each group"
+    );
+
+    let ungrouped: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["orders"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "created_at": { "type": "column", "column": "created_at", "column_type": "DateTime" },
+                "orders": { "type": "aggregate", "aggregate": { "type": "star_count" } }
+            },
+            "group_by": []
+        }
+    }))
+    .expect("valid query request");
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&ungrouped, &test_config(), false),
+            Err(QueryBuilderError::InvalidGroupBy(_))
+        ),
+        "fields of grouped queries are either grouped by or aggregates"
+    );
+}
+
+#[test]
+fn projects_out_of_range_array_elements_as_null() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["articles"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "tenth_tag": {
+                    "type": "computed",
+                    "function": { "type": "array_element", "column": "tags", "index": 10, "element_type": "String" }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(
+            r#"if(length("_origin"."tags") >= 10, arrayElement("_origin"."tags", 10), NULL) AS "_projection.tenth_tag""#
+        ),
+        "returns null when the array is shorter than the index"
+    );
+    assert!(
+        statement.contains(r#""tenth_tag" Nullable(String)"#),
+        "casts the element as its nullable scalar type"
+    );
+
+    let zero_index = serde_json::from_value::<query_request::QueryRequest>(serde_json::json!({
+        "table": ["articles"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "tag": {
+                    "type": "computed",
+                    "function": { "type": "array_element", "column": "tags", "index": 0, "element_type": "String" }
+                }
+            }
+        }
+    }));
+    assert!(zero_index.is_err(), "indices are 1-based");
+}
+
+#[test]
+fn renders_simple_and_searched_case_expressions() {
+    let status = Expr::CompoundIdentifier(vec![Ident::quoted("_origin"), Ident::quoted("status")]);
+    let string = |value: &str| Expr::Value(Value::SingleQuotedString(value.to_owned()));
+
+    let simple = case_expression(
+        Some(status.clone()),
+        vec![
+            (string("a"), string("active")),
+            (string("d"), string("deleted")),
+        ],
+        Some(string("unknown")),
+    );
+    assert_eq!(
+        simple.to_string(),
+        r#"CASE "_origin"."status" WHEN 'a' THEN 'active' WHEN 'd' THEN 'deleted' ELSE 'unknown' END"#
+    );
+
+    let searched = case_expression(
+        None,
+        vec![(
+            Expr::IsNull(Box::new(status.clone())),
+            Expr::Value(Value::Boolean(false)),
+        )],
+        None,
+    );
+    assert_eq!(
+        searched.to_string(),
+        r#"CASE WHEN "_origin"."status" IS NULL THEN FALSE END"#,
+        "leaves out the else branch when there is none"
+    );
+}
+
+#[test]
+fn casts_rows_to_named_tuples() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["articles"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" },
+                "say \"hi\"": { "type": "column", "column": "title", "column_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.starts_with(
+            r#"SELECT CAST(tupleElement("_query"."query", 1) AS Array(Tuple("id" Nullable(UInt32), "say \"hi\"" Nullable(String)))) AS "rows""#
+        ),
+        "casts rows to an array of tuples named after the fields, escaping the names"
+    );
+}
+
+#[test]
+fn returns_parameters_matching_placeholders() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["articles"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "title", "column_type": "String" },
+                "value": { "type": "scalar", "value": "news", "value_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let (statement, parameters) =
+        QueryBuilder::build_parameterized_sql_statement(&request, &test_config(), None)
+            .expect("can build statement");
+    let statement = statement.to_string();
+
+    assert_eq!(parameters.len(), 1, "collects the bound parameter");
+    for (placeholder, parameter) in &parameters {
+        assert!(
+            statement.contains(&format!(r#""_origin"."title" = {placeholder}"#)),
+            "placeholder appears in the statement"
+        );
+        assert_eq!(parameter.value(), serde_json::json!("news"));
+    }
+}
+
+#[test]
+fn writes_clickhouse_query_parameters_only_for_bound_values() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["articles"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "__placeholder__0": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "title", "column_type": "String" },
+                "value": { "type": "scalar", "value": "__placeholder__0", "value_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let ((statement, parameters), _warnings) =
+        QueryBuilder::build_parameterized_sql_statement_with_context(
+            &request,
+            &test_config(),
+            QueryContext::default(),
+        )
+        .expect("can build statement");
+    let statement = statement.to_string();
+
+    assert!(
+        statement.contains(r#""_origin"."title" = {__placeholder__0:String}"#),
+        "annotates the bound value with its type"
+    );
+    assert!(
+        statement.contains(r#""_projection.__placeholder__0""#),
+        "leaves identifiers that look like placeholders as they are"
+    );
+    assert_eq!(
+        parameters::clickhouse_query_parameters(&parameters),
+        vec![("__placeholder__0".to_owned(), "__placeholder__0".to_owned())],
+        "sends the value as text, rather than writing it into the statement"
+    );
+}
+
+#[test]
+fn can_project_trimmed_string_column() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["customers"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String", "trim": true },
+                "email": { "type": "column", "column": "email", "column_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"trimBoth("_origin"."name") AS "_projection.name""#),
+        "trims the requested column"
+    );
+    assert!(
+        statement.contains(r#""_origin"."email" AS "_projection.email""#),
+        "leaves other columns untouched"
+    );
+}
+
+#[test]
+fn can_conditionally_include_relationship() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["authors"],
+        "table_relationships": [{
+            "source_table": ["authors"],
+            "relationships": {
+                "articles": {
+                    "target_table": ["articles"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "author_id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "articles": {
+                    "type": "relationship",
+                    "relationship": "articles",
+                    "include_when": {
+                        "type": "binary_op",
+                        "operator": "equal",
+                        "column": { "name": "active", "column_type": "Bool" },
+                        "value": { "type": "scalar", "value": true, "value_type": "Bool" }
+                    },
+                    "query": {
+                        "fields": {
+                            "title": { "type": "column", "column": "title", "column_type": "String" }
+                        }
+                    }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"CASE WHEN "_origin"."active" = TRUE THEN array("_rel.articles"."query") ELSE array() END AS "_projection.articles""#),
+        "returns no object for parents failing the predicate"
+    );
+    assert!(
+        statement.contains(
+            r#"AS Array(Tuple("articles" Array(Tuple(rows Array(Tuple("title" Nullable(String))))))))"#
+        ),
+        "casts the relationship to an array rather than a nullable tuple, which clickhouse rejects"
+    );
+}
+
+#[test]
+fn filters_by_related_rows_without_repeating_parents() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["authors"],
+        "table_relationships": [{
+            "source_table": ["authors"],
+            "relationships": {
+                "articles": {
+                    "target_table": ["articles"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "author_id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String" }
+            },
+            "where": {
+                "type": "exists",
+                "in_table": { "type": "related", "relationship": "articles" },
+                "where": {
+                    "type": "binary_op",
+                    "operator": "equal",
+                    "column": { "name": "published", "column_type": "Bool" },
+                    "value": { "type": "scalar", "value": true, "value_type": "Bool" }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"LEFT JOIN (SELECT "_exists_0"."author_id" AS "author_id" FROM "articles" AS "_exists_0" WHERE "_exists_0"."published" = TRUE GROUP BY "_exists_0"."author_id") AS "_exists_0" ON "_exists_0"."author_id" = "_origin"."id""#),
+        "groups related rows by the join columns, so each author joins at most one row however many articles match"
+    );
+}
+
+#[test]
+fn warns_on_non_final_replacing_merge_tree_read() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "aggregates": { "count": { "type": "star_count" } }
+        }
+    }))
+    .expect("valid query request");
+    let context = QueryContext {
+        replacing_merge_tree_tables: vec!["users".to_owned()],
+        ..Default::default()
+    };
+
+    let (_statement, warnings) =
+        QueryBuilder::build_sql_statement_with_context(&request, &test_config(), false, context)
+            .expect("can build statement");
+
+    assert_eq!(
+        warnings,
+        vec![QueryWarning::NonFinalReplacingMergeTreeRead(vec![
+            "users".to_owned()
+        ])],
+        "warns once about the non final read"
+    );
+}
+
+#[test]
+fn resolves_templated_table_name_from_session_variable() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            }
+        }
+    }))
+    .expect("valid query request");
+    let config_with_tenant = |tenant: &str| -> Config {
+        serde_json::from_value(serde_json::json!({
+            "url": "http://localhost:8123",
+            "username": "default",
+            "password": "",
+            "tables": [{ "name": "events", "name_template": "events_{x-hasura-tenant-id}" }],
+            "session_variables": { "x-hasura-tenant-id": tenant }
+        }))
+        .expect("valid config")
+    };
+
+    let config = config_with_tenant("tenant123");
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"FROM "events_tenant123" AS "_origin""#),
+        "reads from the tenant table"
+    );
+
+    let config = config_with_tenant("tenant\" OR 1=1");
+    assert!(matches!(
+        QueryBuilder::build_sql_statement(&request, &config, false),
+        Err(QueryBuilderError::UnsafeSessionVariable(_))
+    ));
+}
+
+#[test]
+fn approximates_distinct_counts_over_large_tables() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "aggregates": {
+                "users": { "type": "column_count", "column": "user_id", "distinct": true }
+            }
+        }
+    }))
+    .expect("valid query request");
+    let config = Config {
+        approximate_distinct_count_threshold: Some(1_000_000),
+        ..test_config()
+    };
+    let context_with_estimate = |rows: u64| QueryContext {
+        table_row_estimates: HashMap::from([("events".to_owned(), rows)]),
+        ..Default::default()
+    };
+
+    let (statement, warnings) = QueryBuilder::build_sql_statement_with_context(
+        &request,
+        &config,
+        false,
+        context_with_estimate(1_000),
+    )
+    .expect("can build statement");
+
+    assert!(
+        statement
+            .to_string()
+            .contains(r#"COUNT(DISTINCT "_row"."_projection.users")"#),
+        "counts exactly for small tables"
+    );
+    assert!(warnings.is_empty(), "does not warn for exact counts");
+
+    let (statement, warnings) = QueryBuilder::build_sql_statement_with_context(
+        &request,
+        &config,
+        false,
+        context_with_estimate(50_000_000),
+    )
+    .expect("can build statement");
+
+    assert!(
+        statement
+            .to_string()
+            .contains(r#"uniq("_row"."_projection.users")"#),
+        "approximates counts for large tables"
+    );
+    assert_eq!(
+        warnings,
+        vec![QueryWarning::ApproximateDistinctCount(
+            vec!["events".to_owned()],
+            "user_id".to_owned()
+        )],
+        "reports the approximation"
+    );
+}
+
+#[test]
+fn can_join_polymorphic_relationship_on_any_column() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [{
+            "source_table": ["users"],
+            "relationships": {
+                "comments": {
+                    "target_table": ["comments"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "author_id", "profile_id": "subject_id" },
+                    "match_any_column": true
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "comments": {
+                    "type": "relationship",
+                    "relationship": "comments",
+                    "query": {
+                        "fields": {
+                            "body": { "type": "column", "column": "body", "column_type": "String" }
+                        }
+                    }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"FROM "comments" AS "_child"  JOIN (SELECT "_origin"."id" AS "id", "_origin"."profile_id" AS "profile_id" FROM "users" AS "_origin" GROUP BY "_origin"."id", "_origin"."profile_id") AS "_parent" ON "_child"."author_id" = "_parent"."id" OR "_child"."subject_id" = "_parent"."profile_id""#),
+        "pairs each comment with the key of every user it matches on any column, got {statement}"
+    );
+    assert!(
+        statement.contains(r#"GROUP BY "_row"."_selection._parent.id", "_row"."_selection._parent.profile_id""#),
+        "groups comments by the key of the user they belong to, so each user's comments are in a single group"
+    );
+    assert!(
+        statement.contains(r#"AS "_rel.comments" ON isNotDistinctFrom("_origin"."id", "_rel.comments"."_selection._parent.id") AND isNotDistinctFrom("_origin"."profile_id", "_rel.comments"."_selection._parent.profile_id")"#),
+        "joins each user to exactly the group of its own key, so users are not repeated"
+    );
+    assert!(
+        !statement.contains(r#""_origin"."id" = "_rel.comments""#),
+        "does not join groups of target columns, which may match a user several times"
+    );
+}
+
+#[test]
+fn reads_keys_of_selected_parents_of_polymorphic_relationships() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [{
+            "source_table": ["users"],
+            "relationships": {
+                "comments": {
+                    "target_table": ["comments"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "author_id", "profile_id": "subject_id" },
+                    "match_any_column": true
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "comments": {
+                    "type": "relationship",
+                    "relationship": "comments",
+                    "query": {
+                        "fields": {
+                            "body": { "type": "column", "column": "body", "column_type": "String" }
+                        }
+                    }
+                }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "active", "column_type": "Bool" },
+                "value": { "type": "scalar", "value": true, "value_type": "Bool" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"FROM "users" AS "_origin" WHERE "_origin"."active" = TRUE GROUP BY "_origin"."id", "_origin"."profile_id") AS "_parent""#),
+        "reads only the keys of users the parent query selects, rather than every user, got {statement}"
+    );
+}
+
+#[test]
+fn filters_polymorphic_relationships_without_repeating_rows() {
+    let request = |order_by: serde_json::Value| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [{
+                "source_table": ["users"],
+                "relationships": {
+                    "comments": {
+                        "target_table": ["comments"],
+                        "relationship_type": "array",
+                        "column_mapping": { "id": "author_id", "profile_id": "subject_id" },
+                        "match_any_column": true
+                    }
+                }
+            }],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "where": {
+                    "type": "exists",
+                    "in_table": { "type": "related", "relationship": "comments" },
+                    "where": {
+                        "type": "binary_op",
+                        "operator": "equal",
+                        "column": { "name": "flagged", "column_type": "Bool" },
+                        "value": { "type": "scalar", "value": true, "value_type": "Bool" }
+                    }
+                },
+                "order_by": order_by
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement =
+        QueryBuilder::build_sql_statement(&request(serde_json::Value::Null), &test_config(), false)
+            .expect("can build statement")
+            .to_string();
+    assert!(
+        statement
+            .contains(r#"GROUP BY "_exists_0"."_parent.id", "_exists_0"."_parent.profile_id""#),
+        "groups related rows by the key of the user they match, got {statement}"
+    );
+    assert!(
+        statement.contains(r#"ON (isNotDistinctFrom("_exists_0"."_parent.id", "_origin"."id") AND isNotDistinctFrom("_exists_0"."_parent.profile_id", "_origin"."profile_id"))"#),
+        "joins each user to at most one group"
+    );
+
+    let order_by = serde_json::json!({
+        "relations": { "comments": { "subrelations": {} } },
+        "elements": [{
+            "target_path": ["comments"],
+            "target": { "type": "star_count_aggregate" },
+            "order_direction": "desc"
+        }]
+    });
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request(order_by), &test_config(), false),
+            Err(QueryBuilderError::OrderByMatchAnyColumn(_))
+        ),
+        "rejects ordering through relationships matching any column"
+    );
+}
+
+#[test]
+fn does_not_project_selection_columns_without_relationships() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String" }
+            },
+            "aggregates": {
+                "count": { "type": "star_count" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        !statement.contains("_selection."),
+        "root queries have no parent join to project selection columns for"
+    );
+}
+
+#[test]
+fn projects_only_joined_selection_columns_of_nested_relationships() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [
+            {
+                "source_table": ["users"],
+                "relationships": {
+                    "posts": {
+                        "target_table": ["posts"],
+                        "relationship_type": "array",
+                        "column_mapping": { "id": "author_id" }
+                    }
+                }
+            },
+            {
+                "source_table": ["posts"],
+                "relationships": {
+                    "comments": {
+                        "target_table": ["comments"],
+                        "relationship_type": "array",
+                        "column_mapping": { "id": "post_id" }
+                    }
+                }
+            }
+        ],
+        "query": {
+            "fields": {
+                "posts": {
+                    "type": "relationship",
+                    "relationship": "posts",
+                    "query": {
+                        "fields": {
+                            "title": { "type": "column", "column": "title", "column_type": "String" },
+                            "comments": {
+                                "type": "relationship",
+                                "relationship": "comments",
+                                "query": {
+                                    "fields": {
+                                        "body": { "type": "column", "column": "body", "column_type": "String" }
+                                    },
+                                    "aggregates": {
+                                        "count": { "type": "star_count" }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    let mut selection_columns = statement
+        .split(r#""_selection."#)
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .collect::<Vec<_>>();
+    selection_columns.sort_unstable();
+    selection_columns.dedup();
+    assert_eq!(
+        selection_columns,
+        vec!["author_id", "post_id"],
+        "only the columns joined to a parent are projected as selection columns, got {statement}"
+    );
+}
+
+#[test]
+fn projects_shared_target_column_once() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [{
+            "source_table": ["users"],
+            "relationships": {
+                "comments": {
+                    "target_table": ["comments"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "subject_id", "profile_id": "subject_id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "comments": {
+                    "type": "relationship",
+                    "relationship": "comments",
+                    "query": {
+                        "fields": {
+                            "body": { "type": "column", "column": "body", "column_type": "String" }
+                        }
+                    }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert_eq!(
+        statement
+            .matches(r#""_origin"."subject_id" AS "_selection.subject_id""#)
+            .count(),
+        1,
+        "target column is projected once"
+    );
+}
+
+#[test]
+fn can_query_parameterized_view() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events_by_tenant"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String" }
+            },
+            "view_parameters": {
+                "tenant": { "value": "acme", "value_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"FROM "events_by_tenant"("tenant" = 'acme') AS "_origin""#),
+        "binds view parameters"
+    );
+}
+
+#[test]
+fn can_sum_map_values_by_key() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["metrics"],
+        "table_relationships": [],
+        "query": {
+            "aggregates": {
+                "totals": {
+                    "type": "single_column",
+                    "column": "counters",
+                    "function": "sum_map",
+                    "result_type": "Unknown"
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"toJSONString(sumMap("_row"."_projection.totals"))"#),
+        "rolls up map values by key"
+    );
+}
+
+#[test]
+fn only_injects_raw_sql_predicates_when_enabled() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String" }
+            },
+            "where": {
+                "type": "raw_sql",
+                "sql": "positionCaseInsensitive(name, {0}) > 0",
+                "arguments": [{ "value": "o'clock", "value_type": "String" }]
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request, &test_config(), false),
+            Err(QueryBuilderError::RawSqlNotAllowed)
+        ),
+        "rejects raw sql by default"
+    );
+
+    let config = Config {
+        allow_raw_sql_predicates: Some(true),
+        ..test_config()
+    };
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"WHERE (positionCaseInsensitive(name, 'o\'clock') > 0)"#),
+        "injects the fragment with bound arguments"
+    );
+}
+
+#[test]
+fn binds_raw_sql_arguments_in_a_single_pass() {
+    let raw_sql_request = |sql: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<query_request::QueryRequest>(serde_json::json!({
+            "table": ["events"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "name": { "type": "column", "column": "name", "column_type": "String" }
+                },
+                "where": { "type": "raw_sql", "sql": sql, "arguments": arguments }
+            }
+        }))
+        .expect("valid query request")
+    };
+    let config = Config {
+        allow_raw_sql_predicates: Some(true),
+        ..test_config()
+    };
+
+    let request = raw_sql_request(
+        "name = {0} AND tenant = {1}",
+        serde_json::json!([
+            { "value": "{1}", "value_type": "String" },
+            { "value": ") OR 1=1 OR (", "value_type": "String" }
+        ]),
+    );
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"WHERE (name = '{1}' AND tenant = ') OR 1=1 OR (')"#),
+        "placeholders within arguments are not substituted, got {statement}"
+    );
+
+    let request = raw_sql_request(
+        "name = {1}",
+        serde_json::json!([{ "value": "a", "value_type": "String" }]),
+    );
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request, &config, false),
+            Err(QueryBuilderError::InvalidRawSql(_))
+        ),
+        "rejects placeholders without an argument"
+    );
+
+    let request = raw_sql_request(
+        "name = {0}",
+        serde_json::json!([
+            { "value": "a", "value_type": "String" },
+            { "value": "b", "value_type": "String" }
+        ]),
+    );
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request, &config, false),
+            Err(QueryBuilderError::InvalidRawSql(_))
+        ),
+        "rejects arguments without a placeholder"
+    );
+}
+
+#[test]
+fn can_project_date_difference() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["tickets"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "days_open": {
+                    "type": "computed",
+                    "function": {
+                        "type": "date_diff",
+                        "unit": "day",
+                        "start_column": "opened_on",
+                        "end_column": "closed_on"
+                    }
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"dateDiff('day', "_origin"."opened_on", "_origin"."closed_on") AS "_projection.days_open""#),
+        "projects the day difference"
+    );
+}
+
+#[test]
+fn can_estimate_distinct_count_with_theta_sketch() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "aggregates": {
+                "visitors": {
+                    "type": "single_column",
+                    "column": "user_id",
+                    "function": "uniq_theta",
+                    "result_type": "UInt64"
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"uniqTheta("_row"."_projection.visitors")"#),
+        "estimates the distinct count using a theta sketch"
+    );
+}
+
+#[test]
+fn rejects_order_by_column_outside_order_by_relations() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["posts"],
+        "table_relationships": [{
+            "source_table": ["posts"],
+            "relationships": {
+                "author": {
+                    "target_table": ["users"],
+                    "relationship_type": "object",
+                    "column_mapping": { "author_id": "id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "title": { "type": "column", "column": "title", "column_type": "String" }
+            },
+            "order_by": {
+                "relations": {},
+                "elements": [{
+                    "order_direction": "asc",
+                    "target": { "type": "column", "column": "name" },
+                    "target_path": ["author"]
+                }]
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request, &test_config(), false),
+            Err(QueryBuilderError::OrderByColumnMissing(column, path))
+                if column == "name" && path == vec!["author".to_owned()]
+        ),
+        "rejects the unprojected order by column"
+    );
+}
+
+#[test]
+fn can_filter_on_bitmap_membership() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["segments"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String" },
+                "size": {
+                    "type": "computed",
+                    "function": { "type": "bitmap_cardinality", "column": "user_ids" }
+                }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "_bitmap_contains",
+                "column": { "name": "user_ids", "column_type": "Bitmap" },
+                "value": { "type": "scalar", "value": 42, "value_type": "UInt32" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"WHERE bitmapContains("_origin"."user_ids", 42)"#),
+        "filters on bitmap membership"
+    );
+    assert!(
+        statement.contains(r#"bitmapCardinality("_origin"."user_ids") AS "_projection.size""#),
+        "projects the bitmap cardinality"
+    );
+}
+
+#[test]
+fn casts_avg_aggregate_to_float() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["orders"],
+        "table_relationships": [],
+        "query": {
+            "aggregates": {
+                "average_total": {
+                    "type": "single_column",
+                    "column": "total",
+                    "function": "avg",
+                    "result_type": "Decimal"
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"avg("_row"."_projection.average_total")"#),
+        "averages the column"
+    );
+    assert!(
+        statement.contains(r#"AS Tuple("average_total" Nullable(Float64))"#),
+        "casts the average to a float"
+    );
+}
+
+#[test]
+fn can_match_string_case_insensitively() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "email": { "type": "column", "column": "email", "column_type": "String" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "_ieq",
+                "column": { "name": "email", "column_type": "String" },
+                "value": { "type": "scalar", "value": "Alice@Example.com", "value_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"WHERE lower("_origin"."email") = lower('Alice@Example.com')"#),
+        "lowercases both sides"
+    );
+}
+
+#[test]
+fn can_aggregate_median_and_quantile() {
+    let request = |level: f64| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["requests"],
+            "table_relationships": [],
+            "query": {
+                "aggregates": {
+                    "median_latency": {
+                        "type": "single_column",
+                        "column": "latency",
+                        "function": "median",
+                        "result_type": "Float64"
+                    },
+                    "p95_latency": {
+                        "type": "single_column",
+                        "column": "latency",
+                        "function": { "quantile": { "level": level } },
+                        "result_type": "Float64"
+                    }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request(0.95), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"median("_row"."_projection.median_latency")"#),
+        "aggregates the median"
+    );
+    assert!(
+        statement.contains(r#"quantile(0.95)("_row"."_projection.p95_latency")"#),
+        "aggregates the quantile"
+    );
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request(1.5), &test_config(), false),
+            Err(QueryBuilderError::InvalidQuantileLevel(_))
+        ),
+        "rejects levels outside 0 to 1"
+    );
+    let quantile = |level| query_request::SingleColumnAggregateFunction::Quantile { level };
+    assert_eq!(function_name(&quantile(0.95)), "quantile_0_95");
+    assert_ne!(
+        function_name(&quantile(0.1)),
+        function_name(&quantile(1.0)),
+        "names distinct levels distinctly"
+    );
+    assert_eq!(
+        function_name(&quantile(-0.0)),
+        function_name(&quantile(0.0)),
+        "names equal levels the same"
+    );
+    let hash = |function: &query_request::SingleColumnAggregateFunction| {
+        use std::hash::{BuildHasher, BuildHasherDefault};
+        BuildHasherDefault::<std::collections::hash_map::DefaultHasher>::default()
+            .hash_one(function)
+    };
+    assert_eq!(quantile(-0.0), quantile(0.0));
+    assert_eq!(
+        hash(&quantile(-0.0)),
+        hash(&quantile(0.0)),
+        "equal levels hash the same"
+    );
+}
+
+#[test]
+fn casts_counts_to_uint64_when_large_counts_enabled() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "aggregates": {
+                "count": { "type": "star_count" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"AS Tuple("count" UInt32)"#),
+        "counts are numbers by default"
+    );
+
+    let config = Config {
+        large_count_as_string: Some(true),
+        ..test_config()
+    };
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"AS Tuple("count" UInt64)"#),
+        "counts are not truncated when enabled"
+    );
+}
+
+#[test]
+fn casts_decimals_with_known_precision_and_scale() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["orders"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "total": {
+                    "type": "column",
+                    "column": "total",
+                    "column_type": "Decimal",
+                    "precision": 18,
+                    "scale": 4
+                },
+                "discount": { "type": "column", "column": "discount", "column_type": "Decimal" }
+            },
+            "aggregates": {
+                "sum_total": {
+                    "type": "single_column",
+                    "column": "total",
+                    "function": "sum",
+                    "result_type": "Decimal",
+                    "precision": 18,
+                    "scale": 4
+                },
+                "max_total": {
+                    "type": "single_column",
+                    "column": "total",
+                    "function": "max",
+                    "result_type": "Decimal",
+                    "precision": 18,
+                    "scale": 4
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#""total" Nullable(Decimal(18, 4))"#),
+        "casts the row decimal with its precision and scale"
+    );
+    assert!(
+        statement.contains(r#""discount" Nullable(String)"#),
+        "falls back to strings when precision and scale are unknown"
+    );
+    assert!(
+        statement.contains(r#""sum_total" Nullable(Decimal(38, 4))"#),
+        "widens decimal sums as clickhouse does"
+    );
+    assert!(
+        statement.contains(r#""max_total" Nullable(Decimal(18, 4))"#),
+        "keeps the column precision for other aggregates"
+    );
+}
+
+#[test]
+fn filters_not_null_columns_directly() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "unary_op",
+                "operator": "_is_not_null",
+                "column": { "name": "email", "column_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"WHERE "_origin"."email" IS NOT NULL"#),
+        "emits is not null without negating is null"
+    );
+
+    let config = Config {
+        empty_string_as_null: Some(true),
+        ..test_config()
+    };
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"("_origin"."email" IS NOT NULL AND "_origin"."email" <> '')"#),
+        "empty strings do not match is not null filters"
+    );
+}
+
+#[test]
+fn can_treat_empty_strings_as_null() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "or",
+                "expressions": [
+                    {
+                        "type": "unary_op",
+                        "operator": "is_null",
+                        "column": { "name": "email", "column_type": "String" }
+                    },
+                    {
+                        "type": "binary_op",
+                        "operator": "equal",
+                        "column": { "name": "name", "column_type": "String" },
+                        "value": { "type": "scalar", "value": "", "value_type": "String" }
+                    }
+                ]
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let config = Config {
+        empty_string_as_null: Some(true),
+        ..test_config()
+    };
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"("_origin"."email" IS NULL OR "_origin"."email" = '')"#),
+        "empty strings match is null filters"
+    );
+    assert!(
+        statement.contains(r#"nullIf("_origin"."name", '') = ''"#),
+        "empty strings are compared as null"
+    );
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#""_origin"."email" IS NULL OR "_origin"."name" = ''"#),
+        "empty strings are not null by default"
+    );
+}
+
+#[test]
+fn keeps_datetime_timezones() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "created_at": {
+                    "type": "column",
+                    "column": "created_at",
+                    "column_type": "DateTime64",
+                    "timezone": "UTC"
+                },
+                "updated_at": { "type": "column", "column": "updated_at", "column_type": "DateTime" }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "greater_than",
+                "column": { "name": "created_at", "column_type": "DateTime64", "timezone": "UTC" },
+                "value": { "type": "scalar", "value": "2023-01-01 00:00:00", "value_type": "DateTime64" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#""created_at" Nullable(DateTime64(9, 'UTC'))"#),
+        "casts the row value in its timezone"
+    );
+    assert!(
+        statement.contains(r#""updated_at" Nullable(DateTime)"#),
+        "leaves the timezone off when unknown"
+    );
+    assert!(
+        statement
+            .contains(r#""_origin"."created_at" > toDateTime64('2023-01-01 00:00:00', 9, 'UTC')"#),
+        "reads the filter value in the column timezone"
+    );
+}
+
+#[test]
+fn casts_fixed_strings_with_known_length() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["devices"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "serial": {
+                    "type": "column",
+                    "column": "serial",
+                    "column_type": "FixedString",
+                    "length": 16
+                },
+                "code": { "type": "column", "column": "code", "column_type": "FixedString" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#""serial" Nullable(FixedString(16))"#),
+        "casts the fixed string with its length"
+    );
+    assert!(
+        statement.contains(r#""code" Nullable(String)"#),
+        "falls back to strings when the length is unknown"
+    );
+}
+
+#[test]
+fn projects_masked_columns() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "email": { "type": "column", "column": "email", "column_type": "String" },
+                "phone": { "type": "column", "column": "phone", "column_type": "String" },
+                "name": { "type": "column", "column": "name", "column_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": "",
+        "tables": [{
+            "name": "users",
+            "columns": [
+                { "name": "email", "mask": "partial" },
+                { "name": "phone", "mask": "hash" }
+            ]
+        }]
+    }))
+    .expect("valid config");
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"concat(left("_origin"."email", 2), '***') AS "_projection.email""#),
+        "projects the partially masked email"
+    );
+    assert!(
+        statement.contains(r#"hex(SHA256("_origin"."phone")) AS "_projection.phone""#),
+        "projects the hashed phone number"
+    );
+    assert!(
+        statement.contains(r#""_origin"."name" AS "_projection.name""#),
+        "projects unmasked columns as is"
+    );
+}
+
+#[test]
+fn aggregates_masked_columns_by_their_masked_values() {
+    let request = |function: &str, result_type: &str| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [],
+            "query": {
+                "aggregates": {
+                    "m": {
+                        "type": "single_column",
+                        "column": "email",
+                        "function": function,
+                        "result_type": result_type
+                    }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": "",
+        "tables": [{
+            "name": "users",
+            "columns": [{ "name": "email", "mask": "partial" }]
+        }]
+    }))
+    .expect("valid config");
+
+    let statement = QueryBuilder::build_sql_statement(&request("max", "String"), &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"concat(left("_origin"."email", 2), '***') AS "_projection.m""#),
+        "projects the masked value for the aggregate, got {statement}"
+    );
+    assert!(
+        !statement.contains(r#""_origin"."email" AS"#),
+        "never projects the raw value"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request("sum", "Float64"), &config, false),
+            Err(QueryBuilderError::MaskedColumnNotString(_))
+        ),
+        "rejects numeric aggregates of masked columns"
+    );
+}
+
+#[test]
+fn can_flag_gaps_between_rows() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "timestamp": { "type": "column", "column": "timestamp", "column_type": "DateTime" },
+                "new_session": {
+                    "type": "computed",
+                    "function": {
+                        "type": "gap_exceeds",
+                        "column": "timestamp",
+                        "unit": "minute",
+                        "threshold": 30
+                    }
+                }
+            },
+            "order_by": {
+                "relations": {},
+                "elements": [{
+                    "order_direction": "asc",
+                    "target": { "type": "column", "column": "timestamp" },
+                    "target_path": []
+                }]
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(
+            r#"dateDiff('minute', lagInFrame("_origin"."timestamp", 1, "_origin"."timestamp") OVER (ORDER BY "_origin"."timestamp" ASC NULLS LAST), "_origin"."timestamp") > 30 AS "_projection.new_session""#
+        ),
+        "flags rows more than 30 minutes after the previous row"
+    );
+    assert!(
+        statement.contains(r#""new_session" Nullable(Bool)"#),
+        "casts the flag to a boolean"
+    );
+}
+
+#[test]
+fn caps_relationship_rows_at_default_limit() {
+    let request = |limit: Option<u64>| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["authors"],
+            "table_relationships": [{
+                "source_table": ["authors"],
+                "relationships": {
+                    "articles": {
+                        "target_table": ["articles"],
+                        "relationship_type": "array",
+                        "column_mapping": { "id": "author_id" }
+                    }
+                }
+            }],
+            "query": {
+                "fields": {
+                    "articles": {
+                        "type": "relationship",
+                        "relationship": "articles",
+                        "query": {
+                            "fields": {
+                                "title": { "type": "column", "column": "title", "column_type": "String" }
+                            },
+                            "limit": limit
+                        }
+                    }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+    let config = Config {
+        default_relationship_limit: Some(10),
+        ..test_config()
+    };
+
+    let (statement, warnings) = QueryBuilder::build_sql_statement_with_context(
+        &request(None),
+        &config,
+        false,
+        QueryContext::default(),
+    )
+    .expect("can build statement");
+    assert!(
+        statement
+            .to_string()
+            .contains(r#"LIMIT 10 BY "_origin"."author_id""#),
+        "caps children at the default limit"
+    );
+    assert_eq!(
+        warnings,
+        vec![QueryWarning::DefaultRelationshipLimit(
+            vec!["articles".to_owned()],
+            10
+        )],
+        "reports the default limit"
+    );
+
+    let (statement, warnings) = QueryBuilder::build_sql_statement_with_context(
+        &request(Some(20)),
+        &config,
+        false,
+        QueryContext::default(),
+    )
+    .expect("can build statement");
+    assert!(
+        statement
+            .to_string()
+            .contains(r#"LIMIT 20 BY "_origin"."author_id""#),
+        "keeps the explicit limit"
+    );
+    assert!(warnings.is_empty(), "no default limit to report");
+}
+
+#[test]
+fn emits_not_equal_directly() {
+    let request = |selection: serde_json::Value| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "where": selection
+            }
+        }))
+        .expect("valid query request")
+    };
+    let comparison = |operator: &str| {
+        serde_json::json!({
+            "type": "binary_op",
+            "operator": operator,
+            "column": { "name": "status", "column_type": "String" },
+            "value": { "type": "scalar", "value": "deleted", "value_type": "String" }
+        })
+    };
+
+    let not_equal =
+        QueryBuilder::build_sql_statement(&request(comparison("_neq")), &test_config(), false)
+            .expect("can build statement")
+            .to_string();
+    let negated_equal = QueryBuilder::build_sql_statement(
+        &request(serde_json::json!({ "type": "not", "expression": comparison("equal") })),
+        &test_config(),
+        false,
+    )
+    .expect("can build statement")
+    .to_string();
+
+    assert!(
+        not_equal.contains(r#"WHERE "_origin"."status" <> 'deleted'"#),
+        "emits the inequality directly"
+    );
+    assert!(
+        negated_equal.contains(r#"WHERE NOT ("_origin"."status" = 'deleted')"#),
+        "negated equality remains a separate form"
+    );
+    // both comparisons are NULL for NULL statuses, so neither form keeps those rows without an is_null branch
+    assert!(
+        !not_equal.contains("IS NULL") && !negated_equal.contains("IS NULL"),
+        "neither form adds a null branch"
+    );
+
+    let config = Config {
+        empty_string_as_null: Some(true),
+        ..test_config()
+    };
+    let not_equal = QueryBuilder::build_sql_statement(&request(comparison("_neq")), &config, false)
+        .expect("can build statement")
+        .to_string();
+    let negated_equal = QueryBuilder::build_sql_statement(
+        &request(serde_json::json!({ "type": "not", "expression": comparison("equal") })),
+        &config,
+        false,
+    )
+    .expect("can build statement")
+    .to_string();
+    assert!(
+        not_equal.contains(r#"WHERE nullIf("_origin"."status", '') <> 'deleted'"#),
+        "empty strings take the null branch of the inequality"
+    );
+    assert!(
+        negated_equal.contains(r#"WHERE NOT (nullIf("_origin"."status", '') = 'deleted')"#),
+        "empty strings take the null branch of the negated equality"
+    );
+}
+
+#[test]
+fn reads_allowed_system_tables_read_only() {
+    let request = |table: &str| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["system", table],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "name": { "type": "column", "column": "name", "column_type": "String" }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+    let config = Config {
+        allowed_system_tables: Some(vec!["tables".to_owned()]),
+        ..test_config()
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request("tables"), &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"FROM "system"."tables" AS "_origin""#),
+        "reads the allowed system table"
+    );
+    assert!(
+        statement.ends_with("SETTINGS readonly = 1 FORMAT JSON;"),
+        "runs the statement read only"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request("processes"), &config, false),
+            Err(QueryBuilderError::SystemTableNotAllowed(table)) if table == "processes"
+        ),
+        "blocks system tables that are not allowed"
+    );
+}
+
+#[test]
+fn reads_union_tables_as_one_table() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["events"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String" }
+            },
+            "aggregates": { "count": { "type": "star_count" } },
+            "where": {
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "name", "column_type": "String" },
+                "value": { "type": "scalar", "value": "click", "value_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": "",
+        "tables": [{
+            "name": "events",
+            "union_tables": ["events_2023_01", "events_2023_02"]
+        }]
+    }))
+    .expect("valid config");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+
+    assert_eq!(
+        statement
+            .matches(r#"FROM merge(currentDatabase(), '^(events_2023_01|events_2023_02)$') AS "_origin" WHERE "_origin"."name" = 'click'"#)
+            .count(),
+        2,
+        "rows and aggregates both read the union of tables"
+    );
+}
+
+#[test]
+fn can_filter_by_regular_expression() {
+    let request = |operator: &str| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["logs"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "message": { "type": "column", "column": "message", "column_type": "String" }
+                },
+                "where": {
+                    "type": "binary_op",
+                    "operator": operator,
+                    "column": { "name": "message", "column_type": "String" },
+                    "value": { "type": "scalar", "value": "^error: .*timeout$", "value_type": "String" }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request("_regex"), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"WHERE match("_origin"."message", '^error: .*timeout$')"#),
+        "matches the pattern"
+    );
+
+    let statement = QueryBuilder::build_sql_statement(&request("_nregex"), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"WHERE NOT match("_origin"."message", '^error: .*timeout$')"#),
+        "excludes rows matching the pattern"
+    );
+
+    let (_statement, parameters) =
+        QueryBuilder::build_parameterized_sql_statement(&request("_regex"), &test_config(), None)
+            .expect("can build statement");
+    assert!(
+        parameters.values().any(|parameter| matches!(
+            parameter,
+            BoundParam::Value { value: serde_json::Value::String(pattern), value_type: ScalarType::String }
+                if pattern == "^error: .*timeout$"
+        )),
+        "binds the pattern as a string"
+    );
+}
+
+#[test]
+fn compares_large_integers_given_as_strings() {
+    let request = |column_type: &str, value: serde_json::Value| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["balances"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "where": {
+                    "type": "binary_op",
+                    "operator": "greater_than",
+                    "column": { "name": "amount", "column_type": column_type },
+                    "value": { "type": "scalar", "value": value, "value_type": column_type }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(
+        &request(
+            "Int128",
+            serde_json::json!("-170141183460469231731687303715884105728"),
+        ),
+        &test_config(),
+        false,
+    )
+    .expect("can build statement")
+    .to_string();
+    assert!(
+        statement.contains(
+            r#"WHERE "_origin"."amount" > toInt128('-170141183460469231731687303715884105728')"#
+        ),
+        "parses the Int128 value from its string"
+    );
+
+    let statement = QueryBuilder::build_sql_statement(
+        &request(
+            "Int256",
+            serde_json::json!(
+                "57896044618658097711785492504343953926634992332820282019728792003956564819967"
+            ),
+        ),
+        &test_config(),
+        false,
+    )
+    .expect("can build statement")
+    .to_string();
+    assert!(
+        statement.contains(
+            r#"WHERE "_origin"."amount" > toInt256('57896044618658097711785492504343953926634992332820282019728792003956564819967')"#
+        ),
+        "parses the Int256 value from its string"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(
+                &request("Int128", serde_json::json!("12e5")),
+                &test_config(),
+                false
+            ),
+            Err(QueryBuilderError::InvalidBigIntLiteral(_))
+        ),
+        "rejects values that are not integers"
+    );
+
+    let is_rejected = |column_type: &str, value: serde_json::Value| {
+        matches!(
+            QueryBuilder::build_sql_statement(&request(column_type, value), &test_config(), false),
+            Err(QueryBuilderError::InvalidBigIntLiteral(_))
+        )
+    };
+    assert!(
+        !is_rejected(
+            "UInt128",
+            serde_json::json!("340282366920938463463374607431768211455")
+        ),
+        "accepts the largest UInt128"
+    );
+    assert!(
+        is_rejected(
+            "UInt128",
+            serde_json::json!("340282366920938463463374607431768211456")
+        ),
+        "rejects values above the largest UInt128"
+    );
+    assert!(
+        is_rejected(
+            "Int128",
+            serde_json::json!("-170141183460469231731687303715884105729")
+        ),
+        "rejects values below the smallest Int128"
+    );
+    assert!(
+        is_rejected(
+            "Int256",
+            serde_json::json!(
+                "57896044618658097711785492504343953926634992332820282019728792003956564819968"
+            )
+        ),
+        "rejects values above the largest Int256"
+    );
+    assert!(
+        !is_rejected(
+            "UInt256",
+            serde_json::json!(
+                "00115792089237316195423570985008687907853269984665640564039457584007913129639935"
+            )
+        ),
+        "ignores leading zeros"
+    );
+    assert!(
+        is_rejected("UInt256", serde_json::json!("-1")),
+        "rejects negative UInt256 strings"
+    );
+    assert!(
+        is_rejected("UInt128", serde_json::json!(-1)),
+        "rejects negative UInt128 numbers"
+    );
+}
+
+#[test]
+fn compares_columns_of_the_same_table() {
+    let request = |path: Vec<&str>| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["products"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "where": {
+                    "type": "binary_op",
+                    "operator": "greater_than",
+                    "column": { "name": "price", "column_type": "Float64" },
+                    "value": {
+                        "type": "column",
+                        "column": { "name": "cost", "column_type": "Float64", "path": path }
+                    }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request(vec![]), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"WHERE "_origin"."price" > "_origin"."cost""#),
+        "compares the two columns"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request(vec!["supplier"]), &test_config(), false),
+            Err(QueryBuilderError::RightHandColumnComparisonNotSupported(column)) if column == "cost"
+        ),
+        "rejects comparisons across relationships"
+    );
+}
+
+#[test]
+fn serializes_statement_ast() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement");
+    let ast = serde_json::to_value(&statement).expect("ast serializes");
+
+    assert_eq!(
+        ast["from"][0]["relation"]["Derived"]["alias"],
+        serde_json::json!({ "value": "_query", "quoted": true }),
+        "the top level query selects from the _query subquery"
+    );
+    assert_eq!(
+        ast["projection"][0]["ExprWithAlias"]["alias"]["value"], "rows",
+        "the top level query projects rows"
+    );
+}
+
+#[test]
+fn compares_columns_across_relationship_paths() {
+    let request = |path: Vec<&str>| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["customers"],
+            "table_relationships": [{
+                "source_table": ["customers"],
+                "relationships": {
+                    "orders": {
+                        "target_table": ["orders"],
+                        "relationship_type": "array",
+                        "column_mapping": { "id": "customer_id" }
+                    }
+                }
+            }],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "where": {
+                    "type": "binary_op",
+                    "operator": "greater_than",
+                    "column": { "name": "total", "column_type": "Float64", "path": path },
+                    "value": { "type": "scalar", "value": 100, "value_type": "Float64" }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement =
+        QueryBuilder::build_sql_statement(&request(vec!["orders"]), &test_config(), false)
+            .expect("can build statement")
+            .to_string();
+    assert!(
+        statement.contains(r#"FROM "orders" AS "_exists_0" WHERE "_exists_0"."total" > 100"#),
+        "compares the column of related rows"
+    );
+    assert!(
+        statement.contains(r#"WHERE "_exists_0"."customer_id" = "_origin"."id""#),
+        "matches rows with any related row satisfying the comparison"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request(vec!["invoices"]), &test_config(), false),
+            Err(QueryBuilderError::RelationshipMissingInTable(relationship, _)) if relationship == "invoices"
+        ),
+        "rejects paths naming a missing relationship"
+    );
+}
+
+#[test]
+fn rejects_invalid_limits_without_panicking() {
+    let request =
+        |limit: serde_json::Value, offset: serde_json::Value| -> query_request::QueryRequest {
+            serde_json::from_value(serde_json::json!({
+                "table": ["users"],
+                "table_relationships": [],
+                "query": {
+                    "fields": {
+                        "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                    },
+                    "limit": limit,
+                    "offset": offset
+                }
+            }))
+            .expect("valid query request")
+        };
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(
+                &request(serde_json::json!(2.5), serde_json::Value::Null),
+                &test_config(),
+                false
+            ),
+            Err(QueryBuilderError::InvalidLimit(_))
+        ),
+        "rejects fractional limits"
+    );
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(
+                &request(serde_json::json!(10), serde_json::json!(u64::MAX)),
+                &test_config(),
+                false
+            ),
+            Err(QueryBuilderError::InvalidLimit(_))
+        ),
+        "rejects offsets overflowing the limit"
+    );
+}
+
+#[test]
+fn filters_grouped_aggregates_by_threshold() {
+    let request = |column: &str| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["customers"],
+            "table_relationships": [{
+                "source_table": ["customers"],
+                "relationships": {
+                    "orders": {
+                        "target_table": ["orders"],
+                        "relationship_type": "array",
+                        "column_mapping": { "id": "customer_id" }
+                    }
+                }
+            }],
+            "query": {
+                "fields": {
+                    "orders": {
+                        "type": "relationship",
+                        "relationship": "orders",
+                        "query": {
+                            "aggregates": {
+                                "total": {
+                                    "type": "single_column",
+                                    "function": "sum",
+                                    "column": "total",
+                                    "result_type": "Float64"
+                                }
+                            },
+                            "having": {
+                                "type": "binary_op",
+                                "operator": "greater_than",
+                                "column": { "name": column, "column_type": "Float64" },
+                                "value": { "type": "scalar", "value": 100, "value_type": "Float64" }
+                            }
+                        }
+                    }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request("total"), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(
+            r#"GROUP BY "_row"."_selection.customer_id" HAVING sum("_row"."_projection.total") > 100"#
+        ),
+        "filters each group of orders by the sum of its totals, got {statement}"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request("count"), &test_config(), false),
+            Err(QueryBuilderError::InvalidHaving(_))
+        ),
+        "rejects columns that are not aggregates of the query"
+    );
+}
+
+#[test]
+fn applies_offset_to_aggregates_like_rows() {
+    let query = serde_json::json!({
+        "fields": {
+            "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+        },
+        "aggregates": {
+            "count": { "type": "star_count" }
+        },
+        "order_by": {
+            "relations": {},
+            "elements": [{
+                "target_path": [],
+                "target": { "type": "column", "column": "id" },
+                "order_direction": "asc"
+            }]
+        },
+        "limit": 20,
+        "aggregates_limit": 5,
+        "offset": 10
+    });
+    let request = |table: &str, query: serde_json::Value| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": [table],
+            "table_relationships": [{
+                "source_table": ["customers"],
+                "relationships": {
+                    "orders": {
+                        "target_table": ["orders"],
+                        "relationship_type": "array",
+                        "column_mapping": { "id": "customer_id" }
+                    }
+                }
+            }],
+            "query": query
+        }))
+        .expect("valid query request")
+    };
+
+    let statement =
+        QueryBuilder::build_sql_statement(&request("orders", query.clone()), &test_config(), false)
+            .expect("can build statement")
+            .to_string();
+    assert!(
+        statement.contains(r#"FROM "orders" AS "_origin" ORDER BY "_origin"."id" ASC NULLS LAST LIMIT 20 OFFSET 10)"#),
+        "rows are windowed by limit and offset"
+    );
+    assert!(
+        statement.contains(r#"FROM "orders" AS "_origin" ORDER BY "_origin"."id" ASC NULLS LAST LIMIT 5 OFFSET 10)"#),
+        "aggregates are computed over the same ordering and offset, limited by the aggregates limit"
+    );
+
+    let relationship_query = serde_json::json!({
+        "fields": {
+            "orders": { "type": "relationship", "relationship": "orders", "query": query }
+        }
+    });
+    let statement = QueryBuilder::build_sql_statement(
+        &request("customers", relationship_query),
+        &test_config(),
+        false,
+    )
+    .expect("can build statement")
+    .to_string();
+    assert!(
+        statement.contains(r#"ORDER BY "_origin"."id" ASC NULLS LAST LIMIT 20 OFFSET 10 BY "_origin"."customer_id")"#),
+        "related rows are windowed per parent row"
+    );
+    assert!(
+        statement.contains(r#"ORDER BY "_origin"."id" ASC NULLS LAST LIMIT 5 OFFSET 10 BY "_origin"."customer_id")"#),
+        "related aggregates are windowed per parent row with the same offset"
+    );
+}
+
+#[test]
+fn counts_conditionally_after_filtering() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["orders"],
+        "table_relationships": [],
+        "query": {
+            "aggregates": {
+                "shipped": {
+                    "type": "conditional_count",
+                    "where": {
+                        "type": "binary_op",
+                        "operator": "equal",
+                        "column": { "name": "status", "column_type": "String" },
+                        "value": { "type": "scalar", "value": "shipped", "value_type": "String" }
+                    }
+                }
+            },
+            "where": {
+                "type": "binary_op",
+                "operator": "greater_than",
+                "column": { "name": "total", "column_type": "Float64" },
+                "value": { "type": "scalar", "value": 100, "value_type": "Float64" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(
+            r#"SELECT "_origin"."status" = 'shipped' AS "_projection.shipped" FROM "orders" AS "_origin" WHERE "_origin"."total" > 100"#
+        ),
+        "the condition is evaluated on rows matching the query selection, got {statement}"
+    );
+    assert!(
+        statement.contains(r#"tuple(countIf("_row"."_projection.shipped")) AS "aggregates""#),
+        "only rows matching the condition are counted"
+    );
+}
+
+#[test]
+fn rejects_foreach_rows_with_different_keys() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["sales"],
+        "table_relationships": [],
+        "foreach": [
+            {
+                "year": { "value": 2022, "value_type": "UInt16" },
+                "region": { "value": "north", "value_type": "String" }
+            },
+            {
+                "year": { "value": 2023, "value_type": "UInt16" },
+                "region": { "value": "south", "value_type": "String" }
+            },
+            {
+                "year": { "value": 2023, "value_type": "UInt16" },
+                "country": { "value": "nz", "value_type": "String" }
+            }
+        ],
+        "query": {
+            "aggregates": { "count": { "type": "star_count" } }
+        }
+    }))
+    .expect("valid query request");
+
+    assert!(matches!(
+        QueryBuilder::build_sql_statement(&request, &test_config(), false),
+        Err(QueryBuilderError::MismatchedForeachKeys(2, keys)) if keys == vec!["region", "country"]
+    ));
+}
+
+#[test]
+fn returns_no_rows_for_empty_foreach() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["sales"],
+        "table_relationships": [],
+        "foreach": [],
+        "foreach_total_aggregates": true,
+        "query": {
+            "aggregates": { "count": { "type": "star_count" } }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"FROM (SELECT tuple(array()) AS "query") AS "_query""#),
+        "returns an empty rows array without querying the table, got {statement}"
+    );
+    assert!(
+        statement.contains(r#"FROM "sales" AS "_origin" WHERE FALSE"#),
+        "total aggregates are computed over no rows"
+    );
+}
+
+#[test]
+fn fills_gaps_in_ordered_time_series() {
+    let request = |table_relationship: bool| -> query_request::QueryRequest {
+        let query = serde_json::json!({
+            "fields": {
+                "hour": { "type": "column", "column": "hour", "column_type": "DateTime" },
+                "requests": { "type": "column", "column": "requests", "column_type": "UInt64" }
+            },
+            "order_by": {
+                "relations": {},
+                "elements": [{
+                    "target_path": [],
+                    "target": { "type": "column", "column": "hour" },
+                    "order_direction": "asc",
+                    "fill": {
+                        "from": "2024-01-01 00:00:00",
+                        "to": "2024-01-02 00:00:00",
+                        "value_type": "DateTime",
+                        "step": 1,
+                        "unit": "hour"
+                    }
+                }]
+            }
+        });
+        let (table, query) = if table_relationship {
+            (
+                "sites",
+                serde_json::json!({
+                    "fields": {
+                        "traffic": { "type": "relationship", "relationship": "traffic", "query": query }
+                    }
+                }),
+            )
+        } else {
+            ("hourly_traffic", query)
+        };
+        serde_json::from_value(serde_json::json!({
+            "table": [table],
+            "table_relationships": [{
+                "source_table": ["sites"],
+                "relationships": {
+                    "traffic": {
+                        "target_table": ["hourly_traffic"],
+                        "relationship_type": "array",
+                        "column_mapping": { "id": "site_id" }
+                    }
+                }
+            }],
+            "query": query
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request(false), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(
+            r#"ORDER BY "_projection.hour" ASC NULLS LAST WITH FILL FROM toDateTime('2024-01-01 00:00:00') TO toDateTime('2024-01-02 00:00:00') STEP toIntervalHour(1)"#
+        ),
+        "adds a row for every hour missing from the range, got {statement}"
+    );
+
+    let ((statement, _parameters), _warnings) =
+        QueryBuilder::build_parameterized_sql_statement_with_context(
+            &request(false),
+            &test_config(),
+            QueryContext::default(),
+        )
+        .expect("can build statement");
+    assert!(
+        statement.to_string().contains(
+            r#"WITH FILL FROM toDateTime({__placeholder__0:String}) TO toDateTime({__placeholder__1:String})"#
+        ),
+        "converts bound bounds to the type of the filled column"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request(true), &test_config(), false),
+            Err(QueryBuilderError::InvalidFill(_))
+        ),
+        "filled rows would not belong to any parent row"
+    );
+}
+
+#[test]
+fn fills_gaps_between_grouped_hours() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["page_views"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "hour": { "type": "column", "column": "hour", "column_type": "DateTime" },
+                "views": { "type": "aggregate", "aggregate": { "type": "star_count" } }
+            },
+            "group_by": ["hour"],
+            "order_by": {
+                "relations": {},
+                "elements": [{
+                    "target_path": [],
+                    "target": { "type": "column", "column": "hour" },
+                    "order_direction": "asc",
+                    "fill": {
+                        "from": "2024-01-01 00:00:00",
+                        "to": "2024-01-01 06:00:00",
+                        "value_type": "DateTime",
+                        "step": 1,
+                        "unit": "hour"
+                    }
+                }]
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#"GROUP BY "_origin"."hour" ORDER BY "_projection.hour" ASC NULLS LAST WITH FILL FROM toDateTime('2024-01-01 00:00:00') TO toDateTime('2024-01-01 06:00:00') STEP toIntervalHour(1)"#),
+        "adds a row for every hour without views, got {statement}"
+    );
+}
+
+#[test]
+fn binds_in_lists_as_one_array_parameter() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "binary_arr_op",
+                "operator": "in",
+                "column": { "name": "country", "column_type": "String" },
+                "values": ["nz", "au", "fj"],
+                "value_type": "String"
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let (statement, parameters) =
+        QueryBuilder::build_parameterized_sql_statement(&request, &test_config(), None)
+            .expect("can build statement");
+    assert!(
+        statement
+            .to_string()
+            .contains(r#"WHERE "_origin"."country" IN (__placeholder__0)"#),
+        "binds the whole list as one parameter"
+    );
+    assert_eq!(parameters.len(), 1);
+    assert_eq!(
+        parameters["__placeholder__0"].value(),
+        serde_json::json!(["nz", "au", "fj"])
+    );
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"WHERE "_origin"."country" IN ('nz', 'au', 'fj')"#),
+        "inlines the values as a parenthesized list"
+    );
+}
+
+#[test]
+fn inlines_json_and_array_values_by_type() {
+    let request = |column_type: &str, value: serde_json::Value| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["events"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "where": {
+                    "type": "binary_op",
+                    "operator": "equal",
+                    "column": { "name": "payload", "column_type": column_type },
+                    "value": { "type": "scalar", "value": value, "value_type": column_type }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(
+        &request(
+            "JSON",
+            serde_json::json!({ "tags": ["a", "b's"], "count": 2 }),
+        ),
+        &test_config(),
+        false,
+    )
+    .expect("can build statement")
+    .to_string();
+    assert!(
+        statement.contains(r#"WHERE "_origin"."payload" = '{"count":2,"tags":["a","b\'s"]}'"#),
+        "compares json columns against the serialized json, got {statement}"
+    );
+
+    let statement = QueryBuilder::build_sql_statement(
+        &request("Unknown", serde_json::json!([1, 2, 3])),
+        &test_config(),
+        false,
+    )
+    .expect("can build statement")
+    .to_string();
+    assert!(
+        statement.contains(r#"WHERE "_origin"."payload" = array(1, 2, 3)"#),
+        "compares array columns against an array literal, got {statement}"
+    );
+}
+
+#[test]
+fn truncates_long_generated_identifiers() {
+    let alias = format!("select_{}", "x".repeat(300));
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                alias.clone(): { "type": "column", "column": "id", "column_type": "UInt32" },
+                "order": { "type": "column", "column": "order", "column_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let build = || {
+        QueryBuilder::build_sql_statement(&request, &test_config(), false)
+            .expect("can build statement")
+            .to_string()
+    };
+    let statement = build();
+    let projection_alias = statement
+        .split('"')
+        .find(|identifier| identifier.starts_with("_projection.select_"))
+        .expect("projects the field");
+
+    assert_eq!(
+        projection_alias.len(),
+        255,
+        "truncated to the maximum length"
+    );
+    assert!(
+        projection_alias.contains('~'),
+        "suffixed with a hash of the full identifier"
+    );
+    assert_eq!(
+        statement.matches(projection_alias).count(),
+        2,
+        "the same identifier is used where the field is projected and where it is read"
+    );
+    assert_eq!(build(), statement, "identifiers are stable");
+    assert!(
+        statement.contains(r#""_origin"."order" AS "_projection.order""#),
+        "reserved words are quoted"
+    );
+    assert_eq!(
+        Ident::quoted(r#"say "hi" \o/"#).to_string(),
+        r#""say \"hi\" \\o/""#,
+        "quotes within identifiers are escaped"
+    );
+}
+
+#[test]
+fn projects_related_ids_as_an_array() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["customers"],
+        "table_relationships": [{
+            "source_table": ["customers"],
+            "relationships": {
+                "orders": {
+                    "target_table": ["orders"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "customer_id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "order_ids": {
+                    "type": "related_column",
+                    "relationship": "orders",
+                    "column": "id",
+                    "column_type": "UInt32"
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(
+            r#"LEFT JOIN (SELECT "_origin"."customer_id" AS "_selection.customer_id", groupArray("_origin"."id") AS "query" FROM "orders" AS "_origin" GROUP BY "_origin"."customer_id") AS "_rel.order_ids" ON "_origin"."id" = "_rel.order_ids"."_selection.customer_id""#
+        ),
+        "groups the related ids without building related rows, got {statement}"
+    );
+    assert!(
+        statement.contains(r#""_rel.order_ids"."query" AS "_projection.order_ids""#),
+        "projects the array of related ids"
+    );
+    assert!(
+        statement.contains(r#"Array(Tuple("order_ids" Array(Nullable(UInt32))))"#),
+        "returns the ids as an array"
+    );
+}
+
+#[test]
+fn masks_related_columns() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["teams"],
+        "table_relationships": [{
+            "source_table": ["teams"],
+            "relationships": {
+                "members": {
+                    "target_table": ["users"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "team_id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "member_emails": {
+                    "type": "related_column",
+                    "relationship": "members",
+                    "column": "email",
+                    "column_type": "String"
+                }
+            }
+        }
+    }))
+    .expect("valid query request");
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": "",
+        "tables": [{
+            "name": "users",
+            "columns": [{ "name": "email", "mask": "hash" }]
+        }]
+    }))
+    .expect("valid config");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"groupArray(hex(SHA256("_origin"."email"))) AS "query""#),
+        "groups the masked values of the related column, got {statement}"
+    );
+}
+
+#[test]
+fn filters_by_inclusive_ranges() {
+    let request = |type_name: &str| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["orders"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "where": {
+                    "type": type_name,
+                    "column": { "name": "total", "column_type": "Float64" },
+                    "low": { "type": "scalar", "value": 10, "value_type": "Float64" },
+                    "high": { "type": "scalar", "value": 20, "value_type": "Float64" }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request("between"), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"WHERE "_origin"."total" BETWEEN 10 AND 20"#),
+        "compares the column to both bounds"
+    );
+
+    let (statement, parameters) = QueryBuilder::build_parameterized_sql_statement(
+        &request("not_between"),
+        &test_config(),
+        None,
+    )
+    .expect("can build statement");
+    assert!(
+        statement.to_string().contains(
+            r#"WHERE "_origin"."total" NOT BETWEEN __placeholder__0 AND __placeholder__1"#
+        ),
+        "binds both bounds"
+    );
+    assert_eq!(parameters.len(), 2);
+}
+
+#[test]
+fn counts_distinct_combinations_of_columns() {
+    let request = |distinct: bool| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["events"],
+            "table_relationships": [],
+            "query": {
+                "aggregates": {
+                    "sessions": {
+                        "type": "column_count",
+                        "column": "user_id",
+                        "additional_columns": ["session_id"],
+                        "distinct": distinct
+                    }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request(true), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(
+            r#"COUNT(DISTINCT "_row"."_projection.sessions", "_row"."_projection.sessions.1")"#
+        ),
+        "counts distinct pairs"
+    );
+    assert!(
+        statement.contains(r#""_origin"."session_id" AS "_projection.sessions.1""#),
+        "selects the additional columns from the table"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(&request(false), &test_config(), false),
+            Err(QueryBuilderError::InvalidAggregate(_))
+        ),
+        "rejects counts of more than one column that are not distinct"
+    );
+}
+
+#[test]
+fn approximates_distinct_counts_on_request() {
+    let request = |exact: bool| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["events"],
+            "table_relationships": [],
+            "query": {
+                "aggregates": {
+                    "users": {
+                        "type": "column_count",
+                        "column": "user_id",
+                        "distinct": true,
+                        "exact": exact
+                    }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+    let config = Config {
+        approximate_distinct_count_threshold: Some(1_000_000),
+        ..test_config()
+    };
+    let context = || QueryContext {
+        table_row_estimates: HashMap::from([("events".to_owned(), 50_000_000)]),
+        ..Default::default()
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request(false), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"uniq("_row"."_projection.users")"#),
+        "approximates without a threshold"
+    );
+    assert!(
+        statement.contains(r#"AS Tuple("users" UInt32)"#),
+        "casts approximate counts like exact counts"
+    );
+
+    let (statement, warnings) =
+        QueryBuilder::build_sql_statement_with_context(&request(true), &config, false, context())
+            .expect("can build statement");
+    assert!(
+        statement
+            .to_string()
+            .contains(r#"COUNT(DISTINCT "_row"."_projection.users")"#),
+        "counts exactly over the threshold"
+    );
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn aggregates_column_values_into_arrays() {
+    let request = |limit: Option<u64>| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["orders"],
+            "table_relationships": [],
+            "query": {
+                "aggregates": {
+                    "totals": {
+                        "type": "column_array",
+                        "column": "total",
+                        "column_type": "Decimal",
+                        "precision": 10,
+                        "scale": 2,
+                        "limit": limit
+                    }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(&request(None), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"groupArray("_row"."_projection.totals")"#),
+        "collects every value"
+    );
+    assert!(
+        statement.contains(r#"Tuple("totals" Array(Nullable(Decimal(10, 2))))"#),
+        "casts to an array of the column type"
+    );
+
+    let statement = QueryBuilder::build_sql_statement(&request(Some(5)), &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"groupArray(5)("_row"."_projection.totals")"#),
+        "collects up to the limit"
+    );
+
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "aggregates": {
+                "emails": { "type": "column_array", "column": "email", "column_type": "String" }
+            }
+        }
+    }))
+    .expect("valid query request");
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": "",
+        "tables": [{
+            "name": "users",
+            "columns": [{ "name": "email", "mask": "partial" }]
+        }]
+    }))
+    .expect("valid config");
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(r#"concat(left("_origin"."email", 2), '***') AS "_projection.emails""#),
+        "collects the masked values of masked columns, got {statement}"
+    );
+}
+
+#[test]
+fn caps_unlimited_queries_at_the_default_row_limit() {
+    let request = |limit: Option<u64>| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["events"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "limit": limit
+            }
+        }))
+        .expect("valid query request")
+    };
+    let config = Config {
+        default_row_limit: Some(1000),
+        ..test_config()
+    };
+
+    let (statement, warnings) = QueryBuilder::build_sql_statement_with_context(
+        &request(None),
+        &config,
+        false,
+        QueryContext::default(),
+    )
+    .expect("can build statement");
+    assert!(
+        statement.to_string().contains("LIMIT 1000"),
+        "caps rows at the default limit"
+    );
+    assert_eq!(
+        warnings,
+        vec![QueryWarning::DefaultRowLimit(
+            vec!["events".to_owned()],
+            1000
+        )],
+        "reports the default limit"
+    );
+
+    let (statement, warnings) = QueryBuilder::build_sql_statement_with_context(
+        &request(Some(5000)),
+        &config,
+        false,
+        QueryContext::default(),
+    )
+    .expect("can build statement");
+    assert!(
+        statement.to_string().contains("LIMIT 5000"),
+        "uses the limit of the request"
+    );
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn reads_flagged_tables_with_final() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [{
+            "source_table": ["users"],
+            "relationships": {
+                "sessions": {
+                    "target_table": ["sessions"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "user_id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "name": { "type": "column", "column": "name", "column_type": "String" },
+                "sessions": {
+                    "type": "relationship",
+                    "relationship": "sessions",
+                    "query": {
+                        "fields": {
+                            "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                        }
+                    }
+                }
+            },
+            "aggregates": { "count": { "type": "star_count" } }
+        }
+    }))
+    .expect("valid query request");
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": "",
+        "tables": [{ "name": "users", "read_final": true }]
+    }))
+    .expect("valid config");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &config, false)
+        .expect("can build statement")
+        .to_string();
+
+    assert_eq!(
+        statement
+            .matches(r#"FROM "users" AS "_origin" FINAL"#)
+            .count(),
+        2,
+        "rows and aggregates both read the flagged table with final"
+    );
+    assert!(
+        statement.contains(r#"FROM "sessions" AS "_origin""#)
+            && !statement.contains(r#""sessions" AS "_origin" FINAL"#),
+        "other tables are read as is"
+    );
+}
+
+#[test]
+fn filters_rows_with_prewhere() {
+    let request = |prewhere: serde_json::Value| -> query_request::QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["events"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "payload": { "type": "column", "column": "payload", "column_type": "String" }
+                },
+                "prewhere": prewhere,
+                "where": {
+                    "type": "binary_op",
+                    "operator": "greater_than",
+                    "column": { "name": "duration", "column_type": "UInt32" },
+                    "value": { "type": "scalar", "value": 100, "value_type": "UInt32" }
+                }
+            }
+        }))
+        .expect("valid query request")
+    };
+
+    let statement = QueryBuilder::build_sql_statement(
+        &request(serde_json::json!({
+            "type": "binary_op",
+            "operator": "equal",
+            "column": { "name": "tenant_id", "column_type": "UInt32" },
+            "value": { "type": "scalar", "value": 7, "value_type": "UInt32" }
+        })),
+        &test_config(),
+        false,
+    )
+    .expect("can build statement")
+    .to_string();
+    assert!(
+        statement.contains(
+            r#"FROM "events" AS "_origin" PREWHERE "_origin"."tenant_id" = 7 WHERE "_origin"."duration" > 100"#
+        ),
+        "filters with prewhere before where"
+    );
+
+    assert!(
+        matches!(
+            QueryBuilder::build_sql_statement(
+                &request(serde_json::json!({
+                    "type": "exists",
+                    "in_table": { "type": "unrelated", "table": ["tenants"] },
+                    "where": { "type": "and", "expressions": [] }
+                })),
+                &test_config(),
+                false
+            ),
+            Err(QueryBuilderError::InvalidPrewhere(_))
+        ),
+        "rejects prewhere over other tables"
+    );
+}
+
+#[test]
+fn defines_repeated_subqueries_once() {
+    let articles = serde_json::json!({
+        "type": "relationship",
+        "relationship": "articles",
+        "query": {
+            "fields": {
+                "title": { "type": "column", "column": "title", "column_type": "String" }
+            }
+        }
+    });
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["authors"],
+        "table_relationships": [{
+            "source_table": ["authors"],
+            "relationships": {
+                "articles": {
+                    "target_table": ["articles"],
+                    "relationship_type": "array",
+                    "column_mapping": { "id": "author_id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "articles": articles,
+                "posts": articles
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.starts_with(r#"WITH "_cte_0" AS (SELECT "#),
+        "defines the subquery in a with clause, got {statement}"
+    );
+    assert_eq!(
+        statement.matches(r#"FROM "articles" AS "_origin""#).count(),
+        1,
+        "the subquery is emitted once"
+    );
+    assert!(
+        statement.contains(r#"LEFT JOIN "_cte_0" AS "_rel.articles""#)
+            && statement.contains(r#"LEFT JOIN "_cte_0" AS "_rel.posts""#),
+        "both relationships join the named subquery, got {statement}"
+    );
+}
+
+#[test]
+fn places_nulls_as_requested() {
+    let cases = [
+        ("asc", None, "ASC NULLS LAST"),
+        ("desc", None, "DESC NULLS FIRST"),
+        ("asc", Some("first"), "ASC NULLS FIRST"),
+        ("desc", Some("first"), "DESC NULLS FIRST"),
+        ("asc", Some("last"), "ASC NULLS LAST"),
+        ("desc", Some("last"), "DESC NULLS LAST"),
+        ("asc", Some("default"), "ASC"),
+        ("desc", Some("default"), "DESC"),
+    ];
+
+    for (direction, nulls, expected) in cases {
+        let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "order_by": {
+                    "relations": {},
+                    "elements": [
+                        {
+                            "target_path": [],
+                            "target": { "type": "column", "column": "name" },
+                            "order_direction": direction,
+                            "nulls": nulls
+                        },
+                        {
+                            "target_path": [],
+                            "target": { "type": "column", "column": "id" },
+                            "order_direction": "asc"
+                        }
+                    ]
+                }
+            }
+        }))
+        .expect("valid query request");
+
+        let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+            .expect("can build statement")
+            .to_string();
+        assert!(
+            statement.contains(&format!(
+                r#"ORDER BY "_origin"."name" {expected}, "_origin"."id" ASC NULLS LAST"#
+            )),
+            "{direction} with nulls {nulls:?} orders by {expected}, got {statement}"
+        );
+    }
+}
+
+#[test]
+fn orders_by_functions_of_columns() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["articles"],
+        "table_relationships": [{
+            "source_table": ["articles"],
+            "relationships": {
+                "author": {
+                    "target_table": ["authors"],
+                    "relationship_type": "object",
+                    "column_mapping": { "author_id": "id" }
+                }
+            }
+        }],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "order_by": {
+                "relations": {
+                    "author": { "subrelations": {} }
+                },
+                "elements": [
+                    {
+                        "target_path": [],
+                        "target": { "type": "expression", "column": "description", "function": "length" },
+                        "order_direction": "asc"
+                    },
+                    {
+                        "target_path": ["author"],
+                        "target": { "type": "expression", "column": "name", "function": "lower" },
+                        "order_direction": "asc"
+                    }
+                ]
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        statement.contains(
+            r#"ORDER BY length("_origin"."description") ASC NULLS LAST, lower("_ord.author"."_col.name") ASC NULLS LAST"#
+        ),
+        "orders by the function of each column, got {statement}"
+    );
+    assert!(
+        statement.contains(r#""name" AS "_col.name""#),
+        "projects the related column for the function to apply to"
+    );
+
+    let request = serde_json::json!({
+        "table": ["articles"],
+        "table_relationships": [],
+        "query": {
+            "order_by": {
+                "relations": {},
+                "elements": [{
+                    "target_path": [],
+                    "target": { "type": "expression", "column": "name", "function": "sleep" },
+                    "order_direction": "asc"
+                }]
+            }
+        }
+    });
+    assert!(
+        serde_json::from_value::<query_request::QueryRequest>(request).is_err(),
+        "rejects functions that are not allowed"
+    );
+}
+
+#[test]
+fn compares_uint8_booleans_as_integers() {
+    let request: query_request::QueryRequest = serde_json::from_value(serde_json::json!({
+        "table": ["users"],
+        "table_relationships": [],
+        "query": {
+            "fields": {
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "where": {
+                "type": "and",
+                "expressions": [
+                    {
+                        "type": "binary_op",
+                        "operator": "equal",
+                        "column": { "name": "is_active", "column_type": "UInt8" },
+                        "value": { "type": "scalar", "value": true, "value_type": "Bool" }
+                    },
+                    {
+                        "type": "binary_op",
+                        "operator": "equal",
+                        "column": { "name": "is_admin", "column_type": "UInt8" },
+                        "value": { "type": "scalar", "value": false, "value_type": "Bool" }
+                    },
+                    {
+                        "type": "binary_op",
+                        "operator": "equal",
+                        "column": { "name": "verified", "column_type": "Bool" },
+                        "value": { "type": "scalar", "value": true, "value_type": "Bool" }
+                    }
+                ]
+            }
+        }
+    }))
+    .expect("valid query request");
+
+    let statement = QueryBuilder::build_sql_statement(&request, &test_config(), false)
+        .expect("can build statement")
+        .to_string();
+
+    assert!(
+        statement.contains(r#""_origin"."is_active" = 1"#)
+            && statement.contains(r#""_origin"."is_admin" = 0"#),
+        "booleans compare to uint8 columns as 1 and 0, got {statement}"
+    );
+    assert!(
+        statement.contains(r#""_origin"."verified" = TRUE"#),
+        "booleans compare to bool columns as booleans"
+    );
+}
@@ -14,20 +14,36 @@ pub fn apply_aliases_to_query_request(
     mut request: QueryRequest,
     config: &Config,
 ) -> Result<QueryRequest, QueryBuilderError> {
-    let (foreach, query, request_table, table_relationships) = match request {
+    let (foreach, foreach_collections, query, request_table, table_relationships) = match request {
         QueryRequest::Table {
             ref mut foreach,
+            ref mut foreach_collections,
+            foreach_total_aggregates: _,
             ref mut query,
             ref mut table,
             ref mut table_relationships,
-        } => (foreach, query, table, table_relationships),
+        } => (
+            foreach,
+            foreach_collections,
+            query,
+            table,
+            table_relationships,
+        ),
         QueryRequest::Target {
             ref mut foreach,
+            ref mut foreach_collections,
+            foreach_total_aggregates: _,
             ref mut query,
             ref mut target,
             ref mut table_relationships,
         } => match target {
-            Target::Table { ref mut name } => (foreach, query, name, table_relationships),
+            Target::Table { ref mut name } => (
+                foreach,
+                foreach_collections,
+                query,
+                name,
+                table_relationships,
+            ),
             Target::Interpolated { .. } => {
                 return Err(QueryBuilderError::Internal(
                     "Interpolated targets not supported".to_string(),
@@ -50,11 +66,13 @@ pub fn apply_aliases_to_query_request(
             let (target_table, column_mapping) = match relationship {
                 Relationship::Table {
                     ref mut column_mapping,
+                    match_any_column: _,
                     relationship_type: _,
                     ref mut target_table,
                 } => (target_table, column_mapping),
                 Relationship::Target {
                     ref mut column_mapping,
+                    match_any_column: _,
                     relationship_type: _,
                     ref mut target,
                 } => match target {
@@ -82,7 +100,11 @@ pub fn apply_aliases_to_query_request(
         }
     }
 
-    if let Some(foreach) = foreach.as_mut() {
+    let foreach_collections = foreach_collections
+        .iter_mut()
+        .flat_map(|collections| collections.values_mut());
+
+    for foreach in foreach.iter_mut().chain(foreach_collections) {
         for row in foreach.iter_mut() {
             for (column, value) in row.drain(..).collect::<Vec<_>>() {
                 row.insert(aliased_column_name(request_table, &column, config)?, value);
@@ -103,15 +125,7 @@ fn apply_aliases_to_query(
 ) -> Result<(), QueryBuilderError> {
     if let Some(aggregates) = query.aggregates.as_mut() {
         for aggregate in aggregates.values_mut() {
-            match aggregate {
-                Aggregate::ColumnCount { column, .. } => {
-                    *column = aliased_column_name(table, column, config)?;
-                }
-                Aggregate::SingleColumn { column, .. } => {
-                    *column = aliased_column_name(table, column, config)?;
-                }
-                Aggregate::StarCount => {}
-            }
+            apply_aliases_to_aggregate(table, aggregate, table_relationships, config)?;
         }
     }
 
@@ -121,10 +135,37 @@ fn apply_aliases_to_query(
                 Field::Column { column, .. } => {
                     *column = aliased_column_name(table, column, config)?;
                 }
+                Field::Computed { function } => {
+                    for column in function.columns_mut() {
+                        *column = aliased_column_name(table, column, config)?;
+                    }
+                }
+                Field::Aggregate { aggregate } => {
+                    apply_aliases_to_aggregate(table, aggregate, table_relationships, config)?;
+                }
+                Field::RelatedColumn {
+                    relationship,
+                    column,
+                    ..
+                } => {
+                    let table =
+                        &relationship_target_table(table, relationship, table_relationships)?;
+                    *column = aliased_column_name(table, column, config)?;
+                }
                 Field::Relationship {
+                    include_when,
                     query,
                     relationship,
                 } => {
+                    // the include predicate applies to the parent row
+                    if let Some(expression) = include_when.as_mut() {
+                        apply_aliases_to_expression(
+                            table,
+                            expression,
+                            table_relationships,
+                            config,
+                        )?;
+                    }
                     let table =
                         &relationship_target_table(table, relationship, table_relationships)?;
                     apply_aliases_to_query(table, query, table_relationships, config)?;
@@ -137,6 +178,10 @@ fn apply_aliases_to_query(
         apply_aliases_to_expression(table, expression, table_relationships, config)?;
     }
 
+    if let Some(expression) = query.prewhere.as_mut() {
+        apply_aliases_to_expression(table, expression, table_relationships, config)?;
+    }
+
     if let Some(order_by) = query.order_by.as_mut() {
         for element in order_by.elements.iter_mut() {
             let table = element
@@ -150,7 +195,7 @@ fn apply_aliases_to_query(
                 OrderByTarget::SingleColumnAggregate { column, .. } => {
                     *column = aliased_column_name(table, column, config)?;
                 }
-                OrderByTarget::Column { column } => {
+                OrderByTarget::Column { column } | OrderByTarget::Expression { column, .. } => {
                     *column = aliased_column_name(table, column, config)?;
                 }
             }
@@ -167,6 +212,34 @@ fn apply_aliases_to_query(
     Ok(())
 }
 
+fn apply_aliases_to_aggregate(
+    table: &TableName,
+    aggregate: &mut Aggregate,
+    table_relationships: &[TableRelationships],
+    config: &Config,
+) -> Result<(), QueryBuilderError> {
+    match aggregate {
+        Aggregate::ColumnCount {
+            column,
+            additional_columns,
+            ..
+        } => {
+            *column = aliased_column_name(table, column, config)?;
+            for column in additional_columns.iter_mut().flatten() {
+                *column = aliased_column_name(table, column, config)?;
+            }
+        }
+        Aggregate::SingleColumn { column, .. } | Aggregate::ColumnArray { column, .. } => {
+            *column = aliased_column_name(table, column, config)?;
+        }
+        Aggregate::StarCount => {}
+        Aggregate::ConditionalCount { selection } => {
+            apply_aliases_to_expression(table, selection, table_relationships, config)?;
+        }
+    }
+    Ok(())
+}
+
 fn apply_aliases_to_order_by_relations(
     table: &TableName,
     relations: &mut IndexMap<String, OrderByRelation>,
@@ -219,13 +292,23 @@ fn apply_aliases_to_expression(
             // todo: consider column path. note we don't support this anyways so, perhaps don't bother?
             column.name = aliased_column_name(table, &column.name, config)?;
             match value {
-                ComparisonValue::ScalarValueComparison { .. } => {}
+                ComparisonValue::ScalarValueComparison { .. }
+                | ComparisonValue::ServerTimeAnchor { .. } => {}
                 ComparisonValue::AnotherColumnComparison { column } => {
                     // todo: consider column path. note we don't support this anyways so, perhaps don't bother?
                     column.name = aliased_column_name(table, &column.name, config)?;
                 }
             }
         }
+        Expression::Between { column, low, high }
+        | Expression::NotBetween { column, low, high } => {
+            column.name = aliased_column_name(table, &column.name, config)?;
+            for value in [low, high] {
+                if let ComparisonValue::AnotherColumnComparison { column } = value {
+                    column.name = aliased_column_name(table, &column.name, config)?;
+                }
+            }
+        }
         Expression::BinaryArrayComparisonOperator {
             column,
             operator: _,
@@ -248,6 +331,8 @@ fn apply_aliases_to_expression(
 
             apply_aliases_to_expression(table, selection, table_relationships, config)?;
         }
+        // raw sql refers to physical column names
+        Expression::RawSql { .. } => {}
     }
 
     Ok(())
@@ -276,11 +361,13 @@ fn relationship_target_table<'a>(
     let table_name = match relationship {
         Relationship::Table {
             column_mapping: _,
+            match_any_column: _,
             relationship_type: _,
             target_table,
         } => target_table,
         Relationship::Target {
             column_mapping: _,
+            match_any_column: _,
             relationship_type: _,
             target,
         } => match target {
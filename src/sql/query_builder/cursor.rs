@@ -0,0 +1,625 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::server::{
+    api::{
+        query_request::{
+            BinaryComparisonOperator, ComparisonColumn, ComparisonValue, Expression, Field,
+            OrderByTarget, OrderDirection, Query, QueryRequest, ScalarType,
+            UnaryComparisonOperator,
+        },
+        query_response::{QueryResponse, RowFieldValue},
+    },
+    Config,
+};
+
+use super::{nulls_first, QueryBuilderError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The keyset position of the last row of a page, as encoded in a cursor
+#[derive(Debug, Serialize, Deserialize)]
+struct CursorPosition {
+    /// the table or target of the query the cursor was issued for
+    table: serde_json::Value,
+    /// a digest of the selection of the query the cursor was issued for, so it cannot continue a query of other rows
+    selection: String,
+    columns: Vec<String>,
+    values: Vec<serde_json::Value>,
+}
+
+/// the table and selection digest of the root query, as held by a cursor
+fn cursor_scope(request: &QueryRequest) -> Result<(serde_json::Value, String), QueryBuilderError> {
+    let (table, query) = match request {
+        QueryRequest::Table { table, query, .. } => (serde_json::to_value(table), query),
+        QueryRequest::Target { target, query, .. } => (serde_json::to_value(target), query),
+    };
+    let table = table.map_err(|err| QueryBuilderError::Internal(err.to_string()))?;
+    let selection = serde_json::to_vec(&query.selection)
+        .map_err(|err| QueryBuilderError::Internal(err.to_string()))?;
+    Ok((table, format!("{:x}", Sha256::digest(selection))))
+}
+
+struct KeysetColumn<'a> {
+    alias: &'a String,
+    column: &'a String,
+    column_type: &'a ScalarType,
+    direction: &'a OrderDirection,
+    nulls_first: bool,
+}
+
+/// Filter the root query to rows after the position encoded in its cursor, or given by its after values, if any.
+/// Must be applied after aliases, as cursors hold physical column names
+pub fn apply_cursor_to_query_request(
+    mut request: QueryRequest,
+    config: &Config,
+) -> Result<QueryRequest, QueryBuilderError> {
+    // read before the position is added to the selection
+    let scope = match &request {
+        QueryRequest::Table { query, .. } | QueryRequest::Target { query, .. }
+            if query.cursor.is_some() =>
+        {
+            Some(cursor_scope(&request)?)
+        }
+        _ => None,
+    };
+
+    let query = match request {
+        QueryRequest::Table { ref mut query, .. } | QueryRequest::Target { ref mut query, .. } => {
+            query
+        }
+    };
+
+    if !query.paginate.unwrap_or(false) && query.cursor.is_none() && query.after.is_none() {
+        return Ok(request);
+    }
+
+    let secret = if query.paginate.unwrap_or(false) || query.cursor.is_some() {
+        Some(cursor_secret(config)?)
+    } else {
+        None
+    };
+    let keyset = keyset_columns(query)?;
+
+    let values = match (&query.cursor, &query.after, secret) {
+        (Some(_), Some(_), _) => {
+            return Err(QueryBuilderError::InvalidCursor(
+                "queries may continue from a cursor or after values, but not both".to_owned(),
+            ))
+        }
+        (Some(token), None, Some(secret)) => {
+            let position = verify_cursor(token, secret)?;
+            if scope.is_some_and(|(table, selection)| {
+                position.table != table || position.selection != selection
+            }) {
+                return Err(QueryBuilderError::InvalidCursor(
+                    "cursor was issued for a query of another table or selection".to_owned(),
+                ));
+            }
+            let columns = keyset.iter().map(|key| key.column).collect::<Vec<_>>();
+            if position.columns.iter().collect::<Vec<_>>() != columns
+                || position.values.len() != keyset.len()
+            {
+                return Err(QueryBuilderError::InvalidCursor(
+                    "cursor does not match the query order".to_owned(),
+                ));
+            }
+            position.values
+        }
+        // after values are not signed, as they only filter the query like any other comparison
+        (None, Some(values), _) => {
+            if values.len() != keyset.len() {
+                return Err(QueryBuilderError::InvalidCursor(format!(
+                    "expected {} after values, one for each order column, got {}",
+                    keyset.len(),
+                    values.len()
+                )));
+            }
+            values.to_owned()
+        }
+        _ => return Ok(request),
+    };
+
+    // the position already skips the rows of earlier pages, an offset would skip more
+    if query.offset.is_some() {
+        return Err(QueryBuilderError::InvalidCursor(
+            "queries continuing from a cursor or after values cannot have an offset".to_owned(),
+        ));
+    }
+
+    let column = |key: &KeysetColumn| ComparisonColumn {
+        column_type: key.column_type.to_owned(),
+        name: key.column.to_owned(),
+        path: None,
+        timezone: None,
+    };
+    let comparison = |key: &KeysetColumn, operator, value: &serde_json::Value| {
+        Expression::BinaryComparisonOperator {
+            column: column(key),
+            operator,
+            value: ComparisonValue::ScalarValueComparison {
+                value: value.to_owned(),
+                value_type: key.column_type.to_owned(),
+            },
+        }
+    };
+    let null_check = |key: &KeysetColumn, operator| Expression::UnaryComparisonOperator {
+        column: column(key),
+        operator,
+    };
+    // comparisons with null are never true, so null positions are matched with null checks
+    let equal = |key: &KeysetColumn, value: &serde_json::Value| {
+        if value.is_null() {
+            null_check(key, UnaryComparisonOperator::IsNull)
+        } else {
+            comparison(key, BinaryComparisonOperator::Equal, value)
+        }
+    };
+    let past = |key: &KeysetColumn, value: &serde_json::Value| {
+        let past_operator = match key.direction {
+            OrderDirection::Asc => BinaryComparisonOperator::GreaterThan,
+            OrderDirection::Desc => BinaryComparisonOperator::LessThan,
+        };
+        match (value.is_null(), key.nulls_first) {
+            (true, true) => Some(null_check(key, UnaryComparisonOperator::IsNotNull)),
+            // no value follows nulls ordered last
+            (true, false) => None,
+            (false, true) => Some(comparison(key, past_operator, value)),
+            (false, false) => Some(Expression::Or {
+                expressions: vec![
+                    comparison(key, past_operator, value),
+                    null_check(key, UnaryComparisonOperator::IsNull),
+                ],
+            }),
+        }
+    };
+
+    // rows after the position: equal on all preceding order columns, and past the position on the next one
+    let after_position = Expression::Or {
+        expressions: keyset
+            .iter()
+            .zip(&values)
+            .enumerate()
+            .filter_map(|(index, (key, value))| {
+                Some(Expression::And {
+                    expressions: keyset
+                        .iter()
+                        .zip(&values)
+                        .take(index)
+                        .map(|(key, value)| equal(key, value))
+                        .chain(std::iter::once(past(key, value)?))
+                        .collect(),
+                })
+            })
+            .collect(),
+    };
+
+    query.selection = Some(Expression::And {
+        expressions: query
+            .selection
+            .take()
+            .into_iter()
+            .chain(std::iter::once(after_position))
+            .collect(),
+    });
+
+    Ok(request)
+}
+
+/// A signed cursor for the position after the last row of the response,
+/// if the root query paginates and the page is full, so more rows may follow.
+/// Takes the request as received, before its own cursor or after values were applied to its selection
+pub fn next_cursor(
+    request: &QueryRequest,
+    response: &QueryResponse,
+    config: &Config,
+) -> Result<Option<String>, QueryBuilderError> {
+    let query = match request {
+        QueryRequest::Table { query, .. } | QueryRequest::Target { query, .. } => query,
+    };
+
+    if !query.paginate.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let page_size = query
+        .limit
+        .as_ref()
+        .and_then(|limit| limit.as_u64())
+        .unwrap_or_default();
+    let last_row = match response.rows() {
+        Some(rows) if rows.len() as u64 >= page_size => rows.last(),
+        _ => None,
+    };
+    let last_row = match last_row {
+        Some(last_row) => last_row,
+        None => return Ok(None),
+    };
+
+    let keyset = keyset_columns(query)?;
+    let (table, selection) = cursor_scope(request)?;
+    let position = CursorPosition {
+        table,
+        selection,
+        columns: keyset.iter().map(|key| key.column.to_owned()).collect(),
+        values: keyset
+            .iter()
+            .map(|key| match last_row.get(key.alias) {
+                Some(Some(RowFieldValue::ColumnFieldValue(value))) => value.to_owned(),
+                _ => serde_json::Value::Null,
+            })
+            .collect(),
+    };
+
+    Ok(Some(sign_cursor(&position, cursor_secret(config)?)?))
+}
+
+fn cursor_secret(config: &Config) -> Result<&str, QueryBuilderError> {
+    config
+        .cursor_secret
+        .as_deref()
+        .ok_or_else(|| QueryBuilderError::InvalidCursor("cursors are not enabled".to_owned()))
+}
+
+/// the order columns the keyset is made of. Each must be a root column that is also returned as a field,
+/// so its value can be read from the last row of a page
+fn keyset_columns(query: &Query) -> Result<Vec<KeysetColumn<'_>>, QueryBuilderError> {
+    if query.limit.is_none() {
+        return Err(QueryBuilderError::InvalidCursor(
+            "paginated queries require a limit".to_owned(),
+        ));
+    }
+
+    let elements = query
+        .order_by
+        .as_ref()
+        .map(|order_by| &order_by.elements)
+        .filter(|elements| !elements.is_empty())
+        .ok_or_else(|| {
+            QueryBuilderError::InvalidCursor("paginated queries require an order".to_owned())
+        })?;
+
+    elements
+        .iter()
+        .map(|element| {
+            let column = match &element.target {
+                OrderByTarget::Column { column } if element.target_path.is_empty() => column,
+                _ => {
+                    return Err(QueryBuilderError::InvalidCursor(
+                        "paginated queries may only be ordered by columns of the queried table"
+                            .to_owned(),
+                    ))
+                }
+            };
+            query
+                .fields
+                .iter()
+                .flatten()
+                .find_map(|(alias, field)| match field {
+                    Field::Column {
+                        column: field_column,
+                        column_type,
+                        ..
+                    } if field_column == column => Some(KeysetColumn {
+                        alias,
+                        column,
+                        column_type,
+                        direction: &element.order_direction,
+                        // clickhouse orders nulls last by default
+                        nulls_first: nulls_first(element).unwrap_or(false),
+                    }),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    QueryBuilderError::InvalidCursor(format!(
+                        "order column {} must also be returned as a field",
+                        column
+                    ))
+                })
+        })
+        .collect()
+}
+
+fn cursor_mac(payload: &str, secret: &str) -> Result<HmacSha256, QueryBuilderError> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .map_err(|err| QueryBuilderError::Internal(err.to_string()))?;
+    mac.update(payload.as_bytes());
+    Ok(mac)
+}
+
+fn sign_cursor(position: &CursorPosition, secret: &str) -> Result<String, QueryBuilderError> {
+    let payload =
+        serde_json::to_vec(position).map_err(|err| QueryBuilderError::Internal(err.to_string()))?;
+    let payload = URL_SAFE_NO_PAD.encode(payload);
+    let signature = cursor_mac(&payload, secret)?.finalize().into_bytes();
+    Ok(format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+fn verify_cursor(token: &str, secret: &str) -> Result<CursorPosition, QueryBuilderError> {
+    let invalid = || QueryBuilderError::InvalidCursor("cursor is malformed or tampered".to_owned());
+
+    let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+    cursor_mac(payload, secret)?
+        .verify_slice(&signature)
+        .map_err(|_| invalid())?;
+
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+    serde_json::from_slice(&payload).map_err(|_| invalid())
+}
+
+#[test]
+fn pages_through_rows_with_cursors() {
+    use super::QueryBuilder;
+
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": "",
+        "cursor_secret": "secret"
+    }))
+    .expect("valid config");
+    let request = |cursor: Option<&str>| -> QueryRequest {
+        serde_json::from_value(serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "order_by": {
+                    "relations": {},
+                    "elements": [{
+                        "order_direction": "asc",
+                        "target": { "type": "column", "column": "id" },
+                        "target_path": []
+                    }]
+                },
+                "limit": 2,
+                "paginate": true,
+                "cursor": cursor
+            }
+        }))
+        .expect("valid query request")
+    };
+    // stands in for the database, returning the requested page of ids
+    let page = |ids: &[u32]| -> QueryResponse {
+        serde_json::from_value(serde_json::json!({
+            "rows": ids.iter().map(|id| serde_json::json!({ "id": id })).collect::<Vec<_>>()
+        }))
+        .expect("valid response")
+    };
+
+    let first = apply_cursor_to_query_request(request(None), &config).expect("first page");
+    let first_sql = QueryBuilder::build_sql_statement(&first, &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(!first_sql.contains("WHERE"), "first page is not filtered");
+    let cursor = next_cursor(&request(None), &page(&[1, 2]), &config)
+        .expect("can sign cursor")
+        .expect("full page has a cursor");
+
+    let second =
+        apply_cursor_to_query_request(request(Some(&cursor)), &config).expect("second page");
+    let second_sql = QueryBuilder::build_sql_statement(&second, &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        second_sql.contains(r#"WHERE ("_origin"."id" > 2 OR "_origin"."id" IS NULL)"#),
+        "second page continues after the first"
+    );
+    let cursor = next_cursor(&request(Some(&cursor)), &page(&[3, 4]), &config)
+        .expect("can sign cursor")
+        .expect("full page has a cursor");
+
+    let third = apply_cursor_to_query_request(request(Some(&cursor)), &config).expect("third page");
+    let third_sql = QueryBuilder::build_sql_statement(&third, &config, false)
+        .expect("can build statement")
+        .to_string();
+    assert!(
+        third_sql.contains(r#"WHERE ("_origin"."id" > 4 OR "_origin"."id" IS NULL)"#),
+        "third page continues after the second"
+    );
+    assert!(
+        next_cursor(&request(Some(&cursor)), &page(&[5]), &config)
+            .expect("can sign cursor")
+            .is_none(),
+        "last page has no cursor"
+    );
+
+    let tampered = cursor.replacen('e', "f", 1);
+    assert!(
+        matches!(
+            apply_cursor_to_query_request(request(Some(&tampered)), &config),
+            Err(QueryBuilderError::InvalidCursor(_))
+        ),
+        "rejects tampered cursors"
+    );
+
+    let mut other_table = request(Some(&cursor));
+    if let QueryRequest::Table { table, .. } = &mut other_table {
+        *table = vec!["admins".to_owned()];
+    }
+    let mut other_selection = request(Some(&cursor));
+    if let QueryRequest::Table { query, .. } = &mut other_selection {
+        query.selection = Some(
+            serde_json::from_value(serde_json::json!({
+                "type": "binary_op",
+                "operator": "equal",
+                "column": { "name": "active", "column_type": "Bool" },
+                "value": { "type": "scalar", "value": true, "value_type": "Bool" }
+            }))
+            .expect("valid expression"),
+        );
+    }
+    for (request, query) in [
+        (other_table, "another table"),
+        (other_selection, "another selection"),
+    ] {
+        assert!(
+            matches!(
+                apply_cursor_to_query_request(request, &config),
+                Err(QueryBuilderError::InvalidCursor(_))
+            ),
+            "rejects cursors used for a query of {query}"
+        );
+    }
+}
+
+#[test]
+fn continues_after_given_values_instead_of_an_offset() {
+    use super::QueryBuilder;
+
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": ""
+    }))
+    .expect("valid config");
+    let request = |page: serde_json::Value| -> QueryRequest {
+        let mut query = serde_json::json!({
+            "fields": {
+                "created_at": { "type": "column", "column": "created_at", "column_type": "DateTime" },
+                "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+            },
+            "order_by": {
+                "relations": {},
+                "elements": [{
+                    "order_direction": "desc",
+                    "target": { "type": "column", "column": "created_at" },
+                    "target_path": []
+                }, {
+                    "order_direction": "asc",
+                    "target": { "type": "column", "column": "id" },
+                    "target_path": []
+                }]
+            },
+            "limit": 10
+        });
+        query
+            .as_object_mut()
+            .expect("query is an object")
+            .extend(page.as_object().expect("page is an object").to_owned());
+        serde_json::from_value(serde_json::json!({
+            "table": ["events"],
+            "table_relationships": [],
+            "query": query
+        }))
+        .expect("valid query request")
+    };
+    let sql = |request: QueryRequest| {
+        let request = apply_cursor_to_query_request(request, &config).expect("can apply cursor");
+        QueryBuilder::build_sql_statement(&request, &config, false)
+            .expect("can build statement")
+            .to_string()
+    };
+
+    let offset_sql = sql(request(serde_json::json!({ "offset": 10 })));
+    assert!(
+        offset_sql.contains("LIMIT 10 OFFSET 10"),
+        "offsets skip the rows of earlier pages"
+    );
+    assert!(!offset_sql.contains("WHERE"));
+
+    let keyset_sql = sql(request(serde_json::json!({
+        "after": ["2024-01-01 00:00:00", 10]
+    })));
+    assert!(
+        keyset_sql.contains(
+            r#"WHERE ("_origin"."created_at" < '2024-01-01 00:00:00' OR ("_origin"."created_at" = '2024-01-01 00:00:00' AND ("_origin"."id" > 10 OR "_origin"."id" IS NULL)))"#
+        ),
+        "after values filter to rows past the last row seen, without a cursor secret"
+    );
+    assert!(keyset_sql.contains("LIMIT 10") && !keyset_sql.contains("OFFSET"));
+
+    assert!(
+        matches!(
+            apply_cursor_to_query_request(
+                request(serde_json::json!({ "after": ["2024-01-01 00:00:00"] })),
+                &config
+            ),
+            Err(QueryBuilderError::InvalidCursor(_))
+        ),
+        "requires a value for each order column"
+    );
+    assert!(
+        matches!(
+            apply_cursor_to_query_request(
+                request(serde_json::json!({ "after": ["2024-01-01 00:00:00", 10], "offset": 10 })),
+                &config
+            ),
+            Err(QueryBuilderError::InvalidCursor(_))
+        ),
+        "rejects offsets, which would skip rows past the position"
+    );
+}
+
+#[test]
+fn continues_after_null_order_values() {
+    use super::QueryBuilder;
+
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "url": "http://localhost:8123",
+        "username": "default",
+        "password": ""
+    }))
+    .expect("valid config");
+    let sql = |nulls: serde_json::Value, after: serde_json::Value| {
+        let request: QueryRequest = serde_json::from_value(serde_json::json!({
+            "table": ["users"],
+            "table_relationships": [],
+            "query": {
+                "fields": {
+                    "name": { "type": "column", "column": "name", "column_type": "String" },
+                    "id": { "type": "column", "column": "id", "column_type": "UInt32" }
+                },
+                "order_by": {
+                    "relations": {},
+                    "elements": [{
+                        "order_direction": "asc",
+                        "target": { "type": "column", "column": "name" },
+                        "target_path": [],
+                        "nulls": nulls
+                    }, {
+                        "order_direction": "asc",
+                        "target": { "type": "column", "column": "id" },
+                        "target_path": []
+                    }]
+                },
+                "limit": 10,
+                "after": after
+            }
+        }))
+        .expect("valid query request");
+        let request = apply_cursor_to_query_request(request, &config).expect("can apply cursor");
+        QueryBuilder::build_sql_statement(&request, &config, false)
+            .expect("can build statement")
+            .to_string()
+    };
+
+    let after_null = sql(serde_json::Value::Null, serde_json::json!([null, 10]));
+    assert!(
+        after_null.contains(r#"WHERE ("_origin"."name" IS NULL AND ("_origin"."id" > 10 OR "_origin"."id" IS NULL))"#),
+        "only nulls follow a null ordered last, got {after_null}"
+    );
+    assert!(!after_null.contains("= NULL") && !after_null.contains("> NULL"));
+
+    let after_value = sql(serde_json::Value::Null, serde_json::json!(["a", 10]));
+    assert!(
+        after_value.contains(r#"("_origin"."name" > 'a' OR "_origin"."name" IS NULL)"#),
+        "nulls ordered last follow every value, got {after_value}"
+    );
+
+    let after_null_first = sql(serde_json::json!("first"), serde_json::json!([null, 10]));
+    assert!(
+        after_null_first.contains(r#""_origin"."name" IS NOT NULL"#),
+        "values follow nulls ordered first, got {after_null_first}"
+    );
+    let after_value_first = sql(serde_json::json!("first"), serde_json::json!(["a", 10]));
+    assert!(
+        !after_value_first.contains(r#""_origin"."name" IS NULL"#),
+        "nulls ordered first do not follow values, got {after_value_first}"
+    );
+}
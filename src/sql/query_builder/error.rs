@@ -12,6 +12,33 @@ pub enum QueryBuilderError {
     TableMissing(Vec<String>),
     RelationshipMissingInTable(String, Vec<String>),
     MisshapenTableName(Vec<String>),
+    UnsupportedServerTimeAnchor(String),
+    ServerTimeComparisonOnNonTemporalColumn(String),
+    DuplicateForeachColumn(String),
+    /// the index of a foreach row, and the keys it has or lacks compared to the first row
+    MismatchedForeachKeys(usize, Vec<String>),
+    MissingQueryPortion(&'static str),
+    InvalidIpLiteral(String),
+    InvalidBigIntLiteral(String),
+    InvalidLimit(String),
+    InvalidHaving(String),
+    InvalidAggregate(String),
+    InvalidPrewhere(String),
+    InvalidFill(String),
+    InvalidGroupBy(String),
+    InvalidSample(String),
+    InvalidTableNameTemplate(String),
+    MissingSessionVariable(String),
+    UnsafeSessionVariable(String),
+    RawSqlNotAllowed,
+    InvalidRawSql(String),
+    OrderByColumnMissing(String, Vec<String>),
+    OrderByMatchAnyColumn(String),
+    InvalidCursor(String),
+    InvalidQuantileLevel(f64),
+    /// only string columns can be masked
+    MaskedColumnNotString(String),
+    SystemTableNotAllowed(String),
 }
 
 impl Display for QueryBuilderError {
@@ -23,7 +50,7 @@ impl Display for QueryBuilderError {
             }
             QueryBuilderError::RightHandColumnComparisonNotSupported(column) => write!(
                 f,
-                "Right hand column comparison is not supported, attempted to compare column \"{}\"",
+                "Right hand column comparison across relationships is not supported, attempted to compare column \"{}\"",
                 column
             ),
             QueryBuilderError::UnsupportedColumnComparisonPath(path) => {
@@ -45,6 +72,97 @@ impl Display for QueryBuilderError {
                 "Misshapen table name, expected an array with a single string member, got {:?}",
                 table
             ),
+            QueryBuilderError::UnsupportedServerTimeAnchor(anchor) => write!(
+                f,
+                "Unsupported server time anchor \"{}\", expected one of now, today, yesterday",
+                anchor
+            ),
+            QueryBuilderError::ServerTimeComparisonOnNonTemporalColumn(column) => write!(
+                f,
+                "Server time anchors can only be compared to date or datetime columns, attempted to compare column \"{}\"",
+                column
+            ),
+            QueryBuilderError::InvalidTableNameTemplate(template) => {
+                write!(f, "Invalid table name template: {}", template)
+            }
+            QueryBuilderError::MissingSessionVariable(variable) => write!(
+                f,
+                "Missing session variable {} required by table name template",
+                variable
+            ),
+            QueryBuilderError::UnsafeSessionVariable(variable) => write!(
+                f,
+                "Session variable {} may only contain letters, digits, and underscores when used in a table name",
+                variable
+            ),
+            QueryBuilderError::InvalidSample(message) => write!(f, "Invalid sample: {}", message),
+            QueryBuilderError::InvalidLimit(message) => write!(f, "Invalid limit: {}", message),
+            QueryBuilderError::InvalidHaving(message) => write!(f, "Invalid having: {}", message),
+            QueryBuilderError::InvalidAggregate(message) => {
+                write!(f, "Invalid aggregate: {}", message)
+            }
+            QueryBuilderError::InvalidPrewhere(message) => {
+                write!(f, "Invalid prewhere: {}", message)
+            }
+            QueryBuilderError::InvalidFill(message) => write!(f, "Invalid fill: {}", message),
+            QueryBuilderError::InvalidGroupBy(message) => {
+                write!(f, "Invalid group by: {}", message)
+            }
+            QueryBuilderError::InvalidBigIntLiteral(value) => {
+                write!(f, "Invalid large integer literal: {}", value)
+            }
+            QueryBuilderError::InvalidIpLiteral(value) => {
+                write!(f, "Invalid ip address literal: {}", value)
+            }
+            QueryBuilderError::MissingQueryPortion(portion) => write!(
+                f,
+                "Cannot build the {} portion of a query that does not request it",
+                portion
+            ),
+            QueryBuilderError::OrderByColumnMissing(column, path) => write!(
+                f,
+                "Cannot order by \"{}\" at relationship path {:?}, the path is missing from order by relations",
+                column, path
+            ),
+            QueryBuilderError::InvalidQuantileLevel(level) => write!(
+                f,
+                "Invalid quantile level {}, expected a value between 0 and 1",
+                level
+            ),
+            QueryBuilderError::InvalidCursor(message) => write!(f, "Invalid cursor: {}", message),
+            QueryBuilderError::OrderByMatchAnyColumn(relationship) => write!(
+                f,
+                "Cannot order by relationship \"{}\", as it matches any mapped column",
+                relationship
+            ),
+            QueryBuilderError::RawSqlNotAllowed => write!(
+                f,
+                "Raw sql predicates are not enabled for this source"
+            ),
+            QueryBuilderError::InvalidRawSql(message) => {
+                write!(f, "Invalid raw sql predicate: {}", message)
+            }
+            QueryBuilderError::MaskedColumnNotString(column) => write!(
+                f,
+                "Column \"{}\" is masked, but only string columns can be masked",
+                column
+            ),
+            QueryBuilderError::SystemTableNotAllowed(table) => write!(
+                f,
+                "System table {} is not in the allowed system tables for this source",
+                table
+            ),
+            QueryBuilderError::DuplicateForeachColumn(column) => write!(
+                f,
+                "Column \"{}\" appears in more than one foreach collection",
+                column
+            ),
+            QueryBuilderError::MismatchedForeachKeys(index, keys) => write!(
+                f,
+                "Foreach row {} does not have the same keys as the first row, differing keys: {}",
+                index,
+                keys.join(", ")
+            ),
         }
     }
 }
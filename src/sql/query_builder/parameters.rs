@@ -0,0 +1,253 @@
+use indexmap::IndexMap;
+
+use crate::server::api::query_request::ScalarType;
+use crate::sql::ast::DataType;
+
+use super::{type_cast, BoundParam};
+
+/// The text value of each parameter of a statement built with clickhouse query parameters, to be sent as `param_<name>`.
+/// Clickhouse parses the values as the type annotated in the statement, so they are never parsed as sql
+pub fn clickhouse_query_parameters(
+    parameters: &IndexMap<String, BoundParam>,
+) -> Vec<(String, String)> {
+    parameters
+        .iter()
+        .map(|(placeholder, parameter)| (placeholder.to_owned(), parameter_value(parameter)))
+        .collect()
+}
+
+/// the type a parameter is parsed as. Dates with a time are sent as strings, so functions reading them in a
+/// column timezone parse them like inline literals, and json is sent as its serialized text
+fn scalar_parameter_type(value_type: &ScalarType) -> DataType {
+    match value_type {
+        ScalarType::DateTime | ScalarType::DateTime64 | ScalarType::Json => DataType::String,
+        // nullability is annotated from the value instead
+        value_type => match type_cast(value_type) {
+            DataType::Nullable(data_type) => *data_type,
+            data_type => data_type,
+        },
+    }
+}
+
+/// the type a parameter is annotated with in the statement
+pub(super) fn parameter_type(parameter: &BoundParam) -> DataType {
+    match parameter {
+        BoundParam::Number(number) if number.is_u64() => DataType::UInt64,
+        BoundParam::Number(number) if number.is_i64() => DataType::Int64,
+        BoundParam::Number(_) => DataType::Float64,
+        BoundParam::Value {
+            value: serde_json::Value::Null,
+            value_type,
+        } => DataType::nullable(scalar_parameter_type(value_type)),
+        BoundParam::Value { value_type, .. } => scalar_parameter_type(value_type),
+        BoundParam::Array { values, value_type } => {
+            let element_type = scalar_parameter_type(value_type);
+            if values.iter().any(serde_json::Value::is_null) {
+                DataType::array(DataType::nullable(element_type))
+            } else {
+                DataType::array(element_type)
+            }
+        }
+    }
+}
+
+/// values are sent in the escaped text format clickhouse reads parameters in
+fn parameter_value(parameter: &BoundParam) -> String {
+    match parameter {
+        BoundParam::Number(number) => number.to_string(),
+        BoundParam::Value { value, .. } => match value {
+            serde_json::Value::Null => "\\N".to_owned(),
+            serde_json::Value::String(string) => escape_parameter(string),
+            value => escape_parameter(&value.to_string()),
+        },
+        // elements of arrays are written as literals, with strings quoted
+        BoundParam::Array { values, .. } => {
+            let elements = values
+                .iter()
+                .map(|value| match value {
+                    serde_json::Value::Null => "NULL".to_owned(),
+                    serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+                    serde_json::Value::String(string) => quote(string),
+                    value => quote(&value.to_string()),
+                })
+                .collect::<Vec<_>>();
+            escape_parameter(&format!("[{}]", elements.join(",")))
+        }
+    }
+}
+
+/// Escape a value for the text format clickhouse parses query parameters in, where `\N` is null
+pub fn escape_parameter(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[test]
+fn annotates_each_scalar_type() {
+    let cases = [
+        (ScalarType::Bool, serde_json::json!(true), "Bool", "true"),
+        (
+            ScalarType::String,
+            serde_json::json!("a\tb"),
+            "String",
+            "a\\tb",
+        ),
+        (
+            ScalarType::FixedString,
+            serde_json::json!("ab"),
+            "String",
+            "ab",
+        ),
+        (ScalarType::UInt8, serde_json::json!(1), "UInt8", "1"),
+        (ScalarType::UInt16, serde_json::json!(1), "UInt16", "1"),
+        (ScalarType::UInt32, serde_json::json!(1), "UInt32", "1"),
+        (ScalarType::UInt64, serde_json::json!(1), "UInt64", "1"),
+        (ScalarType::UInt128, serde_json::json!("1"), "UInt128", "1"),
+        (ScalarType::UInt256, serde_json::json!("1"), "UInt256", "1"),
+        (ScalarType::Int8, serde_json::json!(-1), "Int8", "-1"),
+        (ScalarType::Int16, serde_json::json!(-1), "Int16", "-1"),
+        (ScalarType::Int32, serde_json::json!(-1), "Int32", "-1"),
+        (ScalarType::Int64, serde_json::json!(-1), "Int64", "-1"),
+        (ScalarType::Int128, serde_json::json!("-1"), "Int128", "-1"),
+        (ScalarType::Int256, serde_json::json!("-1"), "Int256", "-1"),
+        (
+            ScalarType::Float32,
+            serde_json::json!(1.5),
+            "Float32",
+            "1.5",
+        ),
+        (
+            ScalarType::Float64,
+            serde_json::json!(1.5),
+            "Float64",
+            "1.5",
+        ),
+        (
+            ScalarType::Decimal,
+            serde_json::json!("1.25"),
+            "String",
+            "1.25",
+        ),
+        (
+            ScalarType::Date,
+            serde_json::json!("2024-01-01"),
+            "Date",
+            "2024-01-01",
+        ),
+        (
+            ScalarType::Date32,
+            serde_json::json!("2024-01-01"),
+            "Date32",
+            "2024-01-01",
+        ),
+        (
+            ScalarType::DateTime,
+            serde_json::json!("2024-01-01 00:00:00"),
+            "String",
+            "2024-01-01 00:00:00",
+        ),
+        (
+            ScalarType::DateTime64,
+            serde_json::json!("2024-01-01 00:00:00.5"),
+            "String",
+            "2024-01-01 00:00:00.5",
+        ),
+        (
+            ScalarType::Json,
+            serde_json::json!({ "a": 1 }),
+            "String",
+            r#"{"a":1}"#,
+        ),
+        (
+            ScalarType::Uuid,
+            serde_json::json!("61f0c404-5cb3-11e7-907b-a6006ad3dba0"),
+            "UUID",
+            "61f0c404-5cb3-11e7-907b-a6006ad3dba0",
+        ),
+        (
+            ScalarType::IPv4,
+            serde_json::json!("10.0.0.1"),
+            "IPv4",
+            "10.0.0.1",
+        ),
+        (ScalarType::IPv6, serde_json::json!("::1"), "IPv6", "::1"),
+        (ScalarType::Unknown, serde_json::json!("a"), "String", "a"),
+    ];
+
+    for (value_type, value, expected_type, expected_value) in cases {
+        let parameters = IndexMap::from([(
+            "__placeholder__0".to_owned(),
+            BoundParam::Value {
+                value,
+                value_type: value_type.to_owned(),
+            },
+        )]);
+
+        let values = clickhouse_query_parameters(&parameters);
+        assert_eq!(
+            parameter_type(&parameters["__placeholder__0"]).to_string(),
+            expected_type,
+            "annotates {value_type:?}"
+        );
+        assert_eq!(
+            values,
+            vec![("__placeholder__0".to_owned(), expected_value.to_owned())],
+            "formats {value_type:?}"
+        );
+    }
+}
+
+#[test]
+fn annotates_nulls_and_arrays() {
+    let parameters = IndexMap::from([
+        (
+            "__placeholder__1".to_owned(),
+            BoundParam::Value {
+                value: serde_json::Value::Null,
+                value_type: ScalarType::UInt32,
+            },
+        ),
+        (
+            "__placeholder__10".to_owned(),
+            BoundParam::Array {
+                values: vec![serde_json::json!("it's"), serde_json::Value::Null],
+                value_type: ScalarType::String,
+            },
+        ),
+    ]);
+    let statement = crate::sql::ast::Statement(crate::sql::ast::Query::new(
+        parameters
+            .iter()
+            .map(|(name, parameter)| {
+                crate::sql::ast::SelectItem::UnnamedExpr(crate::sql::ast::Expr::Value(
+                    crate::sql::ast::Value::QueryParameter {
+                        name: name.to_owned(),
+                        data_type: parameter_type(parameter),
+                    },
+                ))
+            })
+            .collect(),
+    ));
+
+    let values = clickhouse_query_parameters(&parameters);
+    assert_eq!(
+        statement.to_string(),
+        "SELECT {__placeholder__1:Nullable(UInt32)}, {__placeholder__10:Array(Nullable(String))} FORMAT JSON;"
+    );
+    assert_eq!(
+        values,
+        vec![
+            ("__placeholder__1".to_owned(), "\\N".to_owned()),
+            (
+                "__placeholder__10".to_owned(),
+                r"['it\\'s',NULL]".to_owned()
+            ),
+        ]
+    );
+}
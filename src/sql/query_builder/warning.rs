@@ -0,0 +1,40 @@
+use std::fmt::{Display, Formatter};
+
+/// Non fatal issues found while building a query, which may affect the correctness of results
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryWarning {
+    NonFinalReplacingMergeTreeRead(Vec<String>),
+    ApproximateDistinctCount(Vec<String>, String),
+    DefaultRelationshipLimit(Vec<String>, u64),
+    DefaultRowLimit(Vec<String>, u64),
+}
+
+impl Display for QueryWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryWarning::NonFinalReplacingMergeTreeRead(table) => write!(
+                f,
+                "Table {} is a ReplacingMergeTree read without FINAL, results may include duplicate rows",
+                table.join(".")
+            ),
+            QueryWarning::ApproximateDistinctCount(table, column) => write!(
+                f,
+                "Distinct count of column {} in table {} is approximate, as the table is estimated to read more rows than the configured threshold",
+                column,
+                table.join(".")
+            ),
+            QueryWarning::DefaultRelationshipLimit(table, limit) => write!(
+                f,
+                "Relationship rows from table {} have no limit, and are capped at the default of {} rows per parent row",
+                table.join("."),
+                limit
+            ),
+            QueryWarning::DefaultRowLimit(table, limit) => write!(
+                f,
+                "Rows from table {} have no limit, and are capped at the default of {} rows",
+                table.join("."),
+                limit
+            ),
+        }
+    }
+}